@@ -0,0 +1,416 @@
+use actix_web::{delete, get, web::{Data, Json, Path, Query}};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, convert::TryFrom, sync::Arc};
+use tracing::{Level, Span, instrument, field::Empty};
+
+use crate::{
+    client_api::auth::AdminToken,
+    error::{Error, ErrorKind},
+    events::{EventContent, room::{Member, Membership, PowerLevels}},
+    sign::Key,
+    state::StateResolver,
+    storage::{EventQuery, QueryType, Storage},
+    util::{MatrixId, StorageExt, storage::NewEvent},
+    ServerState,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct ListRoomsRequest {
+    #[serde(default)]
+    from: Option<String>,
+    #[serde(default = "default_list_rooms_limit")]
+    limit: usize,
+}
+
+fn default_list_rooms_limit() -> usize {
+    100
+}
+
+#[derive(Debug, Serialize)]
+pub struct ListRoomsResponse {
+    rooms: Vec<RoomDetails>,
+    total_rooms: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_batch: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RoomDetails {
+    room_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    creator: Option<MatrixId>,
+    joined_members: usize,
+    invited_members: usize,
+    events: usize,
+}
+
+#[get("/admin/rooms")]
+#[instrument(skip(state, admin), fields(username = Empty), err = Level::DEBUG)]
+pub async fn list_rooms(
+    state: Data<Arc<ServerState>>,
+    admin: AdminToken,
+    req: Query<ListRoomsRequest>,
+) -> Result<Json<ListRoomsResponse>, Error> {
+    Span::current().record("username", &admin.username.as_str());
+    let db = state.db_pool.get_handle().await?;
+    let from = match &req.from {
+        Some(from) => from.parse().map_err(|_| ErrorKind::InvalidParam(String::from("from")))?,
+        None => 0,
+    };
+
+    Ok(Json(room_list(&*db, from, req.limit).await?))
+}
+
+/// Lists up to `limit` rooms, ordered by room ID, starting from the `from`th.
+async fn room_list(
+    db: &dyn Storage,
+    from: usize,
+    limit: usize,
+) -> Result<ListRoomsResponse, Error> {
+    let mut room_ids = db.get_rooms().await?;
+    room_ids.sort();
+
+    let mut rooms = Vec::new();
+    for room_id in room_ids.iter().skip(from).take(limit) {
+        rooms.push(room_details(db, room_id).await?);
+    }
+    let end = from + rooms.len();
+    Ok(ListRoomsResponse {
+        rooms,
+        total_rooms: room_ids.len(),
+        next_batch: if end < room_ids.len() { Some(end.to_string()) } else { None },
+    })
+}
+
+async fn room_details(db: &dyn Storage, room_id: &str) -> Result<RoomDetails, Error> {
+    let (joined_members, invited_members) = db.get_room_member_counts(room_id).await?;
+    let name = match db.get_state_event(room_id, "m.room.name", "").await? {
+        Some(event) => match event.event_content {
+            EventContent::Name(name) => name.name,
+            _ => None,
+        },
+        None => None,
+    };
+    let creator = match db.get_state_event(room_id, "m.room.create", "").await? {
+        Some(event) => match event.event_content {
+            EventContent::Create(create) => Some(create.effective_creator(&event.sender).clone()),
+            _ => None,
+        },
+        None => None,
+    };
+    // stream orderings go up one event at a time from 0, so the latest one says how many events
+    // there are. Asking for the timeline from past its end only gets that back, without any events.
+    let (_, latest) = db.query_pdus(EventQuery {
+        query_type: QueryType::Timeline { from: usize::MAX, to: None },
+        room_id,
+        senders: &[],
+        not_senders: &[],
+        types: &[],
+        not_types: &[],
+        contains_json: None,
+    }, false).await?;
+
+    Ok(RoomDetails {
+        room_id: String::from(room_id),
+        name,
+        creator,
+        joined_members,
+        invited_members,
+        events: latest + 1,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ShutdownRoomRequest {
+    /// Whether to make all of the room's local members leave it
+    #[serde(default = "default_true")]
+    kick_members: bool,
+    /// Whether to delete the room's history as well
+    #[serde(default = "default_true")]
+    purge: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for ShutdownRoomRequest {
+    fn default() -> Self {
+        ShutdownRoomRequest {
+            kick_members: true,
+            purge: true,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShutdownRoomResponse {
+    kicked_users: Vec<MatrixId>,
+    /// Local members who are still in the room, because no one here had the power to kick them
+    failed_to_kick_users: Vec<MatrixId>,
+    local_aliases: Vec<String>,
+    purged: bool,
+}
+
+#[delete("/admin/rooms/{room_id}")]
+#[instrument(skip(state, admin, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn shutdown_room(
+    state: Data<Arc<ServerState>>,
+    admin: AdminToken,
+    Path(room_id): Path<String>,
+    req: Option<Json<ShutdownRoomRequest>>,
+) -> Result<Json<ShutdownRoomResponse>, Error> {
+    Span::current().record("username", &admin.username.as_str());
+    let db = state.db_pool.get_handle().await?;
+    let req = req.map(Json::into_inner).unwrap_or_default();
+
+    Ok(Json(shut_down(
+        &*db,
+        &state.state_resolver,
+        &state.keys,
+        &state.config.domain,
+        &room_id,
+        &req,
+    ).await?))
+}
+
+/// Makes the room unreachable: its local members are kicked, its aliases are deleted, and it's
+/// taken out of the room directory. If `req.purge` is set the room is then deleted from storage
+/// entirely.
+///
+/// Members are kicked by the local member with the most power in the room, who then leaves. Anyone
+/// who can't be kicked is listed in the response, but the rest of the shutdown still goes ahead.
+async fn shut_down(
+    db: &dyn Storage,
+    state_resolver: &StateResolver,
+    keys: &HashMap<String, Key>,
+    server_name: &str,
+    room_id: &str,
+    req: &ShutdownRoomRequest,
+) -> Result<ShutdownRoomResponse, Error> {
+    if !db.get_rooms().await?.iter().any(|id| id == room_id) {
+        return Err(ErrorKind::RoomNotFound.into());
+    }
+
+    let mut kicked_users = Vec::new();
+    let mut failed_to_kick_users = Vec::new();
+    if req.kick_members {
+        let mut creator = None;
+        let mut levels = None;
+        let mut members = Vec::new();
+        for event in db.get_full_state(room_id).await? {
+            match event.event_content {
+                EventContent::Create(content) => {
+                    creator = Some(content.effective_creator(&event.sender).clone());
+                },
+                EventContent::PowerLevels(content) => levels = Some(content),
+                EventContent::Member(member) => {
+                    if !matches!(member.membership, Membership::Join | Membership::Invite) {
+                        continue;
+                    }
+                    // we can only send events on behalf of our own users
+                    match event.state_key.as_deref().map(MatrixId::try_from) {
+                        Some(Ok(user_id)) if user_id.has_domain(server_name) => {
+                            members.push((user_id, member.membership));
+                        },
+                        _ => {},
+                    }
+                },
+                _ => {},
+            }
+        }
+        let levels = match (levels, creator) {
+            (Some(levels), _) => levels,
+            (None, Some(creator)) => PowerLevels::no_event_default_levels(&creator),
+            (None, None) => PowerLevels::default(),
+        };
+        let kicker = members.iter()
+            .filter(|(_, membership)| *membership == Membership::Join)
+            .max_by_key(|(user_id, _)| levels.get_user_level(user_id))
+            .map(|(user_id, _)| user_id.clone());
+        // the kicker goes last, since once they've left they can't kick anyone else
+        members.sort_by_key(|(user_id, _)| Some(user_id) == kicker.as_ref());
+
+        for (user_id, _) in members {
+            let kick = NewEvent {
+                event_content: EventContent::Member(Member {
+                    avatar_url: None,
+                    displayname: None,
+                    membership: Membership::Leave,
+                    is_direct: None,
+                    reason: Some(String::from("The room has been shut down")),
+                    third_party_invite: None,
+                    join_authorised_via_users_server: None,
+                }),
+                // with no one here to kick them, invitees can still turn their invites down
+                sender: kicker.clone().unwrap_or_else(|| user_id.clone()),
+                state_key: Some(user_id.clone_inner()),
+                redacts: None,
+                unsigned: None,
+            };
+            match db.add_event(room_id, kick, state_resolver, keys).await {
+                Ok(_) => kicked_users.push(user_id),
+                Err(e) => {
+                    tracing::warn!(room_id, user_id = user_id.as_str(), error = %e, "Failed to kick a member while shutting down a room");
+                    failed_to_kick_users.push(user_id);
+                },
+            }
+        }
+    }
+
+    let local_aliases = db.get_room_aliases(room_id).await?;
+    for alias in local_aliases.iter() {
+        db.delete_room_alias(alias).await?;
+    }
+    db.set_room_published(room_id, false).await?;
+
+    if req.purge {
+        db.delete_room(room_id).await?;
+    }
+
+    Ok(ShutdownRoomResponse {
+        kicked_users,
+        failed_to_kick_users,
+        local_aliases,
+        purged: req.purge,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        client_api::auth::{admin_username, AccessToken},
+        events::{room::{Membership, Name, PowerLevels}, EventContent},
+        test_util::{assert_errcode, RoomBuilder, with_mem_db},
+        util::MatrixId,
+    };
+
+    use super::{room_list, shut_down, ShutdownRoomRequest};
+
+    #[test]
+    fn list_rooms() {
+        with_mem_db(|db, state_resolver| async move {
+            let admins = vec![String::from("alice")];
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let alice_token = db.create_access_token("alice", "ALICE").await.unwrap();
+            let bob_token = db.create_access_token("bob", "BOB").await.unwrap();
+
+            RoomBuilder::new(&*db, &state_resolver, "!a:example.org", &alice)
+                .state(&alice, EventContent::Name(Name { name: Some(String::from("First")) }), "")
+                .invite(&alice, &bob)
+                .build()
+                .await;
+            RoomBuilder::new(&*db, &state_resolver, "!b:example.org", &bob).build().await;
+            RoomBuilder::new(&*db, &state_resolver, "!c:example.org", &bob)
+                .state(&bob, EventContent::Name(Name { name: Some(String::from("Third")) }), "")
+                .build()
+                .await;
+
+            assert_eq!(
+                admin_username(&*db, &admins, AccessToken(alice_token)).await.unwrap(),
+                "alice",
+            );
+            let err = admin_username(&*db, &admins, AccessToken(bob_token)).await.unwrap_err();
+            assert_errcode!(err, "M_FORBIDDEN");
+
+            let page = room_list(&*db, 0, 2).await.unwrap();
+            assert_eq!(page.total_rooms, 3);
+            assert_eq!(page.next_batch.as_deref(), Some("2"));
+            assert_eq!(page.rooms.len(), 2);
+            let first = &page.rooms[0];
+            assert_eq!(first.room_id, "!a:example.org");
+            assert_eq!(first.name.as_deref(), Some("First"));
+            assert_eq!(first.creator.as_ref(), Some(&alice));
+            assert_eq!(first.joined_members, 1);
+            assert_eq!(first.invited_members, 1);
+            // create, join, join rules, name and invite
+            assert_eq!(first.events, 5);
+            let second = &page.rooms[1];
+            assert_eq!(second.room_id, "!b:example.org");
+            assert_eq!(second.name, None);
+            assert_eq!(second.creator.as_ref(), Some(&bob));
+            assert_eq!(second.invited_members, 0);
+            assert_eq!(second.events, 3);
+
+            let page = room_list(&*db, 2, 2).await.unwrap();
+            assert_eq!(page.rooms.len(), 1);
+            assert_eq!(page.rooms[0].room_id, "!c:example.org");
+            assert_eq!(page.next_batch, None);
+        });
+    }
+
+    #[test]
+    fn shutdown_room() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            let carol = MatrixId::new("carol", "example.org").unwrap();
+            let dave = MatrixId::new("dave", "example.org").unwrap();
+            let room_id = "!doomed:example.org";
+            let kept_id = "!kept:example.org";
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+            let mut users = HashMap::new();
+            users.insert(alice.clone(), 100);
+            users.insert(dave.clone(), 100);
+            RoomBuilder::new(&*db, &state_resolver, kept_id, &alice)
+                .join(&bob)
+                .join(&dave)
+                .state(&alice, EventContent::PowerLevels(PowerLevels {
+                    users,
+                    users_default: Some(0),
+                    ..Default::default()
+                }), "")
+                .invite(&alice, &carol)
+                .build()
+                .await;
+            assert!(db.set_room_alias("#doomed:example.org", room_id, "alice").await.unwrap());
+            assert!(db.set_room_alias("#kept:example.org", kept_id, "alice").await.unwrap());
+            db.set_room_published(room_id, true).await.unwrap();
+
+            let req = ShutdownRoomRequest { kick_members: true, purge: false };
+            let res = shut_down(&*db, &state_resolver, &keys, "example.org", kept_id, &req)
+                .await.unwrap();
+            // alice and dave are as powerful as each other, so whichever kicks can't kick the other
+            assert_eq!(res.kicked_users.len(), 3);
+            assert!(res.kicked_users.contains(&bob));
+            assert!(res.kicked_users.contains(&carol));
+            assert_eq!(res.failed_to_kick_users.len(), 1);
+            let kicker = if res.failed_to_kick_users[0] == alice { &dave } else { &alice };
+            assert!(res.kicked_users.contains(kicker));
+            for user_id in [&bob, &carol, kicker].iter() {
+                let leave = db.get_state_event(kept_id, "m.room.member", user_id.as_str())
+                    .await.unwrap().unwrap();
+                assert_eq!(&leave.sender, kicker);
+                let content = leave.event_content.content_as_json();
+                assert_eq!(content["membership"], "leave");
+                assert_eq!(content["reason"], "The room has been shut down");
+            }
+            let stayed = &res.failed_to_kick_users[0];
+            assert_eq!(db.get_membership(stayed, kept_id).await.unwrap(), Some(Membership::Join));
+            assert!(db.get_rooms().await.unwrap().iter().any(|id| id == kept_id));
+            assert_eq!(db.get_room_alias("#kept:example.org").await.unwrap(), None);
+
+            let res = shut_down(&*db, &state_resolver, &keys, "example.org", room_id, &Default::default())
+                .await.unwrap();
+            assert_eq!(res.local_aliases, vec![String::from("#doomed:example.org")]);
+            assert!(res.purged);
+            assert!(!db.get_rooms().await.unwrap().iter().any(|id| id == room_id));
+            assert_eq!(db.get_room_alias("#doomed:example.org").await.unwrap(), None);
+            assert!(db.get_room_aliases(room_id).await.unwrap().is_empty());
+            assert!(db.get_published_rooms().await.unwrap().is_empty());
+            assert!(db.get_pdu(room_id, "$anything").await.unwrap().is_none());
+
+            let err = shut_down(&*db, &state_resolver, &keys, "example.org", room_id, &Default::default())
+                .await.unwrap_err();
+            assert_errcode!(err, "M_NOT_FOUND");
+        });
+    }
+}
@@ -1,16 +1,20 @@
 use actix_web::{
     dev::Payload,
-    web::{Data, Json},
+    web::{Data, Json, Query},
     get, post, HttpRequest, FromRequest,
 };
+use futures::future::{FutureExt, LocalBoxFuture};
+use ring::digest::{SHA256, digest};
 use tracing::{instrument, Level, span::Span, field::Empty};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{convert::TryFrom, sync::Arc};
+use std::{sync::Arc, time::Duration};
+use tokio::time::delay_for;
 use uuid::Uuid;
 
 use crate::{
-    error::{Error, ErrorKind}, util::MatrixId, ServerState
+    error::{Error, ErrorKind}, storage::{Medium, Storage, StorageManager, Threepid}, util::MatrixId,
+    ServerState
 };
 
 #[derive(Debug, Deserialize)]
@@ -22,33 +26,89 @@ enum LoginType {
 #[derive(Debug)]
 pub struct AccessToken(pub Uuid);
 
+/// Finds the access token in either the Authorization header or the query string.
+fn token_from_request(req: &HttpRequest) -> Result<Uuid, ErrorKind> {
+    if let Some(s) = req.headers().get("Authorization") {
+        let s: &str = s.to_str().map_err(|_| ErrorKind::MissingToken)?;
+        if !s.starts_with("Bearer ") {
+            return Err(ErrorKind::MissingToken);
+        }
+        let token = s.trim_start_matches("Bearer ").parse().map_err(|_| ErrorKind::UnknownToken)?;
+        Ok(token)
+    } else if let Some(pair) = req.uri().query().ok_or(ErrorKind::MissingToken)?.split('&').find(|pair| pair.starts_with("access_token")) {
+        let token = pair.trim_start_matches("access_token=").parse().map_err(|_| ErrorKind::UnknownToken)?;
+        Ok(token)
+    } else {
+        Err(ErrorKind::MissingToken)
+    }
+}
+
 impl FromRequest for AccessToken {
     type Error = Error;
     type Future = futures::future::Ready<Result<Self, Self::Error>>;
     type Config = ();
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
-        let res = (|| {
-            if let Some(s) = req.headers().get("Authorization") {
-                let s: &str = s.to_str().map_err(|_| ErrorKind::MissingToken)?;
-                if !s.starts_with("Bearer ") {
-                    return Err(ErrorKind::MissingToken);
-                }
-                let token = s.trim_start_matches("Bearer ").parse().map_err(|_| ErrorKind::UnknownToken)?;
-                Ok(token)
-            } else if let Some(pair) = req.uri().query().ok_or(ErrorKind::MissingToken)?.split('&').find(|pair| pair.starts_with("access_token")) {
-                let token = pair.trim_start_matches("access_token=").parse().map_err(|_| ErrorKind::UnknownToken)?;
-                Ok(token)
-            } else {
-                Err(ErrorKind::MissingToken)
-            }
-        })();
-        match res {
+        match token_from_request(req) {
             Ok(token) => futures::future::ok(AccessToken(token)),
             Err(e) => futures::future::err(e.into()),
         }
     }
 }
 
+/// An access token for endpoints that can also be used without one, such as reading
+/// world-readable rooms. A token that's there but malformed is still an error.
+#[derive(Debug)]
+pub struct OptionalAccessToken(pub Option<Uuid>);
+
+impl FromRequest for OptionalAccessToken {
+    type Error = Error;
+    type Future = futures::future::Ready<Result<Self, Self::Error>>;
+    type Config = ();
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        match token_from_request(req) {
+            Ok(token) => futures::future::ok(OptionalAccessToken(Some(token))),
+            Err(ErrorKind::MissingToken) => futures::future::ok(OptionalAccessToken(None)),
+            Err(e) => futures::future::err(e.into()),
+        }
+    }
+}
+
+/// An access token belonging to one of the server admins named in the config.
+#[derive(Debug)]
+pub struct AdminToken {
+    pub username: String,
+}
+
+impl FromRequest for AdminToken {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = ();
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let token = AccessToken::from_request(req, payload).into_inner();
+        let state = req.app_data::<Data<Arc<ServerState>>>().cloned();
+        async move {
+            let token = token?;
+            let state = state.expect("server state missing");
+            let db = state.db_pool.get_handle().await?;
+            let username = admin_username(&*db, &state.config.admins, token).await?;
+            Ok(AdminToken { username })
+        }.boxed_local()
+    }
+}
+
+/// Gets the username the token belongs to, as long as they're an admin.
+pub async fn admin_username(
+    db: &dyn Storage,
+    admins: &[String],
+    token: AccessToken,
+) -> Result<String, Error> {
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    if !admins.contains(&username) {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    Ok(username)
+}
+
 #[get("/login")]
 #[instrument]
 pub async fn get_supported_login_types() -> Json<serde_json::Value> {
@@ -70,7 +130,9 @@ pub struct LoginRequest {
     password: Option<String>,
     token: Option<String>,
     device_id: Option<String>,
-    initial_device_display_name: String,
+    initial_device_display_name: Option<String>,
+    #[serde(default)]
+    refresh_token: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -92,10 +154,80 @@ enum Identifier {
     },
 }
 
+/// The token fields shared by the login and registration responses.
+#[derive(Serialize)]
+pub struct IssuedTokens {
+    access_token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_in_ms: Option<u64>,
+}
+
+/// Gets the username meant by the user a client is logging in as, which can be either a full
+/// Matrix ID or just the localpart.
+///
+/// New localparts can't have uppercase letters, so whatever the user typed is lowercased; that
+/// way `Alice` still finds `alice`.
+fn login_username(user: &str) -> String {
+    let localpart = match user.strip_prefix('@') {
+        Some(mxid) => mxid.split(':').next().unwrap(),
+        None => user,
+    };
+    localpart.to_lowercase()
+}
+
+/// Creates an access token for a device. Refresh tokens (and therefore expiring access tokens)
+/// are only handed out to clients which ask for them, as older clients don't expect either.
+async fn issue_tokens(
+    db: &dyn Storage,
+    username: &str,
+    device_id: &str,
+    refresh: bool,
+    lifetime: Duration,
+) -> Result<IssuedTokens, Error> {
+    if refresh {
+        let (access_token, refresh_token) = db.create_refreshable_access_token(
+            username,
+            device_id,
+            lifetime,
+        ).await?;
+        Ok(IssuedTokens {
+            access_token: format!("{}", access_token.to_hyphenated()),
+            refresh_token: Some(format!("{}", refresh_token.to_hyphenated())),
+            expires_in_ms: Some(lifetime.as_millis() as u64),
+        })
+    } else {
+        let access_token = db.create_access_token(username, device_id).await?;
+        Ok(IssuedTokens {
+            access_token: format!("{}", access_token.to_hyphenated()),
+            refresh_token: None,
+            expires_in_ms: None,
+        })
+    }
+}
+
+/// Gives a device the name its client asked for on logging in, unless it already has one.
+async fn name_device(
+    db: &dyn Storage,
+    username: &str,
+    device_id: &str,
+    display_name: Option<&str>,
+) -> Result<(), Error> {
+    if let Some(display_name) = display_name {
+        let device = db.get_device(username, device_id).await?;
+        if device.map(|device| device.display_name.is_none()).unwrap_or(false) {
+            db.set_device_display_name(username, device_id, Some(display_name)).await?;
+        }
+    }
+    Ok(())
+}
+
 #[derive(Serialize)]
 pub struct LoginResponse {
     user_id: MatrixId,
-    access_token: String,
+    #[serde(flatten)]
+    tokens: IssuedTokens,
     device_id: String,
     //TODO: This is deprecated, but Fractal is the only client that doesn't require it. Remove it
     // once all the other clients have updated to current spec
@@ -106,38 +238,44 @@ pub struct LoginResponse {
 #[instrument(skip_all, err = Level::DEBUG)]
 pub async fn login(
     state: Data<Arc<ServerState>>,
+    http_req: HttpRequest,
     req: Json<LoginRequest>,
 ) -> Result<Json<LoginResponse>, Error> {
     let req = req.into_inner();
 
     let username = match req.identifier {
-        Identifier::Username { user } => {
-            let res = MatrixId::try_from(&*user);
-            match res {
-                Ok(mxid) => mxid.localpart().to_string(),
-                Err(_) => user,
-            }
-        },
+        Identifier::Username { user } => login_username(&user),
         _ => return Err(ErrorKind::Unimplemented.into()),
     };
     let password = req.password.ok_or(ErrorKind::Unimplemented)?;
+    // keyed by address as well, so guessing from one address can't lock the user out everywhere
+    let address = http_req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+    let limit_key = format!("{} {}", address, username);
+    state.login_limiter.check(&limit_key)?;
 
     let db = state.db_pool.get_handle().await?;
     if !db.verify_password(&username, &password).await? {
+        state.login_limiter.record_failure(&limit_key);
         return Err(ErrorKind::Forbidden.into());
     }
 
     let device_id = req.device_id.unwrap_or(format!("{:08X}", rand::random::<u32>()));
-    let access_token = db.create_access_token(&username, &device_id).await?;
+    let tokens = issue_tokens(
+        &*db,
+        &username,
+        &device_id,
+        req.refresh_token,
+        Duration::from_millis(state.config.access_token_lifetime_ms),
+    ).await?;
+    name_device(&*db, &username, &device_id, req.initial_device_display_name.as_deref()).await?;
 
     tracing::info!(username = username.as_str(), "User logged in");
 
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
-    let access_token = format!("{}", access_token.to_hyphenated());
 
     Ok(Json(LoginResponse {
         user_id,
-        access_token,
+        tokens,
         device_id,
         home_server: state.config.domain.clone(),
     }))
@@ -147,7 +285,7 @@ pub async fn login(
 #[instrument(skip(state), err = Level::DEBUG)]
 pub async fn logout(state: Data<Arc<ServerState>>, token: AccessToken) -> Result<Json<()>, Error> {
     let db = state.db_pool.get_handle().await?;
-    db.delete_access_token(token.0).await?;
+    db.logout_device(token.0).await?;
     Ok(Json(()))
 }
 
@@ -155,45 +293,133 @@ pub async fn logout(state: Data<Arc<ServerState>>, token: AccessToken) -> Result
 #[instrument(skip(state), err = Level::DEBUG)]
 pub async fn logout_all(state: Data<Arc<ServerState>>, token: AccessToken) -> Result<Json<()>, Error> {
     let db = state.db_pool.get_handle().await?;
-    db.delete_all_access_tokens(token.0).await?;
+    db.logout_all_devices(token.0).await?;
     Ok(Json(()))
 }
 
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[post("/refresh")]
+#[instrument(skip_all, err = Level::DEBUG)]
+pub async fn refresh_access_token(
+    state: Data<Arc<ServerState>>,
+    req: Json<RefreshRequest>,
+) -> Result<Json<IssuedTokens>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let lifetime = Duration::from_millis(state.config.access_token_lifetime_ms);
+    Ok(Json(refresh_tokens(&*db, &req.refresh_token, lifetime).await?))
+}
+
+/// Swaps a refresh token for a new access token and refresh token.
+async fn refresh_tokens(
+    db: &dyn Storage,
+    refresh_token: &str,
+    lifetime: Duration,
+) -> Result<IssuedTokens, Error> {
+    let refresh_token = Uuid::parse_str(refresh_token).map_err(|_| ErrorKind::UnknownToken)?;
+    let (access_token, refresh_token) = db.refresh_access_token(refresh_token, lifetime).await?
+        .ok_or(ErrorKind::UnknownToken)?;
+    Ok(IssuedTokens {
+        access_token: format!("{}", access_token.to_hyphenated()),
+        refresh_token: Some(format!("{}", refresh_token.to_hyphenated())),
+        expires_in_ms: Some(lifetime.as_millis() as u64),
+    })
+}
+
+/// Deletes expired access tokens and user-interactive auth sessions every
+/// `token_purge_interval_ms`, for as long as the server runs, so that clients which never come
+/// back to use them don't leave them around forever.
+pub async fn purge_tokens_periodically(state: Arc<ServerState>) {
+    let interval = Duration::from_millis(state.config.token_purge_interval_ms);
+    loop {
+        delay_for(interval).await;
+        let now = chrono::Utc::now().timestamp_millis();
+        match purge_expired(&*state.db_pool, now).await {
+            Ok((0, 0)) => {},
+            Ok((tokens, sessions)) => {
+                tracing::info!(tokens, sessions, "Purged expired access tokens and auth sessions")
+            },
+            Err(e) => tracing::warn!(error = %e, "Failed to purge expired access tokens"),
+        }
+    }
+}
+
+/// Returns how many access tokens and user-interactive auth sessions were purged.
+async fn purge_expired(db_pool: &dyn StorageManager, now: i64) -> Result<(usize, usize), Error> {
+    let tokens = db_pool.purge_expired_tokens(now).await?;
+    let db = db_pool.get_handle().await?;
+    let sessions = db.purge_expired_uiaa_sessions(now - UIAA_SESSION_LIFETIME_MS).await?;
+    Ok((tokens, sessions))
+}
+
 #[derive(Debug, Deserialize)]
 pub struct RegisterRequest {
-    auth: serde_json::Value,
+    /// Missing on the first request, which gets back the user-interactive auth session to use
+    auth: Option<serde_json::Value>,
+    #[serde(default)]
     bind_email: bool,
+    #[serde(default)]
     bind_msisdn: bool,
     username: String,
     password: String,
     device_id: Option<String>,
-    initial_device_display_name: String,
+    initial_device_display_name: Option<String>,
+    #[serde(default)]
     inhibit_login: bool,
+    #[serde(default)]
+    refresh_token: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThreepidCreds {
+    pub sid: String,
+    pub client_secret: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GuestRegisterRequest {
+    #[serde(default)]
+    refresh_token: bool,
 }
 
 #[post("/register")]
 #[instrument(skip_all, fields(username = Empty), err = Level::DEBUG)]
 pub async fn register(
     state: Data<Arc<ServerState>>,
-    req: Json<RegisterRequest>,
+    req: Json<serde_json::Value>,
     http_req: HttpRequest
 ) -> Result<Json<serde_json::Value>, Error> {
-    let req = req.into_inner();
     let query_string = http_req.query_string();
     match query_string.split('&').find(|s| s.starts_with("kind=")) {
         Some("kind=user") => {},
-        Some("kind=guest") => return Err(ErrorKind::Unimplemented.into()),
+        Some("kind=guest") => {
+            let req: GuestRegisterRequest = serde_json::from_value(req.into_inner())?;
+            return register_guest(&state, req).await;
+        },
         Some(x) => return Err(ErrorKind::InvalidParam(x.to_string()).into()),
         None => return Err(ErrorKind::MissingParam("kind".to_string()).into()),
     }
+    let mut params = req.into_inner();
+    let req: RegisterRequest = serde_json::from_value(params.clone())?;
+    if let Some(params) = params.as_object_mut() {
+        params.remove("auth");
+    }
 
     Span::current().record("username", &&*req.username);
 
     let user_id = MatrixId::new(&req.username, &state.config.domain)
-        .map_err(|e| ErrorKind::BadJson(format!("{}", e)))?;
+        .map_err(|e| ErrorKind::InvalidUsername(e.to_string()))?;
 
     let db = state.db_pool.get_handle().await?;
-    db.create_user(&user_id.localpart(), &req.password).await?;
+    // there's no point making the client authenticate for a name it can't have
+    if db.user_exists(user_id.localpart()).await? {
+        return Err(ErrorKind::UsernameTaken.into());
+    }
+    let threepid = register_auth(&*db, req.auth.as_ref(), &params).await?;
+    create_account(&*db, user_id.localpart(), &req.password, threepid).await?;
     if req.inhibit_login {
         return Ok(Json(json!({
             "user_id": req.username
@@ -201,12 +427,881 @@ pub async fn register(
     }
 
     let device_id = req.device_id.unwrap_or(format!("{:08X}", rand::random::<u32>()));
-    let access_token = db.create_access_token(&user_id.localpart(), &device_id).await?;
-    let access_token = format!("{}", access_token.to_hyphenated());
+    let tokens = issue_tokens(
+        &*db,
+        user_id.localpart(),
+        &device_id,
+        req.refresh_token,
+        Duration::from_millis(state.config.access_token_lifetime_ms),
+    ).await?;
+    name_device(
+        &*db,
+        user_id.localpart(),
+        &device_id,
+        req.initial_device_display_name.as_deref(),
+    ).await?;
+
+    let mut response = serde_json::to_value(tokens).unwrap();
+    response["user_id"] = json!(user_id);
+    response["device_id"] = json!(device_id);
+    Ok(Json(response))
+}
+
+/// Creates an account, bound to the 3pid it was registered with if there is one. If the 3pid
+/// can't be bound, e.g. because someone else got to it first, the account is deleted again so the
+/// client can try again with the same username.
+async fn create_account(
+    db: &dyn Storage,
+    username: &str,
+    password: &str,
+    threepid: Option<Threepid>,
+) -> Result<(), Error> {
+    db.create_user(username, password).await?;
+    if let Some(threepid) = threepid {
+        if let Err(e) = db.add_threepid(username, threepid).await {
+            if let Err(delete_err) = db.delete_user(username).await {
+                tracing::warn!(username, error = %delete_err, "Failed to delete an account whose 3pid couldn't be bound");
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// The sets of user-interactive auth stages that allow registering. Each is a single stage, so the
+/// request completing a flow is always the one carrying that stage's credentials.
+const REGISTER_FLOWS: &[&[&str]] = &[
+    &["m.login.dummy"],
+    &["m.login.email.identity"],
+    &["m.login.msisdn"],
+];
+
+/// How long a user-interactive auth session lasts, in milliseconds.
+const UIAA_SESSION_LIFETIME_MS: i64 = 15 * 60 * 1000;
+
+/// The sets of user-interactive auth stages that confirm a logged in user really is them.
+const PASSWORD_FLOWS: &[&[&str]] = &[&["m.login.password"]];
+
+/// A digest of what a request protected by user-interactive auth asks for, so that a session
+/// started for one request can't be used to finish another. It's hashed since requests can
+/// contain passwords.
+fn request_digest(request: &serde_json::Value) -> String {
+    let json = serde_json::to_vec(request).unwrap();
+    base64::encode_config(digest(&SHA256, &json).as_ref(), base64::STANDARD_NO_PAD)
+}
+
+/// Gets the user-interactive auth session that `auth` continues, or starts a new one if it
+/// doesn't name one. `request` is what the request asks for, minus `auth`; it has to be the same
+/// for every request in a session.
+async fn uiaa_session(
+    db: &dyn Storage,
+    auth: Option<&serde_json::Value>,
+    request: &serde_json::Value,
+) -> Result<String, Error> {
+    let request = request_digest(request);
+    match auth.and_then(|auth| auth["session"].as_str()) {
+        Some(session_id) => {
+            let now = chrono::Utc::now().timestamp_millis();
+            let session = db.get_uiaa_session(session_id).await?
+                .filter(|s| now - s.created_at < UIAA_SESSION_LIFETIME_MS);
+            let session = match session {
+                Some(session) => session,
+                None => {
+                    // expired sessions are just as unusable as ones that never existed
+                    db.delete_uiaa_session(session_id).await?;
+                    let msg = format!("unknown or expired session {}", session_id);
+                    return Err(ErrorKind::InvalidParam(msg).into());
+                },
+            };
+            if session.request != request {
+                // the client authenticated for something other than what it's now asking for
+                return Err(ErrorKind::Forbidden.into());
+            }
+            Ok(session_id.to_owned())
+        },
+        None => db.create_uiaa_session(&request).await,
+    }
+}
+
+/// Succeeds, using up the session, if it has completed any of `flows`. Otherwise fails with
+/// `ErrorKind::AuthRequired`, telling the client what's left to do.
+async fn finish_uiaa(db: &dyn Storage, session_id: &str, flows: &[&[&str]]) -> Result<(), Error> {
+    let completed = db.get_uiaa_session(session_id).await?
+        .map(|session| session.completed)
+        .unwrap_or_default();
+    let is_done = flows.iter()
+        .any(|flow| flow.iter().all(|stage| completed.iter().any(|c| c == stage)));
+    if is_done {
+        db.delete_uiaa_session(session_id).await?;
+        return Ok(());
+    }
+    let flows: Vec<_> = flows.iter().map(|stages| json!({ "stages": stages })).collect();
+    Err(ErrorKind::AuthRequired(json!({
+        "flows": flows,
+        "params": {},
+        "session": session_id,
+        "completed": completed,
+    })).into())
+}
+
+/// Does the user-interactive auth stage in `auth`, if any, as part of registering.
+///
+/// Once a flow is complete this returns the 3pid the client proved it owns, if the flow involved
+/// one.
+async fn register_auth(
+    db: &dyn Storage,
+    auth: Option<&serde_json::Value>,
+    request: &serde_json::Value,
+) -> Result<Option<Threepid>, Error> {
+    let session_id = uiaa_session(db, auth, request).await?;
+    let stage = auth.and_then(|auth| auth["type"].as_str());
+    let threepid = match stage {
+        Some("m.login.dummy") | None => None,
+        Some(stage @ "m.login.email.identity") | Some(stage @ "m.login.msisdn") => {
+            // email and msisdn auth stages carry the credentials of a validated 3pid session
+            let auth = auth.unwrap();
+            let creds = auth.get("threepid_creds").or_else(|| auth.get("threepidCreds"))
+                .ok_or_else(|| ErrorKind::BadJson(String::from("missing threepid_creds")))?;
+            let threepid = validated_threepid(db, &serde_json::from_value(creds.clone())?).await?;
+            let expected = match stage {
+                "m.login.email.identity" => Medium::Email,
+                _ => Medium::Msisdn,
+            };
+            if threepid.medium != expected {
+                return Err(ErrorKind::ThreepidAuthFailed.into());
+            }
+            if db.get_threepid_owner(threepid.medium, &threepid.address).await?.is_some() {
+                return Err(ErrorKind::ThreepidInUse.into());
+            }
+            Some(threepid)
+        },
+        Some(stage) => {
+            return Err(ErrorKind::InvalidParam(format!("unsupported auth type {}", stage)).into());
+        },
+    };
+    if let Some(stage) = stage {
+        db.complete_uiaa_stage(&session_id, stage).await?;
+    }
+    finish_uiaa(db, &session_id, REGISTER_FLOWS).await?;
+    Ok(threepid)
+}
+
+/// Has a logged in user confirm their password through user-interactive auth, before letting
+/// them do something drastic to their account. `request` describes the drastic thing, as for
+/// `uiaa_session`.
+pub async fn confirm_password(
+    db: &dyn Storage,
+    username: &str,
+    auth: Option<&serde_json::Value>,
+    request: &serde_json::Value,
+) -> Result<(), Error> {
+    let session_id = uiaa_session(db, auth, request).await?;
+    match auth.and_then(|auth| auth["type"].as_str()) {
+        Some(stage @ "m.login.password") => {
+            let auth = auth.unwrap();
+            // older clients send the user directly rather than in an identifier
+            let user = auth["identifier"]["user"].as_str()
+                .or_else(|| auth["user"].as_str())
+                .unwrap_or(username);
+            let localpart = login_username(user);
+            let password = auth["password"].as_str()
+                .ok_or_else(|| ErrorKind::BadJson(String::from("missing password")))?;
+            if localpart != username || !db.verify_password(username, password).await? {
+                return Err(ErrorKind::Forbidden.into());
+            }
+            db.complete_uiaa_stage(&session_id, stage).await?;
+        },
+        None => {},
+        Some(stage) => {
+            return Err(ErrorKind::InvalidParam(format!("unsupported auth type {}", stage)).into());
+        },
+    }
+    finish_uiaa(db, &session_id, PASSWORD_FLOWS).await
+}
+
+/// Returns the 3pid that a session has proven ownership of, ready to be bound to an account.
+pub async fn validated_threepid(db: &dyn Storage, creds: &ThreepidCreds) -> Result<Threepid, Error> {
+    let session = db.get_threepid_session(&creds.sid, &creds.client_secret).await?
+        .ok_or(ErrorKind::ThreepidAuthFailed)?;
+    let validated_at = session.validated_at.ok_or(ErrorKind::ThreepidAuthFailed)?;
+    Ok(Threepid {
+        medium: session.medium,
+        address: session.address,
+        validated_at,
+        added_at: chrono::Utc::now().timestamp_millis(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UsernameAvailableRequest {
+    username: String,
+}
+
+#[get("/register/available")]
+#[instrument(skip(state), err = Level::DEBUG)]
+pub async fn check_username_available(
+    state: Data<Arc<ServerState>>,
+    req: Query<UsernameAvailableRequest>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    username_available(&*db, &req.username, &state.config.domain).await?;
+    Ok(Json(json!({ "available": true })))
+}
+
+/// Succeeds if `username` could be registered right now.
+async fn username_available(db: &dyn Storage, username: &str, domain: &str) -> Result<(), Error> {
+    MatrixId::validate_parts(username, domain)
+        .map_err(|e| ErrorKind::InvalidUsername(e.to_string()))?;
+    if db.user_exists(username).await? {
+        return Err(ErrorKind::UsernameTaken.into());
+    }
+    Ok(())
+}
 
-    Ok(Json(json!({
+#[derive(Debug, Deserialize)]
+pub struct EmailTokenRequest {
+    client_secret: String,
+    email: String,
+    send_attempt: u32,
+    next_link: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MsisdnTokenRequest {
+    client_secret: String,
+    country: String,
+    phone_number: String,
+    send_attempt: u32,
+    next_link: Option<String>,
+}
+
+#[post("/register/email/requestToken")]
+#[instrument(skip(state), err = Level::DEBUG)]
+pub async fn request_register_email_token(
+    state: Data<Arc<ServerState>>,
+    req: Json<EmailTokenRequest>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let sid = request_token(
+        &*db,
+        &req.client_secret,
+        Medium::Email,
+        &req.email.to_lowercase(),
+        state.config.auto_validate_3pids,
+    ).await?;
+    Ok(Json(json!({ "sid": sid })))
+}
+
+#[post("/register/msisdn/requestToken")]
+#[instrument(skip(state), err = Level::DEBUG)]
+pub async fn request_register_msisdn_token(
+    state: Data<Arc<ServerState>>,
+    req: Json<MsisdnTokenRequest>,
+) -> Result<Json<serde_json::Value>, Error> {
+    // we have no way of looking up calling codes from `country`, so the number has to include it
+    let address: String = req.phone_number.chars().filter(char::is_ascii_digit).collect();
+    if address.is_empty() {
+        return Err(ErrorKind::InvalidParam(String::from("phone_number")).into());
+    }
+    let db = state.db_pool.get_handle().await?;
+    let sid = request_token(
+        &*db,
+        &req.client_secret,
+        Medium::Msisdn,
+        &address,
+        state.config.auto_validate_3pids,
+    ).await?;
+    Ok(Json(json!({ "msisdn": address, "sid": sid })))
+}
+
+/// Starts a validation session for registering with a 3pid, and returns its ID.
+async fn request_token(
+    db: &dyn Storage,
+    client_secret: &str,
+    medium: Medium,
+    address: &str,
+    auto_validate: bool,
+) -> Result<String, Error> {
+    if db.get_threepid_owner(medium, address).await?.is_some() {
+        return Err(ErrorKind::ThreepidInUse.into());
+    }
+    let sid = db.create_threepid_session(client_secret, medium, address).await?;
+    if auto_validate {
+        db.validate_threepid_session(&sid).await?;
+    } else {
+        //TODO: send the token by email or SMS
+        tracing::warn!(medium = medium.as_str(), "Can't send 3pid validation tokens yet");
+    }
+    Ok(sid)
+}
+
+async fn register_guest(
+    state: &ServerState,
+    req: GuestRegisterRequest,
+) -> Result<Json<serde_json::Value>, Error> {
+    let username = format!("{}", rand::random::<u32>());
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    let db = state.db_pool.get_handle().await?;
+    db.create_guest_user(&username).await?;
+
+    let device_id = format!("{:08X}", rand::random::<u32>());
+    let tokens = issue_tokens(
+        &*db,
+        &username,
+        &device_id,
+        req.refresh_token,
+        Duration::from_millis(state.config.access_token_lifetime_ms),
+    ).await?;
+
+    tracing::info!(username = username.as_str(), "Guest registered");
+
+    let mut response = serde_json::to_value(tokens).unwrap();
+    response["user_id"] = json!(user_id);
+    response["device_id"] = json!(device_id);
+    Ok(Json(response))
+}
+
+// Not subject to the login rate limit, since clients call this often to check their token.
+#[get("/account/whoami")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn whoami(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+) -> Result<Json<serde_json::Value>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    Ok(Json(whoami_response(&*db, &username, &state.config.domain).await?))
+}
+
+async fn whoami_response(
+    db: &dyn Storage,
+    username: &str,
+    domain: &str,
+) -> Result<serde_json::Value, Error> {
+    let user_id = MatrixId::new(username, domain).unwrap();
+    let is_guest = db.is_guest(username).await?;
+    Ok(json!({
         "user_id": user_id,
-        "access_token": access_token,
-        "device_id": device_id
-    })))
+        "is_guest": is_guest,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    /// Missing on the first request, which gets back the user-interactive auth session to use
+    auth: Option<serde_json::Value>,
+    new_password: String,
+    /// Whether to log out every device other than the one making the request
+    #[serde(default = "default_true")]
+    logout_devices: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[post("/account/password")]
+#[instrument(skip_all, fields(username = Empty), err = Level::DEBUG)]
+pub async fn change_password(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    req: Json<ChangePasswordRequest>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    let request = json!({
+        "change_password": username,
+        "new_password": req.new_password,
+        "logout_devices": req.logout_devices,
+    });
+    confirm_password(&*db, &username, req.auth.as_ref(), &request).await?;
+    let salt: [u8; 16] = rand::random();
+    let password_hash = argon2::hash_encoded(
+        req.new_password.as_bytes(),
+        &salt,
+        &Default::default(),
+    )?;
+    db.set_password_hash(&username, &password_hash).await?;
+    if req.logout_devices {
+        let this_device = db.get_token_device(token.0).await?;
+        for device in db.get_devices(&username).await? {
+            if Some(&device.device_id) != this_device.as_ref() {
+                db.delete_device(&username, &device.device_id).await?;
+            }
+        }
+    }
+    tracing::info!(username = username.as_str(), "User changed their password");
+    Ok(Json(json!({})))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use std::{sync::Arc, time::Duration};
+
+    use crate::{
+        storage::{mem::MemStorageManager, Medium, StorageManager},
+        test_util::{assert_errcode, test_app, test_state, with_mem_db},
+    };
+
+    use super::{
+        create_account, issue_tokens, purge_tokens_periodically, refresh_tokens, request_token,
+        username_available, validated_threepid, ThreepidCreds,
+    };
+
+    #[test]
+    fn purge_task_purges_tokens() {
+        let mut sys = actix_web::rt::System::new("purge_task_purges_tokens");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let lifetime = Duration::from_millis(1);
+            db.create_refreshable_access_token("alice", "phone", lifetime).await.unwrap();
+            let state = test_state(db_pool, json!({ "token_purge_interval_ms": 10 })).await;
+
+            actix_web::rt::spawn(purge_tokens_periodically(Arc::clone(&state)));
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+            // the task got to the expired token first
+            let now = chrono::Utc::now().timestamp_millis();
+            assert_eq!(state.db_pool.purge_expired_tokens(now).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn refresh_token_only_when_requested() {
+        let mut sys = actix_web::rt::System::new("refresh_token_only_when_requested");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let state = test_state(db_pool, json!({ "access_token_lifetime_ms": 60_000 })).await;
+            let mut app = test_app(&state).await;
+            let login = |device_id: &str, refresh_token: Option<bool>| {
+                let mut body = json!({
+                    "type": "m.login.password",
+                    "identifier": { "type": "m.id.user", "user": "alice" },
+                    "password": "password",
+                    "device_id": device_id,
+                });
+                if let Some(refresh_token) = refresh_token {
+                    body["refresh_token"] = json!(refresh_token);
+                }
+                test::TestRequest::post().uri("/_matrix/client/r0/login").set_json(&body).to_request()
+            };
+
+            for (device_id, refresh_token) in &[("PHONE", None), ("TABLET", Some(false))] {
+                let json: serde_json::Value =
+                    test::read_response_json(&mut app, login(device_id, *refresh_token)).await;
+                assert!(json.get("access_token").is_some());
+                assert!(json.get("refresh_token").is_none());
+                assert!(json.get("expires_in_ms").is_none());
+            }
+
+            let json: serde_json::Value =
+                test::read_response_json(&mut app, login("LAPTOP", Some(true))).await;
+            assert!(json.get("access_token").is_some());
+            assert!(json.get("refresh_token").is_some());
+            assert_eq!(json.get("expires_in_ms").and_then(|v| v.as_u64()), Some(60_000));
+        });
+    }
+
+    #[test]
+    fn expired_access_token_rejected() {
+        with_mem_db(|db, _| async move {
+            db.create_user("alice", "password").await.unwrap();
+            let (token, _) = db.create_refreshable_access_token("alice", "phone", Duration::from_secs(0))
+                .await.unwrap();
+            let err = db.try_auth(token).await.expect_err("expired token still valid");
+            assert_eq!(err.to_json()["soft_logout"], true);
+        });
+    }
+
+    #[test]
+    fn refresh_rotates_tokens() {
+        with_mem_db(|db, _| async move {
+            db.create_user("alice", "password").await.unwrap();
+            let lifetime = Duration::from_secs(60);
+            let tokens = issue_tokens(&*db, "alice", "phone", true, lifetime).await.unwrap();
+            let json = serde_json::to_value(&tokens).unwrap();
+            let old_access = json["access_token"].as_str().unwrap().parse().unwrap();
+            let old_refresh = json["refresh_token"].as_str().unwrap().to_owned();
+
+            let tokens = refresh_tokens(&*db, &old_refresh, lifetime).await.unwrap();
+            let json = serde_json::to_value(&tokens).unwrap();
+            assert_eq!(json["expires_in_ms"], 60_000);
+            let new_access = json["access_token"].as_str().unwrap().parse().unwrap();
+            let new_refresh = json["refresh_token"].as_str().unwrap().to_owned();
+            assert_ne!(new_refresh, old_refresh);
+            assert_eq!(db.try_auth(new_access).await.unwrap().as_deref(), Some("alice"));
+            assert!(db.try_auth(old_access).await.map(|u| u.is_none()).unwrap_or(true));
+
+            // each refresh token only works once
+            let err = refresh_tokens(&*db, &old_refresh, lifetime).await.err().unwrap();
+            assert_errcode!(err, "M_UNKNOWN_TOKEN");
+            let err = refresh_tokens(&*db, "not a token", lifetime).await.err().unwrap();
+            assert_errcode!(err, "M_UNKNOWN_TOKEN");
+
+            // the tokens it hands out expire like any others
+            let tokens = refresh_tokens(&*db, &new_refresh, Duration::from_secs(0)).await.unwrap();
+            let json = serde_json::to_value(&tokens).unwrap();
+            let expired = json["access_token"].as_str().unwrap().parse().unwrap();
+            let err = db.try_auth(expired).await.expect_err("expired token still valid");
+            assert_eq!(err.to_json()["soft_logout"], true);
+            let fresh = json["refresh_token"].as_str().unwrap();
+            assert!(refresh_tokens(&*db, fresh, lifetime).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn logout_is_soft() {
+        let mut sys = actix_web::rt::System::new("logout_is_soft");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let phone = db.create_access_token("alice", "PHONE").await.unwrap();
+            let laptop = db.create_access_token("alice", "LAPTOP").await.unwrap();
+            let tablet = db.create_access_token("alice", "TABLET").await.unwrap();
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+            let post = |uri: &str, token: &Uuid| {
+                test::TestRequest::post()
+                    .uri(uri)
+                    .header("Authorization", format!("Bearer {}", token))
+                    .set_json(&json!({}))
+                    .to_request()
+            };
+            let whoami = |token: &Uuid| {
+                test::TestRequest::get()
+                    .uri("/_matrix/client/r0/account/whoami")
+                    .header("Authorization", format!("Bearer {}", token))
+                    .to_request()
+            };
+
+            let res = test::call_service(&mut app, post("/_matrix/client/r0/logout", &phone)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let res = test::call_service(&mut app, whoami(&phone)).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_errcode!(body, "M_UNKNOWN_TOKEN");
+            assert_eq!(body["soft_logout"], true);
+            let res = test::call_service(&mut app, whoami(&laptop)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let res = test::call_service(&mut app, post("/_matrix/client/r0/logout/all", &laptop)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            for token in &[laptop, tablet] {
+                let body: serde_json::Value = test::read_response_json(&mut app, whoami(token)).await;
+                assert_errcode!(body, "M_UNKNOWN_TOKEN");
+                assert_eq!(body["soft_logout"], true);
+            }
+        });
+    }
+
+    #[test]
+    fn whoami_reports_guests() {
+        let mut sys = actix_web::rt::System::new("whoami_reports_guests");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let alice = db.create_access_token("alice", "PHONE").await.unwrap();
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+            let whoami = |token: &str| {
+                test::TestRequest::get()
+                    .uri("/_matrix/client/r0/account/whoami")
+                    .header("Authorization", format!("Bearer {}", token))
+                    .to_request()
+            };
+
+            let json: serde_json::Value =
+                test::read_response_json(&mut app, whoami(&alice.to_string())).await;
+            assert_eq!(json["user_id"], "@alice:example.org");
+            assert_eq!(json["is_guest"], false);
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/register?kind=guest")
+                .set_json(&json!({}))
+                .to_request();
+            let guest: serde_json::Value = test::read_response_json(&mut app, req).await;
+            let json: serde_json::Value =
+                test::read_response_json(&mut app, whoami(guest["access_token"].as_str().unwrap())).await;
+            assert_eq!(json["user_id"], guest["user_id"]);
+            assert_eq!(json["is_guest"], true);
+        });
+    }
+
+    #[test]
+    fn login_rate_limit() {
+        let mut sys = actix_web::rt::System::new("login_rate_limit");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let token = db.create_access_token("alice", "PHONE").await.unwrap();
+            let config = json!({ "login_rate_limit": { "attempts": 2, "window_ms": 60_000 } });
+            let state = test_state(db_pool, config).await;
+            let mut app = test_app(&state).await;
+            let login = |address: &str, user: &str, password: &str| {
+                test::TestRequest::post()
+                    .uri("/_matrix/client/r0/login")
+                    .peer_addr(address.parse().unwrap())
+                    .set_json(&json!({
+                        "type": "m.login.password",
+                        "identifier": { "type": "m.id.user", "user": user },
+                        "password": password,
+                    }))
+                    .to_request()
+            };
+            let attacker = "192.0.2.1:1234";
+            let home = "198.51.100.1:1234";
+
+            // logging in successfully never counts towards the limit
+            for _ in 0..3 {
+                let res = test::call_service(&mut app, login(home, "alice", "password")).await;
+                assert_eq!(res.status(), StatusCode::OK);
+            }
+            for _ in 0..2 {
+                let res = test::call_service(&mut app, login(attacker, "alice", "guess")).await;
+                assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            }
+            // the right password doesn't help once the limit's been reached
+            let res = test::call_service(&mut app, login(attacker, "alice", "password")).await;
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_errcode!(body, "M_LIMIT_EXCEEDED");
+            // but alice can still log in from elsewhere, and other users aren't affected
+            let res = test::call_service(&mut app, login(home, "alice", "password")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let res = test::call_service(&mut app, login(attacker, "bob", "password")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            // and whoami is exempt, however often it's called
+            for _ in 0..5 {
+                let req = test::TestRequest::get()
+                    .uri("/_matrix/client/r0/account/whoami")
+                    .header("Authorization", format!("Bearer {}", token))
+                    .to_request();
+                assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::OK);
+            }
+        });
+    }
+
+    #[test]
+    fn register_with_email() {
+        with_mem_db(|db, _| async move {
+
+            // sessions can't be used until they've been validated
+            let sid = request_token(&*db, "secret", Medium::Email, "bob@example.org", false)
+                .await.unwrap();
+            let creds = ThreepidCreds { sid, client_secret: String::from("secret") };
+            let err = validated_threepid(&*db, &creds).await.expect_err("unvalidated 3pid bound");
+            assert_errcode!(err, "M_THREEPID_AUTH_FAILED");
+
+            let sid = request_token(&*db, "secret", Medium::Email, "alice@example.org", true)
+                .await.unwrap();
+            let wrong_secret = ThreepidCreds { sid: sid.clone(), client_secret: String::from("guess") };
+            validated_threepid(&*db, &wrong_secret).await.expect_err("wrong client secret accepted");
+
+            let creds = ThreepidCreds { sid, client_secret: String::from("secret") };
+            let threepid = validated_threepid(&*db, &creds).await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.add_threepid("alice", threepid).await.unwrap();
+
+            let threepids = db.get_threepids("alice").await.unwrap();
+            assert_eq!(threepids.len(), 1);
+            assert_eq!(threepids[0].medium, Medium::Email);
+            assert_eq!(threepids[0].address, "alice@example.org");
+            let json = serde_json::to_value(&threepids[0]).unwrap();
+            assert_eq!(json["medium"], "email");
+
+            // nobody else can register with it now
+            let err = request_token(&*db, "other", Medium::Email, "alice@example.org", true)
+                .await.expect_err("3pid bound twice");
+            assert_errcode!(err, "M_THREEPID_IN_USE");
+            // and anyone who validated it before alice bound it doesn't get an account without it
+            let err = create_account(&*db, "bob", "password", threepids.into_iter().next()).await
+                .expect_err("3pid bound twice");
+            assert_errcode!(err, "M_THREEPID_IN_USE");
+            assert!(!db.user_exists("bob").await.unwrap());
+        });
+    }
+
+    #[test]
+    fn username_availability() {
+        with_mem_db(|db, _| async move {
+            db.create_user("alice", "password").await.unwrap();
+            db.create_guest_user("guest").await.unwrap();
+
+            assert!(username_available(&*db, "bob", "example.org").await.is_ok());
+            let err = username_available(&*db, "alice", "example.org").await.unwrap_err();
+            assert_errcode!(err, "M_USER_IN_USE");
+            let err = username_available(&*db, "guest", "example.org").await.unwrap_err();
+            assert_errcode!(err, "M_USER_IN_USE");
+            let err = username_available(&*db, "Bob!", "example.org").await.unwrap_err();
+            assert_errcode!(err, "M_INVALID_USERNAME");
+        });
+    }
+
+    #[test]
+    fn register_with_uiaa() {
+        let mut sys = actix_web::rt::System::new("register_with_uiaa");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+            let register = |body: serde_json::Value| {
+                test::TestRequest::post()
+                    .uri("/_matrix/client/r0/register?kind=user")
+                    .set_json(&body)
+                    .to_request()
+            };
+
+            // the first request just gets a session and the flows to complete
+            let res = test::call_service(&mut app, register(json!({
+                "username": "alice",
+                "password": "password",
+            }))).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            let session = body["session"].as_str().unwrap().to_owned();
+            let dummy_flow = json!({ "stages": ["m.login.dummy"] });
+            assert!(body["flows"].as_array().unwrap().contains(&dummy_flow));
+            assert_eq!(body["completed"], json!([]));
+            assert!(!db.user_exists("alice").await.unwrap());
+
+            // the session can't be used to register someone else
+            let res = test::call_service(&mut app, register(json!({
+                "username": "mallory",
+                "password": "password",
+                "auth": { "type": "m.login.dummy", "session": session },
+            }))).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_errcode!(body, "M_FORBIDDEN");
+            assert!(!db.user_exists("mallory").await.unwrap());
+
+            let res = test::call_service(&mut app, register(json!({
+                "username": "alice",
+                "password": "password",
+                "auth": { "type": "m.login.dummy", "session": session },
+            }))).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["user_id"], "@alice:example.org");
+            assert!(body["access_token"].is_string());
+            assert!(db.user_exists("alice").await.unwrap());
+
+            // the session is used up once registration goes through
+            for session in &[session.as_str(), "made-up"] {
+                let res = test::call_service(&mut app, register(json!({
+                    "username": "bob",
+                    "password": "password",
+                    "auth": { "type": "m.login.dummy", "session": session },
+                }))).await;
+                assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+                let body: serde_json::Value = test::read_body_json(res).await;
+                assert_errcode!(body, "M_INVALID_PARAM");
+            }
+            assert!(!db.user_exists("bob").await.unwrap());
+        });
+    }
+
+    #[test]
+    fn change_password() {
+        let mut sys = actix_web::rt::System::new("change_password");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "old password").await.unwrap();
+            let phone = db.create_access_token("alice", "phone").await.unwrap();
+            let laptop = db.create_access_token("alice", "laptop").await.unwrap();
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+            let change_password = |body: serde_json::Value| {
+                test::TestRequest::post()
+                    .uri("/_matrix/client/r0/account/password")
+                    .header("Authorization", format!("Bearer {}", phone.to_hyphenated()))
+                    .set_json(&body)
+                    .to_request()
+            };
+
+            let res = test::call_service(&mut app, change_password(json!({
+                "new_password": "new password",
+            }))).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            let session = body["session"].as_str().unwrap().to_owned();
+
+            let res = test::call_service(&mut app, change_password(json!({
+                "new_password": "new password",
+                "auth": {
+                    "type": "m.login.password",
+                    "session": session,
+                    "identifier": { "type": "m.id.user", "user": "alice" },
+                    "password": "old password",
+                },
+            }))).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(!db.verify_password("alice", "old password").await.unwrap());
+            assert!(db.verify_password("alice", "new password").await.unwrap());
+
+            // only the device that changed the password stays logged in
+            assert_eq!(db.try_auth(phone).await.unwrap().as_deref(), Some("alice"));
+            assert_eq!(db.try_auth(laptop).await.unwrap(), None);
+            assert!(db.get_device("alice", "laptop").await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn usernames_are_lowercase() {
+        let mut sys = actix_web::rt::System::new("usernames_are_lowercase");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+
+            for username in &["@Alice", "Alice"] {
+                let req = test::TestRequest::post()
+                    .uri("/_matrix/client/r0/register?kind=user")
+                    .set_json(&json!({ "username": username, "password": "password" }))
+                    .to_request();
+                let res = test::call_service(&mut app, req).await;
+                assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+                let body: serde_json::Value = test::read_body_json(res).await;
+                assert_errcode!(body, "M_INVALID_USERNAME");
+            }
+            assert!(!db.user_exists("Alice").await.unwrap());
+
+            // logging in isn't so strict, since people type their names however they like
+            for user in &["Alice", "@ALICE:example.org"] {
+                let req = test::TestRequest::post()
+                    .uri("/_matrix/client/r0/login")
+                    .set_json(&json!({
+                        "type": "m.login.password",
+                        "identifier": { "type": "m.id.user", "user": user },
+                        "password": "password",
+                    }))
+                    .to_request();
+                let res = test::call_service(&mut app, req).await;
+                assert_eq!(res.status(), StatusCode::OK);
+                let body: serde_json::Value = test::read_body_json(res).await;
+                assert_eq!(body["user_id"], "@alice:example.org");
+            }
+        });
+    }
 }
@@ -0,0 +1,205 @@
+use actix_web::{
+    web::{Data, Json, Path},
+    delete, get, put,
+};
+use tracing::{Level, Span, instrument, field::Empty};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::sync::Arc;
+
+use crate::{
+    ServerState,
+    client_api::auth::{AccessToken, confirm_password},
+    error::{Error, ErrorKind},
+    storage::Device,
+};
+
+#[get("/devices")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_devices(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    let devices = db.get_devices(&username).await?;
+    Ok(Json(json!({ "devices": devices })))
+}
+
+#[get("/devices/{device_id}")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_device(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(device_id): Path<String>,
+) -> Result<Json<Device>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    let device = db.get_device(&username, &device_id).await?.ok_or(ErrorKind::NotFound)?;
+    Ok(Json(device))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateDeviceRequest {
+    display_name: Option<String>,
+}
+
+#[put("/devices/{device_id}")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn update_device(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(device_id): Path<String>,
+    req: Json<UpdateDeviceRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    let display_name = req.display_name.as_deref();
+    if !db.set_device_display_name(&username, &device_id, display_name).await? {
+        return Err(ErrorKind::NotFound.into());
+    }
+    Ok(Json(json!({})))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteDeviceRequest {
+    /// Missing on the first request, which gets back the user-interactive auth session to use
+    auth: Option<JsonValue>,
+}
+
+#[delete("/devices/{device_id}")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn delete_device(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(device_id): Path<String>,
+    req: Option<Json<DeleteDeviceRequest>>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    if db.get_device(&username, &device_id).await?.is_none() {
+        return Err(ErrorKind::NotFound.into());
+    }
+    let auth = req.as_ref().and_then(|req| req.auth.as_ref());
+    let request = json!({ "delete_device": device_id });
+    confirm_password(&*db, &username, auth, &request).await?;
+    db.delete_device(&username, &device_id).await?;
+    Ok(Json(json!({})))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use serde_json::{json, Value as JsonValue};
+
+    use crate::{
+        storage::{StorageManager, mem::MemStorageManager},
+        test_util::{test_app, test_state},
+    };
+
+    #[test]
+    fn list_and_delete_devices() {
+        let mut sys = actix_web::rt::System::new("list_and_delete_devices");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+
+            let mut tokens = Vec::new();
+            let logins = [("PHONE", "Alice's phone"), ("LAPTOP", "Alice's laptop")];
+            for (device_id, display_name) in logins.iter() {
+                let req = test::TestRequest::post()
+                    .uri("/_matrix/client/r0/login")
+                    .set_json(&json!({
+                        "type": "m.login.password",
+                        "identifier": { "type": "m.id.user", "user": "alice" },
+                        "password": "password",
+                        "device_id": device_id,
+                        "initial_device_display_name": display_name,
+                    }))
+                    .to_request();
+                let body: JsonValue = test::read_response_json(&mut app, req).await;
+                tokens.push(body["access_token"].as_str().unwrap().to_owned());
+            }
+            let auth = |req: test::TestRequest| {
+                req.header("Authorization", format!("Bearer {}", tokens[0])).to_request()
+            };
+
+            let req = auth(test::TestRequest::get().uri("/_matrix/client/r0/devices"));
+            let body: JsonValue = test::read_response_json(&mut app, req).await;
+            let mut devices: Vec<_> = body["devices"].as_array().unwrap().iter()
+                .map(|device| (device["device_id"].clone(), device["display_name"].clone()))
+                .collect();
+            devices.sort_by_key(|(device_id, _)| device_id.to_string());
+            assert_eq!(devices, vec![
+                (json!("LAPTOP"), json!("Alice's laptop")),
+                (json!("PHONE"), json!("Alice's phone")),
+            ]);
+
+            let res = test::call_service(&mut app, auth(
+                test::TestRequest::put()
+                    .uri("/_matrix/client/r0/devices/LAPTOP")
+                    .set_json(&json!({ "display_name": "Old laptop" }))
+            )).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let req = auth(test::TestRequest::get().uri("/_matrix/client/r0/devices/LAPTOP"));
+            let body: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(body["display_name"], "Old laptop");
+
+            // deleting needs the password, confirmed through user-interactive auth
+            let delete = |body: JsonValue| auth(
+                test::TestRequest::delete()
+                    .uri("/_matrix/client/r0/devices/LAPTOP")
+                    .set_json(&body)
+            );
+            let res = test::call_service(&mut app, delete(json!({}))).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            let body: JsonValue = test::read_body_json(res).await;
+            let session = body["session"].as_str().unwrap().to_owned();
+            assert_eq!(body["flows"], json!([{ "stages": ["m.login.password"] }]));
+
+            let res = test::call_service(&mut app, delete(json!({
+                "auth": {
+                    "type": "m.login.password",
+                    "session": session,
+                    "identifier": { "type": "m.id.user", "user": "alice" },
+                    "password": "wrong",
+                },
+            }))).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            assert!(db.get_device("alice", "LAPTOP").await.unwrap().is_some());
+
+            let res = test::call_service(&mut app, delete(json!({
+                "auth": {
+                    "type": "m.login.password",
+                    "session": session,
+                    "identifier": { "type": "m.id.user", "user": "@alice:example.org" },
+                    "password": "password",
+                },
+            }))).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            // the laptop's token went with it
+            let laptop_token = tokens[1].parse().unwrap();
+            assert_eq!(db.try_auth(laptop_token).await.unwrap(), None);
+            let req = auth(test::TestRequest::get().uri("/_matrix/client/r0/devices"));
+            let body: JsonValue = test::read_response_json(&mut app, req).await;
+            let devices = body["devices"].as_array().unwrap();
+            assert_eq!(devices.len(), 1);
+            assert_eq!(devices[0]["device_id"], "PHONE");
+            let req = auth(test::TestRequest::get().uri("/_matrix/client/r0/devices/LAPTOP"));
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        });
+    }
+}
@@ -0,0 +1,220 @@
+use actix_web::{
+    web::{Data, Json, Path},
+    delete, get, put,
+};
+use tracing::{Level, Span, instrument, field::Empty};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use std::sync::Arc;
+
+use crate::{
+    ServerState,
+    client_api::auth::AccessToken,
+    error::{Error, ErrorKind},
+    events::EventContent,
+    storage::Storage,
+    util::{MatrixId, PercentDecoded, RoomAliasId},
+};
+
+/// Looks up the room an alias points at.
+pub async fn resolve_alias(
+    db: &dyn Storage,
+    server_name: &str,
+    alias: &RoomAliasId,
+) -> Result<String, Error> {
+    if !alias.has_domain(server_name) {
+        //TODO: ask the alias's server over federation
+        return Err(ErrorKind::Unimplemented.into());
+    }
+    let alias = alias.normalized();
+    Ok(db.get_room_alias(alias.as_str()).await?.ok_or(ErrorKind::NotFound)?)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetAliasRequest {
+    room_id: String,
+}
+
+#[put("/directory/room/{room_alias}")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn set_alias(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(PercentDecoded(alias)): Path<PercentDecoded<RoomAliasId>>,
+    req: Json<SetAliasRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    if !alias.has_domain(&state.config.domain) {
+        let msg = "aliases can only be created on the server they're for";
+        return Err(ErrorKind::InvalidParam(String::from(msg)).into());
+    }
+    if !db.room_exists(&req.room_id).await? {
+        return Err(ErrorKind::RoomNotFound.into());
+    }
+    let alias = alias.normalized();
+    if !db.set_room_alias(alias.as_str(), &req.room_id, &username).await? {
+        return Err(ErrorKind::AliasExists.into());
+    }
+    Ok(Json(json!({})))
+}
+
+#[get("/directory/room/{room_alias}")]
+#[instrument(skip(state), err = Level::DEBUG)]
+pub async fn get_alias(
+    state: Data<Arc<ServerState>>,
+    Path(PercentDecoded(alias)): Path<PercentDecoded<RoomAliasId>>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let room_id = resolve_alias(&*db, &state.config.domain, &alias).await?;
+    Ok(Json(json!({
+        "room_id": room_id,
+        "servers": [state.config.domain],
+    })))
+}
+
+#[delete("/directory/room/{room_alias}")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn delete_alias(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(PercentDecoded(alias)): Path<PercentDecoded<RoomAliasId>>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    let alias = alias.normalized();
+    let room_id = resolve_alias(&*db, &state.config.domain, &alias).await?;
+    let is_creator = db.get_room_alias_creator(alias.as_str()).await?.as_ref() == Some(&username);
+    if !state.config.admins.contains(&username)
+        && !is_creator
+        && !may_edit_aliases(&*db, &room_id, &user_id).await?
+    {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    db.delete_room_alias(alias.as_str()).await?;
+    Ok(Json(json!({})))
+}
+
+/// Whether the user could change the room's published aliases, which is what's needed to remove
+/// one of its aliases from the directory.
+async fn may_edit_aliases(db: &dyn Storage, room_id: &str, user_id: &MatrixId) -> Result<bool, Error> {
+    let levels = match db.get_state_event(room_id, "m.room.power_levels", "").await? {
+        Some(event) => match event.event_content {
+            EventContent::PowerLevels(levels) => levels,
+            _ => return Ok(false),
+        },
+        None => return Ok(false),
+    };
+    Ok(levels.get_user_level(user_id) >= levels.get_event_level("m.room.canonical_alias", true))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use serde_json::{json, Value as JsonValue};
+
+    use crate::{
+        storage::{StorageManager, mem::MemStorageManager},
+        test_util::{test_app, test_state},
+        util::MatrixId,
+    };
+
+    #[test]
+    fn create_resolve_and_join_alias() {
+        let mut sys = actix_web::rt::System::new("create_resolve_and_join_alias");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+
+            let mut tokens = Vec::new();
+            for user in ["alice", "bob"].iter() {
+                let req = test::TestRequest::post()
+                    .uri("/_matrix/client/r0/login")
+                    .set_json(&json!({
+                        "type": "m.login.password",
+                        "identifier": { "type": "m.id.user", "user": user },
+                        "password": "password",
+                    }))
+                    .to_request();
+                let body: JsonValue = test::read_response_json(&mut app, req).await;
+                tokens.push(body["access_token"].as_str().unwrap().to_owned());
+            }
+            let as_user = |i: usize, req: test::TestRequest| {
+                req.header("Authorization", format!("Bearer {}", tokens[i])).to_request()
+            };
+
+            let req = as_user(0, test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .set_json(&json!({ "preset": "public_chat" })));
+            let body: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = body["room_id"].as_str().unwrap().to_owned();
+
+            let set_alias = |i: usize| as_user(i, test::TestRequest::put()
+                .uri("/_matrix/client/r0/directory/room/%23lobby:example.org")
+                .set_json(&json!({ "room_id": room_id })));
+            let res = test::call_service(&mut app, set_alias(0)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let res = test::call_service(&mut app, set_alias(1)).await;
+            assert_eq!(res.status(), StatusCode::CONFLICT);
+            // the domain's case doesn't make it a different alias
+            let req = as_user(1, test::TestRequest::put()
+                .uri("/_matrix/client/r0/directory/room/%23lobby:Example.ORG")
+                .set_json(&json!({ "room_id": room_id })));
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::CONFLICT);
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/directory/room/%23lobby:EXAMPLE.org")
+                .to_request();
+            let body: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(body["room_id"], room_id.as_str());
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/directory/room/%23lobby:example.org")
+                .to_request();
+            let body: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(body, json!({ "room_id": room_id, "servers": ["example.org"] }));
+
+            let req = as_user(1, test::TestRequest::post()
+                .uri("/_matrix/client/r0/join/%23lobby:example.org"));
+            let body: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(body["room_id"], room_id.as_str());
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            assert!(db.get_membership(&bob, &room_id).await.unwrap().is_some());
+
+            // bob doesn't have the power to take the alias down, but alice does
+            let delete_alias = |i: usize| as_user(i, test::TestRequest::delete()
+                .uri("/_matrix/client/r0/directory/room/%23lobby:example.org"));
+            let res = test::call_service(&mut app, delete_alias(1)).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let req = as_user(0, test::TestRequest::delete()
+                .uri("/_matrix/client/r0/directory/room/%23lobby:Example.org"));
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/directory/room/%23lobby:example.org")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+            // but bob can take down an alias of their own
+            let req = as_user(1, test::TestRequest::put()
+                .uri("/_matrix/client/r0/directory/room/%23bobs:example.org")
+                .set_json(&json!({ "room_id": room_id })));
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let req = as_user(1, test::TestRequest::delete()
+                .uri("/_matrix/client/r0/directory/room/%23bobs:example.org"));
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+        });
+    }
+}
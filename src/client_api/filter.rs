@@ -0,0 +1,148 @@
+use serde::Deserialize;
+
+use crate::{
+    error::{Error, ErrorKind},
+    events::{Event, pdu::StoredPdu},
+    storage::{EventQuery, QueryType, Storage},
+    util::MatrixId,
+};
+
+/// A filter for what gets sent down `/sync`.
+///
+/// Only the parts that are actually applied are here; anything else in a filter is ignored.
+#[derive(Debug, Default, Deserialize)]
+pub struct Filter {
+    #[serde(default)]
+    pub room: RoomFilter,
+    /// Which of the user's global account data to include
+    #[serde(default)]
+    pub account_data: AccountDataFilter,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RoomFilter {
+    /// The rooms to include. If this is missing all rooms are included.
+    #[serde(default)]
+    pub rooms: Option<Vec<String>>,
+    /// The rooms to exclude. Exclusion takes priority over `rooms`.
+    #[serde(default)]
+    pub not_rooms: Vec<String>,
+    /// Which events to include in rooms' timelines
+    #[serde(default)]
+    pub timeline: RoomEventFilter,
+    /// Which events to include in rooms' state
+    #[serde(default)]
+    pub state: RoomEventFilter,
+    /// Which of the user's account data for each room to include
+    #[serde(default)]
+    pub account_data: AccountDataFilter,
+}
+
+/// Which types of account data to include. An empty list of types includes everything.
+#[derive(Debug, Default, Deserialize)]
+pub struct AccountDataFilter {
+    #[serde(default)]
+    pub types: Vec<String>,
+    #[serde(default)]
+    pub not_types: Vec<String>,
+}
+
+/// Which of a room's events to include. The lists work like the ones in `EventQuery`, so an empty
+/// list of types or senders includes everything.
+#[derive(Debug, Default, Deserialize)]
+pub struct RoomEventFilter {
+    #[serde(default)]
+    pub types: Vec<String>,
+    #[serde(default)]
+    pub not_types: Vec<String>,
+    #[serde(default)]
+    pub senders: Vec<MatrixId>,
+    #[serde(default)]
+    pub not_senders: Vec<MatrixId>,
+    /// The most events to return. The latest ones are kept.
+    #[serde(default)]
+    pub limit: Option<usize>,
+    /// Only for state: whether to leave out members other than the timeline's senders.
+    #[serde(default)]
+    pub lazy_load_members: bool,
+    /// Only for state, when lazily loading members: whether to send members the client was sent
+    /// before.
+    #[serde(default)]
+    pub include_redundant_members: bool,
+}
+
+impl Filter {
+    /// Gets the filter for a sync request from its `filter` param, which can be either the ID of
+    /// one of the user's uploaded filters or the filter itself as JSON.
+    pub async fn load(db: &dyn Storage, username: &str, param: Option<&str>) -> Result<Self, Error> {
+        let json = match param {
+            Some(json) if json.trim_start().starts_with('{') => return Ok(serde_json::from_str(json)?),
+            Some(filter_id) => db.get_filter(username, filter_id).await?
+                .ok_or_else(|| ErrorKind::InvalidParam(format!("unknown filter {}", filter_id)))?,
+            None => return Ok(Filter::default()),
+        };
+        Ok(serde_json::from_value(json)?)
+    }
+}
+
+impl RoomFilter {
+    pub fn allows(&self, room_id: &str) -> bool {
+        let included = match &self.rooms {
+            Some(rooms) => rooms.iter().any(|r| r == room_id),
+            None => true,
+        };
+        included && !self.not_rooms.iter().any(|r| r == room_id)
+    }
+}
+
+impl AccountDataFilter {
+    pub fn allows(&self, ty: &str) -> bool {
+        (self.types.is_empty() || self.types.iter().any(|t| t == ty))
+            && !self.not_types.iter().any(|t| t == ty)
+    }
+}
+
+impl RoomEventFilter {
+    /// Queries the room's events that this filter lets through.
+    pub async fn query(
+        &self,
+        db: &dyn Storage,
+        room_id: &str,
+        query_type: QueryType<'_>,
+        wait: bool,
+    ) -> Result<(Vec<StoredPdu>, usize), Error> {
+        let types: Vec<&str> = self.types.iter().map(String::as_str).collect();
+        let not_types: Vec<&str> = self.not_types.iter().map(String::as_str).collect();
+        let senders: Vec<&MatrixId> = self.senders.iter().collect();
+        let not_senders: Vec<&MatrixId> = self.not_senders.iter().collect();
+        db.query_pdus(EventQuery {
+            query_type,
+            room_id,
+            senders: &senders,
+            not_senders: &not_senders,
+            types: &types,
+            not_types: &not_types,
+            contains_json: None,
+        }, wait).await
+    }
+
+    /// Whether the filter lets the event through, for events that didn't come from `query`.
+    pub fn allows(&self, event: &Event) -> bool {
+        let ty = event.event_content.get_type();
+        (self.types.is_empty() || self.types.iter().any(|t| t == ty))
+            && !self.not_types.iter().any(|t| t == ty)
+            && (self.senders.is_empty() || self.senders.contains(&event.sender))
+            && !self.not_senders.contains(&event.sender)
+    }
+
+    /// Cuts `events` down to the limit, keeping the latest ones. Returns whether any were cut.
+    pub fn limit<T>(&self, events: &mut Vec<T>) -> bool {
+        match self.limit {
+            Some(limit) if events.len() > limit => {
+                events.drain(..events.len() - limit);
+                true
+            },
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,425 @@
+use actix_web::{
+    web::{Data, Json},
+    post,
+};
+use tracing::{Level, Span, instrument, field::Empty};
+use serde::Deserialize;
+use serde_json::{json, Map, Value as JsonValue};
+use futures::future;
+use std::{collections::HashMap, sync::Arc};
+use tokio::time::Duration;
+
+use crate::{
+    ServerState,
+    client_api::auth::AccessToken,
+    error::{Error, ErrorKind},
+    storage::Storage,
+    util::MatrixId,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct KeysUploadRequest {
+    device_keys: Option<JsonValue>,
+    /// Keys by ID, as `<algorithm>:<key_id>`
+    #[serde(default)]
+    one_time_keys: HashMap<String, JsonValue>,
+}
+
+#[post("/keys/upload")]
+#[instrument(skip_all, fields(username = Empty), err = Level::DEBUG)]
+pub async fn upload_keys(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    req: Json<KeysUploadRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    let req = req.into_inner();
+    let device_id = db.get_token_device(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    if let Some(device_keys) = req.device_keys {
+        let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+        if device_keys["user_id"] != user_id.as_str() || device_keys["device_id"] != device_id {
+            let msg = "device_keys must belong to the uploading device";
+            return Err(ErrorKind::InvalidParam(String::from(msg)).into());
+        }
+        db.set_device_keys(&username, &device_id, device_keys).await?;
+    }
+    if !req.one_time_keys.is_empty() {
+        db.add_one_time_keys(&username, &device_id, req.one_time_keys).await?;
+    }
+    let counts = db.count_one_time_keys(&username, &device_id).await?;
+    Ok(Json(json!({ "one_time_key_counts": counts })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KeysQueryRequest {
+    /// How long to wait for other servers' keys, in milliseconds
+    #[serde(default = "default_keys_query_timeout")]
+    timeout: u32,
+    /// The users to get keys for, each with the devices to get keys for. An empty list means all
+    /// of the user's devices.
+    device_keys: HashMap<MatrixId, Vec<String>>,
+}
+
+fn default_keys_query_timeout() -> u32 {
+    10000
+}
+
+#[post("/keys/query")]
+#[instrument(skip_all, fields(username = Empty), err = Level::DEBUG)]
+pub async fn query_keys(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    req: Json<KeysQueryRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    Ok(Json(keys_for(&*db, &state.config.domain, &req).await?))
+}
+
+/// Gathers the keys asked for in a `/keys/query` request.
+///
+/// Local users' keys come from storage. Remote users' are fetched from all their servers at once,
+/// waiting at most the request's timeout for them all, and servers that don't answer in time are
+/// listed as failures. Fetching them over federation isn't supported yet, so for now every remote
+/// server is a failure.
+async fn keys_for(
+    db: &dyn Storage,
+    domain: &str,
+    req: &KeysQueryRequest,
+) -> Result<JsonValue, Error> {
+    let mut device_keys = Map::new();
+    let mut failures = Map::new();
+    let mut remote: HashMap<&str, HashMap<&MatrixId, &[String]>> = HashMap::new();
+    for (user_id, wanted) in req.device_keys.iter() {
+        if !user_id.has_domain(domain) {
+            remote.entry(user_id.domain()).or_default().insert(user_id, wanted);
+            continue;
+        }
+        let keys: Map<String, JsonValue> = db.get_device_keys(user_id.localpart()).await?
+            .into_iter()
+            .filter(|(device_id, _)| wanted.is_empty() || wanted.contains(device_id))
+            .collect();
+        device_keys.insert(user_id.clone_inner(), JsonValue::Object(keys));
+    }
+
+    let remote: Vec<_> = remote.into_iter().collect();
+    let fetches = remote.iter().map(|(server, users)| remote_keys(server, users));
+    let timeout = Duration::from_millis(req.timeout as _);
+    match tokio::time::timeout(timeout, future::join_all(fetches)).await {
+        Ok(results) => {
+            for ((server, _), result) in remote.iter().zip(results) {
+                match result {
+                    Ok(keys) => device_keys.extend(keys),
+                    Err(e) => {
+                        failures.insert(server.to_string(), e.to_json());
+                    },
+                }
+            }
+        },
+        Err(_) => {
+            let msg = String::from("timed out waiting for keys");
+            let error = Error::from(ErrorKind::Unknown(msg)).to_json();
+            for (server, _) in remote.iter() {
+                failures.insert(server.to_string(), error.clone());
+            }
+        },
+    }
+    Ok(json!({
+        "device_keys": device_keys,
+        "failures": failures,
+    }))
+}
+
+/// Fetches the device keys of some of a remote server's users from it, by user ID.
+async fn remote_keys(
+    _server: &str,
+    _users: &HashMap<&MatrixId, &[String]>,
+) -> Result<Map<String, JsonValue>, Error> {
+    //TODO: fetch these over federation
+    Err(ErrorKind::Unimplemented.into())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct KeysClaimRequest {
+    /// The users to claim keys from, each with the algorithm to claim a key for on each device
+    one_time_keys: HashMap<MatrixId, HashMap<String, String>>,
+}
+
+#[post("/keys/claim")]
+#[instrument(skip_all, fields(username = Empty), err = Level::DEBUG)]
+pub async fn claim_keys(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    req: Json<KeysClaimRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+
+    Ok(Json(claims_for(&*db, &state.config.domain, &req).await?))
+}
+
+/// Claims the one-time keys asked for in a `/keys/claim` request. Devices without a key left for
+/// the algorithm are left out, and remote users' servers are listed as failures in the same way
+/// as for `/keys/query`.
+async fn claims_for(
+    db: &dyn Storage,
+    domain: &str,
+    req: &KeysClaimRequest,
+) -> Result<JsonValue, Error> {
+    let mut one_time_keys = Map::new();
+    let mut failures = Map::new();
+    for (user_id, wanted) in req.one_time_keys.iter() {
+        if !user_id.has_domain(domain) {
+            //TODO: claim these over federation
+            let error = Error::from(ErrorKind::Unimplemented).to_json();
+            failures.insert(user_id.domain().to_string(), error);
+            continue;
+        }
+        let mut claimed = Map::new();
+        for (device_id, algorithm) in wanted.iter() {
+            if let Some((key_id, key)) =
+                db.claim_one_time_key(user_id.localpart(), device_id, algorithm).await?
+            {
+                claimed.insert(device_id.clone(), json!({ key_id: key }));
+            }
+        }
+        one_time_keys.insert(user_id.clone_inner(), JsonValue::Object(claimed));
+    }
+    Ok(json!({
+        "one_time_keys": one_time_keys,
+        "failures": failures,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use serde_json::{json, Value as JsonValue};
+
+    use std::collections::HashMap;
+
+    use crate::{
+        storage::{StorageManager, mem::MemStorageManager},
+        test_util::{test_app, test_state, with_mem_db},
+    };
+
+    use super::{claims_for, keys_for, KeysClaimRequest, KeysQueryRequest};
+
+    #[test]
+    fn query_local_and_remote() {
+        with_mem_db(|db, _| async move {
+            db.create_user("alice", "password").await.unwrap();
+            let phone_keys = json!({
+                "user_id": "@alice:example.org",
+                "device_id": "PHONE",
+                "algorithms": ["m.olm.v1.curve25519-aes-sha2"],
+                "keys": { "curve25519:PHONE": "key" },
+            });
+            db.set_device_keys("alice", "PHONE", phone_keys.clone()).await.unwrap();
+
+            let req: KeysQueryRequest = serde_json::from_value(json!({
+                "timeout": 1000,
+                "device_keys": {
+                    "@alice:example.org": [],
+                    "@bob:elsewhere.org": [],
+                },
+            })).unwrap();
+            assert_eq!(req.timeout, 1000);
+            let res = keys_for(&*db, "example.org", &req).await.unwrap();
+            assert_eq!(res["device_keys"], json!({ "@alice:example.org": { "PHONE": phone_keys } }));
+            assert!(res["failures"]["elsewhere.org"].is_object());
+            assert!(res["failures"].get("example.org").is_none());
+
+            // asking for particular devices leaves the rest out
+            let req: KeysQueryRequest = serde_json::from_value(json!({
+                "device_keys": { "@alice:example.org": ["LAPTOP"] },
+            })).unwrap();
+            assert_eq!(req.timeout, 10000);
+            let res = keys_for(&*db, "example.org", &req).await.unwrap();
+            assert_eq!(res["device_keys"], json!({ "@alice:example.org": {} }));
+            assert_eq!(res["failures"], json!({}));
+        });
+    }
+
+    #[test]
+    fn claim_local_and_remote() {
+        with_mem_db(|db, _| async move {
+            db.create_user("alice", "password").await.unwrap();
+            db.create_access_token("alice", "PHONE").await.unwrap();
+            let mut keys = HashMap::new();
+            keys.insert(String::from("signed_curve25519:AAAA"), json!({ "key": "one" }));
+            db.add_one_time_keys("alice", "PHONE", keys).await.unwrap();
+
+            let req: KeysClaimRequest = serde_json::from_value(json!({
+                "one_time_keys": {
+                    "@alice:example.org": { "PHONE": "signed_curve25519" },
+                    "@bob:elsewhere.org": { "LAPTOP": "signed_curve25519" },
+                },
+            })).unwrap();
+            let res = claims_for(&*db, "example.org", &req).await.unwrap();
+            assert_eq!(res["one_time_keys"], json!({
+                "@alice:example.org": { "PHONE": { "signed_curve25519:AAAA": { "key": "one" } } },
+            }));
+            assert!(res["failures"]["elsewhere.org"].is_object());
+
+            // that was the only one
+            let res = claims_for(&*db, "example.org", &req).await.unwrap();
+            assert_eq!(res["one_time_keys"], json!({ "@alice:example.org": {} }));
+        });
+    }
+
+    #[test]
+    fn upload_and_query() {
+        let mut sys = actix_web::rt::System::new("upload_and_query");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let phone = format!("Bearer {}", db.create_access_token("alice", "PHONE").await.unwrap());
+            let laptop = format!("Bearer {}", db.create_access_token("alice", "LAPTOP").await.unwrap());
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+
+            let device_keys = |device_id: &str| json!({
+                "user_id": "@alice:example.org",
+                "device_id": device_id,
+                "algorithms": ["m.olm.v1.curve25519-aes-sha2"],
+                "keys": { format!("curve25519:{}", device_id): "key" },
+            });
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/keys/upload")
+                .header("Authorization", phone.as_str())
+                .set_json(&json!({
+                    "device_keys": device_keys("PHONE"),
+                    "one_time_keys": {
+                        "signed_curve25519:AAAA": { "key": "one" },
+                        "signed_curve25519:AAAB": { "key": "two" },
+                    },
+                }))
+                .to_request();
+            let body: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(body, json!({ "one_time_key_counts": { "signed_curve25519": 2 } }));
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/sync?timeout=0")
+                .header("Authorization", phone.as_str())
+                .to_request();
+            let body: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(body["device_one_time_keys_count"], json!({ "signed_curve25519": 2 }));
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/keys/upload")
+                .header("Authorization", laptop.as_str())
+                .set_json(&json!({ "device_keys": device_keys("LAPTOP") }))
+                .to_request();
+            let body: JsonValue = test::read_response_json(&mut app, req).await;
+            assert_eq!(body, json!({ "one_time_key_counts": {} }));
+
+            // keys for another device are turned away
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/keys/upload")
+                .header("Authorization", laptop.as_str())
+                .set_json(&json!({ "device_keys": device_keys("PHONE") }))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+            let query = |devices: JsonValue| test::TestRequest::post()
+                .uri("/_matrix/client/r0/keys/query")
+                .header("Authorization", phone.as_str())
+                .set_json(&json!({ "device_keys": { "@alice:example.org": devices } }))
+                .to_request();
+            let body: JsonValue = test::read_response_json(&mut app, query(json!([]))).await;
+            assert_eq!(body["device_keys"], json!({
+                "@alice:example.org": {
+                    "PHONE": device_keys("PHONE"),
+                    "LAPTOP": device_keys("LAPTOP"),
+                },
+            }));
+            let body: JsonValue = test::read_response_json(&mut app, query(json!(["LAPTOP"]))).await;
+            assert_eq!(body["device_keys"], json!({
+                "@alice:example.org": { "LAPTOP": device_keys("LAPTOP") },
+            }));
+        });
+    }
+
+    #[test]
+    fn device_list_changes_in_sync() {
+        let mut sys = actix_web::rt::System::new("device_list_changes_in_sync");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let alice = format!("Bearer {}", db.create_access_token("alice", "PHONE").await.unwrap());
+            let bob = format!("Bearer {}", db.create_access_token("bob", "LAPTOP").await.unwrap());
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header("Authorization", alice.as_str())
+                .set_json(&json!({ "preset": "public_chat" }))
+                .to_request();
+            let body: JsonValue = test::read_response_json(&mut app, req).await;
+            let room_id = body["room_id"].as_str().unwrap().to_owned();
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/join/{}", room_id))
+                .header("Authorization", bob.as_str())
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let sync = |auth: &str, since: Option<&str>| {
+                let uri = match since {
+                    Some(since) => format!("/_matrix/client/r0/sync?timeout=0&since={}", since),
+                    None => String::from("/_matrix/client/r0/sync?timeout=0"),
+                };
+                test::TestRequest::get().uri(&uri).header("Authorization", auth).to_request()
+            };
+            let body: JsonValue = test::read_response_json(&mut app, sync(&bob, None)).await;
+            assert!(body.get("device_lists").is_none());
+            assert!(body.get("device_one_time_keys_count").is_none());
+            let bob_batch = body["next_batch"].as_str().unwrap().to_owned();
+            let body: JsonValue = test::read_response_json(&mut app, sync(&alice, None)).await;
+            let alice_batch = body["next_batch"].as_str().unwrap().to_owned();
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/keys/upload")
+                .header("Authorization", alice.as_str())
+                .set_json(&json!({
+                    "device_keys": {
+                        "user_id": "@alice:example.org",
+                        "device_id": "PHONE",
+                        "algorithms": ["m.olm.v1.curve25519-aes-sha2"],
+                        "keys": { "curve25519:PHONE": "key" },
+                    },
+                    "one_time_keys": { "signed_curve25519:AAAA": { "key": "one" } },
+                }))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body: JsonValue = test::read_response_json(&mut app, sync(&bob, Some(&bob_batch))).await;
+            assert_eq!(body["device_lists"], json!({ "changed": ["@alice:example.org"] }));
+            let bob_batch = body["next_batch"].as_str().unwrap().to_owned();
+            let body: JsonValue = test::read_response_json(&mut app, sync(&bob, Some(&bob_batch))).await;
+            assert!(body.get("device_lists").is_none());
+
+            // once bob has left, alice no longer needs to keep track of their devices
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/leave", room_id))
+                .header("Authorization", bob.as_str())
+                .set_json(&json!({}))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body: JsonValue = test::read_response_json(&mut app, sync(&alice, Some(&alice_batch))).await;
+            assert_eq!(body["device_lists"]["left"], json!(["@bob:example.org"]));
+            assert_eq!(body["device_one_time_keys_count"], json!({ "signed_curve25519": 1 }));
+        });
+    }
+}
@@ -1,20 +1,53 @@
-use actix_web::{get, web::{self, Json}};
-use serde_json::json;
+use actix_web::{get, web::{self, Data, Json, PathConfig}};
+use serde_json::{Map, Value as JsonValue, json};
+use std::sync::Arc;
 
+use crate::{
+    ServerState,
+    client_api::auth::AccessToken,
+    error::{Error, ErrorKind},
+    events::room_version::{DEFAULT_ROOM_VERSION, SUPPORTED_ROOM_VERSIONS},
+};
+
+mod admin;
 mod auth;
+mod device;
+mod directory;
 mod ephemeral;
+mod filter;
+mod keys;
 mod room;
 mod room_events;
 mod user;
 
+pub use auth::purge_tokens_periodically;
+
 pub fn configure_endpoints(cfg: &mut web::ServiceConfig) {
+    // IDs in the path that don't parse get M_INVALID_PARAM, rather than actix's bare 404
+    cfg.app_data(PathConfig::default().error_handler(|e, _req| Error::from(e).into()));
     cfg.service(versions);
     let r0 = web::scope("/r0")
         .service(auth::get_supported_login_types)
         .service(auth::login)
         .service(auth::logout)
         .service(auth::logout_all)
+        .service(auth::refresh_access_token)
         .service(auth::register)
+        .service(auth::check_username_available)
+        .service(auth::request_register_email_token)
+        .service(auth::request_register_msisdn_token)
+        .service(auth::whoami)
+        .service(auth::change_password)
+        .service(capabilities)
+
+        .service(device::get_devices)
+        .service(device::get_device)
+        .service(device::update_device)
+        .service(device::delete_device)
+
+        .service(keys::upload_keys)
+        .service(keys::query_keys)
+        .service(keys::claim_keys)
 
         .service(user::get_avatar_url)
         .service(user::set_avatar_url)
@@ -23,21 +56,55 @@ pub fn configure_endpoints(cfg: &mut web::ServiceConfig) {
         .service(user::get_profile)
         .service(user::search_user_directory)
         .service(user::get_3pids)
+        .service(user::set_account_data)
+        .service(user::get_account_data)
+        .service(user::set_room_account_data)
+        .service(user::get_room_account_data)
+        .service(user::create_filter)
+        .service(user::get_filter)
+        .service(user::bind_3pid)
+        .service(user::unbind_3pid)
 
         .service(room::create_room)
         .service(room::invite)
         .service(room::join_by_id_or_alias)
+        .service(room::join_by_id)
+        .service(room::knock)
+        .service(room::leave)
+        .service(room::forget)
+        .service(room::kick)
+        .service(room::ban)
+        .service(room::unban)
+        .service(room::upgrade)
+
+        .service(directory::set_alias)
+        .service(directory::get_alias)
+        .service(directory::delete_alias)
 
         .service(room_events::sync)
         .service(room_events::get_event)
+        .service(room_events::get_context)
+        .service(room_events::get_relations)
+        .service(room_events::get_relations_by_type)
+        .service(room_events::timestamp_to_event)
+        .service(room_events::messages)
         .service(room_events::get_state_event_no_key)
         .service(room_events::get_state_event_key)
         .service(room_events::get_state)
         .service(room_events::get_members)
         .service(room_events::send_state_event)
         .service(room_events::send_event)
+        .service(room_events::redact)
 
         .service(ephemeral::typing)
+        .service(ephemeral::receipt)
+        .service(ephemeral::read_markers)
+        .service(ephemeral::set_presence)
+        .service(ephemeral::get_presence)
+        .service(ephemeral::send_to_device)
+
+        .service(admin::list_rooms)
+        .service(admin::shutdown_room)
 
         .wrap(actix_cors::Cors::default()
             .send_wildcard()
@@ -59,3 +126,110 @@ async fn versions() -> Json<serde_json::Value> {
         ]
     }))
 }
+
+#[get("/capabilities")]
+async fn capabilities(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    let available: Map<String, JsonValue> = SUPPORTED_ROOM_VERSIONS.iter()
+        .map(|version| (String::from(*version), json!("stable")))
+        .collect();
+    Ok(Json(json!({
+        "capabilities": {
+            "m.change_password": { "enabled": true },
+            "m.set_displayname": { "enabled": true },
+            "m.set_avatar_url": { "enabled": true },
+            "m.room_versions": {
+                "default": DEFAULT_ROOM_VERSION,
+                "available": available,
+            },
+        },
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test;
+    use serde_json::json;
+
+    use crate::{
+        storage::{StorageManager, mem::MemStorageManager},
+        test_util::{assert_errcode, test_app, test_state},
+    };
+
+    #[test]
+    fn default_room_version() {
+        let mut sys = actix_web::rt::System::new("default_room_version");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+            let auth = format!("Bearer {}", token);
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/capabilities")
+                .header("Authorization", auth.as_str())
+                .to_request();
+            let res: serde_json::Value = test::read_response_json(&mut app, req).await;
+            let room_versions = &res["capabilities"]["m.room_versions"];
+            let default = room_versions["default"].as_str().unwrap();
+            assert_eq!(room_versions["available"][default], "stable");
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header("Authorization", auth.as_str())
+                .set_json(&json!({ "visibility": "private" }))
+                .to_request();
+            let res: serde_json::Value = test::read_response_json(&mut app, req).await;
+            let room_id = res["room_id"].as_str().unwrap();
+            let create = db.get_state_event(room_id, "m.room.create", "").await.unwrap().unwrap();
+            assert_eq!(create.event_content.content_as_json()["room_version"], default);
+
+            // and versions that aren't advertised can't be created
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header("Authorization", auth.as_str())
+                .set_json(&json!({ "visibility": "private", "room_version": "1" }))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_errcode!(body, "M_UNSUPPORTED_ROOM_VERSION");
+        });
+    }
+
+    #[test]
+    fn capabilities() {
+        let mut sys = actix_web::rt::System::new("capabilities");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/capabilities")
+                .header("Authorization", format!("Bearer {}", token))
+                .to_request();
+            let res: serde_json::Value = test::read_response_json(&mut app, req).await;
+            assert_eq!(res, json!({
+                "capabilities": {
+                    "m.change_password": { "enabled": true },
+                    "m.set_displayname": { "enabled": true },
+                    "m.set_avatar_url": { "enabled": true },
+                    "m.room_versions": {
+                        "default": "4",
+                        "available": { "4": "stable", "7": "stable" },
+                    },
+                },
+            }));
+        });
+    }
+}
@@ -1,51 +1,72 @@
-use actix_web::{post, web::{Data, Json, Path}};
+use actix_web::{client::Client, post, web::{Data, Json, Path}};
 use tracing::{Level, Span, instrument, field::Empty};
 use serde::Deserialize;
 use serde_json::{Value as JsonValue, json};
 use std::{
     collections::HashMap,
+    convert::TryFrom,
     sync::Arc,
 };
 
 use crate::{
-    client_api::auth::AccessToken,
+    client_api::{auth::AccessToken, directory::resolve_alias},
     error::{Error, ErrorKind},
-    events::{room, EventContent},
-    storage::UserProfile,
-    util::{MatrixId, StorageExt, storage::NewEvent},
+    events::{room, room_version::{self, DEFAULT_ROOM_VERSION}, EventContent},
+    sign::Key,
+    state::StateResolver,
+    storage::{Storage, UserProfile},
+    util::{MatrixId, RoomAliasId, RoomId, StorageExt, storage::NewEvent},
     ServerState
 };
 
 #[derive(Deserialize)]
 pub struct CreateRoomRequest {
+    #[serde(default)]
     visibility: RoomVisibility,
     room_alias_name: Option<String>,
     name: Option<String>,
     topic: Option<String>,
-    invite: Option<Vec<String>>,
+    #[serde(default)]
+    invite: Vec<MatrixId>,
     invite_3pid: Option<Vec<Invite3pid>>,
     room_version: Option<String>,
     creation_content: Option<HashMap<String, JsonValue>>,
     initial_state: Option<Vec<StateEvent>>,
     preset: Option<Preset>,
-    is_direct: Option<bool>,
-    power_level_content_override: Option<crate::events::room::PowerLevels>,
+    #[serde(default)]
+    is_direct: bool,
+    /// Keys to set in the power levels content, on top of the defaults
+    power_level_content_override: Option<serde_json::Map<String, JsonValue>>,
+    /// The room this one replaces, when it's created by an upgrade
+    #[serde(skip)]
+    predecessor: Option<room::PreviousRoom>,
 }
 
-#[derive(Deserialize)]
+#[derive(Default, Deserialize)]
 #[serde(rename_all = "snake_case")]
 enum RoomVisibility {
     Public,
+    #[default]
     Private,
 }
 
 #[derive(Deserialize)]
-struct Invite3pid {
+pub struct Invite3pid {
     id_server: String,
+    /// Proves to the identity server who's storing the invite
+    id_access_token: Option<String>,
     medium: String,
     address: String,
 }
 
+/// What an identity server gives back once it has stored an invite.
+#[derive(Deserialize)]
+struct StoredInvite {
+    token: String,
+    public_keys: Vec<room::ThirdPartyInvitePublicKey>,
+    display_name: String,
+}
+
 #[derive(Deserialize)]
 struct StateEvent {
     #[serde(rename = "type")]
@@ -76,18 +97,91 @@ pub async fn create_room(
     Span::current().record("username", &username.as_str());
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
 
-    let room_version = req.room_version.unwrap_or("4".to_string());
-    if room_version != "4" {
+    let room_id = create_room_as(
+        &*db,
+        &state.state_resolver,
+        &state.keys,
+        &state.config.domain,
+        &user_id,
+        req,
+    ).await?;
+
+    tracing::info!(room_id = room_id.as_str(), "Created room");
+
+    Ok(Json(json!({
+        "room_id": room_id
+    })))
+}
+
+/// Creates a room for `user_id` as described by `req`, returning the new room's ID.
+///
+/// Nothing is created if the requested alias is already taken, and if anything fails partway
+/// through the room is deleted again.
+async fn create_room_as(
+    db: &dyn Storage,
+    state_resolver: &StateResolver,
+    keys: &HashMap<String, Key>,
+    server_name: &str,
+    user_id: &MatrixId,
+    req: CreateRoomRequest,
+) -> Result<String, Error> {
+    if !room_version::is_supported(req.room_version.as_deref().unwrap_or(DEFAULT_ROOM_VERSION)) {
         return Err(ErrorKind::UnsupportedRoomVersion.into());
     }
 
-    let room_id = format!("!{:016X}:{}", rand::random::<i64>(), state.config.domain);
+    let room_id = format!("!{:016X}:{}", rand::random::<i64>(), server_name);
+
+    // claim the alias first so there's nothing to undo if it's taken
+    let alias = match &req.room_alias_name {
+        Some(name) => {
+            let alias = RoomAliasId::new(name, server_name)
+                .map_err(|_| ErrorKind::InvalidParam(String::from("room_alias_name")))?;
+            if !db.set_room_alias(alias.as_str(), &room_id, user_id.localpart()).await? {
+                return Err(ErrorKind::RoomInUse.into());
+            }
+            Some(alias.as_str().to_owned())
+        },
+        None => None,
+    };
+
+    let res = add_initial_events(
+        db,
+        state_resolver,
+        keys,
+        user_id,
+        &room_id,
+        alias,
+        req,
+    ).await;
+    if let Err(e) = res {
+        // the error that stopped the room being set up is the one worth returning
+        if let Err(delete_err) = db.delete_room(&room_id).await {
+            let room_id = room_id.as_str();
+            tracing::warn!(room_id, error = %delete_err, "Failed to delete a room that couldn't be set up");
+        }
+        return Err(e);
+    }
+
+    Ok(room_id)
+}
 
+/// Sends the events which set up a newly created room.
+async fn add_initial_events(
+    db: &dyn Storage,
+    state_resolver: &StateResolver,
+    keys: &HashMap<String, Key>,
+    user_id: &MatrixId,
+    room_id: &str,
+    alias: Option<String>,
+    req: CreateRoomRequest,
+) -> Result<(), Error> {
     db.add_event(&room_id, NewEvent {
         event_content: EventContent::Create(room::Create {
-            creator: user_id.clone(),
-            room_version: Some(room_version),
-            predecessor: None,
+            creator: Some(user_id.clone()),
+            room_version: Some(
+                req.room_version.clone().unwrap_or_else(|| String::from(DEFAULT_ROOM_VERSION))
+            ),
+            predecessor: req.predecessor.clone(),
             extra: match req.creation_content {
                 Some(v) => v,
                 None => HashMap::new(),
@@ -97,15 +191,19 @@ pub async fn create_room(
         state_key: Some(String::new()),
         redacts: None,
         unsigned: None,
-    }, &state.state_resolver).await?;
+    }, state_resolver, keys).await?;
 
     let creator_join = {
-        let UserProfile { avatar_url, displayname } = db.get_profile(&username).await?.unwrap();
+        let UserProfile { avatar_url, displayname } =
+            db.get_profile(user_id.localpart()).await?.unwrap_or_default();
         room::Member {
             avatar_url,
             displayname,
             membership: room::Membership::Join,
-            is_direct: req.is_direct,
+            is_direct: None,
+            reason: None,
+            third_party_invite: None,
+            join_authorised_via_users_server: None,
         }
     };
     db.add_event(&room_id, NewEvent {
@@ -114,36 +212,52 @@ pub async fn create_room(
         state_key: Some(user_id.clone_inner()),
         redacts: None,
         unsigned: None,
-    }, &state.state_resolver).await?;
+    }, state_resolver, keys).await?;
 
-    // TODO: default power levels a bit of a mess
+    let preset = req.preset.unwrap_or(match req.visibility {
+        RoomVisibility::Private => Preset::PrivateChat,
+        RoomVisibility::Public => Preset::PublicChat,
+    });
     db.add_event(&room_id, NewEvent {
-        event_content: EventContent::PowerLevels(
-            req.power_level_content_override.unwrap_or_default()),
+        event_content: EventContent::PowerLevels(initial_power_levels(
+            user_id,
+            &preset,
+            &req.invite,
+            req.power_level_content_override,
+        )?),
         sender: user_id.clone(),
         state_key: Some(String::new()),
         redacts: None,
         unsigned: None,
-    }, &state.state_resolver).await?;
+    }, state_resolver, keys).await?;
+
+    if let Some(alias) = alias {
+        db.add_event(&room_id, NewEvent {
+            event_content: EventContent::CanonicalAlias(room::CanonicalAlias {
+                alias: Some(alias),
+                alt_aliases: Vec::new(),
+            }),
+            sender: user_id.clone(),
+            state_key: Some(String::new()),
+            redacts: None,
+            unsigned: None,
+        }, state_resolver, keys).await?;
+    }
 
     let (join_rule, history_visibility, guest_access) = {
         use room::{JoinRule::*, HistoryVisibilityType::*, GuestAccessType::*};
-        let preset = req.preset.unwrap_or(match req.visibility {
-            RoomVisibility::Private => Preset::PrivateChat,
-            RoomVisibility::Public => Preset::PublicChat,
-        });
         match preset {
             Preset::PrivateChat | Preset::TrustedPrivateChat => (Invite, Shared, CanJoin),
             Preset::PublicChat => (Public, Shared, Forbidden),
         }
     };
     db.add_event(&room_id, NewEvent {
-        event_content: EventContent::JoinRules(room::JoinRules { join_rule }),
+        event_content: EventContent::JoinRules(room::JoinRules { join_rule, allow: Vec::new() }),
         sender: user_id.clone(),
         state_key: Some(String::new()),
         redacts: None,
         unsigned: None,
-    }, &state.state_resolver).await?;
+    }, state_resolver, keys).await?;
     db.add_event(&room_id, NewEvent {
         event_content: EventContent::HistoryVisibility(room::HistoryVisibility {
             history_visibility
@@ -152,14 +266,14 @@ pub async fn create_room(
         state_key: Some(String::new()),
         redacts: None,
         unsigned: None,
-    }, &state.state_resolver).await?;
+    }, state_resolver, keys).await?;
     db.add_event(&room_id, NewEvent {
         event_content: EventContent::GuestAccess(room::GuestAccess { guest_access: Some(guest_access) }),
         sender: user_id.clone(),
         state_key: Some(String::new()),
         redacts: None,
         unsigned: None,
-    }, &state.state_resolver).await?;
+    }, state_resolver, keys).await?;
 
     for event in req.initial_state.into_iter().flatten() {
         db.add_event(&room_id, NewEvent {
@@ -168,7 +282,7 @@ pub async fn create_room(
             state_key: Some(event.state_key),
             redacts: None,
             unsigned: None,
-        }, &state.state_resolver).await?;
+        }, state_resolver, keys).await?;
     }
 
     if let Some(name) = req.name {
@@ -178,7 +292,7 @@ pub async fn create_room(
             state_key: Some(String::new()),
             redacts: None,
             unsigned: None,
-        }, &state.state_resolver).await?;
+        }, state_resolver, keys).await?;
     }
 
     if let Some(topic) = req.topic {
@@ -188,34 +302,139 @@ pub async fn create_room(
             state_key: Some(String::new()),
             redacts: None,
             unsigned: None,
-        }, &state.state_resolver).await?;
+        }, state_resolver, keys).await?;
     }
 
-    for invitee in req.invite.into_iter().flatten() {
+    for invitee in req.invite.iter() {
         db.add_event(&room_id, NewEvent {
             event_content: EventContent::Member(room::Member {
                 avatar_url: None,
                 displayname: None,
                 membership: room::Membership::Invite,
-                is_direct: req.is_direct,
+                is_direct: if req.is_direct { Some(true) } else { None },
+                reason: None,
+                third_party_invite: None,
+                join_authorised_via_users_server: None,
             }),
             sender: user_id.clone(),
-            state_key: Some(invitee),
+            state_key: Some(invitee.clone_inner()),
             redacts: None,
             unsigned: None,
-        }, &state.state_resolver).await?;
+        }, state_resolver, keys).await?;
+    }
+    if req.is_direct && !req.invite.is_empty() {
+        add_direct_room(db, user_id.localpart(), room_id, &req.invite).await?;
     }
 
-    tracing::info!(room_id = room_id.as_str(), "Created room");
+    for invite_3pid in req.invite_3pid.into_iter().flatten() {
+        let event = third_party_invite(room_id, user_id, &invite_3pid).await?;
+        db.add_event(&room_id, event, state_resolver, keys).await?;
+    }
 
-    Ok(Json(json!({
-        "room_id": room_id
-    })))
+    if let RoomVisibility::Public = req.visibility {
+        db.set_room_published(room_id, true).await?;
+    }
+
+    Ok(())
 }
 
+/// The power levels a new room starts with: the creator is the only one with any power, except
+/// that invitees to a trusted private chat get as much as the creator. The keys in `overrides`
+/// then replace the defaults.
+fn initial_power_levels(
+    creator: &MatrixId,
+    preset: &Preset,
+    invitees: &[MatrixId],
+    overrides: Option<serde_json::Map<String, JsonValue>>,
+) -> Result<room::PowerLevels, Error> {
+    let mut users = serde_json::Map::new();
+    users.insert(creator.clone_inner(), json!(100));
+    if let Preset::TrustedPrivateChat = preset {
+        for invitee in invitees {
+            users.insert(invitee.clone_inner(), json!(100));
+        }
+    }
+    let mut content = json!({
+        "ban": 50,
+        "events": {
+            "m.room.avatar": 50,
+            "m.room.canonical_alias": 50,
+            "m.room.history_visibility": 100,
+            "m.room.name": 50,
+            "m.room.power_levels": 100,
+            "m.room.server_acl": 100,
+            "m.room.tombstone": 100,
+        },
+        "events_default": 0,
+        "invite": 0,
+        "kick": 50,
+        "redact": 50,
+        "state_default": 50,
+        "users": users,
+        "users_default": 0,
+    });
+    for (key, value) in overrides.into_iter().flatten() {
+        content[key] = value;
+    }
+    serde_json::from_value(content)
+        .map_err(|e| ErrorKind::BadJson(format!("power_level_content_override: {}", e)).into())
+}
+
+/// Records the room in the user's `m.direct` account data as a direct chat with each of `users`.
+async fn add_direct_room(
+    db: &dyn Storage,
+    username: &str,
+    room_id: &str,
+    users: &[MatrixId],
+) -> Result<(), Error> {
+    let mut direct = match db.get_user_account_data(username).await?.remove("m.direct") {
+        Some(JsonValue::Object(direct)) => direct,
+        _ => serde_json::Map::new(),
+    };
+    for user_id in users {
+        let rooms = direct.entry(user_id.clone_inner()).or_insert_with(|| json!([]));
+        match rooms.as_array_mut() {
+            Some(rooms) if rooms.contains(&json!(room_id)) => {},
+            Some(rooms) => rooms.push(json!(room_id)),
+            None => *rooms = json!([room_id]),
+        }
+    }
+    db.set_user_account_data(username, "m.direct", JsonValue::Object(direct)).await
+}
+
+/// Takes the room out of the user's `m.direct` account data, dropping anyone who's left without
+/// any direct chats. The account data is left alone if the room wasn't in it.
+async fn remove_direct_room(db: &dyn Storage, username: &str, room_id: &str) -> Result<(), Error> {
+    let mut direct = match db.get_user_account_data(username).await?.remove("m.direct") {
+        Some(JsonValue::Object(direct)) => direct,
+        _ => return Ok(()),
+    };
+    let room_id = json!(room_id);
+    let mut changed = false;
+    for rooms in direct.values_mut() {
+        if let Some(rooms) = rooms.as_array_mut() {
+            let before = rooms.len();
+            rooms.retain(|r| *r != room_id);
+            changed |= rooms.len() != before;
+        }
+    }
+    if !changed {
+        return Ok(());
+    }
+    direct.retain(|_user_id, rooms| rooms.as_array().map(|r| !r.is_empty()).unwrap_or(true));
+    db.set_user_account_data(username, "m.direct", JsonValue::Object(direct)).await
+}
+
+/// Either a user ID or a third party identifier to invite.
 #[derive(Deserialize)]
-pub struct InviteRequest {
-    user_id: MatrixId,
+#[serde(untagged)]
+pub enum InviteRequest {
+    User {
+        user_id: MatrixId,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    ThirdParty(Invite3pid),
 }
 
 #[post("/rooms/{room_id}/invite")]
@@ -230,57 +449,1408 @@ pub async fn invite(
     let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
     Span::current().record("username", &username.as_str());
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
-    let invitee = req.into_inner().user_id;
-    let invitee_profile = db.get_profile(&invitee.localpart()).await?.unwrap_or_default();
 
-    let invite_event = NewEvent {
+    invite_to_room(
+        &*db,
+        &state.state_resolver,
+        &state.keys,
+        &room_id,
+        &user_id,
+        req.into_inner(),
+    ).await?;
+
+    Ok(Json(json!({})))
+}
+
+async fn invite_to_room(
+    db: &dyn Storage,
+    state_resolver: &StateResolver,
+    keys: &HashMap<String, Key>,
+    room_id: &str,
+    sender: &MatrixId,
+    req: InviteRequest,
+) -> Result<(), Error> {
+    let invite_event = match req {
+        InviteRequest::User { user_id: invitee, reason } => {
+            match db.get_membership(&invitee, room_id).await? {
+                // inviting someone twice changes nothing, so there's no need for another event,
+                // as long as the sender could have invited them in the first place
+                Some(room::Membership::Invite)
+                    if db.get_membership(sender, room_id).await? == Some(room::Membership::Join) =>
+                {
+                    return Ok(());
+                },
+                Some(room::Membership::Join) | Some(room::Membership::Ban) => {
+                    return Err(ErrorKind::Forbidden.into());
+                },
+                _ => {},
+            }
+            let invitee_profile = db.get_profile(invitee.localpart()).await?.unwrap_or_default();
+            NewEvent {
+                event_content: EventContent::Member(room::Member {
+                    avatar_url: invitee_profile.avatar_url,
+                    displayname: invitee_profile.displayname,
+                    membership: room::Membership::Invite,
+                    is_direct: Some(false),
+                    reason,
+                    third_party_invite: None,
+                    join_authorised_via_users_server: None,
+                }),
+                sender: sender.clone(),
+                state_key: Some(invitee.clone_inner()),
+                redacts: None,
+                unsigned: None,
+            }
+        },
+        InviteRequest::ThirdParty(invite_3pid) => {
+            third_party_invite(room_id, sender, &invite_3pid).await?
+        },
+    };
+
+    // power levels (m.invite) are enforced by the auth rules when the event is added
+    db.add_event(room_id, invite_event, state_resolver, keys).await?;
+    Ok(())
+}
+
+/// Invites someone by email address or phone number: their identity server stores the invite and
+/// lets them know about it, and gives back what goes in the `m.room.third_party_invite` event.
+async fn third_party_invite(
+    room_id: &str,
+    sender: &MatrixId,
+    invite_3pid: &Invite3pid,
+) -> Result<NewEvent, Error> {
+    if invite_3pid.medium != "email" && invite_3pid.medium != "msisdn" {
+        return Err(ErrorKind::InvalidParam(format!("medium: {}", invite_3pid.medium)).into());
+    }
+    let base_url = format!("https://{}", invite_3pid.id_server);
+    let stored = store_invite(&base_url, room_id, sender, invite_3pid).await?;
+    third_party_invite_event(sender, stored)
+}
+
+/// Stores an invite with the identity server at `base_url`.
+async fn store_invite(
+    base_url: &str,
+    room_id: &str,
+    sender: &MatrixId,
+    invite_3pid: &Invite3pid,
+) -> Result<StoredInvite, Error> {
+    let mut req = Client::new().post(format!("{}/_matrix/identity/v2/store-invite", base_url));
+    if let Some(id_access_token) = &invite_3pid.id_access_token {
+        req = req.bearer_auth(id_access_token);
+    }
+    let mut res = req
+        .send_json(&json!({
+            "medium": invite_3pid.medium,
+            "address": invite_3pid.address,
+            "room_id": room_id,
+            "sender": sender.as_str(),
+        }))
+        .await
+        .map_err(|e| ErrorKind::Unknown(format!("couldn't reach identity server: {}", e)))?;
+    if !res.status().is_success() {
+        let msg = format!("identity server refused to store invite: {}", res.status());
+        return Err(ErrorKind::Unknown(msg).into());
+    }
+    res.json().await
+        .map_err(|e| ErrorKind::Unknown(format!("invalid response from identity server: {}", e)).into())
+}
+
+/// Creates the `m.room.third_party_invite` event for an invite that an identity server has stored.
+///
+/// The state key is the token which the invitee presents when they eventually join, and the
+/// public keys are the identity server's, which it signs that token with.
+fn third_party_invite_event(sender: &MatrixId, stored: StoredInvite) -> Result<NewEvent, Error> {
+    // the single key is for clients that don't know about there being several
+    let (key_validity_url, public_key) = match stored.public_keys.first() {
+        Some(key) => (key.key_validity_url.clone(), key.public_key.clone()),
+        None => {
+            let msg = String::from("identity server gave no public keys");
+            return Err(ErrorKind::Unknown(msg).into());
+        },
+    };
+    Ok(NewEvent {
+        event_content: EventContent::ThirdPartyInvite(room::ThirdPartyInvite {
+            display_name: Some(stored.display_name),
+            key_validity_url,
+            public_key: Some(public_key),
+            public_keys: stored.public_keys,
+        }),
+        sender: sender.clone(),
+        state_key: Some(stored.token),
+        redacts: None,
+        unsigned: None,
+    })
+}
+
+#[post("/join/{room_id_or_alias}")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn join_by_id_or_alias(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id_or_alias): Path<String>,
+) -> Result<Json<JsonValue>, Error> {
+    //TODO: implement server_name and third_party_signed args
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let room_id = resolve_room(&*db, &state.config.domain, room_id_or_alias).await?;
+    join_room(&state, &*db, &username, &room_id).await?;
+
+    Ok(Json(serde_json::json!({
+        "room_id": room_id
+    })))
+}
+
+#[post("/rooms/{room_id}/join")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn join_by_id(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    join_room(&state, &*db, &username, &room_id).await?;
+
+    Ok(Json(serde_json::json!({
+        "room_id": room_id
+    })))
+}
+
+/// Finds the room that a path parameter taking either a room ID or an alias refers to.
+async fn resolve_room(
+    db: &dyn Storage,
+    server_name: &str,
+    room_id_or_alias: String,
+) -> Result<String, Error> {
+    if !room_id_or_alias.starts_with('#') {
+        return Ok(room_id_or_alias);
+    }
+    let alias = RoomAliasId::try_from(room_id_or_alias)
+        .map_err(|e| ErrorKind::InvalidParam(e.to_string()))?;
+    resolve_alias(db, server_name, &alias).await
+}
+
+/// Sends the user's join event for the room, using their profile for it.
+async fn join_room(
+    state: &ServerState,
+    db: &dyn Storage,
+    username: &str,
+    room_id: &str,
+) -> Result<(), Error> {
+    let user_id = MatrixId::new(username, &state.config.domain).unwrap();
+    let profile = db.get_profile(username).await?.unwrap_or_default();
+    // accepting an invite to a direct chat makes it one for the invitee too
+    let direct_with = match db.get_state_event(room_id, "m.room.member", user_id.as_str()).await? {
+        Some(event) => match event.event_content {
+            EventContent::Member(member)
+                if member.membership == room::Membership::Invite && member.is_direct == Some(true) =>
+            {
+                Some(event.sender)
+            },
+            _ => None,
+        },
+        None => None,
+    };
+
+    let event = NewEvent {
         event_content: EventContent::Member(room::Member {
-            avatar_url: invitee_profile.avatar_url,
-            displayname: invitee_profile.displayname,
-            membership: room::Membership::Invite,
+            avatar_url: profile.avatar_url,
+            displayname: profile.displayname,
+            membership: room::Membership::Join,
             is_direct: Some(false),
+            reason: None,
+            third_party_invite: None,
+            join_authorised_via_users_server: None,
         }),
         sender: user_id.clone(),
-        state_key: Some(invitee.clone_inner()),
+        state_key: Some(user_id.clone_inner()),
         redacts: None,
         unsigned: None,
     };
 
-    db.add_event(&room_id, invite_event, &state.state_resolver).await?;
+    db.add_event(room_id, event, &state.state_resolver, &state.keys).await?;
+    // coming back to a forgotten room brings it back into the user's syncs
+    db.set_room_forgotten(room_id, &user_id, false).await?;
+    if let Some(inviter) = direct_with {
+        add_direct_room(db, username, room_id, &[inviter]).await?;
+    }
+    Ok(())
+}
 
-    Ok(Json(json!({})))
+#[derive(Deserialize)]
+pub struct KnockRequest {
+    #[serde(default)]
+    reason: Option<String>,
 }
 
-#[post("/join/{room_id_or_alias}")]
-#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
-pub async fn join_by_id_or_alias(
+/// Asks to be let into a room. Anyone in the room who can invite can then let the user in by
+/// inviting them.
+#[post("/knock/{room_id_or_alias}")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn knock(
     state: Data<Arc<ServerState>>,
     token: AccessToken,
     Path(room_id_or_alias): Path<String>,
+    req: Json<KnockRequest>,
 ) -> Result<Json<JsonValue>, Error> {
-    //TODO: implement server_name and third_party_signed args, and room aliases
+    //TODO: implement server_name arg
     let db = state.db_pool.get_handle().await?;
     let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
     Span::current().record("username", &username.as_str());
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+    let room_id = resolve_room(&*db, &state.config.domain, room_id_or_alias).await?;
     let profile = db.get_profile(&username).await?.unwrap_or_default();
 
     let event = NewEvent {
         event_content: EventContent::Member(room::Member {
             avatar_url: profile.avatar_url,
             displayname: profile.displayname,
-            membership: room::Membership::Join,
-            is_direct: Some(false),
+            membership: room::Membership::Knock,
+            is_direct: None,
+            reason: req.into_inner().reason,
+            third_party_invite: None,
+            join_authorised_via_users_server: None,
         }),
         sender: user_id.clone(),
-        state_key: Some(user_id.to_string()),
+        state_key: Some(user_id.clone_inner()),
         redacts: None,
         unsigned: None,
     };
+    // whether the room can be knocked on is up to the auth rules
+    db.add_event(&room_id, event, &state.state_resolver, &state.keys).await?;
 
-    db.add_event(&room_id_or_alias, event, &state.state_resolver).await?;
+    Ok(Json(json!({ "room_id": room_id })))
+}
 
-    Ok(Json(serde_json::json!({
-        "room_id": room_id_or_alias
-    })))
+#[derive(Deserialize)]
+pub struct LeaveRequest {
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+#[post("/rooms/{room_id}/leave")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn leave(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+    req: Json<LeaveRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    let event = NewEvent {
+        event_content: EventContent::Member(room::Member {
+            avatar_url: None,
+            displayname: None,
+            membership: room::Membership::Leave,
+            is_direct: None,
+            reason: req.into_inner().reason,
+            third_party_invite: None,
+            join_authorised_via_users_server: None,
+        }),
+        sender: user_id.clone(),
+        state_key: Some(user_id.clone_inner()),
+        redacts: None,
+        unsigned: None,
+    };
+
+    db.add_event(&room_id, event, &state.state_resolver, &state.keys).await?;
+    remove_direct_room(&*db, &username, &room_id).await?;
+
+    Ok(Json(json!({})))
+}
+
+#[post("/rooms/{room_id}/forget")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn forget(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    match db.get_membership(&user_id, &room_id).await? {
+        Some(room::Membership::Leave) | Some(room::Membership::Ban) => {},
+        Some(_) => {
+            let msg = "only rooms you've left can be forgotten";
+            return Err(ErrorKind::BadState(String::from(msg)).into());
+        },
+        None => return Err(ErrorKind::NotFound.into()),
+    }
+    db.set_room_forgotten(&room_id, &user_id, true).await?;
+    Ok(Json(json!({})))
+}
+
+#[derive(Deserialize)]
+pub struct KickBanRequest {
+    user_id: MatrixId,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+#[post("/rooms/{room_id}/kick")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn kick(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+    req: Json<KickBanRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    set_membership_of(state, token, room_id, req.into_inner(), MembershipChange::Kick).await
+}
+
+#[post("/rooms/{room_id}/ban")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn ban(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+    req: Json<KickBanRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    set_membership_of(state, token, room_id, req.into_inner(), MembershipChange::Ban).await
+}
+
+#[post("/rooms/{room_id}/unban")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn unban(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+    req: Json<KickBanRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    set_membership_of(state, token, room_id, req.into_inner(), MembershipChange::Unban).await
+}
+
+/// The ways one user can change another's membership.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MembershipChange {
+    Kick,
+    Ban,
+    Unban,
+}
+
+impl MembershipChange {
+    fn membership(self) -> room::Membership {
+        match self {
+            MembershipChange::Kick | MembershipChange::Unban => room::Membership::Leave,
+            MembershipChange::Ban => room::Membership::Ban,
+        }
+    }
+
+    /// Whether the change makes sense for a user with the given membership. Anyone can be banned,
+    /// even before they've joined.
+    fn applies_to(self, membership: Option<&room::Membership>) -> bool {
+        use room::Membership::*;
+        match self {
+            // a pending invite or knock can be turned down with a kick, but there's nothing to
+            // kick anyone else out of
+            MembershipChange::Kick => matches!(membership, Some(Join) | Some(Invite) | Some(Knock)),
+            MembershipChange::Ban => true,
+            MembershipChange::Unban => membership == Some(&Ban),
+        }
+    }
+}
+
+/// Sends a member event changing another user's membership, as done by kicks and bans. Whether
+/// the sender has the power to do so is left to the auth rules.
+async fn set_membership_of(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    room_id: String,
+    req: KickBanRequest,
+    change: MembershipChange,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    // the auth rules take kicking oneself as leaving, which is what /leave is for
+    if change == MembershipChange::Kick && req.user_id == user_id {
+        let msg = "you can't kick yourself; leave the room instead";
+        return Err(ErrorKind::BadState(String::from(msg)).into());
+    }
+    let membership = db.get_membership(&req.user_id, &room_id).await?;
+    if !change.applies_to(membership.as_ref()) {
+        let msg = match change {
+            MembershipChange::Unban => format!("{} isn't banned", req.user_id.as_str()),
+            _ => format!("{} isn't in the room", req.user_id.as_str()),
+        };
+        return Err(ErrorKind::BadState(msg).into());
+    }
+
+    let event = NewEvent {
+        event_content: EventContent::Member(room::Member {
+            avatar_url: None,
+            displayname: None,
+            membership: change.membership(),
+            is_direct: None,
+            reason: req.reason,
+            third_party_invite: None,
+            join_authorised_via_users_server: None,
+        }),
+        sender: user_id,
+        state_key: Some(req.user_id.clone_inner()),
+        redacts: None,
+        unsigned: None,
+    };
+
+    db.add_event(&room_id, event, &state.state_resolver, &state.keys).await?;
+    if req.user_id.has_domain(&state.config.domain) {
+        remove_direct_room(&*db, req.user_id.localpart(), &room_id).await?;
+    }
+
+    Ok(Json(json!({})))
+}
+
+#[derive(Deserialize)]
+pub struct UpgradeRequest {
+    new_version: String,
+}
+
+#[post("/rooms/{room_id}/upgrade")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn upgrade(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+    req: Json<UpgradeRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    let replacement_room = upgrade_room(
+        &*db,
+        &state.state_resolver,
+        &state.keys,
+        &state.config.domain,
+        &user_id,
+        &room_id,
+        &req.new_version,
+    ).await?;
+
+    tracing::info!(room_id = room_id.as_str(), replacement_room = replacement_room.as_str(), "Upgraded room");
+
+    Ok(Json(json!({ "replacement_room": replacement_room })))
+}
+
+/// The state that's copied over to the room which replaces an upgraded one.
+const TRANSFERRED_STATE: &[&str] = &[
+    "m.room.name",
+    "m.room.topic",
+    "m.room.avatar",
+    "m.room.join_rules",
+    "m.room.history_visibility",
+    "m.room.guest_access",
+    "m.room.encryption",
+    "m.room.server_acl",
+];
+
+/// Replaces the room with a new one of the given version, returning the new room's ID.
+///
+/// The new room starts out with the old one's power levels and the state in `TRANSFERRED_STATE`,
+/// and everyone else who was in the old room is invited to it. The old room then has its power
+/// levels raised and is tombstoned, after which it can only be left.
+//TODO: move the old room's aliases over as well
+async fn upgrade_room(
+    db: &dyn Storage,
+    state_resolver: &StateResolver,
+    keys: &HashMap<String, Key>,
+    server_name: &str,
+    user_id: &MatrixId,
+    room_id: &str,
+    new_version: &str,
+) -> Result<String, Error> {
+    if !room_version::is_supported(new_version) {
+        return Err(ErrorKind::UnsupportedRoomVersion.into());
+    }
+    if db.get_membership(user_id, room_id).await? != Some(room::Membership::Join) {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    let state = db.get_full_state(room_id).await?;
+    let mut levels = None;
+    let mut creator = None;
+    let mut initial_state = Vec::new();
+    let mut members = Vec::new();
+    for event in state {
+        let state_key = event.state_key.unwrap_or_default();
+        match event.event_content {
+            EventContent::PowerLevels(content) => levels = Some(content),
+            EventContent::Create(content) => {
+                creator = Some(content.effective_creator(&event.sender).clone());
+            },
+            EventContent::Member(member) if member.membership == room::Membership::Join => {
+                match MatrixId::try_from(state_key.as_str()) {
+                    Ok(member_id) if member_id != *user_id => members.push(member_id),
+                    _ => {},
+                }
+            },
+            content if TRANSFERRED_STATE.contains(&content.get_type()) => {
+                initial_state.push(StateEvent {
+                    ty: content.get_type().to_owned(),
+                    state_key,
+                    content: content.content_as_json(),
+                });
+            },
+            _ => {},
+        }
+    }
+    let mut levels = match (levels, creator) {
+        (Some(levels), _) => levels,
+        (None, Some(creator)) => room::PowerLevels::no_event_default_levels(&creator),
+        (None, None) => return Err(ErrorKind::RoomNotFound.into()),
+    };
+    // check before anything's created, so that there's no new room left over if the tombstone
+    // can't be sent
+    if levels.get_user_level(user_id) < levels.get_event_level("m.room.tombstone", true) {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    let (prev_events, _) = db.get_prev_events(room_id).await?;
+    let published = db.get_published_rooms().await?.iter().any(|r| r == room_id);
+    let power_level_content_override = match EventContent::PowerLevels(levels.clone()).content_as_json() {
+        JsonValue::Object(content) => Some(content),
+        _ => None,
+    };
+    let new_room_id = create_room_as(db, state_resolver, keys, server_name, user_id, CreateRoomRequest {
+        visibility: if published { RoomVisibility::Public } else { RoomVisibility::Private },
+        room_alias_name: None,
+        name: None,
+        topic: None,
+        invite: members,
+        invite_3pid: None,
+        room_version: Some(new_version.to_owned()),
+        creation_content: None,
+        initial_state: Some(initial_state),
+        preset: None,
+        is_direct: false,
+        power_level_content_override,
+        predecessor: Some(room::PreviousRoom {
+            room_id: room_id.to_owned(),
+            event_id: prev_events.into_iter().next().unwrap_or_default(),
+        }),
+    }).await?;
+
+    let replacement_room = RoomId::try_from(new_room_id.as_str())
+        .map_err(|e| ErrorKind::Unknown(format!("created a room with a bad ID: {}", e)))?;
+    // stop everyone but moderators from talking or inviting people in the old room, for anyone
+    // whose client ignores the tombstone. This has to come first, as only leaving is allowed once
+    // the tombstone's in.
+    let original_levels = levels.clone();
+    let restricted = std::cmp::max(50, levels.users_default().saturating_add(1));
+    levels.events_default = Some(std::cmp::max(levels.events_default(), restricted));
+    levels.invite = Some(std::cmp::max(levels.invite(), restricted));
+    let raised_levels = state_event(user_id, EventContent::PowerLevels(levels));
+    let res = db.add_event(room_id, raised_levels, state_resolver, keys).await;
+    if let Err(e) = res {
+        abandon_upgrade(db, state_resolver, keys, user_id, room_id, &new_room_id, None).await;
+        return Err(e);
+    }
+    let tombstone = EventContent::Tombstone(room::Tombstone {
+        body: String::from("This room has been replaced"),
+        replacement_room: Some(replacement_room),
+    });
+    let res = db.add_event(room_id, state_event(user_id, tombstone), state_resolver, keys).await;
+    if let Err(e) = res {
+        let original_levels = Some(original_levels);
+        abandon_upgrade(db, state_resolver, keys, user_id, room_id, &new_room_id, original_levels).await;
+        return Err(e);
+    }
+    if published {
+        db.set_room_published(room_id, false).await?;
+    }
+
+    Ok(new_room_id)
+}
+
+fn state_event(sender: &MatrixId, event_content: EventContent) -> NewEvent {
+    NewEvent {
+        event_content,
+        sender: sender.clone(),
+        state_key: Some(String::new()),
+        redacts: None,
+        unsigned: None,
+    }
+}
+
+/// Undoes as much of a failed upgrade as possible: the new room is deleted, and the old room's
+/// power levels are put back if they were raised. Anything that can't be undone is logged, so
+/// that the error which stopped the upgrade is the one that's returned.
+async fn abandon_upgrade(
+    db: &dyn Storage,
+    state_resolver: &StateResolver,
+    keys: &HashMap<String, Key>,
+    user_id: &MatrixId,
+    room_id: &str,
+    new_room_id: &str,
+    original_levels: Option<room::PowerLevels>,
+) {
+    if let Some(levels) = original_levels {
+        let event = state_event(user_id, EventContent::PowerLevels(levels));
+        if let Err(e) = db.add_event(room_id, event, state_resolver, keys).await {
+            tracing::warn!(room_id, error = %e, "Failed to restore power levels after a failed upgrade");
+        }
+    }
+    if let Err(e) = db.delete_room(new_room_id).await {
+        tracing::warn!(room_id = new_room_id, error = %e, "Failed to delete the room from a failed upgrade");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{App, HttpRequest, HttpResponse, http::StatusCode, test, web};
+    use serde_json::{json, Value as JsonValue};
+
+    use std::collections::HashMap;
+
+    use crate::{
+        events::{room::{Create, Member, Membership}, EventContent},
+        state::StateResolver,
+        storage::{mem::MemStorageManager, EventQuery, QueryType, StorageManager},
+        test_util::{assert_errcode, assert_status, RoomBuilder, test_app, test_state, with_mem_db},
+        util::{MatrixId, StorageExt, storage::NewEvent},
+    };
+
+    use super::{
+        create_room_as, invite_to_room, store_invite, third_party_invite_event, Invite3pid,
+        InviteRequest,
+    };
+
+    #[test]
+    fn invite_by_user_id_and_email() {
+        let mut sys = actix_web::rt::System::new("invite_by_user_id_and_email");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            let keys = HashMap::new();
+            let room_id = "!test:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            let carol = MatrixId::new("carol", "example.org").unwrap();
+
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+
+            invite_to_room(&*db, &state_resolver, &keys, room_id, &alice, InviteRequest::User {
+                user_id: bob.clone(),
+                reason: None,
+            }).await.expect("failed to invite by user id");
+            assert_eq!(db.get_membership(&bob, room_id).await.unwrap(), Some(Membership::Invite));
+
+            // bob hasn't joined yet, so they can't invite anyone
+            invite_to_room(&*db, &state_resolver, &keys, room_id, &bob, InviteRequest::User {
+                user_id: carol.clone(),
+                reason: None,
+            }).await.expect_err("invited by a user who isn't in the room");
+            assert_eq!(db.get_membership(&carol, room_id).await.unwrap(), None);
+
+            // the identity server stores the invite, and gives back what goes in the event
+            let id_server = test::start(|| App::new().route(
+                "/_matrix/identity/v2/store-invite",
+                web::post().to(|req: HttpRequest, body: web::Json<JsonValue>| async move {
+                    if req.headers().get("Authorization").map(|v| v == "Bearer id-token") != Some(true) {
+                        return HttpResponse::Unauthorized().json(json!({ "errcode": "M_UNAUTHORIZED" }));
+                    }
+                    assert_eq!(body["address"], "carol@example.com");
+                    assert_eq!(body["room_id"], "!test:example.org");
+                    HttpResponse::Ok().json(json!({
+                        "token": "invite-token",
+                        "public_keys": [{
+                            "public_key": "serverkey",
+                            "key_validity_url": "https://id.example.org/_matrix/identity/v2/pubkey/isvalid",
+                        }],
+                        "display_name": "c...@e...",
+                    }))
+                }),
+            ));
+            let mut invite_3pid = Invite3pid {
+                id_server: String::from("id.example.org"),
+                id_access_token: Some(String::from("id-token")),
+                medium: String::from("email"),
+                address: String::from("carol@example.com"),
+            };
+            let base_url = format!("http://{}", id_server.addr());
+            let stored = store_invite(&base_url, room_id, &alice, &invite_3pid).await
+                .expect("identity server didn't store invite");
+            let event = third_party_invite_event(&alice, stored).unwrap();
+            db.add_event(room_id, event, &state_resolver, &keys).await.unwrap();
+            let invite = db.get_state_event(room_id, "m.room.third_party_invite", "invite-token").await
+                .unwrap()
+                .expect("no m.room.third_party_invite event");
+            let content = invite.event_content.content_as_json();
+            assert_eq!(content["display_name"], "c...@e...");
+            assert_eq!(content["public_key"], "serverkey");
+            assert_eq!(content["public_keys"][0]["public_key"], "serverkey");
+            assert_eq!(
+                content["key_validity_url"],
+                "https://id.example.org/_matrix/identity/v2/pubkey/isvalid",
+            );
+
+            // an identity server that won't store it means there's no invite
+            invite_3pid.id_access_token = None;
+            store_invite(&base_url, room_id, &alice, &invite_3pid).await
+                .expect_err("identity server stored invite without an access token");
+
+            let err = invite_to_room(&*db, &state_resolver, &keys, room_id, &alice, InviteRequest::ThirdParty(
+                Invite3pid {
+                    id_server: String::from("id.example.org"),
+                    id_access_token: None,
+                    medium: String::from("fax"),
+                    address: String::from("555-0100"),
+                }
+            )).await.expect_err("invited by fax");
+            assert_errcode!(err, "M_INVALID_PARAM");
+        });
+    }
+
+    #[test]
+    fn invite_membership_transitions() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            let carol = MatrixId::new("carol", "example.org").unwrap();
+            let dave = MatrixId::new("dave", "example.org").unwrap();
+            let req = serde_json::from_value(serde_json::json!({ "preset": "private_chat" })).unwrap();
+            let room_id = create_room_as(&*db, &state_resolver, &keys, "example.org", &alice, req)
+                .await.unwrap();
+            let invite = |user_id: &MatrixId| InviteRequest::User {
+                user_id: user_id.clone(),
+                reason: None,
+            };
+            let membership = |user_id: &MatrixId, membership: Membership| NewEvent {
+                event_content: EventContent::Member(Member {
+                    avatar_url: None,
+                    displayname: None,
+                    membership,
+                    is_direct: None,
+                    reason: None,
+                    third_party_invite: None,
+                    join_authorised_via_users_server: None,
+                }),
+                sender: alice.clone(),
+                state_key: Some(user_id.clone_inner()),
+                redacts: None,
+                unsigned: None,
+            };
+            let latest_depth = || async {
+                db.get_prev_events(&room_id).await.unwrap().1
+            };
+
+            invite_to_room(&*db, &state_resolver, &keys, &room_id, &alice, invite(&bob))
+                .await.expect("failed to invite");
+            assert_eq!(db.get_membership(&bob, &room_id).await.unwrap(), Some(Membership::Invite));
+
+            // inviting again succeeds without sending anything
+            let depth = latest_depth().await;
+            invite_to_room(&*db, &state_resolver, &keys, &room_id, &alice, invite(&bob))
+                .await.expect("failed to invite again");
+            assert_eq!(latest_depth().await, depth);
+
+            invite_to_room(&*db, &state_resolver, &keys, &room_id, &alice, invite(&carol))
+                .await.unwrap();
+            let mut join = membership(&carol, Membership::Join);
+            join.sender = carol.clone();
+            db.add_event(&room_id, join, &state_resolver, &keys).await.unwrap();
+            let err = invite_to_room(&*db, &state_resolver, &keys, &room_id, &alice, invite(&carol))
+                .await.unwrap_err();
+            assert_errcode!(err, "M_FORBIDDEN");
+
+            db.add_event(&room_id, membership(&dave, Membership::Ban), &state_resolver, &keys)
+                .await.unwrap();
+            let err = invite_to_room(&*db, &state_resolver, &keys, &room_id, &alice, invite(&dave))
+                .await.unwrap_err();
+            assert_errcode!(err, "M_FORBIDDEN");
+            assert_eq!(db.get_membership(&dave, &room_id).await.unwrap(), Some(Membership::Ban));
+        });
+    }
+
+    #[test]
+    fn create_room_with_alias() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let req = || serde_json::from_value(serde_json::json!({
+                "visibility": "public",
+                "room_alias_name": "lobby",
+                "name": "Lobby",
+            })).unwrap();
+
+            let room_id = create_room_as(&*db, &state_resolver, &keys, "example.org", &alice, req())
+                .await.unwrap();
+            assert_eq!(
+                db.get_room_alias("#lobby:example.org").await.unwrap().as_deref(),
+                Some(room_id.as_str()),
+            );
+            let canonical_alias = db.get_state_event(&room_id, "m.room.canonical_alias", "")
+                .await.unwrap().unwrap();
+            assert_eq!(canonical_alias.event_content.content_as_json()["alias"], "#lobby:example.org");
+            assert_eq!(db.get_membership(&alice, &room_id).await.unwrap(), Some(Membership::Join));
+
+            let err = create_room_as(&*db, &state_resolver, &keys, "example.org", &bob, req())
+                .await.unwrap_err();
+            assert_status!(err, StatusCode::BAD_REQUEST);
+            assert_errcode!(err, "M_ROOM_IN_USE");
+            assert_eq!(db.get_rooms().await.unwrap(), vec![room_id.clone()]);
+            assert_eq!(
+                db.get_room_alias("#lobby:example.org").await.unwrap().as_deref(),
+                Some(room_id.as_str()),
+            );
+        });
+    }
+
+    #[test]
+    fn create_public_chat() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let req = serde_json::from_value(serde_json::json!({
+                "preset": "public_chat",
+                "visibility": "public",
+                "name": "Town square",
+                "topic": "Anything goes",
+                "invite": [bob.as_str()],
+                "initial_state": [{
+                    "type": "org.example.rules",
+                    "content": { "rules": "be nice" },
+                }],
+                "power_level_content_override": { "events_default": 10 },
+            })).unwrap();
+
+            let room_id = create_room_as(&*db, &state_resolver, &keys, "example.org", &alice, req)
+                .await.unwrap();
+            let (events, _) = db.query_events(EventQuery {
+                query_type: QueryType::Timeline { from: 0, to: None },
+                room_id: &room_id,
+                senders: &[],
+                not_senders: &[],
+                types: &[],
+                not_types: &[],
+                contains_json: None,
+            }, false).await.unwrap();
+            let types: Vec<_> = events.iter().map(|e| e.event_content.get_type()).collect();
+            assert_eq!(types, vec![
+                "m.room.create",
+                "m.room.member",
+                "m.room.power_levels",
+                "m.room.join_rules",
+                "m.room.history_visibility",
+                "m.room.guest_access",
+                "org.example.rules",
+                "m.room.name",
+                "m.room.topic",
+                "m.room.member",
+            ]);
+
+            let room_state: HashMap<_, _> = db.get_full_state(&room_id).await.unwrap()
+                .into_iter()
+                .filter(|event| event.state_key.as_deref() == Some(""))
+                .map(|event| {
+                    let ty = event.event_content.get_type().to_string();
+                    (ty, event.event_content.content_as_json())
+                })
+                .collect();
+            assert_eq!(room_state["m.room.join_rules"]["join_rule"], "public");
+            assert_eq!(room_state["m.room.history_visibility"]["history_visibility"], "shared");
+            assert_eq!(room_state["m.room.guest_access"]["guest_access"], "forbidden");
+            assert_eq!(room_state["m.room.name"]["name"], "Town square");
+            assert_eq!(room_state["m.room.topic"]["topic"], "Anything goes");
+            let power_levels = &room_state["m.room.power_levels"];
+            assert_eq!(power_levels["users"], serde_json::json!({ "@alice:example.org": 100 }));
+            assert_eq!(power_levels["users_default"], 0);
+            assert_eq!(power_levels["events_default"], 10);
+            assert_eq!(power_levels["events"]["m.room.power_levels"], 100);
+
+            assert_eq!(db.get_membership(&alice, &room_id).await.unwrap(), Some(Membership::Join));
+            assert_eq!(db.get_membership(&bob, &room_id).await.unwrap(), Some(Membership::Invite));
+            assert_eq!(db.get_published_rooms().await.unwrap(), vec![room_id.clone()]);
+        });
+    }
+
+    #[test]
+    fn create_private_chat() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let req = serde_json::from_value(json!({ "preset": "private_chat" })).unwrap();
+
+            // the creator's join comes before the join rules, and is let through because it
+            // directly follows their create event
+            let room_id = create_room_as(&*db, &state_resolver, &keys, "example.org", &alice, req)
+                .await.expect("creator's join was rejected");
+            assert_eq!(db.get_membership(&alice, &room_id).await.unwrap(), Some(Membership::Join));
+            let join_rules = db.get_state_event(&room_id, "m.room.join_rules", "").await.unwrap()
+                .unwrap();
+            assert_eq!(join_rules.event_content.content_as_json()["join_rule"], "invite");
+
+            // nobody else gets in without an invite
+            let join = NewEvent {
+                event_content: EventContent::Member(Member {
+                    avatar_url: None,
+                    displayname: None,
+                    membership: Membership::Join,
+                    is_direct: None,
+                    reason: None,
+                    third_party_invite: None,
+                    join_authorised_via_users_server: None,
+                }),
+                sender: bob.clone(),
+                state_key: Some(bob.clone_inner()),
+                redacts: None,
+                unsigned: None,
+            };
+            let err = db.add_event(&room_id, join, &state_resolver, &keys).await
+                .expect_err("uninvited user joined a private room");
+            assert_errcode!(err, "M_FORBIDDEN");
+            assert_eq!(db.get_membership(&bob, &room_id).await.unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn create_room_versions() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let req = |room_version: &str| serde_json::from_value(serde_json::json!({
+                "visibility": "private",
+                "room_version": room_version,
+            })).unwrap();
+
+            let err = create_room_as(&*db, &state_resolver, &keys, "example.org", &alice, req("2"))
+                .await.unwrap_err();
+            assert_errcode!(err, "M_UNSUPPORTED_ROOM_VERSION");
+            assert!(db.get_rooms().await.unwrap().is_empty());
+
+            for room_version in &["4", "7"] {
+                let req = req(*room_version);
+                let room_id = create_room_as(&*db, &state_resolver, &keys, "example.org", &alice, req)
+                    .await.unwrap();
+                let create = db.get_state_event(&room_id, "m.room.create", "").await.unwrap().unwrap();
+                assert_eq!(create.event_content.content_as_json()["room_version"], *room_version);
+            }
+        });
+    }
+
+    #[test]
+    fn create_direct_room() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let req = serde_json::from_value(serde_json::json!({
+                "visibility": "private",
+                "preset": "trusted_private_chat",
+                "invite": ["@bob:example.org"],
+                "is_direct": true,
+            })).unwrap();
+
+            let room_id = create_room_as(&*db, &state_resolver, &keys, "example.org", &alice, req)
+                .await.unwrap();
+
+            let invite = db.get_state_event(&room_id, "m.room.member", bob.as_str())
+                .await.unwrap().unwrap();
+            let content = invite.event_content.content_as_json();
+            assert_eq!(content["membership"], "invite");
+            assert_eq!(content["is_direct"], true);
+            let join = db.get_state_event(&room_id, "m.room.member", alice.as_str())
+                .await.unwrap().unwrap();
+            assert!(join.event_content.content_as_json().get("is_direct").is_none());
+
+            let account_data = db.get_user_account_data("alice").await.unwrap();
+            assert_eq!(account_data["m.direct"], serde_json::json!({ "@bob:example.org": [room_id] }));
+            assert!(db.get_user_account_data("bob").await.unwrap().get("m.direct").is_none());
+        });
+    }
+
+    #[test]
+    fn direct_rooms_follow_membership() {
+        let mut sys = actix_web::rt::System::new("direct_rooms_follow_membership");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let alice_auth = format!("Bearer {}", db.create_access_token("alice", "phone").await.unwrap());
+            let bob_auth = format!("Bearer {}", db.create_access_token("bob", "phone").await.unwrap());
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+            let direct = |username: &str| {
+                let db = &db;
+                let username = username.to_owned();
+                async move {
+                    db.get_user_account_data(&username).await.unwrap().remove("m.direct")
+                }
+            };
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header("Authorization", alice_auth.as_str())
+                .set_json(&json!({
+                    "preset": "trusted_private_chat",
+                    "invite": ["@bob:example.org"],
+                    "is_direct": true,
+                }))
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+            let room_id = body["room_id"].as_str().unwrap().to_owned();
+
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/join/{}", room_id))
+                .header("Authorization", bob_auth.as_str())
+                .to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::OK);
+            assert_eq!(direct("bob").await, Some(json!({ "@alice:example.org": [room_id] })));
+
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/leave", room_id))
+                .header("Authorization", bob_auth.as_str())
+                .set_json(&json!({}))
+                .to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::OK);
+            assert_eq!(direct("bob").await, Some(json!({})));
+            // alice keeps the room as a direct chat until leaving it too
+            assert_eq!(direct("alice").await, Some(json!({ "@bob:example.org": [room_id] })));
+        });
+    }
+
+    #[test]
+    fn kick_checks_target() {
+        let mut sys = actix_web::rt::System::new("kick_checks_target");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            for user in ["alice", "bob", "carol"].iter() {
+                db.create_user(user, "password").await.unwrap();
+            }
+            let alice_auth = format!("Bearer {}", db.create_access_token("alice", "phone").await.unwrap());
+            let bob_auth = format!("Bearer {}", db.create_access_token("bob", "phone").await.unwrap());
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header("Authorization", alice_auth.as_str())
+                .set_json(&json!({ "preset": "public_chat" }))
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+            let room_id = body["room_id"].as_str().unwrap().to_owned();
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/join/{}", room_id))
+                .header("Authorization", bob_auth.as_str())
+                .to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::OK);
+
+            let kick = |user_id: &str| test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/kick", room_id))
+                .header("Authorization", alice_auth.as_str())
+                .set_json(&json!({ "user_id": user_id }))
+                .to_request();
+            let res = test::call_service(&mut app, kick("@alice:example.org")).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_errcode!(body, "M_BAD_STATE");
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            assert_eq!(db.get_membership(&alice, &room_id).await.unwrap(), Some(Membership::Join));
+
+            let res = test::call_service(&mut app, kick("@carol:example.org")).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_errcode!(body, "M_BAD_STATE");
+
+            let res = test::call_service(&mut app, kick("@bob:example.org")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            assert_eq!(db.get_membership(&bob, &room_id).await.unwrap(), Some(Membership::Leave));
+            // once they're gone, there's no kicking them again
+            let res = test::call_service(&mut app, kick("@bob:example.org")).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn kick_ban_unban_and_forget() {
+        let mut sys = actix_web::rt::System::new("kick_ban_unban_and_forget");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let mut auth = HashMap::new();
+            for user in ["alice", "bob", "carol"].iter() {
+                db.create_user(user, "password").await.unwrap();
+                let token = db.create_access_token(user, "phone").await.unwrap();
+                auth.insert(*user, format!("Bearer {}", token));
+            }
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header("Authorization", auth["alice"].as_str())
+                .set_json(&json!({ "preset": "public_chat" }))
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+            let room_id = body["room_id"].as_str().unwrap().to_owned();
+            let join = |user: &str| test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/join", room_id))
+                .header("Authorization", auth[user].as_str())
+                .to_request();
+            for user in ["bob", "carol"].iter() {
+                let body: serde_json::Value = test::read_response_json(&mut app, join(user)).await;
+                assert_eq!(body["room_id"], room_id.as_str());
+            }
+            // carol's syncs from here on would include the room, even after leaving it
+            let sync = |since: &str| test::TestRequest::get()
+                .uri(&format!("/_matrix/client/r0/sync?timeout=0{}", since))
+                .header("Authorization", auth["carol"].as_str())
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&mut app, sync("")).await;
+            assert!(body["rooms"]["join"].get(&room_id).is_some());
+            let since = format!("&since={}", body["next_batch"].as_str().unwrap());
+
+            let act = |action: &str, by: &str| test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/{}", room_id, action))
+                .header("Authorization", auth[by].as_str())
+                .set_json(&json!({ "user_id": "@carol:example.org", "reason": "testing" }))
+                .to_request();
+            let carol = MatrixId::new("carol", "example.org").unwrap();
+            // bob has no more power than carol
+            let res = test::call_service(&mut app, act("kick", "bob")).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            assert_eq!(db.get_membership(&carol, &room_id).await.unwrap(), Some(Membership::Join));
+            let res = test::call_service(&mut app, act("kick", "alice")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(db.get_membership(&carol, &room_id).await.unwrap(), Some(Membership::Leave));
+
+            let res = test::call_service(&mut app, act("unban", "alice")).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            let res = test::call_service(&mut app, act("ban", "alice")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let res = test::call_service(&mut app, join("carol")).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let res = test::call_service(&mut app, act("unban", "alice")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(db.get_membership(&carol, &room_id).await.unwrap(), Some(Membership::Leave));
+
+            // forgetting only works once carol is out, and hides the room from carol's syncs
+            let forget = |user: &str| test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/forget", room_id))
+                .header("Authorization", auth[user].as_str())
+                .to_request();
+            let res = test::call_service(&mut app, forget("bob")).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            let res = test::call_service(&mut app, forget("carol")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body: serde_json::Value = test::read_response_json(&mut app, sync(&since)).await;
+            assert!(body["rooms"]["leave"].get(&room_id).is_none());
+
+            // rejoining brings it back
+            let res = test::call_service(&mut app, join("carol")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(!db.is_room_forgotten(&room_id, &carol).await.unwrap());
+        });
+    }
+
+    #[test]
+    fn knock_and_be_let_in() {
+        let mut sys = actix_web::rt::System::new("knock_and_be_let_in");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let mut auth = HashMap::new();
+            for user in ["alice", "bob"].iter() {
+                db.create_user(user, "password").await.unwrap();
+                let token = db.create_access_token(user, "phone").await.unwrap();
+                auth.insert(*user, format!("Bearer {}", token));
+            }
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            let create_room = |room_version: &str, join_rule: &str| test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header("Authorization", auth["alice"].as_str())
+                .set_json(&json!({
+                    "room_version": room_version,
+                    "initial_state": [{
+                        "type": "m.room.join_rules",
+                        "content": { "join_rule": join_rule },
+                    }],
+                }))
+                .to_request();
+            let knock = |room_id: &str| test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/knock/{}", room_id))
+                .header("Authorization", auth["bob"].as_str())
+                .set_json(&json!({ "reason": "let me in" }))
+                .to_request();
+
+            // invite-only rooms can't be knocked on, and neither can rooms from before knocking
+            // was added in version 7
+            for (room_version, join_rule) in &[("7", "invite"), ("4", "knock")] {
+                let body: serde_json::Value =
+                    test::read_response_json(&mut app, create_room(*room_version, *join_rule)).await;
+                let room_id = body["room_id"].as_str().unwrap();
+                let res = test::call_service(&mut app, knock(room_id)).await;
+                assert_eq!(res.status(), StatusCode::FORBIDDEN);
+                assert_eq!(db.get_membership(&bob, room_id).await.unwrap(), None);
+            }
+
+            let body: serde_json::Value =
+                test::read_response_json(&mut app, create_room("7", "knock")).await;
+            let room_id = body["room_id"].as_str().unwrap().to_owned();
+            let body: serde_json::Value = test::read_response_json(&mut app, knock(&room_id)).await;
+            assert_eq!(body["room_id"], room_id.as_str());
+            assert_eq!(db.get_membership(&bob, &room_id).await.unwrap(), Some(Membership::Knock));
+            // knocking doesn't get bob in on its own
+            let join = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/join", room_id))
+                .header("Authorization", auth["bob"].as_str());
+            let res = test::call_service(&mut app, join.to_request()).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+            let sync = |since: &str| test::TestRequest::get()
+                .uri(&format!("/_matrix/client/r0/sync?timeout=0{}", since))
+                .header("Authorization", auth["bob"].as_str())
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&mut app, sync("")).await;
+            let knock_state = body["rooms"]["knock"][&room_id]["knock_state"]["events"].as_array().unwrap();
+            assert!(knock_state.iter().any(|e| e["type"] == "m.room.join_rules"));
+            let since = format!("&since={}", body["next_batch"].as_str().unwrap());
+            let body: serde_json::Value = test::read_response_json(&mut app, sync(&since)).await;
+            assert!(body["rooms"]["knock"].get(&room_id).is_none());
+
+            // alice lets bob in by inviting them
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/invite", room_id))
+                .header("Authorization", auth["alice"].as_str())
+                .set_json(&json!({ "user_id": "@bob:example.org" }))
+                .to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::OK);
+            let join = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/join", room_id))
+                .header("Authorization", auth["bob"].as_str());
+            let res = test::call_service(&mut app, join.to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(db.get_membership(&bob, &room_id).await.unwrap(), Some(Membership::Join));
+        });
+    }
+
+    #[test]
+    fn upgrade_room() {
+        let mut sys = actix_web::rt::System::new("upgrade_room");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let mut auth = HashMap::new();
+            for user in ["alice", "bob"].iter() {
+                db.create_user(user, "password").await.unwrap();
+                let token = db.create_access_token(user, "phone").await.unwrap();
+                auth.insert(*user, format!("Bearer {}", token));
+            }
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header("Authorization", auth["alice"].as_str())
+                .set_json(&json!({ "preset": "public_chat", "name": "Lobby", "topic": "chatter" }))
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+            let room_id = body["room_id"].as_str().unwrap().to_owned();
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/join", room_id))
+                .header("Authorization", auth["bob"].as_str())
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let upgrade = |user: &str, version: &str| test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/upgrade", room_id))
+                .header("Authorization", auth[user].as_str())
+                .set_json(&json!({ "new_version": version }))
+                .to_request();
+            let res = test::call_service(&mut app, upgrade("alice", "1")).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            // bob can't send the tombstone, so nothing gets created
+            let rooms_before = db.get_rooms().await.unwrap().len();
+            let res = test::call_service(&mut app, upgrade("bob", "4")).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            assert_eq!(db.get_rooms().await.unwrap().len(), rooms_before);
+            let body: serde_json::Value = test::read_response_json(&mut app, upgrade("alice", "4")).await;
+            let new_room_id = body["replacement_room"].as_str().unwrap().to_owned();
+
+            let tombstone = db.get_state_event(&room_id, "m.room.tombstone", "").await.unwrap().unwrap();
+            assert_eq!(tombstone.event_content.content_as_json()["replacement_room"], new_room_id.as_str());
+            let create = db.get_state_event(&new_room_id, "m.room.create", "").await.unwrap().unwrap();
+            let (last_event, _) = db.get_prev_events(&room_id).await.unwrap();
+            let predecessor = match create.event_content {
+                EventContent::Create(Create { predecessor: Some(predecessor), .. }) => predecessor,
+                _ => panic!("no predecessor"),
+            };
+            assert_eq!(predecessor.room_id, room_id);
+            // the tombstone and the new power levels come after the predecessor's last event
+            assert!(!last_event.contains(&predecessor.event_id));
+            assert!(db.get_pdu(&room_id, &predecessor.event_id).await.unwrap().is_some());
+
+            for (ty, key, value) in [
+                ("m.room.name", "name", json!("Lobby")),
+                ("m.room.topic", "topic", json!("chatter")),
+                ("m.room.join_rules", "join_rule", json!("public")),
+            ].iter() {
+                let event = db.get_state_event(&new_room_id, ty, "").await.unwrap().unwrap();
+                assert_eq!(event.event_content.content_as_json()[key], *value);
+            }
+            let levels = db.get_state_event(&new_room_id, "m.room.power_levels", "").await.unwrap().unwrap();
+            assert_eq!(levels.event_content.content_as_json()["users"], json!({ "@alice:example.org": 100 }));
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            assert_eq!(db.get_membership(&bob, &new_room_id).await.unwrap(), Some(Membership::Invite));
+
+            let levels = db.get_state_event(&room_id, "m.room.power_levels", "").await.unwrap().unwrap();
+            assert_eq!(levels.event_content.content_as_json()["events_default"], 50);
+
+            // nobody can talk in the old room any more, though they can still leave it
+            let send = |user: &str, txn_id: &str| test::TestRequest::put()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/send/m.room.message/{}", room_id, txn_id))
+                .header("Authorization", auth[user].as_str())
+                .set_json(&json!({ "msgtype": "m.text", "body": "hello?" }))
+                .to_request();
+            let res = test::call_service(&mut app, send("bob", "1")).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            let res = test::call_service(&mut app, send("alice", "2")).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            let req = test::TestRequest::post()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/leave", room_id))
+                .header("Authorization", auth["bob"].as_str())
+                .set_json(&json!({}))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            // upgrading it again fails once the new room's made, which is then deleted
+            let rooms_before = db.get_rooms().await.unwrap().len();
+            let res = test::call_service(&mut app, upgrade("alice", "4")).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(db.get_rooms().await.unwrap().len(), rooms_before);
+        });
+    }
 }
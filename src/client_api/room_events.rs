@@ -1,22 +1,28 @@
-use actix_web::{get, put, web::{Data, Json, Path, Query}};
-use futures::FutureExt;
+use actix_web::{HttpResponse, get, put, web::{Bytes, Data, Json, Path, Query}};
+use futures::{FutureExt, Stream, StreamExt, TryStreamExt, future, stream};
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use serde_json::{Value as JsonValue, json};
 use tracing::{Level, Span, instrument, field::Empty};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
     sync::Arc
 };
 use tokio::time::{Duration, delay_for};
 
 use crate::{
-    client_api::auth::AccessToken,
+    client_api::{
+        auth::{AccessToken, OptionalAccessToken},
+        ephemeral::presence_content,
+        filter::{Filter, RoomEventFilter, RoomFilter},
+    },
     error::{Error, ErrorKind},
     events::{
-        Event, EventContent,
-        room::Membership,
+        Event, EventContent, ephemeral::{PresenceState, ToDeviceEvent}, pdu::StoredPdu,
+        room::{self, HistoryVisibility, HistoryVisibilityType, Membership},
     },
-    storage::{EventQuery, QueryType},
+    storage::{Batch, EventQuery, QueryType, Storage, UnreadCounts},
     util::{MatrixId, StorageExt, storage::NewEvent},
     ServerState,
 };
@@ -37,7 +43,7 @@ pub struct SyncRequest {
 }
 
 #[derive(Debug, Deserialize)]
-#[serde(rename = "snake_case")]
+#[serde(rename_all = "snake_case")]
 enum SetPresence {
     Offline,
     Online,
@@ -54,6 +60,13 @@ pub struct SyncResponse {
     rooms: Option<Rooms>,
     presence: Option<Presence>,
     account_data: AccountData,
+    /// How many unclaimed one-time keys the syncing device has, by algorithm
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    device_one_time_keys_count: HashMap<String, usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_lists: Option<DeviceLists>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to_device: Option<ToDevice>,
 }
 
 #[derive(Debug, Default, Serialize)]
@@ -61,6 +74,7 @@ struct Rooms {
     join: HashMap<String, JoinedRoom>,
     invite: HashMap<String, InvitedRoom>,
     leave: HashMap<String, LeftRoom>,
+    knock: HashMap<String, KnockedRoom>,
 }
 
 #[derive(Debug, Serialize)]
@@ -70,6 +84,13 @@ struct JoinedRoom {
     timeline: Timeline,
     ephemeral: Ephemeral,
     account_data: AccountData,
+    unread_notifications: UnreadNotificationCounts,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct UnreadNotificationCounts {
+    highlight_count: usize,
+    notification_count: usize,
 }
 
 #[derive(Debug, Serialize)]
@@ -123,6 +144,16 @@ struct InviteState {
     events: Vec<StrippedState>,
 }
 
+#[derive(Debug, Serialize)]
+struct KnockedRoom {
+    knock_state: KnockState,
+}
+
+#[derive(Debug, Serialize)]
+struct KnockState {
+    events: Vec<StrippedState>,
+}
+
 #[derive(Debug, Serialize)]
 struct StrippedState {
     #[serde(flatten)]
@@ -140,7 +171,35 @@ struct LeftRoom {
 
 #[derive(Debug, Serialize)]
 struct Presence {
-    events: Vec<KvPair>,
+    events: Vec<PresenceEvent>,
+}
+
+#[derive(Debug, Serialize)]
+struct ToDevice {
+    events: Vec<ToDeviceEvent>,
+}
+
+/// Users whose devices the client should query again, and users it no longer needs to track.
+#[derive(Debug, Default, Serialize)]
+struct DeviceLists {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    changed: Vec<MatrixId>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    left: Vec<MatrixId>,
+}
+
+impl DeviceLists {
+    fn is_empty(&self) -> bool {
+        self.changed.is_empty() && self.left.is_empty()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PresenceEvent {
+    content: JsonValue,
+    sender: MatrixId,
+    #[serde(rename = "type")]
+    ty: String,
 }
 
 #[get("/sync")]
@@ -154,8 +213,26 @@ pub async fn sync(
     let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
     Span::current().record("username", &username.as_str());
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+    let device_id = db.get_token_device(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    let filter = Filter::load(&*db, &username, req.filter.as_deref()).await?;
+    // syncing as offline lets a client keep up without letting on that the user is around
+    match req.set_presence {
+        SetPresence::Online => db.set_presence(&username, PresenceState::Online).await?,
+        SetPresence::Unavailable => db.set_presence(&username, PresenceState::Unavailable).await?,
+        SetPresence::Offline => {},
+    }
 
-    let mut batch = db.get_batch(req.since.as_deref().unwrap_or("empty")).await?.unwrap_or_default();
+    let mut batch = match req.since.as_deref() {
+        Some(since) => {
+            // a token we never handed out can't be told apart from a typo, so carrying on with a
+            // full sync would only hide the mistake
+            let batch = db.get_batch(since).await?
+                .ok_or_else(|| ErrorKind::InvalidParam(format!("unknown since token {}", since)))?;
+            // batches that can't be upgraded just result in a full sync
+            batch.upgrade().unwrap_or_default()
+        },
+        None => Batch::default(),
+    };
     let next_batch_id = format!("{:x}", rand::random::<u64>());
     let mut res = SyncResponse {
         next_batch: next_batch_id.clone(),
@@ -164,80 +241,76 @@ pub async fn sync(
         account_data: AccountData {
             events: Vec::new(),
         },
+        device_one_time_keys_count: db.count_one_time_keys(&username, &device_id).await?,
+        device_lists: None,
+        to_device: None,
     };
 
-    let rooms = db.get_rooms().await?;
-    let mut memberships = HashMap::new();
-    for room_id in rooms.iter() {
-        if let Some(membership) = db.get_membership(&user_id, room_id).await? {
-            memberships.insert(room_id, membership);
+    let mut memberships = db.get_user_rooms(&user_id).await?;
+    memberships.retain(|room_id, _| filter.room.allows(room_id));
+    batch.unread.retain(|room_id, _| memberships.get(room_id) == Some(&Membership::Join));
+    for (room_id, membership) in memberships.clone() {
+        if matches!(membership, Membership::Leave | Membership::Ban)
+            && db.is_room_forgotten(&room_id, &user_id).await?
+        {
+            batch.rooms.remove(&room_id);
+            batch.sent_members.remove(&room_id);
+            memberships.remove(&room_id);
         }
     }
+    // room account data is in the same stream as global account data, which is only moved along
+    // once every room has been looked at
+    let account_data_from = batch.account_data;
     let mut something_happened = false;
-    for (&room_id, membership) in memberships.iter() {
+    for (room_id, membership) in memberships.iter() {
+        if *membership != Membership::Knock {
+            batch.knocks.remove(room_id);
+        }
         match membership {
             Membership::Join => {
                 batch.invites.remove(room_id);
                 let from = batch.rooms.get(room_id).map(|v| *v).unwrap_or(0);
-                let (events, progress) = db.query_events(EventQuery {
-                    query_type: QueryType::Timeline { from, to: None },
+                let sent_members = batch.sent_members.entry(room_id.clone()).or_default();
+                let (mut room, progress, is_empty) = joined_room(
+                    &*db,
                     room_id,
-                    senders: &[],
-                    not_senders: &[],
-                    types: &[],
-                    not_types: &[],
-                    contains_json: None,
-                }, false).await?;
+                    &user_id,
+                    from,
+                    req.full_state,
+                    &filter.room,
+                    sent_members,
+                ).await?;
+                room.unread_notifications =
+                    unread_counts(&*db, room_id, &user_id, &mut batch.unread).await?;
                 batch.rooms.insert(room_id.clone(), progress + 1);
-
-                let mut state_events = Vec::new();
-                if req.full_state {
-                    state_events = db.get_full_state(&room_id).await?;
-                }
-
-                if !events.is_empty() || !state_events.is_empty() {
+                let mut account_data =
+                    room_account_data_since(&*db, &username, room_id, account_data_from).await?;
+                account_data.retain(|kv| filter.room.account_data.allows(&kv.ty));
+                if !is_empty || !account_data.is_empty() {
                     something_happened = true;
                 }
-                let (joined, invited) = db.get_room_member_counts(&room_id).await?;
-                let summary = RoomSummary {
-                    heroes: None,
-                    joined_member_count: joined,
-                    invited_member_count: invited,
-                };
-                let state = State { events: state_events };
-                let timeline = Timeline {
-                    events,
-                    limited: false,
-                    prev_batch: String::from("empty"),
-                };
-                let ephemeral = Ephemeral {
-                    events: db.get_all_ephemeral(room_id).await?.into_iter().map(
-                        |(k, v)| KvPair {
-                            ty: k,
-                            content: v,
-                        }).collect()
-                };
-                let account_data = AccountData { events: Vec::new() };
+                room.account_data.events.extend(account_data);
                 res.rooms.get_or_insert_with(Default::default).join.insert(
                     String::from(room_id),
-                    JoinedRoom {
-                        summary,
-                        state,
-                        timeline,
-                        ephemeral,
-                        account_data,
-                    },
+                    room,
+                );
+            },
+            Membership::Leave | Membership::Ban if batch.rooms.contains_key(room_id) => {
+                // send what happened up to the user leaving once, then forget about the room
+                let from = batch.rooms.remove(room_id).unwrap();
+                batch.sent_members.remove(room_id);
+                let mut room = left_room(&*db, room_id, &user_id, from).await?;
+                room.account_data.events =
+                    room_account_data_since(&*db, &username, room_id, account_data_from).await?;
+                room.account_data.events.retain(|kv| filter.room.account_data.allows(&kv.ty));
+                something_happened = true;
+                res.rooms.get_or_insert_with(Default::default).leave.insert(
+                    String::from(room_id),
+                    room,
                 );
             },
             Membership::Invite if !batch.invites.contains(room_id) => {
-                let events = db.get_full_state(&room_id).await?
-                    .into_iter()
-                    .map(|e| StrippedState {
-                        content: e.event_content,
-                        state_key: e.state_key.unwrap(),
-                        sender: e.sender,
-                    })
-                    .collect();
+                let events = stripped_state(&*db, room_id).await?;
                 res.rooms.get_or_insert_with(Default::default).invite.insert(
                     room_id.clone(),
                     InvitedRoom {
@@ -248,36 +321,76 @@ pub async fn sync(
                 );
                 batch.invites.insert(room_id.clone());
             }
+            Membership::Knock if !batch.knocks.contains(room_id) => {
+                let events = stripped_state(&*db, room_id).await?;
+                something_happened = true;
+                res.rooms.get_or_insert_with(Default::default).knock.insert(
+                    room_id.clone(),
+                    KnockedRoom {
+                        knock_state: KnockState {
+                            events,
+                        },
+                    },
+                );
+                batch.knocks.insert(room_id.clone());
+            },
             _ => {},
         }
     }
 
+    res.account_data = account_data_since(&*db, &username, &mut batch).await?;
+    res.account_data.events.retain(|kv| filter.account_data.allows(&kv.ty));
+    if !res.account_data.events.is_empty() {
+        something_happened = true;
+    }
+
+    let joined_rooms = memberships.iter()
+        .filter(|(_, m)| **m == Membership::Join)
+        .map(|(room_id, _)| room_id.as_str());
+    let mut room_members = joined_members(&*db, joined_rooms).await?;
+    room_members.insert(user_id.clone());
+    let presence = presence_since(&*db, &state.config.domain, &room_members, &mut batch).await?;
+    if !presence.is_empty() {
+        something_happened = true;
+        res.presence = Some(Presence { events: presence });
+    }
+    let device_lists =
+        device_lists_since(&*db, &state.config.domain, &room_members, &mut batch).await?;
+    // an initial sync has the client query everyone's devices anyway
+    if req.since.is_some() && !device_lists.is_empty() {
+        something_happened = true;
+        res.device_lists = Some(device_lists);
+    }
+    // syncing from a batch shows that its messages arrived, and the ones after it are kept until
+    // a later sync shows the same, so a response that never arrives doesn't lose any
+    db.delete_to_device_messages(&username, &device_id, batch.to_device).await?;
+    let to_device = db.get_to_device_messages(&username, &device_id, batch.to_device, false).await?;
+    if !to_device.is_empty() {
+        something_happened = true;
+        res.to_device = Some(to_device_since(to_device, &mut batch));
+    }
+
     if something_happened {
         db.set_batch(&next_batch_id, batch).await?;
         return Ok(Json(res));
     }
 
     let mut queries = Vec::new();
-    for (&room_id, _) in memberships.iter().filter(|(_, m)| **m == Membership::Join) {
+    for (room_id, _) in memberships.iter().filter(|(_, m)| **m == Membership::Join) {
         let from = batch.rooms.get(room_id).map(|v| *v).unwrap_or(0);
         let room_id_clone = String::from(room_id);
-        queries.push(db.query_events(EventQuery {
-            query_type: QueryType::Timeline {
-                from, to: None,
-            },
-            room_id,
-            senders: &[],
-            not_senders: &[],
-            types: &[],
-            not_types: &[],
-            contains_json: None,
-        }, true).map(move |r| (r, room_id_clone)));
-    }
-    if queries.is_empty() {
-        // user is not in any rooms. no point waiting for stuff to happen in them
-        db.set_batch(&next_batch_id, batch).await?;
-        return Ok(Json(res));
+        let query = filter.room.timeline.query(&*db, room_id, QueryType::Timeline { from, to: None }, true);
+        queries.push(query.map(move |r| (r, room_id_clone)).boxed_local());
     }
+    let rooms = async move {
+        if queries.is_empty() {
+            // the user isn't in any rooms, so nothing's going to happen in them
+            futures::future::pending().await
+        } else {
+            futures::future::select_all(queries).await
+        }
+    };
+    let to_device = db.get_to_device_messages(&username, &device_id, batch.to_device, true);
 
     let timeout = delay_for(Duration::from_millis(req.timeout as _));
     tokio::select! {
@@ -285,8 +398,22 @@ pub async fn sync(
             db.set_batch(&next_batch_id, batch).await?;
             return Ok(Json(res));
         },
-        ((query_res, room_id), _, _) = futures::future::select_all(queries) => {
-            let (events, progress) = query_res?;
+        to_device = to_device => {
+            res.to_device = Some(to_device_since(to_device?, &mut batch));
+            db.set_batch(&next_batch_id, batch).await?;
+            return Ok(Json(res));
+        },
+        ((query_res, room_id), _, _) = rooms => {
+            let (pdus, progress) = query_res?;
+            let (timeline, mut state_events) =
+                limited_timeline(&*db, &room_id, &filter.room, pdus, progress).await?;
+            if filter.room.state.lazy_load_members {
+                let sent_members = batch.sent_members.entry(room_id.clone()).or_default();
+                state_events.extend(
+                    lazy_members(&*db, &room_id, &timeline.events, &filter.room.state, sent_members)
+                        .await?,
+                );
+            }
             let (joined, invited) = db.get_room_member_counts(&room_id).await?;
             let summary = RoomSummary {
                 heroes: None,
@@ -298,20 +425,18 @@ pub async fn sync(
                 room_id.clone(),
                 JoinedRoom {
                     summary,
-                    timeline: Timeline {
-                        events,
-                        limited: false,
-                        prev_batch: String::from("empty"),
-                    },
-                    state: State { events: Vec::new() },
+                    timeline,
+                    state: State { events: without_room_ids(state_events) },
                     ephemeral: Ephemeral {
-                        events: db.get_all_ephemeral(&room_id).await?.into_iter().map(
+                        events: db.get_all_ephemeral(&room_id, &user_id).await?.into_iter().map(
                             |(k, v)| KvPair {
                                 ty: k,
                                 content: v,
                             }).collect()
                     },
                     account_data: AccountData { events: Vec::new() },
+                    unread_notifications: unread_counts(&*db, &room_id, &user_id, &mut batch.unread)
+                        .await?,
                 }
             );
             db.set_batch(&next_batch_id, batch).await?;
@@ -320,169 +445,1332 @@ pub async fn sync(
     };
 }
 
+/// Turns the device's new to-device messages into their part of the sync response, and moves the
+/// batch along past them.
+fn to_device_since(to_device: Vec<(u64, ToDeviceEvent)>, batch: &mut Batch) -> ToDevice {
+    if let Some(&(last, _)) = to_device.last() {
+        batch.to_device = last;
+    }
+    ToDevice {
+        events: to_device.into_iter().map(|(_, event)| event).collect(),
+    }
+}
+
+/// Gets the room's current state, stripped down to what's shown to users who aren't in it yet.
+async fn stripped_state(db: &dyn Storage, room_id: &str) -> Result<Vec<StrippedState>, Error> {
+    Ok(db.get_full_state(room_id).await?
+        .into_iter()
+        .map(|e| StrippedState {
+            content: e.event_content,
+            state_key: e.state_key.unwrap(),
+            sender: e.sender,
+        })
+        .collect())
+}
+
+/// Gets the user's global account data that changed since the batch, and moves the batch along.
+async fn account_data_since(
+    db: &dyn Storage,
+    username: &str,
+    batch: &mut Batch,
+) -> Result<AccountData, Error> {
+    let (changed, position) = db.get_user_account_data_since(username, batch.account_data).await?;
+    batch.account_data = position;
+    Ok(AccountData {
+        events: changed.into_iter().map(|(ty, content)| KvPair { ty, content }).collect(),
+    })
+}
+
+/// Gets the user's account data for the room that changed since the given position in their
+/// account data stream.
+async fn room_account_data_since(
+    db: &dyn Storage,
+    username: &str,
+    room_id: &str,
+    since: usize,
+) -> Result<Vec<KvPair>, Error> {
+    let changed = db.get_room_account_data_since(username, room_id, since).await?;
+    Ok(changed.into_iter().map(|(ty, content)| KvPair { ty, content }).collect())
+}
+
+/// Gets the events in a joined room since `from`.
+///
+/// Returns the room, the new position in the room's timeline, and whether there was nothing new.
+/// The unread counts are left for the caller to fill in.
+async fn joined_room(
+    db: &dyn Storage,
+    room_id: &str,
+    user_id: &MatrixId,
+    from: usize,
+    full_state: bool,
+    filter: &RoomFilter,
+    sent_members: &mut HashSet<String>,
+) -> Result<(JoinedRoom, usize, bool), Error> {
+    let (pdus, progress) = filter.timeline
+        .query(db, room_id, QueryType::Timeline { from, to: None }, false)
+        .await?;
+    let (timeline, mut state_events) = limited_timeline(db, room_id, filter, pdus, progress).await?;
+
+    if full_state {
+        state_events = db.get_full_state(room_id).await?;
+        state_events.retain(|event| filter.state.allows(event));
+        if filter.state.lazy_load_members {
+            state_events.retain(|event| event.event_content.get_type() != "m.room.member");
+        }
+        filter.state.limit(&mut state_events);
+    }
+    if filter.state.lazy_load_members {
+        state_events.extend(
+            lazy_members(db, room_id, &timeline.events, &filter.state, sent_members).await?,
+        );
+    }
+
+    let is_empty = timeline.events.is_empty() && state_events.is_empty();
+    let (joined, invited) = db.get_room_member_counts(room_id).await?;
+    let summary = RoomSummary {
+        heroes: None,
+        joined_member_count: joined,
+        invited_member_count: invited,
+    };
+    let state = State { events: without_room_ids(state_events) };
+    let ephemeral = Ephemeral {
+        events: db.get_all_ephemeral(room_id, user_id).await?.into_iter().map(
+            |(k, v)| KvPair {
+                ty: k,
+                content: v,
+            }).collect()
+    };
+    let mut account_data = AccountData { events: Vec::new() };
+    if let Some(event_id) = db.get_fully_read(room_id, user_id).await? {
+        account_data.events.push(KvPair {
+            ty: String::from("m.fully_read"),
+            content: json!({ "event_id": event_id }),
+        });
+    }
+    let room = JoinedRoom {
+        summary,
+        state,
+        timeline,
+        ephemeral,
+        account_data,
+        unread_notifications: Default::default(),
+    };
+    Ok((room, progress, is_empty))
+}
+
+/// Cuts a room's new timeline events down to the filter's limit.
+///
+/// If any were cut, the client missed whatever state changed among them, so the room's state from
+/// just before the first event kept is returned too, and `prev_batch` is a `/messages` token for
+/// paging back from that event. `progress` is where the timeline's events were read up to.
+async fn limited_timeline(
+    db: &dyn Storage,
+    room_id: &str,
+    filter: &RoomFilter,
+    mut pdus: Vec<StoredPdu>,
+    progress: usize,
+) -> Result<(Timeline, Vec<Event>), Error> {
+    let limited = filter.timeline.limit(&mut pdus);
+    let mut state_events = Vec::new();
+    let mut prev_batch = String::from("empty");
+    if limited {
+        // a limit of 0 leaves nothing, in which case the gap runs up to the end of the timeline
+        let first = pdus.first().map(|pdu| pdu.stream_ordering).unwrap_or(progress + 1);
+        let state_query = QueryType::State {
+            at: Some(first - 1),
+            state_keys: &[],
+            not_state_keys: &[],
+        };
+        let (state, _) = filter.state.query(db, room_id, state_query, false).await?;
+        state_events = state.into_iter().map(StoredPdu::to_client_format).collect();
+        if filter.state.lazy_load_members {
+            state_events.retain(|event| event.event_content.get_type() != "m.room.member");
+        }
+        filter.state.limit(&mut state_events);
+        prev_batch = first.to_string();
+    }
+    let timeline = Timeline {
+        events: without_room_ids(pdus.into_iter().map(StoredPdu::to_client_format).collect()),
+        limited,
+        prev_batch,
+    };
+    Ok((timeline, state_events))
+}
+
+/// Gets the member events of the timeline's senders, for clients that load members lazily.
+///
+/// Members already in `sent_members` are left out unless the filter asks for redundant members,
+/// and the ones returned are added to it.
+async fn lazy_members(
+    db: &dyn Storage,
+    room_id: &str,
+    timeline: &[Event],
+    filter: &RoomEventFilter,
+    sent_members: &mut HashSet<String>,
+) -> Result<Vec<Event>, Error> {
+    let mut members = Vec::new();
+    let senders = timeline.iter().map(|event| event.sender.as_str()).unique();
+    for sender in senders {
+        if sent_members.contains(sender) && !filter.include_redundant_members {
+            continue;
+        }
+        let member = db.get_state_event(room_id, "m.room.member", sender).await?;
+        if let Some(member) = member.filter(|member| filter.allows(member)) {
+            sent_members.insert(String::from(sender));
+            members.push(member);
+        }
+    }
+    Ok(members)
+}
+
+/// Counts the messages the user hasn't read yet, which are those after their fully read marker
+/// (or after they joined, if they don't have one).
+///
+/// `unread` holds the counts from the last sync, by room. As long as the marker hasn't moved since,
+/// only the messages that have arrived since are counted and added on, and the new counts go back
+/// in `unread` for next time.
+///
+/// There are no push rules yet, so every message from someone else counts as a notification and
+/// the ones mentioning the user count as highlights.
+async fn unread_counts(
+    db: &dyn Storage,
+    room_id: &str,
+    user_id: &MatrixId,
+    unread: &mut HashMap<String, UnreadCounts>,
+) -> Result<UnreadNotificationCounts, Error> {
+    let counted_from = unread_from(db, room_id, user_id).await?;
+    let mut counts = match unread.remove(room_id) {
+        Some(counts) if counts.counted_from == counted_from => counts,
+        _ => UnreadCounts {
+            counted_from,
+            counted_until: counted_from,
+            ..Default::default()
+        },
+    };
+
+    let (pdus, latest) = db.query_pdus(EventQuery {
+        query_type: QueryType::Timeline { from: counts.counted_until, to: None },
+        room_id,
+        senders: &[],
+        not_senders: &[user_id],
+        types: &["m.room.message"],
+        not_types: &[],
+        contains_json: None,
+    }, false).await?;
+    counts.counted_until = counts.counted_until.max(latest + 1);
+
+    let displayname = db.get_profile(user_id.localpart()).await?.and_then(|p| p.displayname);
+    for pdu in pdus.iter() {
+        let content = match pdu.event_content() {
+            EventContent::Unknown { ty, content } if ty == "m.room.message" => content,
+            _ => continue,
+        };
+        counts.notification_count += 1;
+        let body = content.get("body").and_then(JsonValue::as_str).unwrap_or("");
+        if mentions(body, user_id, displayname.as_deref()) {
+            counts.highlight_count += 1;
+        }
+    }
+    let ret = UnreadNotificationCounts {
+        highlight_count: counts.highlight_count,
+        notification_count: counts.notification_count,
+    };
+    unread.insert(room_id.to_owned(), counts);
+    Ok(ret)
+}
+
+/// The stream ordering the user's unread messages start from: just after their fully read
+/// marker, or after their latest membership change if they haven't set one.
+async fn unread_from(db: &dyn Storage, room_id: &str, user_id: &MatrixId) -> Result<usize, Error> {
+    let read_up_to = match db.get_fully_read(room_id, user_id).await? {
+        Some(event_id) => db.get_pdu(room_id, &event_id).await?,
+        None => db.query_pdus(EventQuery {
+            query_type: QueryType::State {
+                at: None,
+                state_keys: &[user_id.as_str()],
+                not_state_keys: &[],
+            },
+            room_id,
+            senders: &[],
+            not_senders: &[],
+            types: &["m.room.member"],
+            not_types: &[],
+            contains_json: None,
+        }, false).await?.0.pop(),
+    };
+    Ok(read_up_to.map_or(0, |pdu| pdu.stream_ordering + 1))
+}
+
+/// Whether a message body mentions the user, going by the default push rules.
+fn mentions(body: &str, user_id: &MatrixId, displayname: Option<&str>) -> bool {
+    let body = body.to_lowercase();
+    let names = [Some(user_id.as_str()), Some(user_id.localpart()), displayname];
+    names.iter().flatten().any(|name| {
+        let name = name.to_lowercase();
+        // names only count as whole words, so "al" doesn't mention "alice"
+        body.match_indices(&name).any(|(i, _)| {
+            let before = body[..i].chars().next_back();
+            let after = body[i + name.len()..].chars().next();
+            !before.map(char::is_alphanumeric).unwrap_or(false)
+                && !after.map(char::is_alphanumeric).unwrap_or(false)
+        })
+    })
+}
+
+/// Events in a room's section of a sync response leave out the room ID, since it's already known
+/// from where they are.
+fn without_room_ids(events: Vec<Event>) -> Vec<Event> {
+    events.into_iter().map(|event| Event { room_id: None, ..event }).collect()
+}
+
+/// Gets the events in a room since `from`, up to and including the user leaving it (or being
+/// kicked or banned from it).
+async fn left_room(
+    db: &dyn Storage,
+    room_id: &str,
+    user_id: &MatrixId,
+    from: usize,
+) -> Result<LeftRoom, Error> {
+    let (mut events, _) = db.query_events(EventQuery {
+        query_type: QueryType::Timeline { from, to: None },
+        room_id,
+        senders: &[],
+        not_senders: &[],
+        types: &[],
+        not_types: &[],
+        contains_json: None,
+    }, false).await?;
+    let leave_pos = events.iter().rposition(|e| {
+        e.event_content.get_type() == "m.room.member"
+            && e.state_key.as_deref() == Some(user_id.as_str())
+    });
+    if let Some(pos) = leave_pos {
+        events.truncate(pos + 1);
+    }
+
+    Ok(LeftRoom {
+        state: State { events: Vec::new() },
+        timeline: Timeline {
+            events: without_room_ids(events),
+            limited: false,
+            prev_batch: String::from("empty"),
+        },
+        account_data: AccountData { events: Vec::new() },
+    })
+}
+
 #[get("/rooms/{room_id}/event/{event_id}")]
 #[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
 pub async fn get_event(
     state: Data<Arc<ServerState>>,
-    token: AccessToken,
+    token: OptionalAccessToken,
     Path((room_id, event_id)): Path<(String, String)>,
 ) -> Result<Json<Event>, Error> {
     let db = state.db_pool.get_handle().await?;
+    let user_id = reader(&*db, token, &state.config.domain, &room_id).await?;
 
-    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
-    Span::current().record("username", &username.as_str());
-    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+    Ok(Json(visible_event(&*db, &room_id, &event_id, user_id.as_ref()).await?))
+}
 
-    if db.get_membership(
-        &user_id,
-        &room_id
-    ).await? != Some(Membership::Join) {
-        return Err(ErrorKind::Forbidden.into());
+/// Gets the user making a request to read a room, if they gave an access token at all.
+///
+/// Only world-readable rooms can be read without one; for any other room a missing token is an
+/// error just as if the endpoint always needed one.
+async fn reader(
+    db: &dyn Storage,
+    token: OptionalAccessToken,
+    domain: &str,
+    room_id: &str,
+) -> Result<Option<MatrixId>, Error> {
+    match token.0 {
+        Some(token) => {
+            let username = db.try_auth(token).await?.ok_or(ErrorKind::UnknownToken)?;
+            Span::current().record("username", &username.as_str());
+            Ok(Some(MatrixId::new(&username, domain).unwrap()))
+        },
+        None if db.room_exists(room_id).await? && world_readable(db, room_id).await? => Ok(None),
+        None => Err(ErrorKind::MissingToken.into()),
     }
+}
 
-    match db.get_pdu(&room_id, &event_id).await? {
-        Some(pdu) => Ok(Json(pdu.to_client_format())),
-        None => Err(ErrorKind::NotFound.into()),
+/// Gets an event if the user is allowed to see it, going by the history visibility and their
+/// membership when it was sent.
+///
+/// Events the user can't see get the same error as ones that don't exist, so that this can't be
+/// used to find out what's in rooms they aren't in.
+async fn visible_event(
+    db: &dyn Storage,
+    room_id: &str,
+    event_id: &str,
+    user_id: Option<&MatrixId>,
+) -> Result<Event, Error> {
+    if !may_read_history(db, room_id, user_id).await? {
+        return Err(ErrorKind::NotFound.into());
     }
+
+    let pdu = db.get_pdu(room_id, event_id).await?.ok_or(ErrorKind::NotFound)?;
+    let at = pdu.stream_ordering;
+    let mut pdus = vec![pdu];
+    ReadState::before(db, room_id, user_id, at).await?.retain_visible(&mut pdus, user_id);
+    Ok(pdus.pop().ok_or(ErrorKind::NotFound)?.to_client_format())
 }
 
-#[get("/rooms/{room_id}/state/{event_id}")]
-pub async fn get_state_event_no_key(
+#[derive(Debug, Deserialize)]
+pub struct RelationsRequest {
+    from: Option<String>,
+    #[serde(default = "default_relations_dir")]
+    dir: Direction,
+    /// Signed so that negative limits get a proper error rather than failing to parse
+    limit: Option<i64>,
+}
+
+fn default_relations_dir() -> Direction {
+    Direction::Backward
+}
+
+#[derive(Debug, Serialize)]
+pub struct RelationsResponse {
+    chunk: Vec<Event>,
+    /// Missing once there are no more relations in this direction
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_batch: Option<String>,
+}
+
+#[get("/rooms/{room_id}/relations/{event_id}")]
+pub async fn get_relations(
     state: Data<Arc<ServerState>>,
-    token: AccessToken,
-    path_args: Path<(String, String)>,
-) -> Result<Json<Event>, Error> {
-    let (room_id, event_type) = path_args.into_inner();
-    get_state_event_inner(state, token, (room_id, event_type, String::new())).await
+    token: OptionalAccessToken,
+    Path((room_id, event_id)): Path<(String, String)>,
+    req: Query<RelationsRequest>,
+) -> Result<Json<RelationsResponse>, Error> {
+    get_relations_inner(state, token, (room_id, event_id, None), req.into_inner()).await
 }
 
-#[get("/rooms/{room_id}/state/{event_id}/{state_key}")]
-pub async fn get_state_event_key(
+#[get("/rooms/{room_id}/relations/{event_id}/{rel_type}")]
+pub async fn get_relations_by_type(
     state: Data<Arc<ServerState>>,
-    token: AccessToken,
-    path_args: Path<(String, String, String)>,
-) -> Result<Json<Event>, Error> {
-    get_state_event_inner(state, token, path_args.into_inner()).await
+    token: OptionalAccessToken,
+    Path((room_id, event_id, rel_type)): Path<(String, String, String)>,
+    req: Query<RelationsRequest>,
+) -> Result<Json<RelationsResponse>, Error> {
+    get_relations_inner(state, token, (room_id, event_id, Some(rel_type)), req.into_inner()).await
 }
 
 #[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
-pub async fn get_state_event_inner(
+async fn get_relations_inner(
     state: Data<Arc<ServerState>>,
-    token: AccessToken,
-    (room_id, event_type, state_key): (String, String, String),
-) -> Result<Json<Event>, Error> {
+    token: OptionalAccessToken,
+    (room_id, event_id, rel_type): (String, String, Option<String>),
+    req: RelationsRequest,
+) -> Result<Json<RelationsResponse>, Error> {
     let db = state.db_pool.get_handle().await?;
-    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
-    Span::current().record("username", &username.as_str());
-    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+    let user_id = reader(&*db, token, &state.config.domain, &room_id).await?;
 
-    if db.get_membership(
-        &user_id,
-        &room_id
-    ).await? != Some(Membership::Join) {
-        return Err(ErrorKind::Forbidden.into());
+    let from = parse_token(req.from.as_deref())?;
+    let limit = page_limit(req.limit, state.config.max_messages_limit)?;
+    let relation = (event_id.as_str(), rel_type.as_deref());
+    let res = relations(&*db, &room_id, relation, user_id.as_ref(), from, &req.dir, limit).await?;
+    Ok(Json(res))
+}
+
+/// Gets up to `limit` of the events relating to an event (with `rel_type`, if given), out of
+/// those the user is allowed to see, going by the history visibility and their membership when
+/// each was sent. Like `/messages`, they're paged from `from` in direction `dir`.
+///
+/// Like `visible_event`, an event the user can't see gets the same error as one that doesn't
+/// exist, rather than giving it away by its relations.
+async fn relations(
+    db: &dyn Storage,
+    room_id: &str,
+    (event_id, rel_type): (&str, Option<&str>),
+    user_id: Option<&MatrixId>,
+    from: Option<usize>,
+    dir: &Direction,
+    limit: usize,
+) -> Result<RelationsResponse, Error> {
+    if !may_read_history(db, room_id, user_id).await? {
+        return Err(ErrorKind::NotFound.into());
     }
 
-    match db.get_state_event(&room_id, &event_type, &state_key).await? {
-        Some(event) => Ok(Json(event)),
-        None => Err(ErrorKind::NotFound.into()),
+    let pdu = db.get_pdu(room_id, event_id).await?.ok_or(ErrorKind::NotFound)?;
+    let at = pdu.stream_ordering;
+    let mut pdus = vec![pdu];
+    ReadState::before(db, room_id, user_id, at).await?.retain_visible(&mut pdus, user_id);
+    if pdus.is_empty() {
+        return Err(ErrorKind::NotFound.into());
     }
+
+    // an event can only be related to once it exists, so there's no need to look before it
+    let (from, to) = match dir {
+        Direction::Forward => (Some(from.unwrap_or(at + 1).max(at + 1)), None),
+        Direction::Backward => (from, Some(at + 1)),
+    };
+    let relates = |pdu: &StoredPdu| {
+        let content = pdu.event_content().content_as_json();
+        let relates_to = &content["m.relates_to"];
+        relates_to["event_id"] == event_id
+            && rel_type.map(|rel_type| relates_to["rel_type"] == rel_type).unwrap_or(true)
+    };
+    let (_, page, end) = timeline_page(db, room_id, user_id, (from, to), dir, limit, relates).await?;
+    Ok(RelationsResponse {
+        // a full page might not be the last one
+        next_batch: end.filter(|_| page.len() == limit).map(|end| end.to_string()),
+        chunk: page.into_iter().map(StoredPdu::to_client_format).collect(),
+    })
 }
 
-#[get("/rooms/{room_id}/state")]
+#[derive(Debug, Deserialize)]
+pub struct ContextRequest {
+    #[serde(default = "default_context_limit")]
+    limit: usize,
+}
+
+fn default_context_limit() -> usize {
+    10
+}
+
+#[derive(Debug, Serialize)]
+pub struct ContextResponse {
+    start: String,
+    end: String,
+    events_before: Vec<Event>,
+    event: Event,
+    events_after: Vec<Event>,
+    state: Vec<Event>,
+}
+
+#[get("/rooms/{room_id}/context/{event_id}")]
 #[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
-pub async fn get_state(
+pub async fn get_context(
     state: Data<Arc<ServerState>>,
     token: AccessToken,
-    Path(room_id): Path<String>,
-) -> Result<Json<Vec<Event>>, Error> {
+    Path((room_id, event_id)): Path<(String, String)>,
+    req: Query<ContextRequest>,
+) -> Result<Json<ContextResponse>, Error> {
     let db = state.db_pool.get_handle().await?;
     let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
     Span::current().record("username", &username.as_str());
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
 
-    match db.get_membership(
-        &user_id,
-        &room_id
-    ).await? {
-        Some(Membership::Join) => {},
-        Some(_) => return Err(ErrorKind::Unimplemented.into()),
-        None => return Err(ErrorKind::Forbidden.into()),
+    if db.get_membership(&user_id, &room_id).await? != Some(Membership::Join) {
+        return Err(ErrorKind::Forbidden.into());
     }
 
-    let state = db.get_full_state(&room_id).await?;
-    Ok(Json(state))
+    Ok(Json(event_context(&*db, &room_id, &event_id, &user_id, req.limit).await?))
 }
 
-#[derive(Deserialize)]
-pub struct MembersRequest {
-    at: String,
-    #[serde(default)]
-    membership: Option<Membership>,
-    #[serde(default)]
-    not_membership: Option<Membership>,
+/// Gets an event along with up to `limit` events around it, half before and half after. Only
+/// events the user is allowed to see are included.
+///
+/// Redacted events come back in their redacted form, since that's how they're stored.
+async fn event_context(
+    db: &dyn Storage,
+    room_id: &str,
+    event_id: &str,
+    user_id: &MatrixId,
+    limit: usize,
+) -> Result<ContextResponse, Error> {
+    let pdu = db.get_pdu(room_id, event_id).await?.ok_or(ErrorKind::NotFound)?;
+    let at = pdu.stream_ordering;
+    let mut pdus = vec![pdu];
+    ReadState::before(db, room_id, Some(user_id), at).await?
+        .retain_visible(&mut pdus, Some(user_id));
+    let event = pdus.pop().ok_or(ErrorKind::NotFound)?.to_client_format();
+
+    // the events either side are paged through the same way as /messages, so only as much of the
+    // timeline as they need is loaded
+    let before = messages_page(
+        db,
+        room_id,
+        Some(user_id),
+        Some(at),
+        None,
+        &Direction::Backward,
+        limit / 2,
+    ).await?;
+    let after = messages_page(
+        db,
+        room_id,
+        Some(user_id),
+        Some(at + 1),
+        None,
+        &Direction::Forward,
+        limit - limit / 2,
+    ).await?;
+    let (state, _) = db.query_events(EventQuery {
+        query_type: QueryType::State {
+            at: Some(at),
+            state_keys: &[],
+            not_state_keys: &[],
+        },
+        room_id,
+        senders: &[],
+        not_senders: &[],
+        types: &[],
+        not_types: &[],
+        contains_json: None,
+    }, false).await?;
+
+    Ok(ContextResponse {
+        start: before.end.unwrap_or_else(|| at.to_string()),
+        end: after.end.unwrap_or_else(|| (at + 1).to_string()),
+        events_before: before.chunk,
+        event,
+        events_after: after.chunk,
+        state,
+    })
 }
 
-#[derive(Serialize)]
-pub struct MembersResponse {
-    chunk: Vec<Event>,
+#[derive(Debug, Deserialize)]
+pub enum Direction {
+    #[serde(rename = "f")]
+    Forward,
+    #[serde(rename = "b")]
+    Backward,
 }
 
-#[get("/rooms/{room_id}/members")]
-#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
-pub async fn get_members(
+#[derive(Debug, Deserialize)]
+pub struct TimestampToEventRequest {
+    ts: i64,
+    dir: Direction,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TimestampToEventResponse {
+    event_id: String,
+    origin_server_ts: i64,
+}
+
+#[get("/rooms/{room_id}/timestamp_to_event")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn timestamp_to_event(
     state: Data<Arc<ServerState>>,
     token: AccessToken,
     Path(room_id): Path<String>,
-    req: Query<MembersRequest>,
-) -> Result<Json<MembersResponse>, Error> {
+    req: Query<TimestampToEventRequest>,
+) -> Result<Json<TimestampToEventResponse>, Error> {
     let db = state.db_pool.get_handle().await?;
     let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
     Span::current().record("username", &username.as_str());
     let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
 
-    match db.get_membership(
-        &user_id,
-        &room_id
-    ).await? {
-        Some(Membership::Join) => {},
-        Some(_) => return Err(ErrorKind::Unimplemented.into()),
-        None => return Err(ErrorKind::Forbidden.into()),
-    }
-
-    let mut state = db.get_full_state(&room_id).await?;
-    state.retain(|event| {
-        if let EventContent::Member(ref content) = &event.event_content {
-            let membership = &content.membership;
-            (if let Some(filter) = &req.membership { membership == filter } else { true }
-             && if let Some(exclude) = &req.not_membership { membership != exclude } else { true })
-        } else {
-            false
-        }
-    });
+    if !may_read_history(&*db, &room_id, Some(&user_id)).await? {
+        return Err(ErrorKind::Forbidden.into());
+    }
 
-    Ok(Json(MembersResponse { chunk: state }))
+    Ok(Json(closest_event(&*db, &room_id, &user_id, req.ts, &req.dir).await?))
 }
 
-#[derive(Serialize)]
-pub struct SendEventResponse {
-    event_id: String,
+/// The history visibility and a user's membership at some point in a room's timeline, which
+/// decide whether they can see the events sent from there on.
+#[derive(Clone)]
+struct ReadState {
+    membership: Option<Membership>,
+    visibility: HistoryVisibilityType,
+    /// Whether the user is in the room now, which lets them see what was shared before they joined
+    joined_now: bool,
 }
 
-#[put("/rooms/{room_id}/state/{event_type}/{state_key}")]
-#[instrument(skip(state, token, event_content), fields(username = Empty), err = Level::DEBUG)]
-pub async fn send_state_event(
-    state: Data<Arc<ServerState>>,
-    token: AccessToken,
-    Path((room_id, event_type, state_key)): Path<(String, String, String)>,
-    event_content: Json<JsonValue>,
-) -> Result<Json<SendEventResponse>, Error> {
-    let db = state.db_pool.get_handle().await?;
-    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
-    Span::current().record("username", &username.as_str());
-    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+impl Default for ReadState {
+    fn default() -> Self {
+        ReadState {
+            membership: None,
+            // rooms without a history visibility event are treated as shared
+            visibility: HistoryVisibilityType::Shared,
+            joined_now: false,
+        }
+    }
+}
 
-    let event = NewEvent {
+impl ReadState {
+    /// The read state in effect for the event at stream ordering `at`, going by the room's
+    /// state just before it.
+    async fn before(
+        db: &dyn Storage,
+        room_id: &str,
+        user_id: Option<&MatrixId>,
+        at: usize,
+    ) -> Result<Self, Error> {
+        let mut ret = ReadState::default();
+        if let Some(user_id) = user_id {
+            ret.joined_now = db.get_membership(user_id, room_id).await? == Some(Membership::Join);
+        }
+        if at == 0 {
+            return Ok(ret);
+        }
+        let (pdus, _) = db.query_pdus(EventQuery {
+            query_type: QueryType::State {
+                at: Some(at - 1),
+                state_keys: &[user_id.map_or("", MatrixId::as_str), ""],
+                not_state_keys: &[],
+            },
+            room_id,
+            senders: &[],
+            not_senders: &[],
+            types: &["m.room.member", "m.room.history_visibility"],
+            not_types: &[],
+            contains_json: None,
+        }, false).await?;
+        for pdu in &pdus {
+            ret.update(pdu, user_id);
+            if let EventContent::HistoryVisibility(content) = pdu.event_content() {
+                ret.visibility = content.history_visibility.clone();
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Takes the user's membership from `pdu`, if it changes it.
+    fn update(&mut self, pdu: &StoredPdu, user_id: Option<&MatrixId>) {
+        match pdu.event_content() {
+            EventContent::Member(content)
+                if user_id.is_some_and(|user_id| pdu.state_key() == Some(user_id.as_str())) =>
+            {
+                self.membership = Some(content.membership.clone());
+            },
+            _ => {},
+        }
+    }
+
+    /// Drops the events in `pdus`, which must carry on from where this read state is, that the
+    /// user isn't allowed to see under the history visibility and membership in effect when each
+    /// was sent. Without a user, only the events sent while the room was world-readable are kept.
+    fn retain_visible(&mut self, pdus: &mut Vec<StoredPdu>, user_id: Option<&MatrixId>) {
+        pdus.retain(|pdu| {
+            // the user can see their own membership changing
+            self.update(pdu, user_id);
+            let visible = match self.visibility {
+                HistoryVisibilityType::WorldReadable => true,
+                HistoryVisibilityType::Shared => {
+                    self.joined_now || self.membership == Some(Membership::Join)
+                },
+                HistoryVisibilityType::Invited => {
+                    matches!(self.membership, Some(Membership::Join) | Some(Membership::Invite))
+                },
+                HistoryVisibilityType::Joined => self.membership == Some(Membership::Join),
+            };
+            if let EventContent::HistoryVisibility(content) = pdu.event_content() {
+                self.visibility = content.history_visibility.clone();
+            }
+            visible
+        });
+    }
+}
+
+/// The stream ordering of the room's latest event. Asking for the timeline from past its end
+/// gets no events back, but still says where it ends.
+async fn latest_stream_ordering(db: &dyn Storage, room_id: &str) -> Result<usize, Error> {
+    let (_, latest) = db.query_pdus(timeline_query(room_id, usize::MAX, None), false).await?;
+    Ok(latest)
+}
+
+fn timeline_query(room_id: &str, from: usize, to: Option<usize>) -> EventQuery<'_> {
+    EventQuery {
+        query_type: QueryType::Timeline { from, to },
+        room_id,
+        senders: &[],
+        not_senders: &[],
+        types: &[],
+        not_types: &[],
+        contains_json: None,
+    }
+}
+
+/// Whether the user can look through the room's timeline: either they're in the room, or anyone
+/// can read it.
+async fn may_read_history(
+    db: &dyn Storage,
+    room_id: &str,
+    user_id: Option<&MatrixId>,
+) -> Result<bool, Error> {
+    if let Some(user_id) = user_id {
+        if db.get_membership(user_id, room_id).await? == Some(Membership::Join) {
+            return Ok(true);
+        }
+    }
+    world_readable(db, room_id).await
+}
+
+/// Whether anyone at all can read the room, even without an account.
+async fn world_readable(db: &dyn Storage, room_id: &str) -> Result<bool, Error> {
+    let visibility = db.get_state_event(room_id, "m.room.history_visibility", "").await?;
+    Ok(matches!(
+        visibility.map(|event| event.event_content),
+        Some(EventContent::HistoryVisibility(HistoryVisibility {
+            history_visibility: HistoryVisibilityType::WorldReadable,
+        }))
+    ))
+}
+
+/// How many events either side of where a search by timestamp lands are looked through for the
+/// closest one.
+const CLOSEST_EVENT_WINDOW: usize = 50;
+
+/// Finds the first event at or after `ts` going forwards, or the last event at or before it going
+/// backwards, out of those the user is allowed to see.
+///
+/// Timestamps come from whichever server sent each event, so the timeline is only roughly sorted
+/// by them. A binary search over the timeline finds about where `ts` falls, and the events within
+/// [`CLOSEST_EVENT_WINDOW`] of there are searched properly.
+async fn closest_event(
+    db: &dyn Storage,
+    room_id: &str,
+    user_id: &MatrixId,
+    ts: i64,
+    dir: &Direction,
+) -> Result<TimestampToEventResponse, Error> {
+    let latest = latest_stream_ordering(db, room_id).await?;
+    let (mut low, mut high) = (0, latest + 1);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let (pdus, _) = db.query_pdus(timeline_query(room_id, mid, Some(mid)), false).await?;
+        match pdus.first() {
+            Some(pdu) if pdu.origin_server_ts() < ts => low = mid + 1,
+            _ => high = mid,
+        }
+    }
+
+    let from = low.saturating_sub(CLOSEST_EVENT_WINDOW);
+    let to = low.saturating_add(CLOSEST_EVENT_WINDOW).min(latest);
+    let (mut pdus, _) = db.query_pdus(timeline_query(room_id, from, Some(to)), false).await?;
+    ReadState::before(db, room_id, Some(user_id), from).await?
+        .retain_visible(&mut pdus, Some(user_id));
+    let pdu = match dir {
+        Direction::Forward => pdus.iter()
+            .filter(|pdu| pdu.origin_server_ts() >= ts)
+            .min_by_key(|pdu| pdu.origin_server_ts()),
+        Direction::Backward => pdus.iter()
+            .filter(|pdu| pdu.origin_server_ts() <= ts)
+            .max_by_key(|pdu| pdu.origin_server_ts()),
+    }.ok_or(ErrorKind::NotFound)?;
+
+    Ok(TimestampToEventResponse {
+        event_id: pdu.event_id().to_owned(),
+        origin_server_ts: pdu.origin_server_ts(),
+    })
+}
+
+const DEFAULT_MESSAGES_LIMIT: usize = 10;
+
+#[derive(Debug, Deserialize)]
+pub struct MessagesRequest {
+    from: Option<String>,
+    to: Option<String>,
+    dir: Direction,
+    /// Signed so that negative limits get a proper error rather than failing to parse
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessagesResponse {
+    start: String,
+    /// Missing once there's nothing further in this direction
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end: Option<String>,
+    chunk: Vec<Event>,
+}
+
+/// Pagination tokens are positions between events: token `n` sits just before the event with
+/// stream ordering `n`, as in the tokens from `/context`.
+fn parse_token(token: Option<&str>) -> Result<Option<usize>, Error> {
+    token.map(|token| token.parse().map_err(|_| {
+        ErrorKind::InvalidParam(format!("invalid pagination token {}", token)).into()
+    })).transpose()
+}
+
+#[get("/rooms/{room_id}/messages")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn messages(
+    state: Data<Arc<ServerState>>,
+    token: OptionalAccessToken,
+    Path(room_id): Path<String>,
+    req: Query<MessagesRequest>,
+) -> Result<Json<MessagesResponse>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let user_id = reader(&*db, token, &state.config.domain, &room_id).await?;
+    if !db.room_exists(&room_id).await? {
+        return Err(ErrorKind::RoomNotFound.into());
+    }
+    if !may_read_history(&*db, &room_id, user_id.as_ref()).await? {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    let from = parse_token(req.from.as_deref())?;
+    let to = parse_token(req.to.as_deref())?;
+    let limit = page_limit(req.limit, state.config.max_messages_limit)?;
+    Ok(Json(messages_page(&*db, &room_id, user_id.as_ref(), from, to, &req.dir, limit).await?))
+}
+
+/// Checks a page size from a client, keeping it within what the server is configured to allow.
+fn page_limit(limit: Option<i64>, max: usize) -> Result<usize, Error> {
+    match limit {
+        Some(limit) if limit < 0 => {
+            Err(ErrorKind::InvalidParam(String::from("limit can't be negative")).into())
+        },
+        Some(limit) => Ok((limit as usize).min(max)),
+        None => Ok(DEFAULT_MESSAGES_LIMIT),
+    }
+}
+
+/// Gets up to `limit` of the events the user can see, starting at `from` and going in direction
+/// `dir` until `to`. Without `from`, paging starts from whichever end of the timeline `dir`
+/// points away from.
+///
+/// The timeline is read `limit` events at a time, so only as much of it is loaded as it takes to
+/// fill the page.
+async fn messages_page(
+    db: &dyn Storage,
+    room_id: &str,
+    user_id: Option<&MatrixId>,
+    from: Option<usize>,
+    to: Option<usize>,
+    dir: &Direction,
+    limit: usize,
+) -> Result<MessagesResponse, Error> {
+    let (start, page, end) =
+        timeline_page(db, room_id, user_id, (from, to), dir, limit, |_| true).await?;
+    Ok(MessagesResponse {
+        start: start.to_string(),
+        end: end.map(|end| end.to_string()),
+        chunk: page.into_iter().map(|pdu| pdu.to_client_format()).collect(),
+    })
+}
+
+/// Like `messages_page`, but only the visible events that `keep` picks count towards the page.
+/// They come between the positions the page starts at and the one just past the last of them, if
+/// there was one.
+async fn timeline_page(
+    db: &dyn Storage,
+    room_id: &str,
+    user_id: Option<&MatrixId>,
+    (from, to): (Option<usize>, Option<usize>),
+    dir: &Direction,
+    limit: usize,
+    keep: impl Fn(&StoredPdu) -> bool,
+) -> Result<(usize, Vec<StoredPdu>, Option<usize>), Error> {
+    let latest = latest_stream_ordering(db, room_id).await?;
+    let mut page = Vec::new();
+
+    let (start, end) = match dir {
+        Direction::Forward => {
+            let from = from.unwrap_or(0);
+            let until = to.unwrap_or(latest + 1).min(latest + 1);
+            let mut read_state = ReadState::before(db, room_id, user_id, from).await?;
+            let mut chunk_start = from;
+            while page.len() < limit && chunk_start < until {
+                let chunk_end = (chunk_start + limit).min(until);
+                let query = timeline_query(room_id, chunk_start, Some(chunk_end - 1));
+                let (mut pdus, _) = db.query_pdus(query, false).await?;
+                read_state.retain_visible(&mut pdus, user_id);
+                page.extend(pdus.into_iter().filter(|pdu| keep(pdu)).take(limit - page.len()));
+                chunk_start = chunk_end;
+            }
+            (from, page.last().map(|pdu: &StoredPdu| pdu.stream_ordering + 1))
+        },
+        Direction::Backward => {
+            let from = from.unwrap_or(latest + 1);
+            let until = to.unwrap_or(0);
+            let mut chunk_end = from.min(latest + 1);
+            while page.len() < limit && chunk_end > until {
+                let chunk_start = chunk_end.saturating_sub(limit).max(until);
+                let query = timeline_query(room_id, chunk_start, Some(chunk_end - 1));
+                let (mut pdus, _) = db.query_pdus(query, false).await?;
+                ReadState::before(db, room_id, user_id, chunk_start).await?
+                    .retain_visible(&mut pdus, user_id);
+                page.extend(pdus.into_iter().rev().filter(|pdu| keep(pdu)).take(limit - page.len()));
+                chunk_end = chunk_start;
+            }
+            (from, page.last().map(|pdu: &StoredPdu| pdu.stream_ordering))
+        },
+    };
+    Ok((start, page, end))
+}
+
+#[get("/rooms/{room_id}/state/{event_id}")]
+pub async fn get_state_event_no_key(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    path_args: Path<(String, String)>,
+) -> Result<Json<Event>, Error> {
+    let (room_id, event_type) = path_args.into_inner();
+    get_state_event_inner(state, token, (room_id, event_type, String::new())).await
+}
+
+#[get("/rooms/{room_id}/state/{event_id}/{state_key}")]
+pub async fn get_state_event_key(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    path_args: Path<(String, String, String)>,
+) -> Result<Json<Event>, Error> {
+    get_state_event_inner(state, token, path_args.into_inner()).await
+}
+
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_state_event_inner(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    (room_id, event_type, state_key): (String, String, String),
+) -> Result<Json<Event>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    if db.get_membership(
+        &user_id,
+        &room_id
+    ).await? != Some(Membership::Join) {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    match db.get_state_event(&room_id, &event_type, &state_key).await? {
+        Some(event) => Ok(Json(event)),
+        None => Err(ErrorKind::NotFound.into()),
+    }
+}
+
+#[get("/rooms/{room_id}/state")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_state(
+    state: Data<Arc<ServerState>>,
+    token: OptionalAccessToken,
+    Path(room_id): Path<String>,
+) -> Result<HttpResponse, Error> {
+    let db = state.db_pool.get_handle().await?;
+    if let Some(user_id) = reader(&*db, token, &state.config.domain, &room_id).await? {
+        if !world_readable(&*db, &room_id).await? {
+            check_joined(&*db, &user_id, &room_id).await?;
+        }
+    }
+
+    if let Some(max) = state.config.max_state_events {
+        if db.count_state_events(&room_id).await? > max {
+            let msg = format!("the room has more than {} state events", max);
+            return Err(ErrorKind::TooLarge(msg).into());
+        }
+    }
+    let mut room_state = db.get_full_state(&room_id).await?;
+    if state.config.embed_member_profiles {
+        fill_member_profiles(&*db, &mut room_state, &state.config.domain).await?;
+    }
+
+    match state.config.state_stream_threshold {
+        Some(threshold) if room_state.len() > threshold => {
+            let events = stream::iter(room_state.into_iter().map(Ok));
+            let body = stream_events(events, b"[", b"]");
+            Ok(HttpResponse::Ok().content_type("application/json").streaming(body))
+        },
+        _ => Ok(HttpResponse::Ok().json(room_state)),
+    }
+}
+
+/// Checks that the user is in the room before they see its state.
+///
+/// A room that doesn't exist is M_NOT_FOUND, but an existing room the user isn't in is
+/// M_FORBIDDEN whatever it is about the room that keeps them out, so that nothing more about
+/// private rooms is given away.
+async fn check_joined(db: &dyn Storage, user_id: &MatrixId, room_id: &str) -> Result<(), Error> {
+    if !db.room_exists(room_id).await? {
+        return Err(ErrorKind::RoomNotFound.into());
+    }
+    match db.get_membership(user_id, room_id).await? {
+        Some(Membership::Join) => Ok(()),
+        _ => Err(ErrorKind::Forbidden.into()),
+    }
+}
+
+/// Fills in the display names and avatars that local users' member events are missing, using
+/// their current profiles.
+async fn fill_member_profiles(
+    db: &dyn Storage,
+    events: &mut [Event],
+    domain: &str,
+) -> Result<(), Error> {
+    for event in events.iter_mut() {
+        let content = match &mut event.event_content {
+            EventContent::Member(content) => content,
+            _ => continue,
+        };
+        if content.displayname.is_some() && content.avatar_url.is_some() {
+            continue;
+        }
+        let user_id = match event.state_key.as_deref().map(MatrixId::try_from) {
+            Some(Ok(user_id)) if user_id.has_domain(domain) => user_id,
+            _ => continue,
+        };
+        if let Some(profile) = db.get_profile(user_id.localpart()).await? {
+            if content.displayname.is_none() {
+                content.displayname = profile.displayname;
+            }
+            if content.avatar_url.is_none() {
+                content.avatar_url = profile.avatar_url;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct MembersRequest {
+    at: String,
+    #[serde(default)]
+    membership: Option<Membership>,
+    #[serde(default)]
+    not_membership: Option<Membership>,
+}
+
+#[derive(Serialize)]
+pub struct MembersResponse {
+    chunk: Vec<Event>,
+}
+
+#[get("/rooms/{room_id}/members")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_members(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(room_id): Path<String>,
+    req: Query<MembersRequest>,
+) -> Result<HttpResponse, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    check_joined(&*db, &user_id, &room_id).await?;
+
+    let profiles_domain = match state.config.embed_member_profiles {
+        true => Some(state.config.domain.clone()),
+        false => None,
+    };
+    if let Some(threshold) = state.config.member_stream_threshold {
+        let probe = db.get_member_pdus(&room_id, None, threshold.saturating_add(1)).await?;
+        if probe.len() > threshold {
+            let pages = MemberPages {
+                db,
+                room_id,
+                after: None,
+                done: false,
+                membership: req.membership.clone(),
+                not_membership: req.not_membership.clone(),
+                profiles_domain,
+            };
+            let body = stream_events(pages.into_stream(), b"{\"chunk\":[", b"]}");
+            return Ok(HttpResponse::Ok().content_type("application/json").streaming(Box::pin(body)));
+        }
+    }
+
+    let mut members =
+        member_events(&*db, &room_id, req.membership.as_ref(), req.not_membership.as_ref()).await?;
+    if let Some(domain) = &profiles_domain {
+        fill_member_profiles(&*db, &mut members, domain).await?;
+    }
+    Ok(HttpResponse::Ok().json(MembersResponse { chunk: members }))
+}
+
+/// How many member events are loaded from storage at a time when a member list is streamed.
+const MEMBER_PAGE_SIZE: usize = 100;
+
+/// Goes through a room's member events a page at a time, in state key order, filtering each page
+/// by membership as it's loaded.
+struct MemberPages {
+    db: Box<dyn Storage>,
+    room_id: String,
+    /// The state key of the last member event loaded so far
+    after: Option<String>,
+    done: bool,
+    membership: Option<Membership>,
+    not_membership: Option<Membership>,
+    /// The server's domain, if local users' profiles are to be filled in
+    profiles_domain: Option<String>,
+}
+
+impl MemberPages {
+    async fn next_page(&mut self) -> Result<Vec<Event>, Error> {
+        let pdus = self.db.get_member_pdus(&self.room_id, self.after.as_deref(), MEMBER_PAGE_SIZE)
+            .await?;
+        self.done = pdus.len() < MEMBER_PAGE_SIZE;
+        self.after = pdus.last().and_then(|pdu| pdu.state_key()).map(String::from);
+        let mut page: Vec<Event> = pdus.into_iter().map(StoredPdu::to_client_format).collect();
+        retain_membership(&mut page, self.membership.as_ref(), self.not_membership.as_ref());
+        if let Some(domain) = &self.profiles_domain {
+            fill_member_profiles(&*self.db, &mut page, domain).await?;
+        }
+        Ok(page)
+    }
+
+    /// The member events, one at a time. Only one page of them is ever loaded at once.
+    fn into_stream(self) -> impl Stream<Item = Result<Event, Error>> {
+        stream::unfold(self, |mut pages| async move {
+            if pages.done {
+                return None;
+            }
+            let page = pages.next_page().await;
+            // there's no carrying on after an error
+            pages.done |= page.is_err();
+            Some((page, pages))
+        })
+        .map_ok(|page| stream::iter(page.into_iter().map(Ok)))
+        .try_flatten()
+    }
+}
+
+/// Finds everyone joined to any of the rooms.
+async fn joined_members(
+    db: &dyn Storage,
+    rooms: impl Iterator<Item = &str>,
+) -> Result<HashSet<MatrixId>, Error> {
+    let mut users = HashSet::new();
+    for room_id in rooms {
+        for member in member_events(db, room_id, Some(&Membership::Join), None).await? {
+            if let Some(Ok(user_id)) = member.state_key.as_deref().map(MatrixId::try_from) {
+                users.insert(user_id);
+            }
+        }
+    }
+    Ok(users)
+}
+
+/// Gets the presence of the local users among `users` where it has changed since it was last
+/// sent, and remembers what was sent in the batch.
+async fn presence_since(
+    db: &dyn Storage,
+    domain: &str,
+    users: &HashSet<MatrixId>,
+    batch: &mut Batch,
+) -> Result<Vec<PresenceEvent>, Error> {
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut events = Vec::new();
+    //TODO: remote users' presence, once it arrives over federation
+    for user_id in users.iter().filter(|user_id| user_id.has_domain(domain)) {
+        let presence = db.get_presence(user_id.localpart()).await?;
+        let state = presence.as_ref().map(|p| p.state_at(now)).unwrap_or(PresenceState::Offline);
+        let last_sent = batch.presence.get(user_id.localpart()).copied()
+            .unwrap_or(PresenceState::Offline);
+        if state == last_sent {
+            continue;
+        }
+        batch.presence.insert(String::from(user_id.localpart()), state);
+        events.push(PresenceEvent {
+            content: presence_content(presence.as_ref(), now),
+            sender: user_id.clone(),
+            ty: String::from("m.presence"),
+        });
+    }
+    Ok(events)
+}
+
+/// Works out whose devices have changed since the batch, out of the `users` who share a room with
+/// the syncing user, and who no longer shares any room with them. The batch is moved along to
+/// the current device lists.
+async fn device_lists_since(
+    db: &dyn Storage,
+    domain: &str,
+    users: &HashSet<MatrixId>,
+    batch: &mut Batch,
+) -> Result<DeviceLists, Error> {
+    let mut lists = DeviceLists::default();
+    let mut versions = HashMap::new();
+    for user_id in users.iter() {
+        //TODO: remote users' device lists, once updates arrive over federation
+        let version = match user_id.has_domain(domain) {
+            true => db.get_device_list_version(user_id.localpart()).await?,
+            false => 0,
+        };
+        // users who have only just come to share a room count as changed too
+        if batch.device_lists.get(user_id.as_str()) != Some(&version) {
+            lists.changed.push(user_id.clone());
+        }
+        versions.insert(user_id.clone_inner(), version);
+    }
+    for user_id in batch.device_lists.keys().filter(|user_id| !versions.contains_key(*user_id)) {
+        if let Ok(user_id) = MatrixId::try_from(user_id.as_str()) {
+            lists.left.push(user_id);
+        }
+    }
+    batch.device_lists = versions;
+    Ok(lists)
+}
+
+/// Gets the room's member events, filtered by membership.
+///
+/// Only member events are fetched, and the `membership` filter is handed to storage, so the rest
+/// of the room's state never has to be loaded.
+async fn member_events(
+    db: &dyn Storage,
+    room_id: &str,
+    membership: Option<&Membership>,
+    not_membership: Option<&Membership>,
+) -> Result<Vec<Event>, Error> {
+    let (mut members, _) = db.query_events(EventQuery {
+        query_type: QueryType::State {
+            at: None,
+            state_keys: &[],
+            not_state_keys: &[],
+        },
+        room_id,
+        senders: &[],
+        not_senders: &[],
+        types: &["m.room.member"],
+        not_types: &[],
+        contains_json: membership.map(|m| json!({ "membership": m })),
+    }, false).await?;
+    retain_membership(&mut members, None, not_membership);
+    Ok(members)
+}
+
+/// Keeps the member events with the `membership` asked for, if any, and not the excluded one.
+fn retain_membership(
+    members: &mut Vec<Event>,
+    membership: Option<&Membership>,
+    not_membership: Option<&Membership>,
+) {
+    members.retain(|event| match &event.event_content {
+        EventContent::Member(content) => {
+            membership.map_or(true, |m| &content.membership == m)
+                && not_membership.map_or(true, |m| &content.membership != m)
+        },
+        _ => false,
+    });
+}
+
+/// Serializes a JSON array of events one event at a time as the response is sent, so there's
+/// never one big serialized body. The array is wrapped in `open` and `close`, which have to start
+/// and end it.
+fn stream_events(
+    events: impl Stream<Item = Result<Event, Error>>,
+    open: &'static [u8],
+    close: &'static [u8],
+) -> impl Stream<Item = Result<Bytes, Error>> {
+    let events = events.enumerate().map(|(i, event)| {
+        let mut buf = if i == 0 { Vec::new() } else { vec![b','] };
+        serde_json::to_writer(&mut buf, &event?)?;
+        Ok(Bytes::from(buf))
+    });
+    stream::once(future::ready(Ok(Bytes::from_static(open))))
+        .chain(events)
+        .chain(stream::once(future::ready(Ok(Bytes::from_static(close)))))
+}
+
+#[derive(Serialize)]
+pub struct SendEventResponse {
+    event_id: String,
+}
+
+#[put("/rooms/{room_id}/state/{event_type}/{state_key}")]
+#[instrument(skip(state, token, event_content), fields(username = Empty), err = Level::DEBUG)]
+pub async fn send_state_event(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((room_id, event_type, state_key)): Path<(String, String, String)>,
+    event_content: Json<JsonValue>,
+) -> Result<Json<SendEventResponse>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    let event = NewEvent {
         event_content: EventContent::new(&event_type, event_content.into_inner())?,
         sender: user_id,
         state_key: Some(state_key),
@@ -490,7 +1778,7 @@ pub async fn send_state_event(
         unsigned: None,
     };
 
-    let event_id = db.add_event(&room_id, event, &state.state_resolver).await?;
+    let event_id = db.add_event(&room_id, event, &state.state_resolver, &state.keys).await?;
 
     tracing::trace!(event_id = &event_id.as_str(), "Added event");
 
@@ -525,7 +1813,7 @@ pub async fn send_event(
 
     //TODO: is this right in the eyes of the spec? also does it matter?
     db.set_typing(&room_id, &user_id, false, 0).await?;
-    let event_id = db.add_event(&room_id, event, &state.state_resolver).await?;
+    let event_id = db.add_event(&room_id, event, &state.state_resolver, &state.keys).await?;
 
     tracing::trace!(event_id = &event_id.as_str(), "Added event");
 
@@ -533,3 +1821,1489 @@ pub async fn send_event(
         event_id,
     }))
 }
+
+#[derive(Debug, Deserialize)]
+pub struct RedactRequest {
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+#[put("/rooms/{room_id}/redact/{event_id}/{txn_id}")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn redact(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((room_id, event_id, txn_id)): Path<(String, String, String)>,
+    req: Json<RedactRequest>,
+) -> Result<Json<SendEventResponse>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    if !db.record_txn(token.0, txn_id.clone()).await? {
+        return Err(ErrorKind::TxnIdExists.into());
+    }
+    let user_id = MatrixId::new(&username, &state.config.domain).unwrap();
+
+    if db.get_pdu(&room_id, &event_id).await?.is_none() {
+        return Err(ErrorKind::NotFound.into());
+    }
+    // whether the user may redact it is left to the auth rules, which allow users to redact
+    // their own events as well as anyone with the redact power level
+    let event = NewEvent {
+        event_content: EventContent::Redaction(room::Redaction {
+            reason: req.into_inner().reason,
+        }),
+        sender: user_id,
+        state_key: None,
+        redacts: Some(event_id),
+        unsigned: Some(json!({"transaction_id": txn_id})),
+    };
+    let event_id = db.add_event(&room_id, event, &state.state_resolver, &state.keys).await?;
+
+    Ok(Json(SendEventResponse {
+        event_id,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{ResponseError, http::StatusCode, test};
+    use futures::{StreamExt, stream};
+    use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+    use serde_json::json;
+
+    use std::collections::{HashMap, HashSet};
+
+    use crate::{
+        client_api::filter::RoomFilter,
+        error::Error,
+        events::{
+            room::{
+                HistoryVisibility, HistoryVisibilityType, JoinRule, JoinRules, Member,
+                Membership,
+            },
+            Event, EventContent,
+            ephemeral::ReceiptType,
+            pdu::StoredPdu,
+            room_version::{v4::UnhashedPdu, VersionedPdu},
+        },
+        state::StateResolver,
+        storage::{mem::MemStorageManager, Batch, Storage, StorageManager},
+        test_util::{
+            assert_errcode, invite_event, join_event, message_event, state_event, RoomBuilder,
+            test_app, test_state, with_mem_db,
+        },
+        util::{MatrixId, StorageExt, storage::NewEvent},
+        validate::auth::AuthStatus,
+    };
+
+    use tokio::time::{Duration, delay_for};
+
+    use super::{
+        account_data_since, check_joined, closest_event, event_context, may_read_history, relations, visible_event, Direction, fill_member_profiles, joined_room, left_room, JoinedRoom,
+        member_events, messages_page, stream_events, unread_counts, MemberPages, MembersResponse,
+        MessagesResponse, UnreadNotificationCounts, MEMBER_PAGE_SIZE,
+    };
+
+    fn member_event(user_id: &str, displayname: Option<&str>) -> Event {
+        Event {
+            event_content: EventContent::Member(Member {
+                avatar_url: None,
+                displayname: displayname.map(String::from),
+                membership: Membership::Join,
+                is_direct: None,
+                reason: None,
+                third_party_invite: None,
+                join_authorised_via_users_server: None,
+            }),
+            sender: MatrixId::new("alice", "example.org").unwrap(),
+            room_id: None,
+            state_key: Some(String::from(user_id)),
+            unsigned: None,
+            redacts: None,
+            origin_server_ts: None,
+        }
+    }
+
+    fn displayname(event: &Event) -> Option<&str> {
+        match &event.event_content {
+            EventContent::Member(content) => content.displayname.as_deref(),
+            _ => panic!("not a member event"),
+        }
+    }
+
+    #[test]
+    fn member_profile_embedding() {
+        with_mem_db(|db, _| async move {
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            db.set_display_name("alice", "Alice").await.unwrap();
+            db.set_display_name("bob", "Bob").await.unwrap();
+
+            let mut events = vec![
+                member_event("@alice:example.org", None),
+                member_event("@bob:example.org", Some("Bobby")),
+                member_event("@alice:elsewhere.org", None),
+            ];
+            fill_member_profiles(&*db, &mut events, "example.org").await.unwrap();
+            assert_eq!(displayname(&events[0]), Some("Alice"));
+            // the member event is authoritative when it has a display name
+            assert_eq!(displayname(&events[1]), Some("Bobby"));
+            // remote users' profiles aren't ours to fill in
+            assert_eq!(displayname(&events[2]), None);
+        });
+    }
+
+    fn membership(user_id: &MatrixId, membership: Membership, reason: Option<&str>) -> NewEvent {
+        NewEvent {
+            event_content: EventContent::Member(Member {
+                avatar_url: None,
+                displayname: None,
+                membership,
+                is_direct: None,
+                reason: reason.map(String::from),
+                third_party_invite: None,
+                join_authorised_via_users_server: None,
+            }),
+            sender: user_id.clone(),
+            state_key: Some(user_id.clone_inner()),
+            redacts: None,
+            unsigned: None,
+        }
+    }
+
+    #[test]
+    fn private_receipts_in_sync() {
+        with_mem_db(|db, state_resolver| async move {
+            let room_id = "!test:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+
+            let room = RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+            let join_id = room.event_ids[1].clone();
+
+            db.set_receipt(room_id, &alice, ReceiptType::ReadPrivate, &join_id).await.unwrap();
+            db.set_receipt(room_id, &bob, ReceiptType::Read, &join_id).await.unwrap();
+
+            let receipts = |room: JoinedRoom| {
+                room.ephemeral.events.into_iter()
+                    .find(|e| e.ty == "m.receipt")
+                    .map(|e| e.content[&join_id].clone())
+                    .unwrap()
+            };
+            let (alice_room, _, _) = unfiltered_room(&*db, room_id, &alice, 0, false)
+                .await.unwrap();
+            let alice_receipts = receipts(alice_room);
+            assert!(alice_receipts["m.read.private"][alice.as_str()]["ts"].is_i64());
+            assert!(alice_receipts["m.read"][bob.as_str()]["ts"].is_i64());
+
+            let (bob_room, _, _) = unfiltered_room(&*db, room_id, &bob, 0, false).await.unwrap();
+            let bob_receipts = receipts(bob_room);
+            assert!(bob_receipts.get("m.read.private").is_none());
+            assert!(bob_receipts["m.read"][bob.as_str()]["ts"].is_i64());
+        });
+    }
+
+    #[test]
+    fn fully_read_clears_highlights() {
+        with_mem_db(|db, state_resolver| async move {
+            let room_id = "!test:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            db.create_user("alice", "password").await.unwrap();
+
+            let room = RoomBuilder::new(&*db, &state_resolver, room_id, &alice)
+                .join(&bob)
+                .message(&bob, "hey Alice!")
+                .message(&bob, "anyone around?")
+                .build()
+                .await;
+            let last_id = room.message_ids[1].clone();
+
+            let (room, _, _) = unfiltered_room(&*db, room_id, &alice, 0, false).await.unwrap();
+            assert_eq!(room.unread_notifications, UnreadNotificationCounts {
+                highlight_count: 1,
+                notification_count: 2,
+            });
+
+            db.set_fully_read(room_id, &alice, &last_id).await.unwrap();
+            let (room, _, _) = unfiltered_room(&*db, room_id, &alice, 0, false).await.unwrap();
+            assert_eq!(room.unread_notifications, UnreadNotificationCounts::default());
+        });
+    }
+
+    #[test]
+    fn unread_counts_carry_on() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let room_id = "!test:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            let room = RoomBuilder::new(&*db, &state_resolver, room_id, &alice)
+                .join(&bob)
+                .message(&bob, "hey alice")
+                .build()
+                .await;
+            let say = |body: &str| NewEvent {
+                event_content: message(body),
+                sender: bob.clone(),
+                state_key: None,
+                redacts: None,
+                unsigned: None,
+            };
+
+            let mut unread = HashMap::new();
+            let counts = unread_counts(&*db, room_id, &alice, &mut unread).await.unwrap();
+            assert_eq!(counts, UnreadNotificationCounts {
+                highlight_count: 1,
+                notification_count: 1,
+            });
+
+            // later syncs only count what's new, and add it on
+            let counted_until = unread[room_id].counted_until;
+            let last_id = db.add_event(room_id, say("still there?"), &state_resolver, &keys)
+                .await.unwrap();
+            let counts = unread_counts(&*db, room_id, &alice, &mut unread).await.unwrap();
+            assert_eq!(counts, UnreadNotificationCounts {
+                highlight_count: 1,
+                notification_count: 2,
+            });
+            assert_eq!(unread[room_id].counted_until, counted_until + 1);
+
+            // moving the marker starts the count again from there
+            db.set_fully_read(room_id, &alice, &room.message_ids[0]).await.unwrap();
+            let counts = unread_counts(&*db, room_id, &alice, &mut unread).await.unwrap();
+            assert_eq!(counts, UnreadNotificationCounts {
+                highlight_count: 0,
+                notification_count: 1,
+            });
+            db.set_fully_read(room_id, &alice, &last_id).await.unwrap();
+            let counts = unread_counts(&*db, room_id, &alice, &mut unread).await.unwrap();
+            assert_eq!(counts, UnreadNotificationCounts::default());
+        });
+    }
+
+    /// `joined_room` with no filter, for a client that hasn't been sent anything yet.
+    async fn unfiltered_room(
+        db: &dyn Storage,
+        room_id: &str,
+        user_id: &MatrixId,
+        from: usize,
+        full_state: bool,
+    ) -> Result<(JoinedRoom, usize, bool), Error> {
+        let filter = Default::default();
+        let (mut room, progress, is_empty) =
+            joined_room(db, room_id, user_id, from, full_state, &filter, &mut HashSet::new()).await?;
+        room.unread_notifications = unread_counts(db, room_id, user_id, &mut HashMap::new()).await?;
+        Ok((room, progress, is_empty))
+    }
+
+    #[test]
+    fn lazy_members_sent_once() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let room_id = "!lazy:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+            db.add_event(room_id, NewEvent {
+                event_content: EventContent::JoinRules(JoinRules {
+                    join_rule: JoinRule::Public,
+                    allow: Vec::new(),
+                }),
+                sender: alice.clone(),
+                state_key: Some(String::new()),
+                redacts: None,
+                unsigned: None,
+            }, &state_resolver, &keys).await.unwrap();
+            db.add_event(room_id, join_event(&bob), &state_resolver, &keys)
+                .await.unwrap();
+            let say = |body: &str| NewEvent {
+                event_content: message(body),
+                sender: bob.clone(),
+                state_key: None,
+                redacts: None,
+                unsigned: None,
+            };
+            db.add_event(room_id, say("hello"), &state_resolver, &keys).await.unwrap();
+
+            let lazy = |include_redundant_members: bool| -> RoomFilter {
+                serde_json::from_value(json!({
+                    "timeline": { "types": ["m.room.message"] },
+                    "state": {
+                        "lazy_load_members": true,
+                        "include_redundant_members": include_redundant_members,
+                    },
+                })).unwrap()
+            };
+            let state_keys = |room: &JoinedRoom| room.state.events.iter()
+                .map(|event| event.state_key.clone().unwrap())
+                .collect::<Vec<_>>();
+
+            let mut sent_members = HashSet::new();
+            let (room, progress, _) =
+                joined_room(&*db, room_id, &alice, 0, false, &lazy(false),
+                    &mut sent_members)
+                    .await.unwrap();
+            assert_eq!(state_keys(&room), vec![bob.clone_inner()]);
+
+            // bob speaks again, but the client already has his member event
+            db.add_event(room_id, say("anyone?"), &state_resolver, &keys).await.unwrap();
+            let from = progress + 1;
+            let (room, _, is_empty) =
+                joined_room(&*db, room_id, &alice, from, false, &lazy(false),
+                    &mut sent_members)
+                    .await.unwrap();
+            assert!(!is_empty);
+            assert_eq!(room.timeline.events.len(), 1);
+            assert!(state_keys(&room).is_empty());
+
+            let (room, _, _) =
+                joined_room(&*db, room_id, &alice, from, false, &lazy(true),
+                    &mut sent_members)
+                    .await.unwrap();
+            assert_eq!(state_keys(&room), vec![bob.clone_inner()]);
+        });
+    }
+
+    fn message(body: &str) -> EventContent {
+        EventContent::new("m.room.message", json!({
+            "msgtype": "m.text",
+            "body": body,
+        })).unwrap()
+    }
+
+    #[test]
+    fn room_ids_outside_sync_only() {
+        with_mem_db(|db, state_resolver| async move {
+            let room_id = "!test:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+            let message_id = db.add_event(room_id, NewEvent {
+                event_content: message("hello"),
+                sender: alice.clone(),
+                state_key: None,
+                redacts: None,
+                unsigned: None,
+            }, &state_resolver, &HashMap::new()).await.unwrap();
+
+            // what /event responds with
+            let event = db.get_pdu(room_id, &message_id).await.unwrap().unwrap().to_client_format();
+            assert_eq!(serde_json::to_value(&event).unwrap()["room_id"], room_id);
+
+            let context = event_context(&*db, room_id, &message_id, &alice, 2).await.unwrap();
+            assert_eq!(serde_json::to_value(&context.event).unwrap()["room_id"], room_id);
+            assert_eq!(context.events_before[0].room_id.as_deref(), Some(room_id));
+
+            let (room, _, _) = unfiltered_room(&*db, room_id, &alice, 0, true).await.unwrap();
+            assert!(!room.timeline.events.is_empty());
+            assert!(!room.state.events.is_empty());
+            for event in room.timeline.events.iter().chain(room.state.events.iter()) {
+                assert!(serde_json::to_value(event).unwrap().get("room_id").is_none());
+            }
+        });
+    }
+
+    #[test]
+    fn events_hidden_from_non_members() {
+        with_mem_db(|db, state_resolver| async move {
+            let room_id = "!test:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let eve = MatrixId::new("eve", "example.org").unwrap();
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+            let message_id = db.add_event(room_id, NewEvent {
+                event_content: message("secret"),
+                sender: alice.clone(),
+                state_key: None,
+                redacts: None,
+                unsigned: None,
+            }, &state_resolver, &HashMap::new()).await.unwrap();
+
+            visible_event(&*db, room_id, &message_id, Some(&alice)).await.unwrap();
+
+            let existing = visible_event(&*db, room_id, &message_id, Some(&eve)).await.unwrap_err();
+            let missing = visible_event(&*db, room_id, "$nonexistent", Some(&eve)).await.unwrap_err();
+            assert_errcode!(existing, "M_NOT_FOUND");
+            assert_eq!(existing.to_json(), missing.to_json());
+            assert_eq!(existing.status_code(), missing.status_code());
+        });
+    }
+
+    #[test]
+    fn event_visible_when_sent() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let room_id = "!test:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            let visibility = |history_visibility| state_event(
+                &alice,
+                EventContent::HistoryVisibility(HistoryVisibility { history_visibility }),
+                "",
+            );
+            let room = RoomBuilder::new(&*db, &state_resolver, room_id, &alice)
+                .message(&alice, "shared")
+                .build()
+                .await;
+            let shared_id = room.message_ids[0].clone();
+            let joined = visibility(HistoryVisibilityType::Joined);
+            db.add_event(room_id, joined, &state_resolver, &keys).await.unwrap();
+            let joined_id = db.add_event(room_id, message_event(&alice, "joined"), &state_resolver, &keys)
+                .await.unwrap();
+            db.add_event(room_id, join_event(&bob), &state_resolver, &keys).await.unwrap();
+            let world_readable = visibility(HistoryVisibilityType::WorldReadable);
+            db.add_event(room_id, world_readable, &state_resolver, &keys).await.unwrap();
+            let public_id = db.add_event(room_id, message_event(&alice, "public"), &state_resolver, &keys)
+                .await.unwrap();
+
+            // bob joined after "joined" was sent, but "shared" was shared with anyone who joins
+            for event_id in &[&shared_id, &public_id] {
+                visible_event(&*db, room_id, event_id, Some(&bob)).await.unwrap();
+            }
+            let err = visible_event(&*db, room_id, &joined_id, Some(&bob)).await.unwrap_err();
+            assert_errcode!(err, "M_NOT_FOUND");
+
+            // the room being world-readable now doesn't open up what was sent before then
+            visible_event(&*db, room_id, &public_id, None).await.unwrap();
+            for event_id in &[&shared_id, &joined_id] {
+                let err = visible_event(&*db, room_id, event_id, None).await.unwrap_err();
+                assert_errcode!(err, "M_NOT_FOUND");
+            }
+
+            for event_id in &[&shared_id, &joined_id, &public_id] {
+                visible_event(&*db, room_id, event_id, Some(&alice)).await.unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn relations_visible_when_sent() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let room_id = "!test:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            let carol = MatrixId::new("carol", "example.org").unwrap();
+            let room = RoomBuilder::new(&*db, &state_resolver, room_id, &alice)
+                .message(&alice, "parent")
+                .build()
+                .await;
+            let parent_id = room.message_ids[0].clone();
+            let reaction = |key: &str| NewEvent {
+                event_content: EventContent::Unknown {
+                    ty: String::from("m.reaction"),
+                    content: json!({
+                        "m.relates_to": {
+                            "rel_type": "m.annotation",
+                            "event_id": parent_id,
+                            "key": key,
+                        },
+                    }),
+                },
+                sender: alice.clone(),
+                state_key: None,
+                redacts: None,
+                unsigned: None,
+            };
+            db.add_event(room_id, reaction("shared"), &state_resolver, &keys).await.unwrap();
+            let joined = state_event(
+                &alice,
+                EventContent::HistoryVisibility(HistoryVisibility {
+                    history_visibility: HistoryVisibilityType::Joined,
+                }),
+                "",
+            );
+            db.add_event(room_id, joined, &state_resolver, &keys).await.unwrap();
+            db.add_event(room_id, reaction("joined"), &state_resolver, &keys).await.unwrap();
+            db.add_event(room_id, join_event(&bob), &state_resolver, &keys).await.unwrap();
+            db.add_event(room_id, reaction("member"), &state_resolver, &keys).await.unwrap();
+            db.add_event(room_id, message_event(&alice, "unrelated"), &state_resolver, &keys)
+                .await.unwrap();
+
+            let keys_of = |events: Vec<Event>| events.into_iter()
+                .map(|event| event.event_content.content_as_json()["m.relates_to"]["key"].clone())
+                .collect::<Vec<_>>();
+            let parent = (parent_id.as_str(), None);
+            let forward = &Direction::Forward;
+            let seen = relations(&*db, room_id, parent, Some(&alice), None, forward, 10).await.unwrap();
+            assert_eq!(keys_of(seen.chunk), vec![json!("shared"), json!("joined"), json!("member")]);
+            assert!(seen.next_batch.is_none());
+            // bob joined after "joined" was sent, but "shared" was shared with anyone who joins
+            let seen = relations(&*db, room_id, parent, Some(&bob), None, forward, 10).await.unwrap();
+            assert_eq!(keys_of(seen.chunk), vec![json!("shared"), json!("member")]);
+
+            // paging back from the newest, skipping the unrelated events in between
+            let mut from = None;
+            let mut pages = Vec::new();
+            loop {
+                let page = relations(&*db, room_id, parent, Some(&alice), from, &Direction::Backward, 2)
+                    .await.unwrap();
+                pages.push(keys_of(page.chunk));
+                from = match page.next_batch {
+                    Some(next_batch) => Some(next_batch.parse().unwrap()),
+                    None => break,
+                };
+            }
+            assert_eq!(pages, vec![
+                vec![json!("member"), json!("joined")],
+                vec![json!("shared")],
+            ]);
+
+            let annotations = (parent_id.as_str(), Some("m.annotation"));
+            let seen = relations(&*db, room_id, annotations, Some(&alice), None, forward, 10).await.unwrap();
+            assert_eq!(seen.chunk.len(), 3);
+            let replacements = (parent_id.as_str(), Some("m.replace"));
+            let seen = relations(&*db, room_id, replacements, Some(&alice), None, forward, 10).await.unwrap();
+            assert!(seen.chunk.is_empty());
+
+            for user_id in &[None, Some(&carol)] {
+                let err = relations(&*db, room_id, parent, *user_id, None, forward, 10).await.unwrap_err();
+                assert_errcode!(err, "M_NOT_FOUND");
+            }
+            let missing = ("$missing", None);
+            let err = relations(&*db, room_id, missing, Some(&alice), None, forward, 10).await.unwrap_err();
+            assert_errcode!(err, "M_NOT_FOUND");
+        });
+    }
+
+    #[test]
+    fn context_around_redaction() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let room_id = "!test:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+
+            let event = |content: EventContent, redacts: Option<&str>| NewEvent {
+                event_content: content,
+                sender: alice.clone(),
+                state_key: None,
+                redacts: redacts.map(String::from),
+                unsigned: None,
+            };
+            db.add_event(room_id, event(message("before"), None), &state_resolver, &keys)
+                .await.unwrap();
+            let message_id = db.add_event(room_id, event(message("oops"), None), &state_resolver, &keys)
+                .await.unwrap();
+            db.add_event(room_id, event(message("after"), None), &state_resolver, &keys)
+                .await.unwrap();
+            let redaction = serde_json::from_value(json!({})).unwrap();
+            db.add_event(
+                room_id,
+                event(EventContent::Redaction(redaction), Some(&message_id)),
+                &state_resolver,
+                &keys,
+            ).await.unwrap();
+
+            let context = event_context(&*db, room_id, &message_id, &alice, 2).await.unwrap();
+            let center = serde_json::to_value(&context.event).unwrap();
+            assert_eq!(center["type"], "m.room.message");
+            assert_eq!(center["content"], json!({}));
+            assert_eq!(center["unsigned"]["redacted_because"]["redacts"], message_id.as_str());
+
+            assert_eq!(context.events_before.len(), 1);
+            assert_eq!(context.events_before[0].event_content.content_as_json()["body"], "before");
+            assert_eq!(context.events_after.len(), 1);
+            assert_eq!(context.events_after[0].event_content.content_as_json()["body"], "after");
+
+            // the state is the room's state at the event, not what it's become since
+            let topic = EventContent::new("m.room.topic", json!({ "topic": "later" })).unwrap();
+            let topic = NewEvent { state_key: Some(String::new()), ..event(topic, None) };
+            db.add_event(room_id, topic, &state_resolver, &keys).await.unwrap();
+            let context = event_context(&*db, room_id, &message_id, &alice, 2).await.unwrap();
+            let state_types: Vec<_> = context.state.iter().map(|e| e.event_content.get_type()).collect();
+            assert!(state_types.contains(&"m.room.member"));
+            assert!(!state_types.contains(&"m.room.topic"));
+
+            event_context(&*db, room_id, "$nonexistent", &alice, 2).await
+                .expect_err("context for an event that doesn't exist");
+        });
+    }
+
+    #[test]
+    fn filtered_sync() {
+        let mut sys = actix_web::rt::System::new("filtered_sync");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            for room_id in &["!one:example.org", "!two:example.org", "!three:example.org"] {
+                RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+            }
+
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+            let sync = |filter: serde_json::Value| {
+                let filter = utf8_percent_encode(&filter.to_string(), NON_ALPHANUMERIC).to_string();
+                test::TestRequest::get()
+                    .uri(&format!("/_matrix/client/r0/sync?filter={}", filter))
+                    .header("Authorization", format!("Bearer {}", token))
+                    .to_request()
+            };
+            let joined_rooms = |res: serde_json::Value| {
+                let mut rooms = res["rooms"]["join"].as_object().unwrap().keys().cloned()
+                    .collect::<Vec<_>>();
+                rooms.sort();
+                rooms
+            };
+
+            let filter = json!({ "room": { "rooms": ["!two:example.org"] } });
+            let res = test::read_response_json(&mut app, sync(filter)).await;
+            assert_eq!(joined_rooms(res), vec!["!two:example.org"]);
+            let filter = json!({ "room": { "not_rooms": ["!two:example.org"] } });
+            let res = test::read_response_json(&mut app, sync(filter)).await;
+            assert_eq!(joined_rooms(res), vec!["!one:example.org", "!three:example.org"]);
+            let res = test::read_response_json(&mut app, sync(json!({}))).await;
+            assert_eq!(joined_rooms(res).len(), 3);
+        });
+    }
+
+    #[test]
+    fn context_hides_history_before_join() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let room_id = "!joined:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+            let visibility = EventContent::HistoryVisibility(HistoryVisibility {
+                history_visibility: HistoryVisibilityType::Joined,
+            });
+            db.add_event(room_id, state_event(&alice, visibility, ""), &state_resolver, &keys)
+                .await.unwrap();
+            let message = |body: &str| message_event(&alice, body);
+            let secret_id = db.add_event(room_id, message("before bob"), &state_resolver, &keys)
+                .await.unwrap();
+            db.add_event(room_id, join_event(&bob), &state_resolver, &keys).await.unwrap();
+            let message_id = db.add_event(room_id, message("after bob"), &state_resolver, &keys)
+                .await.unwrap();
+
+            // bob can see their own join, and the room's setup from back when its history was
+            // shared, but not what was said between the two
+            let context = event_context(&*db, room_id, &message_id, &bob, 10).await.unwrap();
+            assert_eq!(context.events_before[0].state_key.as_deref(), Some(bob.as_str()));
+            assert!(context.events_before.iter()
+                .all(|e| e.event_content.content_as_json()["body"] != "before bob"));
+            event_context(&*db, room_id, &secret_id, &bob, 10).await
+                .expect_err("context for an event from before bob joined");
+
+            // alice has been there all along
+            let context = event_context(&*db, room_id, &message_id, &alice, 10).await.unwrap();
+            assert!(context.events_before.iter()
+                .any(|e| e.event_content.content_as_json()["body"] == "before bob"));
+        });
+    }
+
+    #[test]
+    fn messages_hide_history_before_join() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let room_id = "!joined:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+            let visibility = EventContent::HistoryVisibility(HistoryVisibility {
+                history_visibility: HistoryVisibilityType::Joined,
+            });
+            db.add_event(room_id, state_event(&alice, visibility, ""), &state_resolver, &keys)
+                .await.unwrap();
+            for body in &["before bob 1", "before bob 2"] {
+                db.add_event(room_id, message_event(&alice, body), &state_resolver, &keys)
+                    .await.unwrap();
+            }
+            db.add_event(room_id, join_event(&bob), &state_resolver, &keys).await.unwrap();
+            for body in &["after bob 1", "after bob 2"] {
+                db.add_event(room_id, message_event(&alice, body), &state_resolver, &keys)
+                    .await.unwrap();
+            }
+
+            // paging one event at a time only ever looks at a little of the timeline, but still
+            // has to know what the history visibility and bob's membership were at each point
+            let bodies = |events: &[Event]| events.iter()
+                .filter_map(|event| {
+                    event.event_content.content_as_json()["body"].as_str().map(String::from)
+                })
+                .collect::<Vec<_>>();
+            for dir in &[Direction::Forward, Direction::Backward] {
+                let mut seen = Vec::new();
+                let mut from = None;
+                loop {
+                    let page = messages_page(&*db, room_id, Some(&bob), from, None, dir, 1)
+                        .await.unwrap();
+                    seen.extend(bodies(&page.chunk));
+                    match page.end {
+                        Some(end) => from = Some(end.parse().unwrap()),
+                        None => break,
+                    }
+                }
+                if let Direction::Backward = dir {
+                    seen.reverse();
+                }
+                assert_eq!(seen, vec!["after bob 1", "after bob 2"]);
+            }
+        });
+    }
+
+    #[test]
+    fn shared_history_hidden_from_outsiders() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let room_id = "!shared:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let eve = MatrixId::new("eve", "example.org").unwrap();
+
+            // rooms start out shared
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice)
+                .message(&alice, "shared")
+                .build()
+                .await;
+            db.add_event(room_id, NewEvent {
+                event_content: EventContent::HistoryVisibility(HistoryVisibility {
+                    history_visibility: HistoryVisibilityType::WorldReadable,
+                }),
+                sender: alice.clone(),
+                state_key: Some(String::new()),
+                redacts: None,
+                unsigned: None,
+            }, &state_resolver, &keys).await.unwrap();
+            db.add_event(room_id, NewEvent {
+                event_content: message("world-readable"),
+                sender: alice.clone(),
+                state_key: None,
+                redacts: None,
+                unsigned: None,
+            }, &state_resolver, &keys).await.unwrap();
+
+            let bodies = |page: MessagesResponse| page.chunk.iter()
+                .filter_map(|event| {
+                    event.event_content.content_as_json()["body"].as_str().map(String::from)
+                })
+                .collect::<Vec<_>>();
+            // eve was never in the room, so only what's been world-readable is hers to see
+            let page = messages_page(&*db, room_id, Some(&eve), None, None, &Direction::Forward, 10)
+                .await.unwrap();
+            assert_eq!(bodies(page), vec!["world-readable"]);
+            let page = messages_page(&*db, room_id, Some(&alice), None, None, &Direction::Forward, 10)
+                .await.unwrap();
+            assert_eq!(bodies(page), vec!["shared", "world-readable"]);
+
+            // joining lets her see what was shared before
+            db.add_event(room_id, join_event(&eve), &state_resolver, &keys)
+                .await.unwrap();
+            let page = messages_page(&*db, room_id, Some(&eve), None, None, &Direction::Forward, 10)
+                .await.unwrap();
+            assert_eq!(bodies(page), vec!["shared", "world-readable"]);
+        });
+    }
+
+    #[test]
+    fn timestamp_to_event() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let room_id = "!test:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+
+            let event = |body: &str| NewEvent {
+                event_content: message(body),
+                sender: alice.clone(),
+                state_key: None,
+                redacts: None,
+                unsigned: None,
+            };
+            let first_id = db.add_event(room_id, event("first"), &state_resolver, &keys)
+                .await.unwrap();
+            // make sure the two messages get timestamps far enough apart to fit one between
+            delay_for(Duration::from_millis(10)).await;
+            let second_id = db.add_event(room_id, event("second"), &state_resolver, &keys)
+                .await.unwrap();
+
+            let first_ts = db.get_pdu(room_id, &first_id).await.unwrap().unwrap().origin_server_ts();
+            let second_ts = db.get_pdu(room_id, &second_id).await.unwrap().unwrap().origin_server_ts();
+            assert!(second_ts - first_ts >= 2);
+            let between = first_ts + 1;
+
+            let forward = closest_event(&*db, room_id, &alice, between, &Direction::Forward).await.unwrap();
+            assert_eq!(forward.event_id, second_id);
+            assert_eq!(forward.origin_server_ts, second_ts);
+            let backward = closest_event(&*db, room_id, &alice, between, &Direction::Backward).await.unwrap();
+            assert_eq!(backward.event_id, first_id);
+            assert_eq!(backward.origin_server_ts, first_ts);
+
+            closest_event(&*db, room_id, &alice, second_ts + 1, &Direction::Forward).await
+                .expect_err("nothing after the last message");
+
+            // an event from a server whose clock is behind lands after the others in the
+            // timeline, but with an earlier timestamp than any of them but the create event
+            let latecomer = VersionedPdu::V4(UnhashedPdu {
+                event_content: message("latecomer"),
+                room_id: String::from(room_id),
+                sender: alice.clone(),
+                state_key: None,
+                unsigned: None,
+                redacts: None,
+                origin: String::from("example.org"),
+                origin_server_ts: 1,
+                prev_events: vec![second_id.clone()],
+                depth: 100,
+                auth_events: Vec::new(),
+            }.finalize());
+            let latecomer_id = latecomer.event_id();
+            db.add_pdus(&[StoredPdu::new(latecomer, AuthStatus::Pass)]).await.unwrap();
+
+            let backward = closest_event(&*db, room_id, &alice, between, &Direction::Backward).await.unwrap();
+            assert_eq!(backward.event_id, first_id);
+            let forward = closest_event(&*db, room_id, &alice, 1, &Direction::Forward).await.unwrap();
+            assert_eq!(forward.event_id, latecomer_id);
+            let backward = closest_event(&*db, room_id, &alice, 1, &Direction::Backward).await.unwrap();
+            assert_eq!(backward.event_id, latecomer_id);
+
+            assert!(may_read_history(&*db, room_id, Some(&alice)).await.unwrap());
+            assert!(!may_read_history(&*db, room_id, Some(&bob)).await.unwrap());
+            // bob can't find what was sent before joining while history was only for members
+            db.add_event(room_id, NewEvent {
+                event_content: EventContent::HistoryVisibility(HistoryVisibility {
+                    history_visibility: HistoryVisibilityType::Joined,
+                }),
+                sender: alice.clone(),
+                state_key: Some(String::new()),
+                redacts: None,
+                unsigned: None,
+            }, &state_resolver, &keys).await.unwrap();
+            delay_for(Duration::from_millis(10)).await;
+            let hidden_id = db.add_event(room_id, event("hidden"), &state_resolver, &keys)
+                .await.unwrap();
+            let hidden_ts = db.get_pdu(room_id, &hidden_id).await.unwrap().unwrap()
+                .origin_server_ts();
+            delay_for(Duration::from_millis(10)).await;
+            let join = join_event(&bob);
+            let join_id = db.add_event(room_id, join, &state_resolver, &keys).await.unwrap();
+
+            let forward = closest_event(&*db, room_id, &alice, hidden_ts, &Direction::Forward)
+                .await.unwrap();
+            assert_eq!(forward.event_id, hidden_id);
+            let forward = closest_event(&*db, room_id, &bob, hidden_ts, &Direction::Forward)
+                .await.unwrap();
+            assert_eq!(forward.event_id, join_id);
+        });
+    }
+
+    #[test]
+    fn account_data_changes_in_sync() {
+        with_mem_db(|db, _| async move {
+            db.create_user("alice", "password").await.unwrap();
+            db.set_user_account_data("alice", "m.push_rules", json!({ "global": {} }))
+                .await.unwrap();
+
+            // an initial sync gets everything
+            let mut batch = Batch::default();
+            let account_data = account_data_since(&*db, "alice", &mut batch).await.unwrap();
+            assert_eq!(account_data.events.len(), 1);
+
+            db.set_user_account_data("alice", "org.example.setting", json!({ "on": true }))
+                .await.unwrap();
+            let account_data = account_data_since(&*db, "alice", &mut batch).await.unwrap();
+            assert_eq!(account_data.events.len(), 1);
+            assert_eq!(account_data.events[0].ty, "org.example.setting");
+            assert_eq!(account_data.events[0].content, json!({ "on": true }));
+
+            let account_data = account_data_since(&*db, "alice", &mut batch).await.unwrap();
+            assert!(account_data.events.is_empty());
+        });
+    }
+
+    #[test]
+    fn global_account_data_in_sync() {
+        let mut sys = actix_web::rt::System::new("global_account_data_in_sync");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let auth = format!("Bearer {}", db.create_access_token("alice", "phone").await.unwrap());
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+
+            let direct = json!({ "@bob:example.org": ["!dm:example.org"] });
+            let req = test::TestRequest::put()
+                .uri("/_matrix/client/r0/user/@alice:example.org/account_data/m.direct")
+                .header("Authorization", auth.as_str())
+                .set_json(&direct)
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), actix_web::http::StatusCode::OK);
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/sync")
+                .header("Authorization", auth.as_str())
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+            assert_eq!(body["account_data"]["events"], json!([{ "type": "m.direct", "content": direct }]));
+
+            // filtered out types are left out, even from a full sync
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/sync?filter=%7B%22account_data%22%3A%7B%22not_types%22%3A%5B%22m.direct%22%5D%7D%7D")
+                .header("Authorization", auth.as_str())
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+            assert_eq!(body["account_data"]["events"], json!([]));
+        });
+    }
+
+    #[test]
+    fn ban_reason_in_sync() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let room_id = "!test:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).join(&bob).build().await;
+
+            // both users have synced up to this point
+            let (_, progress, _) = unfiltered_room(&*db, room_id, &alice, 0, false).await.unwrap();
+
+            let mut ban = membership(&bob, Membership::Ban, Some("spam"));
+            ban.sender = alice.clone();
+            db.add_event(room_id, ban, &state_resolver, &keys).await.unwrap();
+
+            let is_ban = |event: &&Event| match &event.event_content {
+                EventContent::Member(content) => {
+                    event.state_key.as_deref() == Some(bob.as_str())
+                        && content.membership == Membership::Ban
+                        && content.reason.as_deref() == Some("spam")
+                },
+                _ => false,
+            };
+
+            let (alice_room, _, is_empty) =
+                unfiltered_room(&*db, room_id, &alice, progress + 1, false).await.unwrap();
+            assert!(!is_empty);
+            assert_eq!(alice_room.timeline.events.iter().filter(is_ban).count(), 1);
+
+            let bob_room = left_room(&*db, room_id, &bob, progress + 1).await.unwrap();
+            assert_eq!(bob_room.timeline.events.iter().filter(is_ban).count(), 1);
+        });
+    }
+
+    #[test]
+    fn streamed_state_matches() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let room_id = "!state:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+            db.add_event(room_id, NewEvent {
+                event_content: EventContent::JoinRules(JoinRules {
+                    join_rule: JoinRule::Public,
+                    allow: Vec::new(),
+                }),
+                sender: alice.clone(),
+                state_key: Some(String::new()),
+                redacts: None,
+                unsigned: None,
+            }, &state_resolver, &keys).await.unwrap();
+            for i in 0..5 {
+                let user_id = MatrixId::new(&format!("user{}", i), "example.org").unwrap();
+                db.add_event(room_id, join_event(&user_id), &state_resolver, &keys)
+                    .await.unwrap();
+            }
+
+            let room_state = db.get_full_state(room_id).await.unwrap();
+            let expected = serde_json::to_value(&room_state).unwrap();
+            let events = stream::iter(room_state.into_iter().map(Ok));
+            let body: Vec<u8> = stream_events(events, b"[", b"]")
+                .map(|chunk| chunk.unwrap().to_vec())
+                .concat()
+                .await;
+            let streamed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(streamed, expected);
+            assert_eq!(streamed.as_array().unwrap().len(), 8);
+
+            let body: Vec<u8> = stream_events(stream::empty(), b"[", b"]")
+                .map(|chunk| chunk.unwrap().to_vec())
+                .concat()
+                .await;
+            assert_eq!(body, b"[]");
+        });
+    }
+
+    #[test]
+    fn streamed_members_match() {
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            let keys = HashMap::new();
+            let room_id = "!members:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+            // enough members to take a few pages, some of them only invited
+            for i in 0..(MEMBER_PAGE_SIZE * 2) {
+                let user_id = MatrixId::new(&format!("user{}", i), "example.org").unwrap();
+                let event = match i % 10 {
+                    0 => invite_event(&alice, &user_id),
+                    _ => join_event(&user_id),
+                };
+                db.add_event(room_id, event, &state_resolver, &keys).await.unwrap();
+            }
+
+            for (filter, exclude) in vec![
+                (None, None),
+                (Some(Membership::Join), None),
+                (Some(Membership::Invite), None),
+                (None, Some(Membership::Join)),
+                (Some(Membership::Join), Some(Membership::Join)),
+            ] {
+                let mut members = member_events(&*db, room_id, filter.as_ref(), exclude.as_ref())
+                    .await.unwrap();
+                members.sort_by(|a, b| a.state_key.cmp(&b.state_key));
+                let expected = serde_json::to_value(MembersResponse { chunk: members }).unwrap();
+                let pages = MemberPages {
+                    db: db_pool.get_handle().await.unwrap(),
+                    room_id: String::from(room_id),
+                    after: None,
+                    done: false,
+                    membership: filter,
+                    not_membership: exclude,
+                    profiles_domain: None,
+                };
+                let body: Vec<u8> = stream_events(pages.into_stream(), b"{\"chunk\":[", b"]}")
+                    .map(|chunk| chunk.unwrap().to_vec())
+                    .concat()
+                    .await;
+                let streamed: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(streamed, expected);
+            }
+            let joined = member_events(&*db, room_id, Some(&Membership::Join), None).await.unwrap();
+            assert_eq!(joined.len(), MEMBER_PAGE_SIZE * 2 / 10 * 9 + 1);
+            let invited = member_events(&*db, room_id, Some(&Membership::Invite), None).await.unwrap();
+            assert_eq!(invited.len(), MEMBER_PAGE_SIZE * 2 / 10);
+        });
+    }
+
+    #[test]
+    fn missing_room_state() {
+        with_mem_db(|db, state_resolver| async move {
+            let room_id = "!private:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+
+            check_joined(&*db, &alice, room_id).await.unwrap();
+            let err = check_joined(&*db, &bob, room_id).await.unwrap_err();
+            assert_errcode!(err, "M_FORBIDDEN");
+            let err = check_joined(&*db, &bob, "!nowhere:example.org").await.unwrap_err();
+            assert_errcode!(err, "M_NOT_FOUND");
+
+            // having been in the room once doesn't help
+            let keys = HashMap::new();
+            for change in vec![Membership::Join, Membership::Leave] {
+                db.add_event(room_id, membership(&bob, change, None), &state_resolver, &keys)
+                    .await.unwrap();
+            }
+            let err = check_joined(&*db, &bob, room_id).await.unwrap_err();
+            assert_errcode!(err, "M_FORBIDDEN");
+        });
+    }
+
+    #[test]
+    fn incremental_sync() {
+        let mut sys = actix_web::rt::System::new("incremental_sync");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            let room_id = "!incremental:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+            let sync = |since: Option<&str>| {
+                let uri = match since {
+                    Some(since) => format!("/_matrix/client/r0/sync?since={}", since),
+                    None => String::from("/_matrix/client/r0/sync"),
+                };
+                test::TestRequest::get()
+                    .uri(&uri)
+                    .header("Authorization", format!("Bearer {}", token))
+                    .to_request()
+            };
+            let timeline = |res: &serde_json::Value| {
+                res["rooms"]["join"][room_id]["timeline"]["events"].as_array().unwrap()
+                    .iter()
+                    .map(|event| event["type"].as_str().unwrap().to_owned())
+                    .collect::<Vec<_>>()
+            };
+
+            let res: serde_json::Value = test::read_response_json(&mut app, sync(None)).await;
+            assert_eq!(timeline(&res).len(), 3);
+            let since = res["next_batch"].as_str().unwrap().to_owned();
+
+            db.add_event(room_id, NewEvent {
+                event_content: message("anything new?"),
+                sender: alice.clone(),
+                state_key: None,
+                redacts: None,
+                unsigned: None,
+            }, &state.state_resolver, &state.keys).await.unwrap();
+            let res: serde_json::Value = test::read_response_json(&mut app, sync(Some(&since))).await;
+            assert_eq!(timeline(&res), vec!["m.room.message"]);
+            let events = &res["rooms"]["join"][room_id]["timeline"]["events"];
+            assert_eq!(events[0]["content"]["body"], "anything new?");
+
+            // the old token still picks up from where it was
+            let res: serde_json::Value = test::read_response_json(&mut app, sync(Some(&since))).await;
+            assert_eq!(timeline(&res).len(), 1);
+
+            let res = test::call_service(&mut app, sync(Some("deadbeef"))).await;
+            assert_eq!(res.status(), actix_web::http::StatusCode::BAD_REQUEST);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_errcode!(body, "M_INVALID_PARAM");
+        });
+    }
+
+    #[test]
+    fn uploaded_filter() {
+        let mut sys = actix_web::rt::System::new("uploaded_filter");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            let room_id = "!filtered:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+            for body in &["one", "two", "three"] {
+                db.add_event(room_id, NewEvent {
+                    event_content: message(body),
+                    sender: alice.clone(),
+                    state_key: None,
+                    redacts: None,
+                    unsigned: None,
+                }, &state_resolver, &HashMap::new()).await.unwrap();
+            }
+
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+            let auth = format!("Bearer {}", token);
+
+            let filter = json!({
+                "room": { "timeline": { "types": ["m.room.message"], "limit": 2 } },
+            });
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/user/@alice:example.org/filter")
+                .header("Authorization", auth.as_str())
+                .set_json(&filter)
+                .to_request();
+            let res: serde_json::Value = test::read_response_json(&mut app, req).await;
+            let filter_id = res["filter_id"].as_str().unwrap().to_owned();
+
+            let req = test::TestRequest::get()
+                .uri(&format!("/_matrix/client/r0/user/@alice:example.org/filter/{}", filter_id))
+                .header("Authorization", auth.as_str())
+                .to_request();
+            let res: serde_json::Value = test::read_response_json(&mut app, req).await;
+            assert_eq!(res, filter);
+
+            let req = test::TestRequest::get()
+                .uri(&format!("/_matrix/client/r0/sync?filter={}", filter_id))
+                .header("Authorization", auth.as_str())
+                .to_request();
+            let res: serde_json::Value = test::read_response_json(&mut app, req).await;
+            let timeline = &res["rooms"]["join"][room_id]["timeline"];
+            let bodies: Vec<_> = timeline["events"].as_array().unwrap()
+                .iter()
+                .map(|event| event["content"]["body"].as_str().unwrap())
+                .collect();
+            assert_eq!(bodies, vec!["two", "three"]);
+            assert_eq!(timeline["limited"], true);
+
+            // the client is given the state it missed, and can page back over the gap
+            let room = &res["rooms"]["join"][room_id];
+            let state_types: Vec<_> = room["state"]["events"].as_array().unwrap()
+                .iter()
+                .map(|event| event["type"].as_str().unwrap())
+                .collect();
+            assert!(state_types.contains(&"m.room.create"));
+            assert!(state_types.contains(&"m.room.join_rules"));
+            assert!(state_types.contains(&"m.room.member"));
+            let prev_batch = timeline["prev_batch"].as_str().unwrap();
+            let req = test::TestRequest::get()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/messages?dir=b&from={}", room_id, prev_batch))
+                .header("Authorization", auth.as_str())
+                .to_request();
+            let res: serde_json::Value = test::read_response_json(&mut app, req).await;
+            assert_eq!(res["chunk"][0]["content"]["body"], "one");
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/sync?filter=nonexistent")
+                .header("Authorization", auth.as_str())
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), actix_web::http::StatusCode::BAD_REQUEST);
+
+            for bad_filter in &[json!([]), json!({ "room": { "timeline": { "limit": "two" } } })] {
+                let req = test::TestRequest::post()
+                    .uri("/_matrix/client/r0/user/@alice:example.org/filter")
+                    .header("Authorization", auth.as_str())
+                    .set_json(bad_filter)
+                    .to_request();
+                let res = test::call_service(&mut app, req).await;
+                assert_eq!(res.status(), actix_web::http::StatusCode::BAD_REQUEST);
+                let body: serde_json::Value = test::read_body_json(res).await;
+                assert_errcode!(body, "M_BAD_JSON");
+            }
+        });
+    }
+
+    #[test]
+    fn paginate_messages() {
+        let mut sys = actix_web::rt::System::new("paginate_messages");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            let room_id = "!paginated:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+            for i in 0..25 {
+                db.add_event(room_id, NewEvent {
+                    event_content: message(&i.to_string()),
+                    sender: alice.clone(),
+                    state_key: None,
+                    redacts: None,
+                    unsigned: None,
+                }, &state_resolver, &HashMap::new()).await.unwrap();
+            }
+
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+            let messages = |query: String| {
+                test::TestRequest::get()
+                    .uri(&format!("/_matrix/client/r0/rooms/{}/messages?{}", room_id, query))
+                    .header("Authorization", format!("Bearer {}", token))
+                    .to_request()
+            };
+            let bodies = |res: &serde_json::Value| {
+                res["chunk"].as_array().unwrap()
+                    .iter()
+                    .filter_map(|event| event["content"]["body"].as_str())
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            };
+
+            // page backwards from the latest message, ten at a time
+            let mut seen = Vec::new();
+            let mut from: Option<String> = None;
+            let mut pages = 0;
+            loop {
+                let query = match &from {
+                    Some(from) => format!("dir=b&limit=10&from={}", from),
+                    None => String::from("dir=b&limit=10"),
+                };
+                let res: serde_json::Value = test::read_response_json(&mut app, messages(query)).await;
+                assert!(res["chunk"].as_array().unwrap().len() <= 10);
+                if let Some(start) = &from {
+                    assert_eq!(res["start"], start.as_str());
+                }
+                seen.extend(bodies(&res));
+                match res["end"].as_str() {
+                    Some(end) => from = Some(end.to_owned()),
+                    None => break,
+                }
+                pages += 1;
+            }
+            // 25 messages and the room's three creation events make three pages
+            assert_eq!(pages, 3);
+            let expected: Vec<_> = (0..25).rev().map(|i| i.to_string()).collect();
+            assert_eq!(seen, expected);
+
+            // tokens work going forwards too, and `to` stops the page early
+            let res: serde_json::Value =
+                test::read_response_json(&mut app, messages(String::from("dir=f&limit=5"))).await;
+            assert_eq!(bodies(&res), vec!["0", "1"]);
+            let end = res["end"].as_str().unwrap();
+            let res: serde_json::Value = test::read_response_json(
+                &mut app,
+                messages(format!("dir=f&from={}&to={}", end, end.parse::<usize>().unwrap() + 2)),
+            ).await;
+            assert_eq!(bodies(&res), vec!["2", "3"]);
+
+            let res = test::call_service(&mut app, messages(String::from("dir=b&from=nope"))).await;
+            assert_eq!(res.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        });
+    }
+
+    #[test]
+    fn messages_limits() {
+        let mut sys = actix_web::rt::System::new("messages_limits");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            let room_id = "!limited:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+            for i in 0..30 {
+                db.add_event(room_id, NewEvent {
+                    event_content: message(&i.to_string()),
+                    sender: alice.clone(),
+                    state_key: None,
+                    redacts: None,
+                    unsigned: None,
+                }, &state_resolver, &HashMap::new()).await.unwrap();
+            }
+
+            let state = test_state(db_pool, json!({
+                "max_messages_limit": 20,
+            })).await;
+            let mut app = test_app(&state).await;
+            let messages = |query: &str| {
+                test::TestRequest::get()
+                    .uri(&format!("/_matrix/client/r0/rooms/{}/messages?{}", room_id, query))
+                    .header("Authorization", format!("Bearer {}", token))
+                    .to_request()
+            };
+
+            let res: serde_json::Value = test::read_response_json(&mut app, messages("dir=b")).await;
+            assert_eq!(res["chunk"].as_array().unwrap().len(), 10);
+            let res: serde_json::Value =
+                test::read_response_json(&mut app, messages("dir=b&limit=1000000")).await;
+            assert_eq!(res["chunk"].as_array().unwrap().len(), 20);
+
+            let res = test::call_service(&mut app, messages("dir=b&limit=-1")).await;
+            assert_eq!(res.status(), actix_web::http::StatusCode::BAD_REQUEST);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_errcode!(body, "M_INVALID_PARAM");
+        });
+    }
+
+    #[test]
+    fn redact_message() {
+        let mut sys = actix_web::rt::System::new("redact_message");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            let room_id = "!redact:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            db.create_user("bob", "password").await.unwrap();
+            let alice_token = db.create_access_token("alice", "phone").await.unwrap();
+            let bob_token = db.create_access_token("bob", "phone").await.unwrap();
+            let room = RoomBuilder::new(&*db, &state_resolver, room_id, &alice)
+                .join(&bob)
+                .message(&alice, "hello")
+                .message(&bob, "spam")
+                .build()
+                .await;
+            let alice_message = room.message_ids[0].clone();
+            let bob_message = room.message_ids[1].clone();
+
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+            let redact = |token: &uuid::Uuid, event_id: &str, txn_id: &str| {
+                test::TestRequest::put()
+                    .uri(&format!("/_matrix/client/r0/rooms/{}/redact/{}/{}", room_id, event_id, txn_id))
+                    .header("Authorization", format!("Bearer {}", token))
+                    .set_json(&json!({ "reason": "spam" }))
+                    .to_request()
+            };
+
+            // bob doesn't have the power to redact other people's messages
+            let res = test::call_service(&mut app, redact(&bob_token, &alice_message, "1")).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let pdu = db.get_pdu(room_id, &alice_message).await.unwrap().unwrap();
+            assert_eq!(pdu.event_content().content_as_json()["body"], "hello");
+
+            let res = test::call_service(&mut app, redact(&alice_token, &bob_message, "1")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+
+            let req = test::TestRequest::get()
+                .uri(&format!("/_matrix/client/r0/rooms/{}/event/{}", room_id, bob_message))
+                .header("Authorization", format!("Bearer {}", alice_token))
+                .to_request();
+            let event: serde_json::Value = test::read_response_json(&mut app, req).await;
+            assert_eq!(event["content"], json!({}));
+            assert_eq!(event["unsigned"]["redacted_because"]["redacts"], bob_message.as_str());
+            assert_eq!(event["unsigned"]["redacted_because"]["content"]["reason"], "spam");
+
+            let res = test::call_service(&mut app, redact(&alice_token, "$nothing", "2")).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        });
+    }
+
+    #[test]
+    fn world_readable_without_token() {
+        let mut sys = actix_web::rt::System::new("world_readable_without_token");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            let keys = HashMap::new();
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let message = || message_event(&alice, "hello");
+            let public_room = "!public:example.org";
+            RoomBuilder::new(&*db, &state_resolver, public_room, &alice).build().await;
+            let visibility = EventContent::HistoryVisibility(HistoryVisibility {
+                history_visibility: HistoryVisibilityType::WorldReadable,
+            });
+            db.add_event(public_room, state_event(&alice, visibility, ""), &state_resolver, &keys)
+                .await.unwrap();
+            let public_message = db.add_event(public_room, message(), &state_resolver, &keys)
+                .await.unwrap();
+            let private_room = "!private:example.org";
+            RoomBuilder::new(&*db, &state_resolver, private_room, &alice).build().await;
+            let private_message = db.add_event(private_room, message(), &state_resolver, &keys)
+                .await.unwrap();
+
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+            let get = |uri: String| test::TestRequest::get().uri(&uri).to_request();
+
+            let uri = format!("/_matrix/client/r0/rooms/{}/event/{}", public_room, public_message);
+            let event: serde_json::Value = test::read_response_json(&mut app, get(uri)).await;
+            assert_eq!(event["content"]["body"], "hello");
+            let uri = format!("/_matrix/client/r0/rooms/{}/state", public_room);
+            let room_state: serde_json::Value = test::read_response_json(&mut app, get(uri)).await;
+            assert_eq!(room_state.as_array().unwrap().len(), 4);
+            // only what was sent once the room was world-readable can be read anonymously
+            let uri = format!("/_matrix/client/r0/rooms/{}/messages?dir=f", public_room);
+            let res: serde_json::Value = test::read_response_json(&mut app, get(uri)).await;
+            let types: Vec<_> = res["chunk"].as_array().unwrap().iter()
+                .map(|event| event["type"].as_str().unwrap())
+                .collect();
+            assert_eq!(types, vec!["m.room.message"]);
+
+            for uri in vec![
+                format!("/_matrix/client/r0/rooms/{}/event/{}", private_room, private_message),
+                format!("/_matrix/client/r0/rooms/{}/state", private_room),
+                format!("/_matrix/client/r0/rooms/{}/messages?dir=f", private_room),
+                // whether a room exists isn't given away without a token either
+                String::from("/_matrix/client/r0/rooms/!nowhere:example.org/messages?dir=f"),
+            ] {
+                let res = test::call_service(&mut app, get(uri)).await;
+                assert_eq!(res.status(), StatusCode::FORBIDDEN);
+                let body: serde_json::Value = test::read_body_json(res).await;
+                assert_errcode!(body, "M_MISSING_TOKEN");
+            }
+        });
+    }
+}
@@ -5,17 +5,24 @@ use actix_web::{
 use tracing::{Level, Span, instrument, field::Empty};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value as JsonValue};
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 
-use crate::{ServerState, client_api::auth::AccessToken, error::{Error, ErrorKind}, storage::UserProfile, util::MatrixId};
+use crate::{
+    ServerState,
+    client_api::{auth::{AccessToken, ThreepidCreds, validated_threepid}, filter::Filter},
+    error::{Error, ErrorKind},
+    events::{EventContent, room::{JoinRule, JoinRules, Member, Membership}},
+    storage::{Medium, Storage, Threepid, UserProfile},
+    util::{MatrixId, PercentDecoded},
+};
 
 #[get("/profile/{user_id}/avatar_url")]
 #[instrument(skip(state), err = Level::DEBUG)]
 pub async fn get_avatar_url(
     state: Data<Arc<ServerState>>,
-    Path(user_id): Path<MatrixId>
+    Path(PercentDecoded(user_id)): Path<PercentDecoded<MatrixId>>
 ) -> Result<Json<JsonValue>, Error> {
-    if user_id.domain() != state.config.domain {
+    if !user_id.has_domain(&state.config.domain) {
         return Err(ErrorKind::Unimplemented.into());
     }
 
@@ -35,7 +42,7 @@ pub async fn get_avatar_url(
 pub async fn set_avatar_url(
     state: Data<Arc<ServerState>>,
     token: AccessToken,
-    Path(req_id): Path<MatrixId>,
+    Path(PercentDecoded(req_id)): Path<PercentDecoded<MatrixId>>,
     body: Json<JsonValue>
 ) -> Result<Json<()>, Error> {
     let db = state.db_pool.get_handle().await?;
@@ -45,7 +52,7 @@ pub async fn set_avatar_url(
     if req_id.localpart() != username {
         return Err(ErrorKind::Forbidden.into());
     }
-    if req_id.domain() != state.config.domain {
+    if !req_id.has_domain(&state.config.domain) {
         return Err(ErrorKind::Unknown("User does not live on this homeserver".to_string()).into());
     }
 
@@ -60,9 +67,9 @@ pub async fn set_avatar_url(
 #[instrument(skip(state), err = Level::DEBUG)]
 pub async fn get_display_name(
     state: Data<Arc<ServerState>>,
-    Path(user_id): Path<MatrixId>
+    Path(PercentDecoded(user_id)): Path<PercentDecoded<MatrixId>>
 ) -> Result<Json<JsonValue>, Error> {
-    if user_id.domain() != state.config.domain {
+    if !user_id.has_domain(&state.config.domain) {
         return Err(ErrorKind::Unknown("User does not live on this homeserver".to_string()).into());
     }
 
@@ -82,7 +89,7 @@ pub async fn get_display_name(
 pub async fn set_display_name(
     state: Data<Arc<ServerState>>,
     token: AccessToken,
-    Path(req_id): Path<MatrixId>,
+    Path(PercentDecoded(req_id)): Path<PercentDecoded<MatrixId>>,
     body: Json<JsonValue>
 ) -> Result<Json<()>, Error> {
     let db = state.db_pool.get_handle().await?;
@@ -92,7 +99,7 @@ pub async fn set_display_name(
     if req_id.localpart() != username {
         return Err(ErrorKind::Forbidden.into());
     }
-    if req_id.domain() != state.config.domain {
+    if !req_id.has_domain(&state.config.domain) {
         return Err(ErrorKind::Unknown("User does not live on this homeserver".to_string()).into());
     }
 
@@ -107,9 +114,9 @@ pub async fn set_display_name(
 #[instrument(skip(state), err = Level::DEBUG)]
 pub async fn get_profile(
     state: Data<Arc<ServerState>>,
-    Path(user_id): Path<MatrixId>
+    Path(PercentDecoded(user_id)): Path<PercentDecoded<MatrixId>>
 ) -> Result<Json<JsonValue>, Error> {
-    if user_id.domain() != state.config.domain {
+    if !user_id.has_domain(&state.config.domain) {
         return Err(ErrorKind::Unknown("User does not live on this homeserver".to_string()).into());
     }
 
@@ -148,62 +155,532 @@ struct User {
     display_name: Option<String>,
 }
 
-//TODO: actually implement this
 #[post("/user_directory/search")]
-#[instrument(skip_all, err = Level::DEBUG)]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
 pub async fn search_user_directory(
     state: Data<Arc<ServerState>>,
+    token: AccessToken,
     req: Json<UserDirSearchRequest>,
 ) -> Result<Json<UserDirSearchResponse>, Error> {
-    let req = req.into_inner();
     let db = state.db_pool.get_handle().await?;
-    let searched_user = MatrixId::new(&req.search_term, &state.config.domain)
-        .map_err(|e| ErrorKind::Unknown(e.to_string()))?;
-    let user_profile = db.get_profile(searched_user.localpart()).await?;
-    match user_profile {
-        Some(p) => Ok(Json(UserDirSearchResponse {
-            results: vec![User {
-                user_id: searched_user,
-                avatar_url: p.avatar_url,
-                display_name: p.displayname,
-            }],
-            limited: false,
-        })),
-        None => Ok(Json(UserDirSearchResponse {
-            results: Vec::new(),
-            limited: false,
-        })),
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let searcher = MatrixId::new(&username, &state.config.domain).unwrap();
+    let limit = req.limit.unwrap_or(10);
+    Ok(Json(search_users(&*db, &searcher, &req.search_term, limit).await?))
+}
+
+/// Searches users by username and display name. Exact matches come first, then names starting
+/// with the search term, then names merely containing it, with ties going in user ID order.
+///
+/// Only users who share a room with the searcher or are in a public room are found.
+async fn search_users(
+    db: &dyn Storage,
+    searcher: &MatrixId,
+    search_term: &str,
+    limit: usize,
+) -> Result<UserDirSearchResponse, Error> {
+    let search_term = search_term.to_lowercase();
+    let visible = visible_users(db, searcher).await?;
+    let mut matches = db.search_users(&search_term).await?
+        .into_iter()
+        .filter_map(|(username, profile)| {
+            let rank = match_rank(&search_term, &username, &profile);
+            let user_id = MatrixId::new(&username, searcher.domain()).ok()?;
+            if !visible.contains(user_id.as_str()) {
+                return None;
+            }
+            Some((rank, user_id, profile))
+        })
+        .collect::<Vec<_>>();
+    matches.sort_by(|(a_rank, a_id, _), (b_rank, b_id, _)| {
+        (a_rank, a_id.as_str()).cmp(&(b_rank, b_id.as_str()))
+    });
+    let limited = matches.len() > limit;
+    let results = matches.into_iter()
+        .take(limit)
+        .map(|(_, user_id, profile)| User {
+            user_id,
+            avatar_url: profile.avatar_url,
+            display_name: profile.displayname,
+        })
+        .collect();
+    Ok(UserDirSearchResponse { results, limited })
+}
+
+/// The IDs of the users joined to rooms which `searcher` is joined to or which anyone can join.
+async fn visible_users(db: &dyn Storage, searcher: &MatrixId) -> Result<HashSet<String>, Error> {
+    let mut ret = HashSet::new();
+    for room_id in db.get_rooms().await? {
+        let state = db.get_full_state(&room_id).await?;
+        let is_public = state.iter().any(|event| matches!(
+            &event.event_content,
+            EventContent::JoinRules(JoinRules { join_rule: JoinRule::Public, .. }),
+        ));
+        let members = state.into_iter()
+            .filter(|event| matches!(
+                &event.event_content,
+                EventContent::Member(Member { membership: Membership::Join, .. }),
+            ))
+            .filter_map(|event| event.state_key)
+            .collect::<Vec<_>>();
+        if is_public || members.iter().any(|user_id| user_id == searcher.as_str()) {
+            ret.extend(members);
+        }
     }
+    Ok(ret)
 }
 
-#[derive(Serialize)]
-pub struct Get3pidsResponse {
-    threepids: Vec<Threepid>,
+/// How well a user matches a lowercase search term: 0 for an exact match, 1 for a prefix match,
+/// and 2 for anything else.
+fn match_rank(search_term: &str, username: &str, profile: &UserProfile) -> u8 {
+    std::iter::once(username)
+        .chain(profile.displayname.as_deref())
+        .map(|name| {
+            let name = name.to_lowercase();
+            if name == search_term {
+                0
+            } else if name.starts_with(search_term) {
+                1
+            } else {
+                2
+            }
+        })
+        .min()
+        .unwrap_or(2)
 }
 
-#[derive(Serialize)]
-struct Threepid {
-    medium: Medium,
-    address: String,
-    validated_at: u64,
-    added_at: u64,
+#[put("/user/{user_id}/account_data/{type}")]
+#[instrument(skip(state, token, body), fields(username = Empty), err = Level::DEBUG)]
+pub async fn set_account_data(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((PercentDecoded(user_id), ty)): Path<(PercentDecoded<MatrixId>, String)>,
+    body: Json<JsonValue>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    if user_id.localpart() != username || !user_id.has_domain(&state.config.domain) {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    if !body.is_object() {
+        return Err(ErrorKind::BadJson(String::from("account data must be an object")).into());
+    }
+
+    db.set_user_account_data(&username, &ty, body.into_inner()).await?;
+    Ok(Json(json!({})))
+}
+
+#[get("/user/{user_id}/account_data/{type}")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_account_data(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((PercentDecoded(user_id), ty)): Path<(PercentDecoded<MatrixId>, String)>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    if user_id.localpart() != username || !user_id.has_domain(&state.config.domain) {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    let mut account_data = db.get_user_account_data(&username).await?;
+    account_data.remove(&ty).map(Json).ok_or_else(|| ErrorKind::NotFound.into())
+}
+
+#[put("/user/{user_id}/rooms/{room_id}/account_data/{type}")]
+#[instrument(skip(state, token, body), fields(username = Empty), err = Level::DEBUG)]
+pub async fn set_room_account_data(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((PercentDecoded(user_id), room_id, ty)): Path<(PercentDecoded<MatrixId>, String, String)>,
+    body: Json<JsonValue>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    if user_id.localpart() != username || !user_id.has_domain(&state.config.domain) {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    if !body.is_object() {
+        return Err(ErrorKind::BadJson(String::from("account data must be an object")).into());
+    }
+    if ty == "m.fully_read" {
+        let msg = "the fully read marker can only be set through /read_markers";
+        return Err(ErrorKind::BadJson(String::from(msg)).into());
+    }
+
+    db.set_room_account_data(&username, &room_id, &ty, body.into_inner()).await?;
+    Ok(Json(json!({})))
+}
+
+#[get("/user/{user_id}/rooms/{room_id}/account_data/{type}")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_room_account_data(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((PercentDecoded(user_id), room_id, ty)): Path<(PercentDecoded<MatrixId>, String, String)>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    if user_id.localpart() != username || !user_id.has_domain(&state.config.domain) {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    if ty == "m.fully_read" {
+        let event_id = db.get_fully_read(&room_id, &user_id).await?.ok_or(ErrorKind::NotFound)?;
+        return Ok(Json(json!({ "event_id": event_id })));
+    }
+    let mut account_data = db.get_room_account_data_since(&username, &room_id, 0).await?;
+    account_data.remove(&ty).map(Json).ok_or_else(|| ErrorKind::NotFound.into())
+}
+
+#[post("/user/{user_id}/filter")]
+#[instrument(skip(state, token, body), fields(username = Empty), err = Level::DEBUG)]
+pub async fn create_filter(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path(PercentDecoded(user_id)): Path<PercentDecoded<MatrixId>>,
+    body: Json<JsonValue>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    if user_id.localpart() != username || !user_id.has_domain(&state.config.domain) {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    if !body.is_object() {
+        return Err(ErrorKind::BadJson(String::from("filter must be an object")).into());
+    }
+    // the filter is stored as it was sent, but it has to make sense when it's used
+    serde_json::from_value::<Filter>(body.clone())
+        .map_err(|e| ErrorKind::BadJson(format!("invalid filter: {}", e)))?;
+
+    let filter_id = db.create_filter(&username, body.into_inner()).await?;
+    Ok(Json(json!({ "filter_id": filter_id })))
+}
+
+#[get("/user/{user_id}/filter/{filter_id}")]
+#[instrument(skip(state, token), fields(username = Empty), err = Level::DEBUG)]
+pub async fn get_filter(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    Path((PercentDecoded(user_id), filter_id)): Path<(PercentDecoded<MatrixId>, String)>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    if user_id.localpart() != username || !user_id.has_domain(&state.config.domain) {
+        return Err(ErrorKind::Forbidden.into());
+    }
+
+    let filter = db.get_filter(&username, &filter_id).await?;
+    filter.map(Json).ok_or_else(|| ErrorKind::NotFound.into())
 }
 
 #[derive(Serialize)]
-pub enum Medium {
-    Email,
-    // Phone number, including calling code
-    Msisdn,
+pub struct Get3pidsResponse {
+    threepids: Vec<Threepid>,
 }
 
 #[get("/account/3pid")]
-#[instrument(skip_all, err = Level::DEBUG)]
+#[instrument(skip_all, fields(username = Empty), err = Level::DEBUG)]
 pub async fn get_3pids(
-    _state: Data<Arc<ServerState>>,
-    _token: AccessToken,
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
 ) -> Result<Json<Get3pidsResponse>, Error> {
-    //TODO: implement
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
     Ok(Json(Get3pidsResponse {
-        threepids: Vec::new(),
+        threepids: db.get_threepids(&username).await?,
     }))
 }
+
+/// The identity server and its access token are left out, since there's no identity server support
+/// yet.
+#[derive(Debug, Deserialize)]
+pub struct Bind3pidRequest {
+    #[serde(flatten)]
+    creds: ThreepidCreds,
+}
+
+/// Binds a validated 3pid to the user's account. There's no identity server support yet, so the
+/// binding is only recorded locally.
+#[post("/account/3pid/bind")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn bind_3pid(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    req: Json<Bind3pidRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    let threepid = validated_threepid(&*db, &req.creds).await?;
+    db.add_threepid(&username, threepid).await?;
+    Ok(Json(json!({})))
+}
+
+/// The identity server is left out, since there's no identity server support yet.
+#[derive(Debug, Deserialize)]
+pub struct Unbind3pidRequest {
+    medium: Medium,
+    address: String,
+}
+
+/// Unbinds a 3pid from the user's account. Like binding, this only happens locally.
+#[post("/account/3pid/unbind")]
+#[instrument(skip(state, token, req), fields(username = Empty), err = Level::DEBUG)]
+pub async fn unbind_3pid(
+    state: Data<Arc<ServerState>>,
+    token: AccessToken,
+    req: Json<Unbind3pidRequest>,
+) -> Result<Json<JsonValue>, Error> {
+    let db = state.db_pool.get_handle().await?;
+    let username = db.try_auth(token.0).await?.ok_or(ErrorKind::UnknownToken)?;
+    Span::current().record("username", &username.as_str());
+    if !db.remove_threepid(&username, req.medium, &req.address).await? {
+        return Err(ErrorKind::NotFound.into());
+    }
+    // we didn't talk to an identity server, so we can't claim to have unbound it there
+    Ok(Json(json!({
+        "id_server_unbind_result": "no-support"
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test};
+    use serde_json::{json, Value as JsonValue};
+
+    use crate::{
+        events::room::{JoinRule, JoinRules},
+        storage::{mem::MemStorageManager, Medium, StorageManager},
+        test_util::{assert_errcode, RoomBuilder, test_app, test_state, with_mem_db},
+        util::MatrixId,
+    };
+
+    use super::search_users;
+
+    #[test]
+    fn bind_and_unbind_3pid() {
+        let mut sys = actix_web::rt::System::new("bind_and_unbind_3pid");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            let mut auths = Vec::new();
+            for user in ["alice", "bob"].iter() {
+                db.create_user(user, "password").await.unwrap();
+                let token = db.create_access_token(user, "phone").await.unwrap();
+                auths.push(format!("Bearer {}", token));
+            }
+            let sid = db.create_threepid_session("secret", Medium::Email, "alice@example.org")
+                .await.unwrap();
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+
+            let bind = |i: usize| test::TestRequest::post()
+                .uri("/_matrix/client/r0/account/3pid/bind")
+                .header("Authorization", auths[i].as_str())
+                .set_json(&json!({
+                    "sid": sid,
+                    "client_secret": "secret",
+                    "id_server": "id.example.org",
+                    "id_access_token": "token",
+                }))
+                .to_request();
+            let unbind = |i: usize, medium: &str| test::TestRequest::post()
+                .uri("/_matrix/client/r0/account/3pid/unbind")
+                .header("Authorization", auths[i].as_str())
+                .set_json(&json!({ "medium": medium, "address": "alice@example.org" }))
+                .to_request();
+            let get_threepids = || test::TestRequest::get()
+                .uri("/_matrix/client/r0/account/3pid")
+                .header("Authorization", auths[0].as_str())
+                .to_request();
+            let res = test::call_service(&mut app, bind(0)).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            let body: JsonValue = test::read_response_json(&mut app, get_threepids()).await;
+            assert_eq!(body, json!({ "threepids": [] }));
+
+            db.validate_threepid_session(&sid).await.unwrap();
+            let res = test::call_service(&mut app, bind(0)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            // binding again is harmless
+            let res = test::call_service(&mut app, bind(0)).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body: JsonValue = test::read_response_json(&mut app, get_threepids()).await;
+            assert_eq!(body["threepids"].as_array().unwrap().len(), 1);
+            assert_eq!(body["threepids"][0]["address"], "alice@example.org");
+
+            let body: JsonValue = test::read_response_json(&mut app, bind(1)).await;
+            assert_errcode!(body, "M_THREEPID_IN_USE");
+
+            // only the user it's bound to can unbind it
+            let res = test::call_service(&mut app, unbind(1, "email")).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            let res = test::call_service(&mut app, unbind(0, "fax")).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            let body: JsonValue = test::read_response_json(&mut app, unbind(0, "email")).await;
+            assert_eq!(body["id_server_unbind_result"], "no-support");
+            let body: JsonValue = test::read_response_json(&mut app, get_threepids()).await;
+            assert_eq!(body, json!({ "threepids": [] }));
+        });
+    }
+
+    #[test]
+    fn percent_encoded_user_ids() {
+        let mut sys = actix_web::rt::System::new("percent_encoded_user_ids");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            for (username, displayname) in &[("alice", "Alice"), ("carol/dev", "Carol")] {
+                db.create_user(username, "password").await.unwrap();
+                db.set_display_name(username, displayname).await.unwrap();
+            }
+            let state = test_state(db_pool, json!({ "domain": "local" })).await;
+            let mut app = test_app(&state).await;
+
+            for (path, displayname) in &[
+                ("/_matrix/client/r0/profile/%40alice%3Alocal/displayname", "Alice"),
+                ("/_matrix/client/r0/profile/@alice:local/displayname", "Alice"),
+                ("/_matrix/client/r0/profile/%40carol%2Fdev%3Alocal/displayname", "Carol"),
+            ] {
+                let req = test::TestRequest::get().uri(path).to_request();
+                let res: serde_json::Value = test::read_response_json(&mut app, req).await;
+                assert_eq!(res, json!({ "displayname": displayname }), "{}", path);
+            }
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/profile/%40ALICE%3Alocal/displayname")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            let res: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+            assert_errcode!(res, "M_INVALID_PARAM");
+        });
+    }
+
+    #[test]
+    fn room_account_data_in_sync() {
+        let mut sys = actix_web::rt::System::new("room_account_data_in_sync");
+        sys.block_on(async {
+            let db_pool = MemStorageManager::new();
+            let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let auth = format!("Bearer {}", db.create_access_token("alice", "phone").await.unwrap());
+            let state = test_state(db_pool, json!({})).await;
+            let mut app = test_app(&state).await;
+
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/createRoom")
+                .header("Authorization", auth.as_str())
+                .set_json(&json!({}))
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+            let room_id = body["room_id"].as_str().unwrap().to_owned();
+            let uri = format!(
+                "/_matrix/client/r0/user/@alice:example.org/rooms/{}/account_data/m.tag",
+                room_id,
+            );
+            let tags = json!({ "tags": { "u.work": { "order": 0.5 } } });
+
+            let req = test::TestRequest::put()
+                .uri(&uri)
+                .header("Authorization", auth.as_str())
+                .set_json(&tags)
+                .to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::OK);
+            let req = test::TestRequest::get()
+                .uri(&uri)
+                .header("Authorization", auth.as_str())
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+            assert_eq!(body, tags);
+
+            let req = test::TestRequest::get()
+                .uri("/_matrix/client/r0/sync")
+                .header("Authorization", auth.as_str())
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+            assert_eq!(
+                body["rooms"]["join"][&room_id]["account_data"]["events"],
+                json!([{ "type": "m.tag", "content": tags }]),
+            );
+
+            // it isn't sent again until it changes
+            let req = test::TestRequest::get()
+                .uri(&format!("/_matrix/client/r0/sync?since={}", body["next_batch"].as_str().unwrap()))
+                .header("Authorization", auth.as_str())
+                .to_request();
+            let body: serde_json::Value = test::read_response_json(&mut app, req).await;
+            assert_eq!(body["rooms"]["join"][&room_id]["account_data"]["events"], json!([]));
+        });
+    }
+
+    #[test]
+    fn user_search_ranking() {
+        with_mem_db(|db, state_resolver| async move {
+            for username in &["natalie", "alice", "bob", "carol", "alan"] {
+                db.create_user(username, "password").await.unwrap();
+            }
+            db.set_display_name("bob", "Al").await.unwrap();
+            db.create_guest_user("alfred").await.unwrap();
+            let user_id = |username: &str| MatrixId::new(username, "example.org").unwrap();
+            let invite_only = || JoinRules {
+                join_rule: JoinRule::Invite,
+                allow: Vec::new(),
+            };
+            RoomBuilder::new(&*db, &state_resolver, "!public:example.org", &user_id("natalie"))
+                .join(&user_id("alice"))
+                .build()
+                .await;
+            RoomBuilder::new(&*db, &state_resolver, "!shared:example.org", &user_id("carol"))
+                .join_rules(invite_only())
+                .invite(&user_id("carol"), &user_id("bob"))
+                .join(&user_id("bob"))
+                .build()
+                .await;
+            // carol isn't in alan's room, and it's not public, so alan can't be found
+            RoomBuilder::new(&*db, &state_resolver, "!hidden:example.org", &user_id("alan"))
+                .join_rules(invite_only())
+                .build()
+                .await;
+
+            let res = search_users(&*db, &user_id("carol"), "AL", 10).await.unwrap();
+            let user_ids = res.results.iter().map(|u| u.user_id.as_str()).collect::<Vec<_>>();
+            assert_eq!(user_ids, vec!["@bob:example.org", "@alice:example.org", "@natalie:example.org"]);
+            assert!(!res.limited);
+
+            let res = search_users(&*db, &user_id("carol"), "al", 2).await.unwrap();
+            assert_eq!(res.results.len(), 2);
+            assert_eq!(res.results[1].user_id.as_str(), "@alice:example.org");
+            assert!(res.limited);
+
+            let res = search_users(&*db, &user_id("alan"), "al", 10).await.unwrap();
+            let user_ids = res.results.iter().map(|u| u.user_id.as_str()).collect::<Vec<_>>();
+            assert_eq!(user_ids, vec!["@alan:example.org", "@alice:example.org", "@natalie:example.org"]);
+        });
+    }
+
+    #[test]
+    fn user_search_needs_token() {
+        let mut sys = actix_web::rt::System::new("user_search_needs_token");
+        sys.block_on(async {
+            let state = test_state(MemStorageManager::new(), json!({})).await;
+            let mut app = test_app(&state).await;
+            let req = test::TestRequest::post()
+                .uri("/_matrix/client/r0/user_directory/search")
+                .set_json(&json!({ "search_term": "a" }))
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let body: JsonValue = test::read_body_json(res).await;
+            assert_errcode!(body, "M_MISSING_TOKEN");
+        });
+    }
+}
@@ -1,6 +1,9 @@
 use std::{fmt::Display, str::Utf8Error, string::FromUtf8Error};
 
-use actix_web::{HttpResponse, ResponseError, dev::HttpResponseBuilder, error::JsonPayloadError, http::StatusCode};
+use actix_web::{
+    HttpResponse, ResponseError, dev::HttpResponseBuilder,
+    error::{JsonPayloadError, PathError}, http::StatusCode,
+};
 use displaydoc::Display;
 use serde_json::{Error as JsonError, json};
 use tracing_error::SpanTrace;
@@ -33,10 +36,11 @@ pub enum ErrorKind {
     Forbidden,
     /// The access token specified was not recognised.
     UnknownToken,
+    /// The access token specified has expired or been logged out; log in again as the same device.
+    SoftLogout,
     /// No access token was specified for the request.
     MissingToken,
-    /// Request contained valid JSON, but it was malformed in some way, e.g. missing required keys,
-    /// invalid values for keys: {0}
+    /// Request contained valid JSON, but it was malformed in some way: {0}
     BadJson(String),
     /// Request did not contain valid JSON: {0}
     NotJson(String),
@@ -48,6 +52,8 @@ pub enum ErrorKind {
     RoomNotFound,
     /// That username is already taken.
     UsernameTaken,
+    /// That username is not valid: {0}
+    InvalidUsername(String),
     /// Too many requests have been sent in a short period of time.
     LimitExceeded,
     /// A required URL parameter was missing from the request: {0}
@@ -58,6 +64,22 @@ pub enum ErrorKind {
     UnsupportedRoomVersion,
     /// The specified transaction has already been started.
     TxnIdExists,
+    /// That third party identifier is already in use by another user.
+    ThreepidInUse,
+    /// The third party identifier could not be validated.
+    ThreepidAuthFailed,
+    /// That room alias is already taken.
+    RoomInUse,
+    /// A room alias with that name already exists.
+    AliasExists,
+    /// The request can't be carried out given the state of the room: {0}
+    BadState(String),
+    /// The request or its response would be too large: {0}
+    TooLarge(String),
+    /// Further authentication is needed to complete the request.
+    ///
+    /// This holds the user-interactive auth state that's sent back instead of a normal error.
+    AuthRequired(serde_json::Value),
 
     /// An encoded string in the URL was not valid UTF-8: {0}
     UrlNotUtf8(Utf8Error),
@@ -67,8 +89,13 @@ pub enum ErrorKind {
     #[cfg(feature = "storage-sled")]
     /// A database error occurred: {0}.
     BincodeError(bincode::Error),
+    #[cfg(feature = "storage-postgres")]
+    /// A database error occurred: {0}.
+    PostgresError(pg::Error),
     /// A password error occurred: {0}
     PasswordError(argon2::Error),
+    /// The storage backend could not be reached: {0}
+    StorageUnavailable(String),
     /// The requested feature is unimplemented.
     Unimplemented,
     /// An invalid event was sent to a room: {0}
@@ -82,42 +109,73 @@ impl ResponseError for Error {
         use ErrorKind::*;
         match self.inner {
             Forbidden | UnknownToken | MissingToken | UsernameTaken => StatusCode::FORBIDDEN,
+            SoftLogout | ThreepidAuthFailed | AuthRequired(_) => StatusCode::UNAUTHORIZED,
             NotFound | UserNotFound | RoomNotFound => StatusCode::NOT_FOUND,
             BadJson(_) | NotJson(_) | MissingParam(_) | InvalidParam(_) | UnsupportedRoomVersion
                 | UrlNotUtf8(_) | PasswordError(_) | Unknown(_)
-                | TxnIdExists => StatusCode::BAD_REQUEST,
+                | TxnIdExists | ThreepidInUse | RoomInUse | InvalidUsername(_) | BadState(_)
+                => StatusCode::BAD_REQUEST,
+            AliasExists => StatusCode::CONFLICT,
             LimitExceeded => StatusCode::TOO_MANY_REQUESTS,
+            TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
             AddEventError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             #[cfg(feature = "storage-sled")]
             SledError(_) | BincodeError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            #[cfg(feature = "storage-postgres")]
+            PostgresError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            StorageUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
             Unimplemented => StatusCode::NOT_IMPLEMENTED,
         }
     }
     fn error_response(&self) -> HttpResponse {
+        HttpResponseBuilder::new(self.status_code()).json(self.to_json())
+    }
+}
+
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.inner
+    }
+
+    /// The JSON body sent to clients for this error.
+    pub fn to_json(&self) -> serde_json::Value {
         use ErrorKind::*;
-        let errcode = match self.inner {
+        let errcode = match &self.inner {
             Forbidden => "M_FORBIDDEN",
-            UnknownToken => "M_UNKNOWN_TOKEN",
+            UnknownToken | SoftLogout => "M_UNKNOWN_TOKEN",
             MissingToken => "M_MISSING_TOKEN",
             BadJson(_) => "M_BAD_JSON",
             NotJson(_) => "M_NOT_JSON",
             NotFound | UserNotFound | RoomNotFound => "M_NOT_FOUND",
             UsernameTaken => "M_USER_IN_USE",
+            InvalidUsername(_) => "M_INVALID_USERNAME",
             LimitExceeded => "M_LIMIT_EXCEEDED",
             MissingParam(_) => "M_MISSING_PARAM",
             InvalidParam(_) => "M_INVALID_PARAM",
             UnsupportedRoomVersion => "M_UNSUPPORTED_ROOM_VERSION",
-            TxnIdExists | UrlNotUtf8(_) | PasswordError(_)
-                | Unimplemented | AddEventError(_) | Unknown(_) => "M_UNKNOWN",
+            ThreepidInUse => "M_THREEPID_IN_USE",
+            ThreepidAuthFailed => "M_THREEPID_AUTH_FAILED",
+            RoomInUse => "M_ROOM_IN_USE",
+            BadState(_) => "M_BAD_STATE",
+            TooLarge(_) => "M_TOO_LARGE",
+            // user-interactive auth state is sent as it is
+            AuthRequired(uiaa) => return uiaa.clone(),
+            TxnIdExists | UrlNotUtf8(_) | PasswordError(_) | StorageUnavailable(_)
+                | Unimplemented | AliasExists | AddEventError(_) | Unknown(_) => "M_UNKNOWN",
             #[cfg(feature = "storage-sled")]
             SledError(_) | BincodeError(_) => "M_UNKNOWN",
+            #[cfg(feature = "storage-postgres")]
+            PostgresError(_) => "M_UNKNOWN",
         };
         let error = format!("{}", self);
-        HttpResponseBuilder::new(self.status_code())
-            .json(json!({
-                "errcode": errcode,
-                "error": error
-            }))
+        let mut body = json!({
+            "errcode": errcode,
+            "error": error
+        });
+        if let SoftLogout = self.inner {
+            body["soft_logout"] = json!(true);
+        }
+        body
     }
 }
 
@@ -145,6 +203,14 @@ impl From<JsonPayloadError> for ErrorKind {
     }
 }
 
+impl From<PathError> for ErrorKind {
+    fn from(e: PathError) -> Self {
+        match e {
+            PathError::Deserialize(e) => ErrorKind::InvalidParam(format!("{}", e)),
+        }
+    }
+}
+
 impl From<JsonError> for ErrorKind {
     fn from(e: JsonError) -> Self {
         use serde_json::error::Category;
@@ -180,3 +246,10 @@ impl From<bincode::Error> for ErrorKind {
         ErrorKind::BincodeError(e)
     }
 }
+
+#[cfg(feature = "storage-postgres")]
+impl From<pg::Error> for ErrorKind {
+    fn from(e: pg::Error) -> Self {
+        ErrorKind::PostgresError(e)
+    }
+}
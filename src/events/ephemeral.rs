@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use serde_json::Value as JsonValue;
+use std::collections::{HashMap, HashSet};
 
 use crate::util::MatrixId;
 
@@ -8,3 +9,79 @@ use crate::util::MatrixId;
 pub struct Typing {
     pub user_ids: HashSet<MatrixId>,
 }
+
+/// The `presence` of an `m.presence` event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceState {
+    Online,
+    Offline,
+    Unavailable,
+}
+
+impl PresenceState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PresenceState::Online => "online",
+            PresenceState::Offline => "offline",
+            PresenceState::Unavailable => "unavailable",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum ReceiptType {
+    #[serde(rename = "m.read")]
+    Read,
+    /// A read receipt which only the user who sent it can see.
+    #[serde(rename = "m.read.private")]
+    ReadPrivate,
+}
+
+impl ReceiptType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReceiptType::Read => "m.read",
+            ReceiptType::ReadPrivate => "m.read.private",
+        }
+    }
+
+    pub fn is_private(&self) -> bool {
+        *self == ReceiptType::ReadPrivate
+    }
+}
+
+/// `m.receipt`, which maps event IDs to receipt types to the users who sent them.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Receipts(pub HashMap<String, HashMap<String, HashMap<MatrixId, Receipt>>>);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Receipt {
+    /// Milliseconds since the unix epoch
+    pub ts: i64,
+}
+
+impl Receipts {
+    pub fn insert(&mut self, event_id: &str, ty: ReceiptType, user_id: &MatrixId, receipt: Receipt) {
+        self.0.entry(event_id.to_string())
+            .or_default()
+            .entry(ty.as_str().to_string())
+            .or_default()
+            .insert(user_id.clone(), receipt);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// A message sent straight to one of a user's devices rather than through a room, in the form
+/// it's delivered in.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ToDeviceEvent {
+    pub sender: MatrixId,
+    #[serde(rename = "type")]
+    pub ty: String,
+    pub content: JsonValue,
+}
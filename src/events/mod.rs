@@ -172,16 +172,24 @@ define_event_content! {
         HistoryVisibility(room::HistoryVisibility),
         #[ty = "m.room.guest_access"]
         GuestAccess(room::GuestAccess),
+        #[ty = "m.room.retention"]
+        Retention(room::Retention),
         #[ty = "m.room.name"]
         Name(room::Name),
         #[ty = "m.room.topic"]
         Topic(room::Topic),
+        #[ty = "m.room.canonical_alias"]
+        CanonicalAlias(room::CanonicalAlias),
+        #[ty = "m.room.tombstone"]
+        Tombstone(room::Tombstone),
         #[ty = "m.room.power_levels"]
         PowerLevels(room::PowerLevels),
         #[ty = "m.room.member"]
         Member(room::Member),
         #[ty = "m.room.redaction"]
         Redaction(room::Redaction),
+        #[ty = "m.room.third_party_invite"]
+        ThirdPartyInvite(room::ThirdPartyInvite),
 
         Unknown {
             ty: String,
@@ -6,12 +6,53 @@ use crate::{events::EventContent, util::MatrixId, validate::auth::AuthStatus};
 use super::{Event, room_version::VersionedPdu};
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(from = "RawStoredPdu")]
 pub struct StoredPdu {
     pub inner: VersionedPdu,
     pub auth_status: AuthStatus,
+    /// Where this event is in its room's timeline. This is assigned by storage when the event is
+    /// added, so whatever it's set to beforehand is ignored.
+    pub stream_ordering: usize,
+    /// Computed once from the PDU's reference hash. Redaction, signing and unsigned data don't
+    /// change the hash, so this stays correct as the PDU is modified in those ways.
+    event_id: String,
+}
+
+/// A `StoredPdu` as it was saved, which may be from before event IDs were stored alongside PDUs.
+#[derive(Deserialize)]
+struct RawStoredPdu {
+    inner: VersionedPdu,
+    auth_status: AuthStatus,
+    #[serde(default)]
+    stream_ordering: usize,
+    #[serde(default)]
+    event_id: Option<String>,
+}
+
+impl From<RawStoredPdu> for StoredPdu {
+    fn from(raw: RawStoredPdu) -> Self {
+        let inner = raw.inner;
+        let event_id = raw.event_id.unwrap_or_else(|| inner.event_id());
+        StoredPdu {
+            inner,
+            auth_status: raw.auth_status,
+            stream_ordering: raw.stream_ordering,
+            event_id,
+        }
+    }
 }
 
 impl StoredPdu {
+    pub fn new(inner: VersionedPdu, auth_status: AuthStatus) -> Self {
+        let event_id = inner.event_id();
+        StoredPdu {
+            inner,
+            auth_status,
+            stream_ordering: 0,
+            event_id,
+        }
+    }
+
     pub fn did_pass_auth(&self) -> bool {
         self.auth_status == AuthStatus::Pass
     }
@@ -72,12 +113,25 @@ impl StoredPdu {
         StoredPdu {
             inner: self.inner.redact(),
             auth_status: self.auth_status,
+            stream_ordering: self.stream_ordering,
+            event_id: self.event_id,
         }
     }
 
-    // TODO: actually completely wrong
-    // event_id should probably be stored in StoredPdu because it is not part of a pdu
-    pub fn event_id(&self) -> String {
-        self.inner.event_id()
+    /// Redacts this event on account of `redaction`, which is recorded in
+    /// `unsigned.redacted_because` for clients.
+    ///
+    /// Redaction events can themselves be redacted, which only strips their reason; it doesn't
+    /// undo the redaction they made.
+    pub fn redact_because(self, redaction: &StoredPdu) -> Self {
+        let because = serde_json::to_value(redaction.clone().to_client_format())
+            .expect("failed to serialize redaction");
+        let mut redacted = self.redact();
+        redacted.inner.set_unsigned(Some(serde_json::json!({ "redacted_because": because })));
+        redacted
+    }
+
+    pub fn event_id(&self) -> &str {
+        &self.event_id
     }
 }
@@ -2,14 +2,17 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 use std::collections::HashMap;
 
-use crate::util::MatrixId;
+use crate::util::{MatrixId, RoomId};
 
 use super::Redactable;
 
 /// m.room.create
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Create {
-    pub creator: MatrixId,
+    /// Left out from room version 11 on, where the create event's sender is the creator instead
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creator: Option<MatrixId>,
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub room_version: Option<String>,
@@ -26,6 +29,18 @@ pub struct PreviousRoom {
     pub event_id: String,
 }
 
+impl Create {
+    /// The room's creator, given the sender of the create event this is the content of.
+    pub fn effective_creator<'a>(&'a self, sender: &'a MatrixId) -> &'a MatrixId {
+        self.creator.as_ref().unwrap_or(sender)
+    }
+
+    /// The room's version. Rooms made before versions existed don't say, and are version 1.
+    pub fn version(&self) -> &str {
+        self.room_version.as_deref().unwrap_or("1")
+    }
+}
+
 impl Redactable for Create {
     fn redact(self) -> Self {
         Create {
@@ -41,6 +56,11 @@ impl Redactable for Create {
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct JoinRules {
     pub join_rule: JoinRule,
+    /// For restricted rooms, the conditions under which users may join without an invite. These
+    /// need room version 8, which rooms can't be created in yet, so they're only kept for parsing.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<AllowCondition>,
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -50,11 +70,29 @@ pub enum JoinRule {
     Knock,
     Invite,
     Private,
+    Restricted,
+    KnockRestricted,
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type")]
+pub enum AllowCondition {
+    /// Users joined to `room_id` may join.
+    #[serde(rename = "m.room_membership")]
+    RoomMembership { room_id: String },
+    /// Conditions we don't understand never let anyone in.
+    #[serde(other)]
+    Unknown,
 }
 
 impl Redactable for JoinRules {
     fn redact(self) -> Self {
-        self
+        // `allow` only survives redaction from room version 8. PDUs here are all in the version 4
+        // format, and so get version 4's redaction; keeping it would change their event IDs.
+        JoinRules {
+            join_rule: self.join_rule,
+            allow: Vec::new(),
+        }
     }
 }
 
@@ -79,6 +117,35 @@ impl Redactable for HistoryVisibility {
     }
 }
 
+/// m.room.retention
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Retention {
+    /// How long, in milliseconds, events are kept before they're purged. None means forever.
+    #[serde(default, deserialize_with = "positive_lifetime")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_lifetime: Option<i64>,
+    /// How long, in milliseconds, events are kept at the least.
+    #[serde(default, deserialize_with = "positive_lifetime")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_lifetime: Option<i64>,
+}
+
+/// Deserializes a lifetime in milliseconds, which has to be positive if it's given at all.
+pub fn positive_lifetime<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<i64>, D::Error> {
+    match Option::<i64>::deserialize(d)? {
+        Some(lifetime) if lifetime <= 0 => {
+            Err(serde::de::Error::custom(format!("lifetime must be positive, not {}", lifetime)))
+        },
+        lifetime => Ok(lifetime),
+    }
+}
+
+impl Redactable for Retention {
+    fn redact(self) -> Self {
+        Retention::default()
+    }
+}
+
 /// m.room.guest_access
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct GuestAccess {
@@ -131,24 +198,140 @@ impl Redactable for Topic {
     }
 }
 
+/// m.room.canonical_alias
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CanonicalAlias {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub alt_aliases: Vec<String>,
+}
+
+impl Redactable for CanonicalAlias {
+    fn redact(self) -> Self {
+        CanonicalAlias {
+            alias: None,
+            alt_aliases: Vec::new(),
+        }
+    }
+}
+
+/// m.room.tombstone
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Tombstone {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub body: String,
+    /// expected to only be None when redacted
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replacement_room: Option<RoomId>,
+}
+
+impl Redactable for Tombstone {
+    fn redact(self) -> Self {
+        Tombstone {
+            body: String::new(),
+            replacement_room: None,
+        }
+    }
+}
+
 /// m.room.power_levels
+///
+/// Levels may be given as strings containing integers (e.g. `"50"`), which room versions before
+/// 10 allow. Use `PowerLevels::from_json_strict` for later versions.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PowerLevels {
+    #[serde(default, deserialize_with = "int_or_string::option")]
     pub ban: Option<u32>,
+    #[serde(default, deserialize_with = "int_or_string::option")]
     pub invite: Option<u32>,
+    #[serde(default, deserialize_with = "int_or_string::option")]
     pub kick: Option<u32>,
+    #[serde(default, deserialize_with = "int_or_string::option")]
     pub redact: Option<u32>,
+    #[serde(default, deserialize_with = "int_or_string::map")]
     pub events: HashMap<String, u32>,
+    #[serde(default, deserialize_with = "int_or_string::option")]
     pub events_default: Option<u32>,
+    #[serde(default, deserialize_with = "int_or_string::option")]
     pub state_default: Option<u32>,
+    #[serde(default, deserialize_with = "int_or_string::map")]
     pub users: HashMap<MatrixId, u32>,
+    #[serde(default, deserialize_with = "int_or_string::option")]
     pub users_default: Option<u32>,
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub notifications: Option<Notifications>,
 }
 
+/// Deserializers for power levels which accept both integers and strings containing integers.
+mod int_or_string {
+    use serde::{de::Error, Deserialize, Deserializer};
+    use std::{collections::HashMap, hash::Hash};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum IntOrString {
+        Int(u32),
+        String(String),
+    }
+
+    impl IntOrString {
+        fn into_int<E: Error>(self) -> Result<u32, E> {
+            match self {
+                IntOrString::Int(v) => Ok(v),
+                IntOrString::String(s) => s.trim().parse().map_err(|_| {
+                    E::custom(format!("invalid power level: {:?}", s))
+                }),
+            }
+        }
+    }
+
+    pub fn int<'de, D: Deserializer<'de>>(d: D) -> Result<u32, D::Error> {
+        IntOrString::deserialize(d)?.into_int()
+    }
+
+    pub fn option<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u32>, D::Error> {
+        Option::<IntOrString>::deserialize(d)?.map(IntOrString::into_int).transpose()
+    }
+
+    pub fn map<'de, D, K>(d: D) -> Result<HashMap<K, u32>, D::Error>
+    where
+        D: Deserializer<'de>,
+        K: Deserialize<'de> + Eq + Hash,
+    {
+        HashMap::<K, IntOrString>::deserialize(d)?
+            .into_iter()
+            .map(|(k, v)| Ok((k, v.into_int()?)))
+            .collect()
+    }
+}
+
 impl PowerLevels {
+    /// Parses power levels for room versions 10 and later, where levels must be integers.
+    #[cfg(test)]
+    pub fn from_json_strict(content: JsonValue) -> Result<Self, serde_json::Error> {
+        use serde::de::Error;
+        let levels = [
+            "ban", "invite", "kick", "redact", "events_default", "state_default", "users_default",
+        ].iter().filter_map(|key| content.get(key));
+        let maps = ["events", "users"].iter()
+            .filter_map(|key| content.get(key).and_then(JsonValue::as_object))
+            .flat_map(|map| map.values());
+        let notifications = content.get("notifications").and_then(|n| n.get("room"));
+        for level in levels.chain(maps).chain(notifications) {
+            if !level.is_u64() {
+                let msg = format!("power level not an integer: {}", level);
+                return Err(serde_json::Error::custom(msg));
+            }
+        }
+        serde_json::from_value(content)
+    }
+
     /// This function returns the effective power levels for when a room has no power levels event.
     /// The values are the same as when there is an event but the values are unspecified
     /// (i.e. `None`), with the exception that state_default is 0 and the creator of the room has
@@ -183,13 +366,16 @@ impl PowerLevels {
     }
 
     pub fn redact(&self) -> u32 {
-        self.kick.unwrap_or(50)
+        self.redact.unwrap_or(50)
     }
 
     pub fn events_default(&self) -> u32 {
         self.events_default.unwrap_or(0)
     }
 
+    /// The level needed to send state events not listed in `events`. This is 50 when the power
+    /// levels event leaves it out; rooms without a power levels event at all get
+    /// `no_event_default_levels`, which sets it to 0 explicitly.
     pub fn state_default(&self) -> u32 {
         self.state_default.unwrap_or(50)
     }
@@ -203,14 +389,14 @@ impl PowerLevels {
     }
 
     pub fn get_user_level(&self, user_id: &MatrixId) -> u32 {
-        self.users.get(user_id).copied().unwrap_or(self.users_default.unwrap_or(0))
+        self.users.get(user_id).copied().unwrap_or_else(|| self.users_default())
     }
 
     pub fn get_event_level(&self, event_type: &str, is_state_event: bool) -> u32 {
         let default = if is_state_event {
-            self.state_default.unwrap_or(50)
+            self.state_default()
         } else {
-            self.events_default.unwrap_or(0)
+            self.events_default()
         };
         self.events.get(event_type).copied().unwrap_or(default)
     }
@@ -235,7 +421,8 @@ impl Redactable for PowerLevels {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Notifications {
-    room: u32,
+    #[serde(deserialize_with = "int_or_string::int")]
+    pub room: u32,
 }
 
 impl Default for PowerLevels {
@@ -276,6 +463,31 @@ pub struct Member {
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub is_direct: Option<bool>,
+    /// Optional reason given for a kick, ban or leave.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    /// Present when this membership is the result of redeeming a third party invite.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub third_party_invite: Option<MemberThirdPartyInvite>,
+    /// For joins to restricted rooms, the member of the room who let the user in.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub join_authorised_via_users_server: Option<MatrixId>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MemberThirdPartyInvite {
+    pub display_name: String,
+    pub signed: SignedThirdPartyInvite,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SignedThirdPartyInvite {
+    pub mxid: MatrixId,
+    pub token: String,
+    pub signatures: HashMap<String, HashMap<String, String>>,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
@@ -290,11 +502,16 @@ pub enum Membership {
 
 impl Redactable for Member {
     fn redact(self) -> Self {
+        // Only `membership` survives redaction in v4 rooms. Later room versions (v11) keep
+        // `third_party_invite.signed` as well, but `reason` never survives.
         Member {
             avatar_url: None,
             displayname: None,
             membership: self.membership,
             is_direct: None,
+            reason: None,
+            third_party_invite: None,
+            join_authorised_via_users_server: None,
         }
     }
 }
@@ -329,10 +546,48 @@ impl ToString for Membership {
     }
 }
 
+/// m.room.third_party_invite
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ThirdPartyInvite {
+    /// A user-readable string which represents the invitee, e.g. an obfuscated email address.
+    /// Expected to only be None when redacted, as with the other fields.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_validity_url: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub public_keys: Vec<ThirdPartyInvitePublicKey>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ThirdPartyInvitePublicKey {
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_validity_url: Option<String>,
+    pub public_key: String,
+}
+
+impl Redactable for ThirdPartyInvite {
+    fn redact(self) -> Self {
+        ThirdPartyInvite {
+            display_name: None,
+            key_validity_url: None,
+            public_key: None,
+            public_keys: Vec::new(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Redaction {
     #[serde(skip_serializing_if = "Option::is_none")]
-    reason: Option<String>,
+    pub reason: Option<String>,
 }
 
 impl Redactable for Redaction {
@@ -340,3 +595,204 @@ impl Redactable for Redaction {
         Redaction { reason: None }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{AllowCondition, Create, JoinRule, JoinRules, Member, Membership, PowerLevels, Retention};
+    use crate::{
+        events::{EventContent, Redactable, room_version::{v4::UnhashedPdu, VersionedPdu}},
+        util::MatrixId,
+    };
+
+    #[test]
+    fn member_reason_round_trip() {
+        let json = json!({
+            "membership": "ban",
+            "reason": "spam",
+        });
+        let member: Member = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(member.membership, Membership::Ban);
+        assert_eq!(member.reason.as_deref(), Some("spam"));
+        assert_eq!(serde_json::to_value(&member).unwrap(), json);
+
+        let redacted = member.redact();
+        assert_eq!(serde_json::to_value(&redacted).unwrap(), json!({ "membership": "ban" }));
+    }
+
+    #[test]
+    fn create_with_and_without_creator() {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+
+        // up to room version 10, the creator is given explicitly
+        let json = json!({ "creator": "@alice:example.org", "room_version": "4" });
+        let create: Create = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(create.creator.as_ref(), Some(&alice));
+        assert_eq!(create.effective_creator(&bob), &alice);
+        assert_eq!(serde_json::to_value(&create).unwrap(), json);
+        assert_eq!(serde_json::to_value(create.redact()).unwrap(), json!({ "creator": "@alice:example.org" }));
+
+        // from version 11, it's whoever sent the event
+        let json = json!({ "room_version": "11" });
+        let create: Create = serde_json::from_value(json.clone()).unwrap();
+        assert!(create.creator.is_none());
+        assert_eq!(create.effective_creator(&bob), &bob);
+        assert_eq!(serde_json::to_value(&create).unwrap(), json);
+        assert_eq!(serde_json::to_value(create.redact()).unwrap(), json!({}));
+        let content = EventContent::new("m.room.create", json).unwrap();
+        assert_eq!(content.get_type(), "m.room.create");
+    }
+
+    #[test]
+    fn tombstone_round_trip() {
+        let json = json!({
+            "body": "This room has been replaced",
+            "replacement_room": "!new:example.org",
+        });
+        let content = EventContent::new("m.room.tombstone", json.clone()).unwrap();
+        assert_eq!(content.get_type(), "m.room.tombstone");
+        assert_eq!(content.content_as_json(), json);
+        let tombstone = match content {
+            EventContent::Tombstone(tombstone) => tombstone,
+            _ => panic!("not a tombstone"),
+        };
+        assert_eq!(tombstone.replacement_room.as_ref().map(|r| r.as_str()), Some("!new:example.org"));
+        assert_eq!(serde_json::to_value(tombstone.redact()).unwrap(), json!({}));
+    }
+
+    #[test]
+    fn restricted_join_rules_round_trip() {
+        let json = json!({
+            "join_rule": "restricted",
+            "allow": [{ "type": "m.room_membership", "room_id": "!space:example.org" }],
+        });
+        let join_rules: JoinRules = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(join_rules.join_rule, JoinRule::Restricted);
+        assert_eq!(join_rules.allow, vec![
+            AllowCondition::RoomMembership { room_id: String::from("!space:example.org") },
+        ]);
+        assert_eq!(serde_json::to_value(&join_rules).unwrap(), json);
+        let redacted = json!({ "join_rule": "restricted" });
+        assert_eq!(serde_json::to_value(join_rules.redact()).unwrap(), redacted);
+
+        let json = json!({
+            "join_rule": "knock_restricted",
+            "allow": [{ "type": "m.room_membership", "room_id": "!space:example.org" }],
+        });
+        let join_rules: JoinRules = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(join_rules.join_rule, JoinRule::KnockRestricted);
+        assert_eq!(serde_json::to_value(&join_rules).unwrap(), json);
+        let redacted = json!({ "join_rule": "knock_restricted" });
+        assert_eq!(serde_json::to_value(join_rules.redact()).unwrap(), redacted);
+
+        let join_rules: JoinRules = serde_json::from_value(json!({
+            "join_rule": "restricted",
+            "allow": [{ "type": "org.example.future_condition" }],
+        })).unwrap();
+        assert_eq!(join_rules.allow, vec![AllowCondition::Unknown]);
+    }
+
+    #[test]
+    fn stringified_power_levels() {
+        let json = json!({
+            "ban": 50,
+            "kick": "50",
+            "users_default": "0",
+            "events": { "m.room.name": " 75" },
+            "users": { "@alice:example.org": "100" },
+            "notifications": { "room": "20" },
+        });
+        let content = EventContent::new("m.room.power_levels", json.clone()).unwrap();
+        let levels = match content {
+            EventContent::PowerLevels(levels) => levels,
+            _ => panic!("not power levels"),
+        };
+        assert_eq!(levels.ban(), 50);
+        assert_eq!(levels.kick(), 50);
+        assert_eq!(levels.users_default(), 0);
+        assert_eq!(levels.get_event_level("m.room.name", true), 75);
+        assert_eq!(levels.get_user_level(&MatrixId::new("alice", "example.org").unwrap()), 100);
+
+        PowerLevels::from_json_strict(json).expect_err("strict parsing accepted strings");
+        PowerLevels::from_json_strict(json!({ "ban": 50, "users": { "@alice:example.org": 100 } }))
+            .expect("strict parsing rejected integers");
+        serde_json::from_value::<PowerLevels>(json!({ "ban": "fifty" }))
+            .expect_err("accepted a non-numeric power level");
+    }
+
+    #[test]
+    fn power_level_getters() {
+        let levels: PowerLevels = serde_json::from_value(json!({
+            "ban": 70,
+            "invite": 10,
+            "kick": 60,
+            "redact": 40,
+            "events_default": 5,
+            "state_default": 30,
+            "users_default": 1,
+        })).unwrap();
+        assert_eq!(levels.ban(), 70);
+        assert_eq!(levels.invite(), 10);
+        assert_eq!(levels.kick(), 60);
+        // called as a path, since `Redactable::redact` is picked first for owned values
+        assert_eq!(PowerLevels::redact(&levels), 40);
+        assert_eq!(levels.events_default(), 5);
+        assert_eq!(levels.state_default(), 30);
+        assert_eq!(levels.users_default(), 1);
+        assert_eq!(levels.get_event_level("m.room.topic", true), 30);
+        assert_eq!(levels.get_event_level("m.room.message", false), 5);
+
+        // left out of an event, state needs 50; without any event, it needs nothing
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let levels: PowerLevels = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(levels.state_default(), 50);
+        assert_eq!(levels.get_user_level(&alice), 0);
+        let levels = PowerLevels::no_event_default_levels(&alice);
+        assert_eq!(levels.state_default(), 0);
+        assert_eq!(levels.get_event_level("m.room.topic", true), 0);
+        assert_eq!(levels.get_user_level(&alice), 100);
+    }
+
+    #[test]
+    fn retention_round_trip() {
+        let json = json!({ "max_lifetime": 86400000, "min_lifetime": 3600000 });
+        let content = EventContent::new("m.room.retention", json.clone()).unwrap();
+        let retention = match &content {
+            EventContent::Retention(retention) => retention.clone(),
+            _ => panic!("not a retention policy"),
+        };
+        assert_eq!(retention.max_lifetime, Some(86400000));
+        assert_eq!(retention.min_lifetime, Some(3600000));
+        assert_eq!(serde_json::to_value(&retention).unwrap(), json);
+        assert_eq!(serde_json::to_value(retention.redact()).unwrap(), json!({}));
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let pdu = VersionedPdu::V4(UnhashedPdu {
+            event_content: content,
+            room_id: String::from("!retention:example.org"),
+            sender: alice,
+            state_key: Some(String::new()),
+            unsigned: None,
+            redacts: None,
+            origin: String::from("example.org"),
+            origin_server_ts: 0,
+            prev_events: Vec::new(),
+            depth: 0,
+            auth_events: Vec::new(),
+        }.finalize());
+        let pdu: VersionedPdu = serde_json::from_value(serde_json::to_value(&pdu).unwrap()).unwrap();
+        assert_eq!(pdu.event_content().get_type(), "m.room.retention");
+        assert_eq!(pdu.event_content().content_as_json(), json);
+        assert_eq!(pdu.redact().event_content().content_as_json(), json!({}));
+
+        let partial: Retention = serde_json::from_value(json!({ "max_lifetime": 1000 })).unwrap();
+        assert_eq!(serde_json::to_value(&partial).unwrap(), json!({ "max_lifetime": 1000 }));
+
+        for lifetime in &[0, -1, i64::MIN] {
+            assert!(serde_json::from_value::<Retention>(json!({ "max_lifetime": lifetime })).is_err());
+            assert!(serde_json::from_value::<Retention>(json!({ "min_lifetime": lifetime })).is_err());
+        }
+    }
+}
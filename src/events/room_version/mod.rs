@@ -1,12 +1,52 @@
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
 
-use crate::util::MatrixId;
+use std::collections::HashMap;
+
+use crate::{sign::Key, util::MatrixId};
 
 use super::{Event, EventContent, room_version::v4::PduV4};
 
 pub mod v4;
 
+/// The room versions rooms can be created in, and that are advertised to clients.
+///
+/// Versions 5 to 7 keep version 4's event format. What 5 adds only matters over federation, 6's
+/// redaction rules are the ones used here for every version (`m.room.aliases` keeps nothing),
+/// and 7 adds knocking.
+pub const SUPPORTED_ROOM_VERSIONS: &[&str] = &["4", "7"];
+
+/// The version new rooms get when they don't ask for one. This must be in
+/// `SUPPORTED_ROOM_VERSIONS`.
+pub const DEFAULT_ROOM_VERSION: &str = "4";
+
+pub fn is_supported(room_version: &str) -> bool {
+    SUPPORTED_ROOM_VERSIONS.contains(&room_version)
+}
+
+/// Whether rooms of `room_version` have what came in with version `since`. Versions which aren't
+/// numbers are unstable ones, and aren't assumed to have anything.
+fn is_at_least(room_version: &str, since: u32) -> bool {
+    room_version.parse::<u32>().map(|v| v >= since).unwrap_or(false)
+}
+
+/// Whether changing who can notify the whole room takes power in rooms of `room_version`.
+pub fn has_notifications_power_level(room_version: &str) -> bool {
+    is_at_least(room_version, 6)
+}
+
+/// Whether the `knock` join rule and membership mean anything in rooms of `room_version`.
+pub fn has_knocking(room_version: &str) -> bool {
+    is_at_least(room_version, 7)
+}
+
+/// Whether power levels in rooms of `room_version` have to be integers, rather than strings
+/// containing them.
+#[cfg(test)]
+pub fn has_integer_power_levels(room_version: &str) -> bool {
+    is_at_least(room_version, 10)
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum VersionedPdu {
@@ -15,6 +55,13 @@ pub enum VersionedPdu {
 
 /// Getter functions for all non-version-specific fields
 impl VersionedPdu {
+    /// The earliest version of the room the PDU could belong to, going by its format
+    pub fn room_version(&self) -> &'static str {
+        match self {
+            VersionedPdu::V4(_) => "4",
+        }
+    }
+
     pub fn event_content(&self) -> &EventContent {
         match self {
             VersionedPdu::V4(pdu) => &pdu.event_content,
@@ -87,8 +134,20 @@ impl VersionedPdu {
         }
     }
 
-    // TODO: actually completely wrong
-    // event_id should probably be stored in StoredPdu because it is not part of a pdu
+    pub fn sign(&mut self, server_name: &str, keys: &HashMap<String, Key>) {
+        match self {
+            VersionedPdu::V4(pdu) => pdu.sign(server_name, keys),
+        }
+    }
+
+    pub fn set_unsigned(&mut self, unsigned: Option<JsonValue>) {
+        match self {
+            VersionedPdu::V4(pdu) => pdu.unsigned = unsigned,
+        }
+    }
+
+    /// Computes the event's ID from its reference hash. This isn't cheap, so prefer
+    /// `StoredPdu::event_id` where there is one.
     pub fn event_id(&self) -> String {
         match self {
             VersionedPdu::V4(pdu) => pdu.event_id(),
@@ -99,8 +158,15 @@ impl VersionedPdu {
 /// Delegations to version-specific functionality
 impl VersionedPdu {
     pub fn to_client_format(self) -> Event {
-        match self {
+        let room_version = self.room_version();
+        let mut event = match self {
             VersionedPdu::V4(pdu) => pdu.to_client_format(),
+        };
+        // clients take a create event without a version to mean version 1, so they're told the
+        // version the room is really in
+        if let EventContent::Create(create) = &mut event.event_content {
+            create.room_version.get_or_insert_with(|| String::from(room_version));
         }
+        event
     }
 }
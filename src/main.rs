@@ -6,15 +6,18 @@ use error::Error;
 use serde::Deserialize;
 use state::StateResolver;
 use tracing_subscriber::EnvFilter;
-use std::{sync::Arc, collections::HashMap};
+use std::{sync::Arc, collections::HashMap, path::PathBuf};
 
 mod client_api;
 mod error;
 mod events;
+mod retention;
 mod server_api;
 mod sign;
 mod state;
 mod storage;
+#[cfg(test)]
+mod test_util;
 mod util;
 mod validate;
 
@@ -23,9 +26,129 @@ use util::StorageExt;
 
 #[derive(Deserialize)]
 pub struct Config {
+    /// The server name, lowercased in the same way as the domains of Matrix IDs so the two can
+    /// be compared
+    #[serde(deserialize_with = "deserialize_domain")]
     domain: String,
     bind_address: String,
     storage: String,
+    /// How long access tokens last, for clients which support refresh tokens
+    #[serde(default = "default_access_token_lifetime_ms")]
+    access_token_lifetime_ms: u64,
+    /// How often expired access tokens are deleted
+    #[serde(default = "default_token_purge_interval_ms")]
+    token_purge_interval_ms: u64,
+    /// Whether member events returned by the state and members endpoints should have missing
+    /// profile fields filled in from the user's current profile. Membership events are meant to
+    /// be authoritative, so this is off by default.
+    #[serde(default)]
+    embed_member_profiles: bool,
+    #[serde(default)]
+    signing: SigningConfig,
+    #[serde(default)]
+    retention: RetentionConfig,
+    /// Treat third party identifiers as validated as soon as a token is requested for them. We
+    /// can't send emails or texts yet, so this is only for development.
+    #[serde(default)]
+    auto_validate_3pids: bool,
+    /// The most forward extremities a new event will reference. When a room has more than this,
+    /// empty events are sent first to merge them.
+    #[serde(default = "default_max_prev_events")]
+    max_prev_events: usize,
+    /// How often logging in as each user can fail from each address
+    #[serde(default)]
+    login_rate_limit: util::RateLimitConfig,
+    /// Usernames of the users who can use the admin endpoints
+    #[serde(default)]
+    admins: Vec<String>,
+    /// Rooms with more member events than this have their member lists streamed to the client a
+    /// page at a time rather than loaded and serialized in one go. Unset means they never are.
+    #[serde(default)]
+    member_stream_threshold: Option<usize>,
+    /// Room state with more events than this is streamed to the client in the same way as member
+    /// lists. Unset means it never is.
+    #[serde(default)]
+    state_stream_threshold: Option<usize>,
+    /// The most events `/messages` returns at once, however many the client asks for
+    #[serde(default = "default_max_messages_limit")]
+    max_messages_limit: usize,
+    /// The most state events that can be fetched at once; rooms with more get `M_TOO_LARGE` from
+    /// the state endpoint. Unset means there's no limit.
+    #[serde(default)]
+    max_state_events: Option<usize>,
+    /// Connection string for the postgres storage backend, e.g.
+    /// `host=localhost user=kerux dbname=kerux`. Only one server can use each database.
+    #[cfg(feature = "storage-postgres")]
+    #[serde(default)]
+    database_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SigningConfig {
+    /// Directory holding the server's signing keys, one file per key named after its key ID (e.g.
+    /// `ed25519:abc123`). A key is generated here on first run if there are none.
+    #[serde(default = "default_key_path")]
+    key_path: PathBuf,
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        SigningConfig {
+            key_path: default_key_path(),
+        }
+    }
+}
+
+/// How many idle postgres connections are kept around to be reused
+#[cfg(feature = "storage-postgres")]
+const POSTGRES_POOL_SIZE: usize = 16;
+
+#[derive(Deserialize)]
+pub struct RetentionConfig {
+    /// How long, in milliseconds, events are kept in rooms without an `m.room.retention` event.
+    /// Unset means forever.
+    #[serde(default, deserialize_with = "events::room::positive_lifetime")]
+    default_max_lifetime_ms: Option<i64>,
+    /// How often expired events are looked for
+    #[serde(default = "default_purge_interval_ms")]
+    purge_interval_ms: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            default_max_lifetime_ms: None,
+            purge_interval_ms: default_purge_interval_ms(),
+        }
+    }
+}
+
+fn default_purge_interval_ms() -> u64 {
+    60 * 60 * 1000
+}
+
+fn deserialize_domain<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    util::Domain::deserialize(deserializer).map(|domain| domain.to_string())
+}
+
+fn default_key_path() -> PathBuf {
+    PathBuf::from("keys")
+}
+
+fn default_max_prev_events() -> usize {
+    StateResolver::DEFAULT_MAX_PREV_EVENTS
+}
+
+fn default_max_messages_limit() -> usize {
+    100
+}
+
+fn default_access_token_lifetime_ms() -> u64 {
+    5 * 60 * 1000
+}
+
+fn default_token_purge_interval_ms() -> u64 {
+    10 * 60 * 1000
 }
 
 pub struct ServerState {
@@ -33,6 +156,7 @@ pub struct ServerState {
     pub db_pool: Box<dyn StorageManager>,
     pub state_resolver: StateResolver,
     pub keys: HashMap<String, sign::Key>,
+    pub login_limiter: util::RateLimiter,
 }
 
 fn init_tracing() {
@@ -61,11 +185,21 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
             storage
         },
         "sled" => Box::new(storage::sled::SledStorage::new("sled")?) as _,
+        #[cfg(feature = "storage-postgres")]
+        "postgres" => {
+            let url = config.database_url.as_deref()
+                .ok_or("database_url must be set to use postgres storage")?;
+            Box::new(storage::postgres::PgStorageManager::connect(url, POSTGRES_POOL_SIZE).await?) as _
+        },
         _ => panic!("invalid storage type"),
     };
-    let state_resolver = StateResolver::new(db_pool.get_handle().await?);
-    let keys = sign::load_keys(&std::env::current_dir().unwrap()).await?;
-    let server_state = Arc::new(ServerState { config, db_pool, state_resolver, keys });
+    let state_resolver = StateResolver::new(db_pool.get_handle().await?)
+        .with_max_prev_events(config.max_prev_events);
+    let keys = sign::load_or_generate_keys(&config.signing.key_path).await?;
+    let login_limiter = util::RateLimiter::new(config.login_rate_limit);
+    let server_state = Arc::new(ServerState { config, db_pool, state_resolver, keys, login_limiter });
+    actix_web::rt::spawn(retention::purge_periodically(Arc::clone(&server_state)));
+    actix_web::rt::spawn(client_api::purge_tokens_periodically(Arc::clone(&server_state)));
 
     let server_state2 = Arc::clone(&server_state);
     actix_web::HttpServer::new(move || {
@@ -73,6 +207,7 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
             .data(Arc::clone(&server_state))
             .data(JsonConfig::default().error_handler(|e, _req| Error::from(e).into()))
             .service(web::scope("/_matrix/client").configure(client_api::configure_endpoints))
+            .service(web::scope("/_matrix/key/v2").configure(server_api::configure_key_endpoints))
             .service(util::print_the_world)
     })
         .bind(&server_state2.config.bind_address)?
@@ -0,0 +1,141 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::time::delay_for;
+use tracing::{info, warn};
+
+use crate::{
+    error::Error,
+    events::{EventContent, room::Retention},
+    storage::Storage,
+    ServerState,
+};
+
+/// How long a room's events are kept: its own `m.room.retention` if it has one, otherwise the
+/// server's default. None means forever.
+async fn max_lifetime(
+    db: &dyn Storage,
+    room_id: &str,
+    default_max_lifetime: Option<i64>,
+) -> Result<Option<i64>, Error> {
+    let policy = db.get_state_event(room_id, "m.room.retention", "").await?;
+    match policy.map(|event| event.event_content) {
+        Some(EventContent::Retention(Retention { max_lifetime, .. })) => Ok(max_lifetime),
+        _ => Ok(default_max_lifetime),
+    }
+}
+
+/// Purges the events in every room which have outlived the room's retention policy as of `now`
+/// (in milliseconds since the unix epoch), and returns how many were purged.
+pub async fn purge_expired(
+    db: &dyn Storage,
+    default_max_lifetime: Option<i64>,
+    now: i64,
+) -> Result<usize, Error> {
+    let mut purged = 0;
+    for room_id in db.get_rooms().await? {
+        if let Some(max_lifetime) = max_lifetime(db, &room_id, default_max_lifetime).await? {
+            purged += db.purge_events(&room_id, now.saturating_sub(max_lifetime)).await?;
+        }
+    }
+    Ok(purged)
+}
+
+/// Purges expired events every `purge_interval_ms`, for as long as the server runs.
+pub async fn purge_periodically(state: Arc<ServerState>) {
+    let interval = Duration::from_millis(state.config.retention.purge_interval_ms);
+    loop {
+        delay_for(interval).await;
+        let now = chrono::Utc::now().timestamp_millis();
+        let res = match state.db_pool.get_handle().await {
+            Ok(db) => purge_expired(&*db, state.config.retention.default_max_lifetime_ms, now).await,
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(0) => {},
+            Ok(purged) => info!(purged, "Purged expired events"),
+            Err(e) => warn!(error = %e, "Failed to purge expired events"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use crate::{
+        events::{
+            EventContent, pdu::StoredPdu,
+            room::{Name, Retention},
+        },
+        test_util::{message_event, state_event, RoomBuilder, with_mem_db},
+        util::{MatrixId, StorageExt},
+        RetentionConfig,
+    };
+
+    use super::purge_expired;
+
+    #[test]
+    fn retention_purges_messages() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let state = |event_content| state_event(&alice, event_content, "");
+            let message = || message_event(&alice, "this won't last");
+
+            // one room with a short policy of its own, and one relying on the server's default
+            let short = "!short:example.org";
+            RoomBuilder::new(&*db, &state_resolver, short, &alice).build().await;
+            db.add_event(short, state(EventContent::Retention(Retention {
+                max_lifetime: Some(1000),
+                min_lifetime: None,
+            })), &state_resolver, &keys).await.unwrap();
+            db.add_event(short, state(EventContent::Name(Name {
+                name: Some(String::from("Mayflies")),
+            })), &state_resolver, &keys).await.unwrap();
+            let short_message = db.add_event(short, message(), &state_resolver, &keys)
+                .await.unwrap();
+
+            let default = "!default:example.org";
+            RoomBuilder::new(&*db, &state_resolver, default, &alice).build().await;
+            let default_message = db.add_event(default, message(), &state_resolver, &keys)
+                .await.unwrap();
+
+            let content = |pdu: Option<StoredPdu>| pdu.unwrap().event_content().content_as_json();
+            let now = chrono::Utc::now().timestamp_millis();
+
+            // nothing has outlived its policy yet, and there's no default
+            assert_eq!(purge_expired(&*db, None, now).await.unwrap(), 0);
+
+            let later = now + 5000;
+            assert_eq!(purge_expired(&*db, None, later).await.unwrap(), 1);
+            assert_eq!(content(db.get_pdu(short, &short_message).await.unwrap()), json!({}));
+            assert_ne!(content(db.get_pdu(default, &default_message).await.unwrap()), json!({}));
+            let name = db.get_state_event(short, "m.room.name", "").await.unwrap().unwrap();
+            assert_eq!(name.event_content.content_as_json(), json!({ "name": "Mayflies" }));
+            let state = db.get_full_state(short).await.unwrap();
+            assert!(state.iter().any(|event| event.event_content.get_type() == "m.room.retention"));
+
+            // the default covers rooms without a policy, and purging again is a no-op
+            assert_eq!(purge_expired(&*db, Some(10_000), later).await.unwrap(), 0);
+            assert_eq!(purge_expired(&*db, Some(1000), later).await.unwrap(), 1);
+            assert_eq!(content(db.get_pdu(default, &default_message).await.unwrap()), json!({}));
+            assert_eq!(purge_expired(&*db, Some(1000), later).await.unwrap(), 0);
+
+            // a huge lifetime can't overflow into the future
+            assert_eq!(purge_expired(&*db, Some(i64::MAX), i64::MIN + 1).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn lifetimes_must_be_positive() {
+        let config = |lifetime| serde_json::from_value::<RetentionConfig>(json!({
+            "default_max_lifetime_ms": lifetime,
+        }));
+        assert_eq!(config(json!(1000)).unwrap().default_max_lifetime_ms, Some(1000));
+        assert_eq!(config(json!(null)).unwrap().default_max_lifetime_ms, None);
+        assert!(config(json!(0)).is_err());
+        assert!(config(json!(-1000)).is_err());
+    }
+}
@@ -0,0 +1,65 @@
+use actix_web::{get, web::{Data, Json}};
+use serde_json::{json, Value as JsonValue};
+use std::{collections::HashMap, sync::Arc};
+use tracing::{instrument, Level};
+
+use crate::{error::{Error, ErrorKind}, sign::{sign_json, Key}, ServerState};
+
+/// How long other servers may cache our keys for before fetching them again.
+const KEY_VALIDITY_MS: i64 = 24 * 60 * 60 * 1000;
+
+#[get("/server")]
+#[instrument(skip(state), err = Level::DEBUG)]
+pub async fn get_server_keys(state: Data<Arc<ServerState>>) -> Result<Json<JsonValue>, Error> {
+    let valid_until_ts = chrono::Utc::now().timestamp_millis() + KEY_VALIDITY_MS;
+    Ok(Json(server_keys(&state.config.domain, &state.keys, valid_until_ts)?))
+}
+
+/// Builds the key publication response for `server_name`, signed with the keys it lists.
+fn server_keys(
+    server_name: &str,
+    keys: &HashMap<String, Key>,
+    valid_until_ts: i64,
+) -> Result<JsonValue, Error> {
+    let verify_keys = keys.iter()
+        .map(|(key_name, key)| (key_name.clone(), json!({ "key": key.public_key_base64() })))
+        .collect::<serde_json::Map<_, _>>();
+    let mut response = json!({
+        "server_name": server_name,
+        "verify_keys": verify_keys,
+        "old_verify_keys": {},
+        "valid_until_ts": valid_until_ts,
+    });
+    let signatures = sign_json(&response, keys)
+        .map_err(|e| ErrorKind::Unknown(e.to_string()))?;
+    response["signatures"] = json!({ server_name: signatures });
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::sign::{load_or_generate_keys, verify_json};
+
+    use super::server_keys;
+
+    #[test]
+    fn server_keys_verify() {
+        let path = Path::new("key-test-server-keys");
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let keys = rt.block_on(load_or_generate_keys(path)).unwrap();
+        let _ = std::fs::remove_dir_all(path);
+        let (key_name, key) = keys.iter().next().unwrap();
+
+        let mut response = server_keys("example.org", &keys, 1000).unwrap();
+        assert_eq!(response["server_name"], "example.org");
+        assert_eq!(response["valid_until_ts"], 1000);
+        assert_eq!(response["verify_keys"][key_name]["key"], key.public_key_base64());
+
+        let signatures = response.as_object_mut().unwrap().remove("signatures").unwrap();
+        let signature = signatures["example.org"][key_name].as_str().unwrap();
+        assert!(verify_json(&response, &key.public_key_base64(), signature).unwrap());
+    }
+}
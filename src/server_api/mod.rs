@@ -1,8 +1,14 @@
-use actix_web::client::ClientRequest;
+use actix_web::{client::ClientRequest, web};
 use serde_json::{json, Value as JsonValue};
 
 use crate::sign::sign_json;
 
+mod key;
+
+pub fn configure_key_endpoints(cfg: &mut web::ServiceConfig) {
+    cfg.service(key::get_server_keys);
+}
+
 pub fn server_request(
     mut req: ClientRequest,
     state: &crate::ServerState,
@@ -1,7 +1,10 @@
 use std::{collections::HashMap, path::Path};
 
-use base64::URL_SAFE_NO_PAD;
-use ring::signature::{Ed25519KeyPair, Signature};
+use base64::STANDARD_NO_PAD;
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair, Signature},
+};
 use serde::Serialize;
 use tokio::fs;
 
@@ -21,10 +24,36 @@ impl Key {
             Key::Ed25519(key) => key.sign(v),
         }
     }
+
+    /// The public half of the key, in unpadded base64.
+    pub fn public_key_base64(&self) -> String {
+        match self {
+            Key::Ed25519(key) => base64::encode_config(key.public_key().as_ref(), STANDARD_NO_PAD),
+        }
+    }
+}
+
+/// Loads the signing keys in `key_dir`, where each file is named after its key ID (e.g.
+/// `ed25519:abc123`) and contains a PKCS#8 document.
+///
+/// If there are no keys, a new one is generated and saved so that it is used from then on.
+pub async fn load_or_generate_keys(key_dir: &Path) -> Result<HashMap<String, Key>, std::io::Error> {
+    fs::create_dir_all(key_dir).await?;
+    let keys = load_keys(key_dir).await?;
+    if !keys.is_empty() {
+        return Ok(keys);
+    }
+
+    let key_name = format!("ed25519:{:08x}", rand::random::<u32>());
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+        .map_err(|_| std::io::Error::other("failed to generate key"))?;
+    fs::write(key_dir.join(&key_name), pkcs8.as_ref()).await?;
+    tracing::info!(key = key_name.as_str(), "Generated a new signing key");
+    load_keys(key_dir).await
 }
 
-pub async fn load_keys(kerux_root: &Path) -> Result<HashMap<String, Key>, std::io::Error> {
-    let mut entries = fs::read_dir(kerux_root.join("keys")).await?;
+pub async fn load_keys(key_dir: &Path) -> Result<HashMap<String, Key>, std::io::Error> {
+    let mut entries = fs::read_dir(key_dir).await?;
     let mut ret = HashMap::new();
     while let Some(key_file) = entries.next_entry().await? {
         let file_name = key_file.file_name();
@@ -48,9 +77,97 @@ pub fn sign_json(
     let res = keys.iter()
         .map(|(name, key)| {
             let signature = key.sign(json.as_bytes());
-            let base64 = base64::encode_config(&signature, URL_SAFE_NO_PAD);
+            let base64 = base64::encode_config(&signature, STANDARD_NO_PAD);
             (name.clone(), base64)
         })
         .collect();
     Ok(res)
 }
+
+/// Checks an ed25519 signature made by `sign_json`, given the key's public half and the
+/// signature, both in unpadded base64.
+#[cfg(test)]
+pub fn verify_json(
+    object: &impl Serialize,
+    public_key: &str,
+    signature: &str,
+) -> Result<bool, serde_canonical::error::Error> {
+    use ring::signature::{UnparsedPublicKey, ED25519};
+
+    let json = serde_canonical::ser::to_string(&object)?;
+    let (public_key, signature) = match (
+        base64::decode_config(public_key, STANDARD_NO_PAD),
+        base64::decode_config(signature, STANDARD_NO_PAD),
+    ) {
+        (Ok(k), Ok(s)) => (k, s),
+        _ => return Ok(false),
+    };
+    let public_key = UnparsedPublicKey::new(&ED25519, public_key);
+    Ok(public_key.verify(json.as_bytes(), &signature).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        events::{EventContent, room::Name, room_version::v4::UnhashedPdu},
+        util::MatrixId,
+    };
+
+    use super::{load_or_generate_keys, verify_json};
+
+    #[test]
+    fn key_persists() {
+        let path = Path::new("sign-test-key-persists");
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async {
+            let first = load_or_generate_keys(path).await.unwrap();
+            assert_eq!(first.len(), 1);
+            let second = load_or_generate_keys(path).await.unwrap();
+            assert_eq!(second.len(), 1);
+            let (name, key) = first.iter().next().unwrap();
+            assert!(name.starts_with("ed25519:"));
+            assert_eq!(second[name].public_key_base64(), key.public_key_base64());
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn signed_pdu_verifies() {
+        let path = Path::new("sign-test-signed-pdu");
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let keys = rt.block_on(load_or_generate_keys(path)).unwrap();
+        let _ = std::fs::remove_dir_all(path);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let mut pdu = UnhashedPdu {
+            event_content: EventContent::Name(Name { name: Some(String::from("room")) }),
+            room_id: String::from("!test:example.org"),
+            sender: alice,
+            state_key: Some(String::new()),
+            unsigned: None,
+            redacts: None,
+            origin: String::from("example.org"),
+            origin_server_ts: 0,
+            prev_events: Vec::new(),
+            depth: 1,
+            auth_events: Vec::new(),
+        }.finalize();
+        pdu.sign("example.org", &keys);
+
+        let (key_name, key) = keys.iter().next().unwrap();
+        let signatures = pdu.signatures.clone().unwrap();
+        let signature = signatures["example.org"][key_name].as_str().unwrap().to_owned();
+        let mut signed_form = pdu.clone().redact();
+        signed_form.signatures = None;
+        assert!(verify_json(&signed_form, &key.public_key_base64(), &signature).unwrap());
+
+        // signatures don't survive tampering
+        let mut tampered = signed_form;
+        tampered.origin_server_ts = 1;
+        assert!(!verify_json(&tampered, &key.public_key_base64(), &signature).unwrap());
+    }
+}
@@ -3,7 +3,7 @@ use std::{borrow::Cow, cmp::Ordering, collections::{BTreeSet, HashMap, HashSet},
 use futures::stream::{StreamExt, TryStreamExt};
 use tracing::trace;
 
-use crate::{error::Error, events::{EventContent, EventType, pdu::StoredPdu, room::{Member, Membership}, room_version::VersionedPdu}, storage::Storage, validate::auth::AuthStatus};
+use crate::{error::{Error, ErrorKind}, events::{EventContent, EventType, pdu::StoredPdu, room::{Member, Membership}, room_version::VersionedPdu}, storage::Storage, validate::auth::AuthStatus};
 
 use super::StorageExt;
 
@@ -26,18 +26,24 @@ impl State {
 
     pub async fn get_content<T: EventType>(&self, db: &dyn Storage, state_key: &str) -> Result<Option<T>, Error> {
         if let Some(event_id) = self.get((T::EVENT_TYPE, state_key)) {
-            let event = db.get_pdu(&self.room_id, &event_id).await?
-                .expect("event in state doesn't exist");
-            return Ok(Some(event.event_content().clone().try_into().map_err(|_| ()).unwrap()))
+            let event = db.get_referenced_pdu(&self.room_id, &event_id).await?;
+            let content = event.event_content().clone().try_into().map_err(|_| {
+                ErrorKind::Unknown(format!("{} in state isn't a {}", event_id, T::EVENT_TYPE))
+            })?;
+            return Ok(Some(content));
         }
         Ok(None)
     }
 
-    pub fn insert_event(&mut self, pdu: &VersionedPdu) {
+    pub fn insert_event(&mut self, pdu: &VersionedPdu) -> Result<(), Error> {
+        let state_key = pdu.state_key().ok_or_else(|| {
+            ErrorKind::Unknown(format!("{} isn't a state event", pdu.event_id()))
+        })?;
         self.map.insert(
-            (Cow::from(pdu.event_content().get_type().to_string()), Cow::from(pdu.state_key().unwrap().to_string())),
+            (Cow::from(pdu.event_content().get_type().to_string()), Cow::from(state_key.to_string())),
             pdu.event_id().to_string()
         );
+        Ok(())
     }
 }
 
@@ -46,16 +52,32 @@ pub struct StateResolver {
     cache: Arc<Mutex<HashMap<BTreeSet<String>, State>>>,
     // TODO: do we want to keep this around, or pass it by function arguments?
     db: Box<dyn Storage>,
+    /// The most forward extremities a new event can reference in its prev_events
+    max_prev_events: usize,
 }
 
 impl StateResolver {
+    pub const DEFAULT_MAX_PREV_EVENTS: usize = 20;
+
     pub fn new(db: Box<dyn Storage>) -> Self {
         Self {
             cache: Arc::new(Mutex::new(HashMap::new())),
             db,
+            max_prev_events: Self::DEFAULT_MAX_PREV_EVENTS,
         }
     }
 
+    /// Sets how many forward extremities new events can reference. Any fewer than 2 and
+    /// extremities could never be merged, so that's the minimum.
+    pub fn with_max_prev_events(mut self, max_prev_events: usize) -> Self {
+        self.max_prev_events = usize::max(max_prev_events, 2);
+        self
+    }
+
+    pub fn max_prev_events(&self) -> usize {
+        self.max_prev_events
+    }
+
     pub async fn resolve(&self, room_id: &str, events: &[String]) -> Result<State, Error> {
         self.resolve_v2(room_id, events).await
     }
@@ -77,15 +99,15 @@ impl StateResolver {
         }
 
         if events.len() == 1 {
-            let event = self.db.get_pdu(room_id, &events[0]).await?.unwrap();
+            let event = self.db.get_referenced_pdu(room_id, &events[0]).await?;
             let mut state = self.resolve_v2(room_id, event.prev_events()).await?;
-            if event.did_pass_auth() && event.state_key().is_some() {
+            if let Some(state_key) = event.state_key().filter(|_| event.did_pass_auth()) {
                 trace!(
                     event_type=event.event_content().get_type(),
-                    state_key=event.state_key().unwrap(),
+                    state_key,
                     "applying one event on top of state"
                 );
-                state.insert_event(&event.inner());
+                state.insert_event(&event.inner())?;
             }
             self.cache.lock().unwrap().insert(BTreeSet::from_iter([events[0].clone()]), state.clone());
             return Ok(state);
@@ -150,7 +172,7 @@ impl StateResolver {
         // Or I can't read
         let mut power_events = HashSet::new();
         for event_id in full_conflicted_set.iter() {
-            let event = self.db.get_pdu(room_id, event_id).await?.unwrap();
+            let event = self.db.get_referenced_pdu(room_id, event_id).await?;
             if is_power_event(&event.inner()) {
                 power_events.insert(event_id.clone());
             }
@@ -195,7 +217,7 @@ impl StateResolver {
         let get_power_levels = |event: VersionedPdu| async move {
             let auth_events = event.auth_events().clone();
             for auth_event_id in auth_events.iter() {
-                let auth_event = self.db.get_pdu(room_id, auth_event_id).await?.unwrap();
+                let auth_event = self.db.get_referenced_pdu(room_id, auth_event_id).await?;
                 match auth_event.event_content() {
                     EventContent::PowerLevels(_) =>
                         return Result::<Option<String>, Error>::Ok(Some(auth_event_id.clone())),
@@ -206,14 +228,16 @@ impl StateResolver {
             Result::<Option<String>, Error>::Ok(None)
         };
 
-        // i seriously have no idea what to do if this doesn't exist
-        let mainline_starting_point = partially_resolved_state
-            .get(("m.room.power_levels", "")).expect("oh no");
-        let mut mainline = vec![mainline_starting_point.to_owned()];
-        let mut current = mainline_starting_point;
-        while let Some(parent) = get_power_levels(self.db.get_pdu(room_id, &current).await?.unwrap().inner().clone()).await? {
-            mainline.push(parent.clone());
-            current = &mainline.last().unwrap();
+        // without a power levels event everyone has the default levels, so there's no mainline and
+        // the remaining events are just ordered by timestamp and ID
+        let mut mainline = Vec::new();
+        if let Some(starting_point) = partially_resolved_state.get(("m.room.power_levels", "")) {
+            let mut current = starting_point.to_owned();
+            mainline.push(current.clone());
+            while let Some(parent) = get_power_levels(self.db.get_referenced_pdu(room_id, &current).await?.inner().clone()).await? {
+                mainline.push(parent.clone());
+                current = parent;
+            }
         }
 
         // Tuple of event_id and index of closest mainline event to that event
@@ -225,14 +249,14 @@ impl StateResolver {
                     break 'inner index;
                 }
 
-                let current_event = self.db.get_pdu(room_id, &current).await?.unwrap();
+                let current_event = self.db.get_referenced_pdu(room_id, &current).await?;
                 match get_power_levels(current_event.inner().clone()).await? {
                     Some(id) => current = id.clone(),
                     None => break 'inner std::usize::MAX,
                 }
             };
 
-            let event = self.db.get_pdu(room_id, event_id).await?.unwrap();
+            let event = self.db.get_referenced_pdu(room_id, event_id).await?;
             events_with_closest_mainlines.push((event, closest_mainline));
         }
 
@@ -262,7 +286,7 @@ impl StateResolver {
         to_check.extend_from_slice(event_ids);
         while !to_check.is_empty() {
             let event_id = to_check.pop().unwrap();
-            let pdu = self.db.get_pdu(room_id, &event_id).await?.expect("event not found");
+            let pdu = self.db.get_referenced_pdu(room_id, &event_id).await?;
             for auth_event_id in pdu.auth_events() {
                 if !ret.contains(auth_event_id) {
                     to_check.push(auth_event_id.clone());
@@ -300,7 +324,7 @@ impl StateResolver {
     async fn reverse_topological_power_ordering(&self, room_id: &str, event_ids: HashSet<String>) -> Result<Vec<String>, Error> {
         let mut events = HashMap::new();
         for event_id in event_ids {
-            let event = self.db.get_pdu(room_id, &event_id).await?.expect("event not found");
+            let event = self.db.get_referenced_pdu(room_id, &event_id).await?;
             events.insert(event_id, event);
         }
 
@@ -356,10 +380,9 @@ impl StateResolver {
             let future_iter = event
                 .auth_events()
                 .iter()
-                .map(|event_id| self.db.get_pdu(&state.room_id, event_id));
+                .map(|event_id| self.db.get_referenced_pdu(&state.room_id, event_id));
             let auth_events = futures::stream::iter(future_iter)
                 .then(|f| f)
-                .try_filter_map(|opt| async { Ok(opt) })    // Ok(Some(x)) -> Ok(x), Ok(None) filtered out
                 .try_collect::<Vec<_>>()
                 .await?;
 
@@ -367,17 +390,19 @@ impl StateResolver {
             let mut frankenstate = state.clone();
             for auth_key in auth_types_for_event(event) {
                 if !frankenstate.map.contains_key(&State::key(auth_key)) {
+                    // events the event didn't cite are left for the auth check to deal with
                     let fallback_event = auth_events
                         .iter()
-                        .find(|pdu| pdu.event_content().get_type() == auth_key.0 && pdu.state_key() == Some(auth_key.1))
-                        .expect("auth event wasn't present in db");
-                    frankenstate.insert_event(&fallback_event.inner());
+                        .find(|pdu| pdu.event_content().get_type() == auth_key.0 && pdu.state_key() == Some(auth_key.1));
+                    if let Some(fallback_event) = fallback_event {
+                        frankenstate.insert_event(&fallback_event.inner())?;
+                    }
                 }
             }
 
             // if it passes auth now, we can add it to the state
             if crate::validate::auth::auth_check_v1(&*self.db, &event, &frankenstate).await? == AuthStatus::Pass {
-                state.insert_event(&event);
+                state.insert_event(&event)?;
             }
         }
 
@@ -405,8 +430,8 @@ fn auth_types_for_event(pdu: &VersionedPdu) -> HashSet<(&str, &str)> {
     ret.insert(("m.room.member", &pdu.sender().as_str()));
     ret.insert(("m.room.power_levels", ""));
 
-    if let EventContent::Member(ref member) = pdu.event_content() {
-        ret.insert(("m.room.member", pdu.state_key().as_ref().unwrap()));
+    if let (EventContent::Member(member), Some(state_key)) = (pdu.event_content(), pdu.state_key()) {
+        ret.insert(("m.room.member", state_key));
 
         let membership = &member.membership;
         if *membership == Membership::Join || *membership == Membership::Invite {
@@ -444,7 +469,7 @@ fn mainline_cmp(x: &(StoredPdu, usize), y: &(StoredPdu, usize)) -> Ordering {
 mod tests {
     use std::collections::HashMap;
 
-    use crate::{storage::{Storage, StorageManager}, error::Error, util::{StorageExt, storage::NewEvent, MatrixId}, events::{room::{Create, Name, Member, Membership}, EventContent, room_version::{v4::UnhashedPdu, VersionedPdu}, pdu::StoredPdu}};
+    use crate::{storage::{EventQuery, QueryType, Storage, StorageManager}, error::{Error, ErrorKind}, test_util::create_event, util::{StorageExt, storage::NewEvent, MatrixId}, events::{room::{Name, Member, Membership, PowerLevels}, EventContent, room_version::{v4::UnhashedPdu, VersionedPdu}, pdu::StoredPdu}};
 
     use super::StateResolver;
 
@@ -462,29 +487,9 @@ mod tests {
             room_id: &str,
             creator: &MatrixId,
         ) -> Result<TestRoom<'db>, Error> {
-            let creation = UnhashedPdu {
-                event_content: EventContent::Create(Create {
-                    creator: creator.clone(),
-                    room_version: Some(String::from("4")),
-                    predecessor: None,
-                    extra: HashMap::new(),
-                }),
-                room_id: String::from(room_id),
-                sender: creator.clone(),
-                state_key: Some(String::new()),
-                unsigned: None,
-                redacts: None,
-                origin: String::from("example.org"),
-                origin_server_ts: 0,
-                prev_events: Vec::new(),
-                depth: 0,
-                auth_events: Vec::new(),
-            }.finalize();
-            let creation_id = creation.event_id();
-            db.add_pdus(&[StoredPdu {
-                inner: VersionedPdu::V4(creation),
-                auth_status: crate::validate::auth::AuthStatus::Pass,
-            }]).await?;
+            let creation = create_event(room_id, creator);
+            let creation_id = creation.event_id().to_owned();
+            db.add_pdus(&[creation]).await?;
             Ok(TestRoom {
                 db,
                 room_id: room_id.to_owned(),
@@ -502,7 +507,8 @@ mod tests {
             state_key: Option<&str>,
             state_resolver: &StateResolver,
         ) -> Result<String, Error> {
-            let prev_depth = depth.checked_sub(1).unwrap();
+            let prev_depth = depth.checked_sub(1)
+                .ok_or_else(|| ErrorKind::Unknown(String::from("only the create event has depth 0")))?;
             let prev_events = &self.depth_map[prev_depth];
             let state = state_resolver.resolve(&self.room_id, &prev_events).await?;
 
@@ -514,7 +520,7 @@ mod tests {
                 unsigned: None,
             };
 
-            let auth_events = crate::util::storage::calc_auth_events(&new_event, &state);
+            let auth_events = crate::util::storage::calc_auth_events(&new_event, &state)?;
             let pdu = VersionedPdu::V4(UnhashedPdu {
                 event_content: new_event.event_content,
                 room_id: self.room_id.clone(),
@@ -538,10 +544,7 @@ mod tests {
             self.depth_map[depth].push(event_id.clone());
 
             let auth_status = crate::validate::auth::auth_check_v1(self.db, &pdu, &state).await?;
-            self.db.add_pdus(&[StoredPdu {
-                inner: pdu,
-                auth_status,
-            }]).await?;
+            self.db.add_pdus(&[StoredPdu::new(pdu, auth_status)]).await?;
 
             Ok(event_id)
         }
@@ -550,48 +553,139 @@ mod tests {
     async fn construct_cursed_room(db: &dyn Storage, resolver: &StateResolver) -> Result<(), Error> {
         let room_id = "!cursed:example.org";
         let alice = MatrixId::new("alice", "example.org").unwrap();
-        db.add_pdus(&[StoredPdu {
-            inner: VersionedPdu::V4(UnhashedPdu {
-                event_content: EventContent::Create(Create {
-                    creator: alice.clone(),
-                    room_version: Some(String::from("4")),
-                    predecessor: None,
-                    extra: HashMap::new(),
-                }),
-                room_id: String::from(room_id),
-                sender: alice.clone(),
-                state_key: Some(String::new()),
-                unsigned: None,
-                redacts: None,
-                origin: String::from("example.org"),
-                origin_server_ts: 0,
-                prev_events: Vec::new(),
-                depth: 0,
-                auth_events: Vec::new(),
-            }.finalize()),
-            auth_status: crate::validate::auth::AuthStatus::Pass,
-        }]).await?;
+        db.add_pdus(&[create_event(room_id, &alice)]).await?;
         db.add_event(room_id, NewEvent {
             event_content: EventContent::Member(Member {
                 avatar_url: None,
                 displayname: None,
                 membership: Membership::Join,
-                is_direct: false,
+                is_direct: None,
+                reason: None,
+                third_party_invite: None,
+                join_authorised_via_users_server: None,
             }),
             sender: alice.clone(),
             state_key: Some(alice.clone_inner()),
             redacts: None,
             unsigned: None
-        }, resolver).await?;
+        }, resolver, &HashMap::new()).await?;
         db.add_event(room_id, NewEvent {
             event_content: EventContent::Name(Name {
-                name: String::from("one"),
+                name: Some(String::from("one")),
             }),
             sender: alice.clone(),
             state_key: Some(String::new()),
             redacts: None,
             unsigned: None,
-        }, resolver).await?;
+        }, resolver, &HashMap::new()).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn missing_events_are_errors() {
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        rt.block_on(missing_events_are_errors_inner()).unwrap();
+    }
+
+    async fn missing_events_are_errors_inner() -> Result<(), Error> {
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!broken:example.org";
+        let mut room = TestRoom::create(&*db, room_id, &alice).await?;
+        let create_id = room.depth_map[0][0].clone();
+        let missing = String::from("$missing:example.org");
+
+        // an event that was stored without one of its auth events
+        let name = VersionedPdu::V4(UnhashedPdu {
+            event_content: EventContent::Name(Name { name: Some(String::from("broken")) }),
+            room_id: String::from(room_id),
+            sender: alice.clone(),
+            state_key: Some(String::new()),
+            unsigned: None,
+            redacts: None,
+            origin: String::from("example.org"),
+            origin_server_ts: 0,
+            prev_events: vec![create_id.clone()],
+            depth: 1,
+            auth_events: vec![create_id.clone(), missing.clone()],
+        }.finalize());
+        let name_id = name.event_id();
+        db.add_pdus(&[StoredPdu::new(name.clone(), crate::validate::auth::AuthStatus::Pass)]).await?;
+
+        // resolving state, through a missing prev event or through the auth chains
+        assert!(resolver.resolve(room_id, &[missing.clone()]).await.is_err());
+        assert!(resolver.resolve(room_id, &[name_id.clone(), create_id.clone()]).await.is_err());
+
+        // auth checks and power levels
+        let state = resolver.resolve(room_id, &[create_id.clone()]).await?;
+        assert!(crate::validate::auth::auth_check_v1(&*db, &name, &state).await.is_err());
+        assert!(db.get_sender_power_level(room_id, &name_id).await.is_err());
+
+        // state that points at an event that isn't there
+        let mut broken_state = state.clone();
+        broken_state.map.insert(super::State::key(("m.room.name", "")), missing.clone());
+        assert!(broken_state.get_content::<Name>(&*db, "").await.is_err());
+        // or at an event of the wrong type
+        broken_state.map.insert(super::State::key(("m.room.name", "")), create_id.clone());
+        assert!(broken_state.get_content::<Name>(&*db, "").await.is_err());
+
+        // working out a new event's auth events without a create event in the state
+        let empty_state = resolver.resolve(room_id, &[]).await?;
+        let new_name = NewEvent {
+            event_content: EventContent::Name(Name { name: None }),
+            sender: alice.clone(),
+            state_key: Some(String::new()),
+            redacts: None,
+            unsigned: None,
+        };
+        assert!(crate::util::storage::calc_auth_events(&new_name, &empty_state).is_err());
+
+        // member events without a user to be about just fail
+        let alice_join = room.add(1, &alice, Member {
+            avatar_url: None,
+            displayname: None,
+            membership: Membership::Join,
+            is_direct: None,
+            reason: None,
+            third_party_invite: None,
+            join_authorised_via_users_server: None,
+        }, Some(alice.as_str()), &resolver).await?;
+        let state = resolver.resolve(room_id, &[alice_join.clone()]).await?;
+        let targetless = [
+            (Membership::Invite, None),
+            (Membership::Leave, None),
+            (Membership::Ban, None),
+            (Membership::Leave, Some("bob")),
+            (Membership::Ban, Some("bob")),
+        ];
+        for (membership, target) in targetless.iter() {
+            let member = VersionedPdu::V4(UnhashedPdu {
+                event_content: EventContent::Member(Member {
+                    avatar_url: None,
+                    displayname: None,
+                    membership: membership.clone(),
+                    is_direct: None,
+                    reason: None,
+                    third_party_invite: None,
+                    join_authorised_via_users_server: None,
+                }),
+                room_id: String::from(room_id),
+                sender: alice.clone(),
+                state_key: target.map(String::from),
+                unsigned: None,
+                redacts: None,
+                origin: String::from("example.org"),
+                origin_server_ts: 0,
+                prev_events: vec![alice_join.clone()],
+                depth: 2,
+                auth_events: vec![create_id.clone(), alice_join.clone()],
+            }.finalize());
+            let auth_status = crate::validate::auth::auth_check_v1(&*db, &member, &state).await?;
+            assert_eq!(auth_status, crate::validate::auth::AuthStatus::Fail);
+        }
         Ok(())
     }
 
@@ -614,22 +708,127 @@ mod tests {
             avatar_url: None,
             displayname: None,
             membership: Membership::Join,
-            is_direct: false,
+            is_direct: None,
+            reason: None,
+            third_party_invite: None,
+            join_authorised_via_users_server: None,
         }, Some(alice.as_str()), &resolver).await?;
         let name1 = room.add(2, &alice, Name {
-            name: String::from("one"),
+            name: Some(String::from("one")),
         }, Some(""), &resolver).await?;
 
         let state1 = resolver.resolve(room_id, &[name1.clone()]).await?;
-        assert_eq!(state1.get_content::<Name>(&*db, "").await?.unwrap().name, "one");
+        assert_eq!(state1.get_content::<Name>(&*db, "").await?.unwrap().name.as_deref(), Some("one"));
 
         let name2 = room.add(3, &alice, Name {
-            name: String::from("two"),
+            name: Some(String::from("two")),
         }, Some(""), &resolver).await?;
         let state2 = resolver.resolve(room_id, &[name2]).await?;
-        assert_eq!(state2.get_content::<Name>(&*db, "").await?.unwrap().name, "two");
+        assert_eq!(state2.get_content::<Name>(&*db, "").await?.unwrap().name.as_deref(), Some("two"));
         let state1 = resolver.resolve(room_id, &[name1]).await?;
-        assert_eq!(state1.get_content::<Name>(&*db, "").await?.unwrap().name, "one");
+        assert_eq!(state1.get_content::<Name>(&*db, "").await?.unwrap().name.as_deref(), Some("one"));
+        Ok(())
+    }
+
+    #[test]
+    fn conflicts_without_power_levels() {
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        rt.block_on(conflicts_without_power_levels_inner()).unwrap();
+    }
+
+    async fn conflicts_without_power_levels_inner() -> Result<(), Error> {
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!powerless:example.org";
+        let mut room = TestRoom::create(&*db, room_id, &alice).await?;
+        room.add(1, &alice, Member {
+            avatar_url: None,
+            displayname: None,
+            membership: Membership::Join,
+            is_direct: None,
+            reason: None,
+            third_party_invite: None,
+            join_authorised_via_users_server: None,
+        }, Some(alice.as_str()), &resolver).await?;
+        let name1 = room.add(2, &alice, Name {
+            name: Some(String::from("one")),
+        }, Some(""), &resolver).await?;
+        let name2 = room.add(2, &alice, Name {
+            name: Some(String::from("two")),
+        }, Some(""), &resolver).await?;
+
+        // with no power levels there's no mainline, so the tie is broken by event ID
+        let state = resolver.resolve(room_id, &[name1.clone(), name2.clone()]).await?;
+        let winner = if name1 < name2 { "two" } else { "one" };
+        assert_eq!(state.get_content::<Name>(&*db, "").await?.unwrap().name.as_deref(), Some(winner));
+        Ok(())
+    }
+
+    #[test]
+    fn bounded_prev_events() {
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        rt.block_on(bounded_prev_events_inner()).unwrap();
+    }
+
+    async fn bounded_prev_events_inner() -> Result<(), Error> {
+        let storage_manager = crate::storage::mem::MemStorageManager::new();
+        let db = storage_manager.get_handle().await?;
+        let resolver = StateResolver::new(storage_manager.get_handle().await?)
+            .with_max_prev_events(10);
+
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let room_id = "!extremities:example.org";
+        let mut room = TestRoom::create(&*db, room_id, &alice).await?;
+        room.add(1, &alice, Member {
+            avatar_url: None,
+            displayname: None,
+            membership: Membership::Join,
+            is_direct: None,
+            reason: None,
+            third_party_invite: None,
+            join_authorised_via_users_server: None,
+        }, Some(alice.as_str()), &resolver).await?;
+        room.add(2, &alice, PowerLevels::default(), Some(""), &resolver).await?;
+        // as if 30 messages had been sent at once
+        for i in 0..30 {
+            let content = EventContent::new("m.room.message", serde_json::json!({
+                "msgtype": "m.text",
+                "body": i.to_string(),
+            })).unwrap();
+            room.add(3, &alice, content, None, &resolver).await?;
+        }
+        assert_eq!(db.get_prev_events(room_id).await?.0.len(), 30);
+
+        for _ in 0..3 {
+            let event_id = db.add_event(room_id, NewEvent {
+                event_content: EventContent::new("m.room.message", serde_json::json!({
+                    "msgtype": "m.text",
+                    "body": "afterwards",
+                })).unwrap(),
+                sender: alice.clone(),
+                state_key: None,
+                redacts: None,
+                unsigned: None,
+            }, &resolver, &HashMap::new()).await?;
+            let pdu = db.get_pdu(room_id, &event_id).await?.unwrap();
+            assert!(pdu.prev_events().len() <= 10);
+        }
+        assert_eq!(db.get_prev_events(room_id).await?.0.len(), 1);
+
+        let (pdus, _) = db.query_pdus(EventQuery {
+            query_type: QueryType::Timeline { from: 0, to: None },
+            room_id,
+            senders: &[],
+            not_senders: &[],
+            types: &[],
+            not_types: &[],
+            contains_json: None,
+        }, false).await?;
+        assert!(pdus.iter().all(|pdu| pdu.prev_events().len() <= 10));
+        assert!(pdus.iter().any(|pdu| pdu.event_content().get_type() == "org.matrix.dummy_event"));
         Ok(())
     }
 }
@@ -1,36 +1,107 @@
 use async_trait::async_trait;
 use serde_json::Value as JsonValue;
 use std::{
-    collections::{HashMap, HashSet},
-    sync::Arc, time::{Duration, Instant},
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use tokio::sync::{RwLock, broadcast::{channel, Sender}};
+use tokio::{sync::{RwLock, broadcast::{channel, Sender}}, time::delay_until};
 use uuid::Uuid;
 
-use crate::{error::{Error, ErrorKind}, events::{EventContent, ephemeral::Typing, pdu::StoredPdu}, storage::{Batch, EventQuery, QueryType, Storage, StorageManager, UserProfile}, util::MatrixId};
+use crate::{error::{Error, ErrorKind}, events::{EventContent, ephemeral::{PresenceState, Receipt, ReceiptType, Receipts, ToDeviceEvent, Typing}, pdu::StoredPdu, room::Membership}, storage::{Batch, Device, EventQuery, Medium, Presence, Storage, StorageManager, Threepid, ThreepidSession, UiaaSession, UserProfile, count_by_algorithm, logged_out_expiry, should_purge, user_matches}, util::MatrixId};
 
+#[derive(Clone)]
 struct MemStorage {
     rooms: HashMap<String, Room>,
     users: Vec<User>,
-    access_tokens: HashMap<Uuid, String>,
+    access_tokens: HashMap<Uuid, AccessToken>,
+    refresh_tokens: HashMap<Uuid, RefreshToken>,
     batches: HashMap<String, Batch>,
     txn_ids: HashMap<Uuid, HashSet<String>>,
+    /// Bound third party identifiers and the usernames they're bound to
+    threepids: Vec<(String, Threepid)>,
+    threepid_sessions: HashMap<String, ThreepidSession>,
+    uiaa_sessions: HashMap<String, UiaaSession>,
+    /// Room aliases and the rooms they point at
+    aliases: HashMap<String, String>,
+    /// Room aliases and the users who created them
+    alias_creators: HashMap<String, String>,
+    /// Rooms listed in the public room directory
+    published_rooms: HashSet<String>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct Room {
     events: Vec<StoredPdu>,
     ephemeral: HashMap<String, JsonValue>,
     typing: HashMap<MatrixId, Instant>,
+    /// Each user's public read receipt, as (event_id, ts)
+    receipts: HashMap<MatrixId, (String, i64)>,
+    /// Each user's private read receipt, as (event_id, ts)
+    private_receipts: HashMap<MatrixId, (String, i64)>,
+    /// Each user's fully read marker
+    fully_read: HashMap<MatrixId, String>,
+    /// The users who have forgotten the room
+    forgotten_by: HashSet<MatrixId>,
     notify_send: Sender<()>,
+    /// When the latest burst of ephemeral changes started. Anyone waiting on the room is woken
+    /// `EPHEMERAL_DEBOUNCE` after that.
+    ephemeral_changed_at: Option<Instant>,
 }
 
-#[derive(Debug)]
+/// How long ephemeral changes are collected before waking anyone waiting on the room, so that a
+/// burst of them wakes each sync once rather than once per change.
+pub(super) const EPHEMERAL_DEBOUNCE: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Debug)]
+struct AccessToken {
+    username: String,
+    device_id: String,
+    /// Milliseconds since the unix epoch, or None if the token never expires
+    expires_at: Option<i64>,
+    /// Whether the token's device has been logged out. Such tokens are kept around for
+    /// `LOGGED_OUT_TOKEN_LIFETIME` so that clients using them can be told to log in again as the
+    /// same device.
+    logged_out: bool,
+}
+
+#[derive(Clone, Debug)]
+struct RefreshToken {
+    username: String,
+    device_id: String,
+    /// The access token issued alongside this refresh token
+    access_token: Uuid,
+}
+
+#[derive(Clone, Debug)]
 struct User {
     username: String,
     password_hash: String,
     profile: UserProfile,
     account_data: HashMap<String, JsonValue>,
+    /// Where each type of account data was last changed in the user's account data stream
+    account_data_changes: HashMap<String, usize>,
+    account_data_position: usize,
+    /// Account data for each room, by room ID then type, along with where it was last changed in
+    /// the account data stream
+    room_account_data: HashMap<String, HashMap<String, (JsonValue, usize)>>,
+    /// Sync filters, by ID
+    filters: HashMap<String, JsonValue>,
+    /// The user's devices, by ID
+    devices: HashMap<String, Device>,
+    /// The identity keys uploaded by the user's devices, by device ID
+    device_keys: HashMap<String, JsonValue>,
+    /// The one-time keys uploaded by the user's devices, by device ID then key ID
+    one_time_keys: HashMap<String, HashMap<String, JsonValue>>,
+    /// Messages waiting to be delivered to the user's devices, by device ID, along with their IDs
+    to_device: HashMap<String, Vec<(u64, ToDeviceEvent)>>,
+    /// How many to-device messages have ever been queued for the user, which gives each one its ID
+    to_device_position: u64,
+    /// Wakes up syncs waiting for to-device messages
+    to_device_notify: Sender<()>,
+    device_list_version: usize,
+    presence: Option<Presence>,
+    is_guest: bool,
 }
 
 pub struct MemStorageManager {
@@ -41,13 +112,96 @@ pub struct MemStorageHandle {
     inner: Arc<RwLock<MemStorage>>,
 }
 
+/// A copy of everything a `MemStorageManager` held at some point, which it can be put back to.
+#[cfg(test)]
+pub struct Snapshot(MemStorage);
+
+impl User {
+    /// The messages queued for the device after the one with ID `after`
+    fn to_device_after(&self, device_id: &str, after: u64) -> Vec<(u64, ToDeviceEvent)> {
+        self.to_device.get(device_id)
+            .map(|messages| messages.iter().filter(|(id, _)| *id > after).cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
 impl Room {
     fn new() -> Self {
         Room {
             events: Vec::new(),
             ephemeral: HashMap::new(),
             typing: Default::default(),
+            receipts: HashMap::new(),
+            private_receipts: HashMap::new(),
+            fully_read: HashMap::new(),
+            forgotten_by: HashSet::new(),
             notify_send: channel(1).0,
+            ephemeral_changed_at: None,
+        }
+    }
+
+    /// Has anyone waiting on the room woken after `EPHEMERAL_DEBOUNCE`, unless a burst of
+    /// changes is already underway. Changes made before then are seen by whoever it wakes.
+    ///
+    /// Nothing is scheduled here; waiters are told a burst has started, and wait out the rest of
+    /// it themselves.
+    fn notify_ephemeral(&mut self) {
+        let now = Instant::now();
+        if self.ephemeral_due(now).is_some() {
+            return;
+        }
+        self.ephemeral_changed_at = Some(now);
+        let _ = self.notify_send.send(());
+    }
+
+    /// When the current burst of ephemeral changes is over, if one is underway.
+    fn ephemeral_due(&self, now: Instant) -> Option<Instant> {
+        self.ephemeral_changed_at
+            .map(|changed_at| changed_at + EPHEMERAL_DEBOUNCE)
+            .filter(|due| *due > now)
+    }
+
+    /// When anyone waiting on the room should next be woken without being notified: the end of
+    /// the current burst of ephemeral changes, or the next typing notification running out.
+    fn wakeup_due(&self, now: Instant) -> Option<Instant> {
+        let typing_expiry = self.typing.values().copied().filter(|timeout| *timeout > now).min();
+        match (self.ephemeral_due(now), typing_expiry) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn next_stream_ordering(&self) -> usize {
+        self.events.last().map(|pdu| pdu.stream_ordering + 1).unwrap_or(0)
+    }
+
+    /// The room's events with stream orderings from `from` to `to` inclusive.
+    fn events_between(&self, from: usize, to: usize) -> impl Iterator<Item = &StoredPdu> {
+        self.events.iter()
+            .skip_while(move |pdu| pdu.stream_ordering < from)
+            .take_while(move |pdu| pdu.stream_ordering <= to)
+    }
+}
+
+impl MemStorage {
+    /// Drops the logged out tokens of a device once it has logged in again.
+    fn forget_logged_out(&mut self, username: &str, device_id: &str) {
+        self.access_tokens.retain(|_token, data| {
+            !data.logged_out || data.username != username || data.device_id != device_id
+        });
+    }
+
+    /// Marks a device as seen, creating it first if it's new.
+    fn touch_device(&mut self, username: &str, device_id: &str) {
+        let now = chrono::Utc::now().timestamp_millis();
+        if let Some(user) = self.users.iter_mut().find(|u| u.username == username) {
+            user.devices.entry(device_id.to_string())
+                .or_insert_with(|| Device {
+                    device_id: device_id.to_string(),
+                    display_name: None,
+                    last_seen_ts: now,
+                })
+                .last_seen_ts = now;
         }
     }
 }
@@ -59,13 +213,35 @@ impl MemStorageManager {
                 rooms: HashMap::new(),
                 users: Vec::new(),
                 access_tokens: HashMap::new(),
+                refresh_tokens: HashMap::new(),
                 batches: HashMap::new(),
                 txn_ids: HashMap::new(),
+                threepids: Vec::new(),
+                threepid_sessions: HashMap::new(),
+                uiaa_sessions: HashMap::new(),
+                aliases: HashMap::new(),
+                alias_creators: HashMap::new(),
+                published_rooms: HashSet::new(),
             })),
         }
     }
 }
 
+#[cfg(test)]
+impl MemStorageManager {
+    /// Copies everything that's stored, so that a test can set things up once and go back to them
+    /// between cases.
+    pub async fn snapshot(&self) -> Snapshot {
+        Snapshot(self.storage.read().await.clone())
+    }
+
+    /// Puts everything back the way it was when the snapshot was taken, for existing handles as
+    /// well as new ones. Rooms keep notifying whoever was already waiting on them.
+    pub async fn restore(&self, snapshot: &Snapshot) {
+        *self.storage.write().await = snapshot.0.clone();
+    }
+}
+
 #[async_trait]
 impl StorageManager for MemStorageManager {
     async fn get_handle(&self) -> Result<Box<dyn Storage>, Error> {
@@ -73,6 +249,13 @@ impl StorageManager for MemStorageManager {
             inner: Arc::clone(&self.storage),
         }))
     }
+
+    async fn purge_expired_tokens(&self, now: i64) -> Result<usize, Error> {
+        let mut db = self.storage.write().await;
+        let before = db.access_tokens.len();
+        db.access_tokens.retain(|_token, data| data.expires_at.map(|t| t > now).unwrap_or(true));
+        Ok(before - db.access_tokens.len())
+    }
 }
 
 #[async_trait]
@@ -95,10 +278,74 @@ impl Storage for MemStorageHandle {
                 displayname: None,
             },
             account_data: HashMap::new(),
+            account_data_changes: HashMap::new(),
+            account_data_position: 0,
+            room_account_data: HashMap::new(),
+            filters: HashMap::new(),
+            devices: HashMap::new(),
+            device_keys: HashMap::new(),
+            one_time_keys: HashMap::new(),
+            to_device: HashMap::new(),
+            to_device_position: 0,
+            to_device_notify: channel(1).0,
+            device_list_version: 0,
+            presence: None,
+            is_guest: false,
         });
         Ok(())
     }
 
+    async fn create_guest_user(&self, username: &str) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        if db.users.iter().find(|u| u.username == username).is_some() {
+            return Err(ErrorKind::UsernameTaken.into());
+        }
+        db.users.push(User {
+            username: username.to_string(),
+            password_hash: String::new(),
+            profile: UserProfile::default(),
+            account_data: HashMap::new(),
+            account_data_changes: HashMap::new(),
+            account_data_position: 0,
+            room_account_data: HashMap::new(),
+            filters: HashMap::new(),
+            devices: HashMap::new(),
+            device_keys: HashMap::new(),
+            one_time_keys: HashMap::new(),
+            to_device: HashMap::new(),
+            to_device_position: 0,
+            to_device_notify: channel(1).0,
+            device_list_version: 0,
+            presence: None,
+            is_guest: true,
+        });
+        Ok(())
+    }
+
+    async fn delete_user(&self, username: &str) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        db.users.retain(|u| u.username != username);
+        Ok(())
+    }
+
+    async fn is_guest(&self, username: &str) -> Result<bool, Error> {
+        let db = self.inner.read().await;
+        db.users
+            .iter()
+            .find(|u| u.username == username)
+            .map(|u| u.is_guest)
+            .ok_or_else(|| ErrorKind::UserNotFound.into())
+    }
+
+    async fn set_password_hash(&self, username: &str, password_hash: &str) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let user = db.users.iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.password_hash = password_hash.to_string();
+        Ok(())
+    }
+
     async fn verify_password(&self, username: &str, password: &str) -> Result<bool, Error> {
         let db = self.inner.read().await;
         let user = db.users.iter().find(|u| u.username == username);
@@ -116,36 +363,340 @@ impl Storage for MemStorageHandle {
     async fn create_access_token(
         &self,
         username: &str,
-        _device_id: &str,
+        device_id: &str,
     ) -> Result<Uuid, Error> {
         let mut db = self.inner.write().await;
         let token = Uuid::new_v4();
         if db.users.iter().find(|u| u.username == username).is_none() {
             return Err(ErrorKind::UserNotFound.into());
         }
-        db.access_tokens.insert(token, username.to_string());
+        db.forget_logged_out(username, device_id);
+        db.touch_device(username, device_id);
+        db.access_tokens.insert(token, AccessToken {
+            username: username.to_string(),
+            device_id: device_id.to_string(),
+            expires_at: None,
+            logged_out: false,
+        });
         Ok(token)
     }
 
+    async fn create_refreshable_access_token(
+        &self,
+        username: &str,
+        device_id: &str,
+        lifetime: Duration,
+    ) -> Result<(Uuid, Uuid), Error> {
+        let mut db = self.inner.write().await;
+        if db.users.iter().find(|u| u.username == username).is_none() {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        let access_token = Uuid::new_v4();
+        let refresh_token = Uuid::new_v4();
+        let expires_at = chrono::Utc::now().timestamp_millis() + lifetime.as_millis() as i64;
+        db.forget_logged_out(username, device_id);
+        db.touch_device(username, device_id);
+        db.access_tokens.insert(access_token, AccessToken {
+            username: username.to_string(),
+            device_id: device_id.to_string(),
+            expires_at: Some(expires_at),
+            logged_out: false,
+        });
+        db.refresh_tokens.insert(refresh_token, RefreshToken {
+            username: username.to_string(),
+            device_id: device_id.to_string(),
+            access_token,
+        });
+        Ok((access_token, refresh_token))
+    }
+
+    async fn refresh_access_token(
+        &self,
+        refresh_token: Uuid,
+        lifetime: Duration,
+    ) -> Result<Option<(Uuid, Uuid)>, Error> {
+        let data = {
+            let mut db = self.inner.write().await;
+            let data = match db.refresh_tokens.remove(&refresh_token) {
+                Some(data) => data,
+                None => return Ok(None),
+            };
+            db.access_tokens.remove(&data.access_token);
+            data
+        };
+        self.create_refreshable_access_token(&data.username, &data.device_id, lifetime)
+            .await
+            .map(Some)
+    }
+
     async fn delete_access_token(&self, token: Uuid) -> Result<(), Error> {
         let mut db = self.inner.write().await;
         db.access_tokens.remove(&token);
+        db.refresh_tokens.retain(|_token, data| data.access_token != token);
         Ok(())
     }
 
     async fn delete_all_access_tokens(&self, token: Uuid) -> Result<(), Error> {
         let mut db = self.inner.write().await;
         let username = match db.access_tokens.get(&token) {
-            Some(v) => v.clone(),
+            Some(v) => v.username.clone(),
             None => return Ok(()),
         };
-        db.access_tokens.retain(|_token, name| *name != username);
+        db.access_tokens.retain(|_token, data| data.username != username);
+        db.refresh_tokens.retain(|_token, data| data.username != username);
+        Ok(())
+    }
+
+    async fn logout_device(&self, token: Uuid) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let (username, device_id) = match db.access_tokens.get(&token) {
+            Some(v) => (v.username.clone(), v.device_id.clone()),
+            None => return Ok(()),
+        };
+        for data in db.access_tokens.values_mut() {
+            if data.username == username && data.device_id == device_id {
+                data.logged_out = true;
+                data.expires_at = Some(logged_out_expiry(data.expires_at));
+            }
+        }
+        db.refresh_tokens.retain(|_token, data| {
+            data.username != username || data.device_id != device_id
+        });
+        Ok(())
+    }
+
+    async fn logout_all_devices(&self, token: Uuid) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let username = match db.access_tokens.get(&token) {
+            Some(v) => v.username.clone(),
+            None => return Ok(()),
+        };
+        for data in db.access_tokens.values_mut() {
+            if data.username == username {
+                data.logged_out = true;
+                data.expires_at = Some(logged_out_expiry(data.expires_at));
+            }
+        }
+        db.refresh_tokens.retain(|_token, data| data.username != username);
         Ok(())
     }
 
     async fn try_auth(&self, token: Uuid) -> Result<Option<String>, Error> {
+        let mut db = self.inner.write().await;
+        let now = chrono::Utc::now().timestamp_millis();
+        let (username, device_id) = match db.access_tokens.get(&token) {
+            Some(data) if data.logged_out => return Err(ErrorKind::SoftLogout.into()),
+            Some(data) if data.expires_at.map(|t| t <= now).unwrap_or(false) => {
+                return Err(ErrorKind::SoftLogout.into());
+            },
+            Some(data) => (data.username.clone(), data.device_id.clone()),
+            None => return Ok(None),
+        };
+        db.touch_device(&username, &device_id);
+        Ok(Some(username))
+    }
+
+    async fn get_token_device(&self, token: Uuid) -> Result<Option<String>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.access_tokens.get(&token).map(|data| data.device_id.clone()))
+    }
+
+    async fn get_devices(&self, username: &str) -> Result<Vec<Device>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.users.iter()
+            .find(|u| u.username == username)
+            .map(|u| u.devices.values().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    async fn get_device(&self, username: &str, device_id: &str) -> Result<Option<Device>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.users.iter()
+            .find(|u| u.username == username)
+            .and_then(|u| u.devices.get(device_id).cloned()))
+    }
+
+    async fn set_device_display_name(
+        &self,
+        username: &str,
+        device_id: &str,
+        display_name: Option<&str>,
+    ) -> Result<bool, Error> {
+        let mut db = self.inner.write().await;
+        let device = db.users.iter_mut()
+            .find(|u| u.username == username)
+            .and_then(|u| u.devices.get_mut(device_id));
+        match device {
+            Some(device) => {
+                device.display_name = display_name.map(String::from);
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    async fn delete_device(&self, username: &str, device_id: &str) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        if let Some(user) = db.users.iter_mut().find(|u| u.username == username) {
+            user.devices.remove(device_id);
+            user.device_keys.remove(device_id);
+            user.one_time_keys.remove(device_id);
+            user.to_device.remove(device_id);
+            user.device_list_version += 1;
+        }
+        db.access_tokens.retain(|_token, data| {
+            data.username != username || data.device_id != device_id
+        });
+        db.refresh_tokens.retain(|_token, data| {
+            data.username != username || data.device_id != device_id
+        });
+        Ok(())
+    }
+
+    async fn set_device_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+        keys: JsonValue,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let user = db.users.iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.device_keys.insert(device_id.to_string(), keys);
+        user.device_list_version += 1;
+        Ok(())
+    }
+
+    async fn get_device_list_version(&self, username: &str) -> Result<usize, Error> {
+        let db = self.inner.read().await;
+        Ok(db.users.iter()
+            .find(|u| u.username == username)
+            .map(|u| u.device_list_version)
+            .unwrap_or(0))
+    }
+
+    async fn get_device_keys(&self, username: &str) -> Result<HashMap<String, JsonValue>, Error> {
         let db = self.inner.read().await;
-        Ok(db.access_tokens.get(&token).cloned())
+        Ok(db.users.iter()
+            .find(|u| u.username == username)
+            .map(|u| u.device_keys.clone())
+            .unwrap_or_default())
+    }
+
+    async fn add_one_time_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+        keys: HashMap<String, JsonValue>,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let user = db.users.iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.one_time_keys.entry(device_id.to_string()).or_default().extend(keys);
+        Ok(())
+    }
+
+    async fn claim_one_time_key(
+        &self,
+        username: &str,
+        device_id: &str,
+        algorithm: &str,
+    ) -> Result<Option<(String, JsonValue)>, Error> {
+        // the write lock is held from finding the key to removing it, so nobody else can claim it
+        let mut db = self.inner.write().await;
+        let keys = match db.users.iter_mut()
+            .find(|u| u.username == username)
+            .and_then(|u| u.one_time_keys.get_mut(device_id))
+        {
+            Some(keys) => keys,
+            None => return Ok(None),
+        };
+        let prefix = format!("{}:", algorithm);
+        let key_id = match keys.keys().find(|key_id| key_id.starts_with(&prefix)) {
+            Some(key_id) => key_id.clone(),
+            None => return Ok(None),
+        };
+        Ok(keys.remove_entry(&key_id))
+    }
+
+    async fn count_one_time_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+    ) -> Result<HashMap<String, usize>, Error> {
+        let db = self.inner.read().await;
+        let keys = db.users.iter()
+            .find(|u| u.username == username)
+            .and_then(|u| u.one_time_keys.get(device_id));
+        Ok(match keys {
+            Some(keys) => count_by_algorithm(keys.keys().map(String::as_str)),
+            None => HashMap::new(),
+        })
+    }
+
+    async fn add_to_device_message(
+        &self,
+        username: &str,
+        device_id: &str,
+        event: ToDeviceEvent,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let user = db.users.iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.to_device_position += 1;
+        let id = user.to_device_position;
+        user.to_device.entry(device_id.to_string()).or_default().push((id, event));
+
+        let _ = user.to_device_notify.send(());
+        Ok(())
+    }
+
+    async fn get_to_device_messages(
+        &self,
+        username: &str,
+        device_id: &str,
+        after: u64,
+        wait: bool,
+    ) -> Result<Vec<(u64, ToDeviceEvent)>, Error> {
+        let mut recv = {
+            let db = self.inner.read().await;
+            let user = match db.users.iter().find(|u| u.username == username) {
+                Some(user) => user,
+                None => return Ok(Vec::new()),
+            };
+            let messages = user.to_device_after(device_id, after);
+            if !(wait && messages.is_empty()) {
+                return Ok(messages);
+            }
+            user.to_device_notify.subscribe()
+        };
+        // as with events, the lock is released while waiting so that the message can be queued
+        let _ = recv.recv().await;
+
+        let db = self.inner.read().await;
+        Ok(db.users.iter()
+            .find(|u| u.username == username)
+            .map(|user| user.to_device_after(device_id, after))
+            .unwrap_or_default())
+    }
+
+    async fn delete_to_device_messages(
+        &self,
+        username: &str,
+        device_id: &str,
+        up_to: u64,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let messages = db.users.iter_mut()
+            .find(|u| u.username == username)
+            .and_then(|u| u.to_device.get_mut(device_id));
+        if let Some(messages) = messages {
+            messages.retain(|(id, _)| *id > up_to);
+        }
+        Ok(())
     }
 
     async fn record_txn(&self, token: Uuid, txn_id: String) -> Result<bool, Error> {
@@ -163,6 +714,18 @@ impl Storage for MemStorageHandle {
             .map(|u| u.profile.clone()))
     }
 
+    async fn search_users(&self, search_term: &str)
+        -> Result<Vec<(String, UserProfile)>, Error> {
+        let search_term = search_term.to_lowercase();
+        let db = self.inner.read().await;
+        Ok(db
+            .users
+            .iter()
+            .filter(|u| !u.is_guest && user_matches(&u.username, &u.profile, &search_term))
+            .map(|u| (u.username.clone(), u.profile.clone()))
+            .collect())
+    }
+
     async fn set_avatar_url(&self, username: &str, avatar_url: &str) -> Result<(), Error> {
         let mut db = self.inner.write().await;
         let user = db
@@ -185,9 +748,136 @@ impl Storage for MemStorageHandle {
         Ok(())
     }
 
+    async fn create_threepid_session(
+        &self,
+        client_secret: &str,
+        medium: Medium,
+        address: &str,
+    ) -> Result<String, Error> {
+        let mut db = self.inner.write().await;
+        let sid = Uuid::new_v4().to_simple().to_string();
+        db.threepid_sessions.insert(sid.clone(), ThreepidSession {
+            client_secret: client_secret.to_string(),
+            medium,
+            address: address.to_string(),
+            validated_at: None,
+        });
+        Ok(sid)
+    }
+
+    async fn validate_threepid_session(&self, sid: &str) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        if let Some(session) = db.threepid_sessions.get_mut(sid) {
+            session.validated_at = Some(chrono::Utc::now().timestamp_millis());
+        }
+        Ok(())
+    }
+
+    async fn get_threepid_session(
+        &self,
+        sid: &str,
+        client_secret: &str,
+    ) -> Result<Option<ThreepidSession>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.threepid_sessions
+            .get(sid)
+            .filter(|session| session.client_secret == client_secret)
+            .cloned())
+    }
+
+    async fn create_uiaa_session(&self, request: &str) -> Result<String, Error> {
+        let mut db = self.inner.write().await;
+        let session_id = Uuid::new_v4().to_simple().to_string();
+        db.uiaa_sessions.insert(session_id.clone(), UiaaSession {
+            created_at: chrono::Utc::now().timestamp_millis(),
+            completed: Vec::new(),
+            request: request.to_string(),
+        });
+        Ok(session_id)
+    }
+
+    async fn get_uiaa_session(&self, session_id: &str) -> Result<Option<UiaaSession>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.uiaa_sessions.get(session_id).cloned())
+    }
+
+    async fn complete_uiaa_stage(&self, session_id: &str, stage: &str) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        if let Some(session) = db.uiaa_sessions.get_mut(session_id) {
+            session.completed.push(stage.to_string());
+        }
+        Ok(())
+    }
+
+    async fn delete_uiaa_session(&self, session_id: &str) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        db.uiaa_sessions.remove(session_id);
+        Ok(())
+    }
+
+    async fn purge_expired_uiaa_sessions(&self, before: i64) -> Result<usize, Error> {
+        let mut db = self.inner.write().await;
+        let count = db.uiaa_sessions.len();
+        db.uiaa_sessions.retain(|_, session| session.created_at >= before);
+        Ok(count - db.uiaa_sessions.len())
+    }
+
+    async fn add_threepid(&self, username: &str, threepid: Threepid) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let existing = db.threepids.iter()
+            .find(|(_, t)| t.medium == threepid.medium && t.address == threepid.address);
+        match existing {
+            Some((owner, _)) if owner == username => return Ok(()),
+            Some(_) => return Err(ErrorKind::ThreepidInUse.into()),
+            None => {},
+        }
+        db.threepids.push((username.to_string(), threepid));
+        Ok(())
+    }
+
+    async fn get_threepid_owner(
+        &self,
+        medium: Medium,
+        address: &str,
+    ) -> Result<Option<String>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.threepids.iter()
+            .find(|(_, t)| t.medium == medium && t.address == address)
+            .map(|(owner, _)| owner.clone()))
+    }
+
+    async fn get_threepids(&self, username: &str) -> Result<Vec<Threepid>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.threepids.iter()
+            .filter(|(owner, _)| owner == username)
+            .map(|(_, t)| t.clone())
+            .collect())
+    }
+
+    async fn remove_threepid(
+        &self,
+        username: &str,
+        medium: Medium,
+        address: &str,
+    ) -> Result<bool, Error> {
+        let mut db = self.inner.write().await;
+        let old_len = db.threepids.len();
+        db.threepids.retain(|(owner, t)| {
+            owner != username || t.medium != medium || t.address != address
+        });
+        Ok(db.threepids.len() != old_len)
+    }
+
     async fn add_pdus(&self, pdus: &[StoredPdu]) -> Result<(), Error> {
         let mut db = self.inner.write().await;
         for pdu in pdus {
+            let is_stored = db.rooms
+                .get(pdu.room_id())
+                .map(|room| room.events.iter().any(|e| e.event_id() == pdu.event_id()))
+                .unwrap_or(false);
+            if is_stored {
+                continue;
+            }
             match pdu.event_content() {
                 EventContent::Create(_) => {
                     db.rooms.insert(
@@ -197,11 +887,20 @@ impl Storage for MemStorageHandle {
                 }
                 _ => {},
             }
-            db.rooms
+            let room = db.rooms
                 .get_mut(pdu.room_id())
-                .ok_or(ErrorKind::RoomNotFound)?
-                .events
-                .push(pdu.clone());
+                .ok_or(ErrorKind::RoomNotFound)?;
+            let mut pdu = pdu.clone();
+            pdu.stream_ordering = room.next_stream_ordering();
+            room.events.push(pdu.clone());
+
+            if let (EventContent::Redaction(_), Some(target_id)) = (pdu.event_content(), pdu.redacts()) {
+                if pdu.did_pass_auth() {
+                    if let Some(target) = room.events.iter_mut().find(|e| e.event_id() == target_id) {
+                        *target = target.clone().redact_because(&pdu);
+                    }
+                }
+            }
         }
         Ok(())
     }
@@ -217,7 +916,7 @@ impl Storage for MemStorageHandle {
         }
         let event_ids = prev_events
             .iter()
-            .map(|pdu| pdu.event_id())
+            .map(|pdu| pdu.event_id().to_owned())
             .collect::<Vec<_>>();
         let max_depth = prev_events
             .iter()
@@ -232,76 +931,188 @@ impl Storage for MemStorageHandle {
         query: EventQuery<'a>,
         wait: bool,
     ) -> Result<(Vec<StoredPdu>, usize), Error> {
-        let mut ret = Vec::new();
-        let (mut from, mut to) = match &query.query_type {
-            &QueryType::Timeline { from, to } => {
-                (from, to)
-            },
-            &QueryType::State { at, .. } => {
-                (0, at)
-            },
+        let (from, to) = query.query_type.range();
+
+        let (mut recv, seen, mut due) = {
+            let db = self.inner.read().await;
+            let room = db.rooms.get(query.room_id)
+                .ok_or(ErrorKind::RoomNotFound)?;
+            let to = to.unwrap_or_else(|| room.next_stream_ordering() - 1);
+            let ret = query.select(room.events_between(from, to).cloned());
+            if !(wait && ret.is_empty() && query.query_type.is_timeline()) {
+                return Ok((ret, to));
+            }
+            let due = room.wakeup_due(Instant::now());
+            (room.notify_send.subscribe(), room.next_stream_ordering(), due)
         };
+        // The lock is released while waiting, or the events we're waiting for couldn't be added.
+        loop {
+            // This returns a result, but one of the possible errors is "there are multiple
+            // events" which is what we're waiting for anyway, and the other is "send half has
+            // been dropped" which means the room is gone, and that's noticed below
+            let notified = match due {
+                Some(due) => tokio::select! {
+                    _ = recv.recv() => true,
+                    _ = delay_until(tokio::time::Instant::from_std(due)) => false,
+                },
+                None => {
+                    let _ = recv.recv().await;
+                    true
+                },
+            };
+            if !notified {
+                break;
+            }
+            // new events are returned straight away, but ephemeral changes are collected until
+            // their burst is over
+            let db = self.inner.read().await;
+            let room = db.rooms.get(query.room_id)
+                .ok_or(ErrorKind::RoomNotFound)?;
+            let now = Instant::now();
+            if room.next_stream_ordering() != seen || room.ephemeral_due(now).is_none() {
+                break;
+            }
+            due = room.wakeup_due(now);
+        }
 
+        // same again, up to whatever has arrived
         let db = self.inner.read().await;
         let room = db.rooms.get(query.room_id)
             .ok_or(ErrorKind::RoomNotFound)?;
-        if let None = to {
-            to = Some(room.events.len() - 1);
-        }
+        let to = room.next_stream_ordering() - 1;
+        Ok((query.select(room.events_between(from, to).cloned()), to))
+    }
 
-        if let Some(range) = room.events.get(from..=to.unwrap()) {
-            ret.extend(
-                range.iter()
-                .filter(|pdu| query.matches(&pdu.inner()))
-                .cloned());
+    async fn get_user_rooms(&self, user_id: &MatrixId) -> Result<HashMap<String, Membership>, Error> {
+        let db = self.inner.read().await;
+        let mut ret = HashMap::new();
+        for (room_id, room) in db.rooms.iter() {
+            let membership = room.events.iter().rev().find_map(|pdu| match pdu.event_content() {
+                EventContent::Member(content) if pdu.state_key() == Some(user_id.as_str()) => {
+                    Some(content.membership.clone())
+                },
+                _ => None,
+            });
+            if let Some(membership) = membership {
+                ret.insert(room_id.clone(), membership);
+            }
         }
+        Ok(ret)
+    }
 
-        if wait && ret.is_empty() && query.query_type.is_timeline() {
-            let mut recv = room.notify_send.subscribe();
-            // Release locks; we are about to wait for new events to come in, and they can't if we've
-            // locked the db
-            drop(db);
-            // This returns a result, but one of the possible errors is "there are multiple
-            // events" which is what we're waiting for anyway, and the other is "send half has
-            // been dropped" which would mean we have bigger problems than this one query
-            let _ = recv.recv().await;
-            from = to.unwrap();
-            to = None;
-        } else {
-            return Ok((ret, to.unwrap()));
+    async fn get_member_pdus(
+        &self,
+        room_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StoredPdu>, Error> {
+        let db = self.inner.read().await;
+        let room = db.rooms.get(room_id).ok_or(ErrorKind::RoomNotFound)?;
+        // later events replace earlier ones, leaving each member's current event
+        let mut members = BTreeMap::new();
+        for pdu in room.events.iter() {
+            if pdu.event_content().get_type() != "m.room.member" {
+                continue;
+            }
+            match pdu.state_key() {
+                Some(state_key) if after.map_or(true, |after| state_key > after) => {
+                    members.insert(state_key, pdu);
+                },
+                _ => {},
+            }
         }
+        Ok(members.values().take(limit).map(|pdu| (*pdu).clone()).collect())
+    }
 
-        // same again
+    async fn count_state_events(&self, room_id: &str) -> Result<usize, Error> {
         let db = self.inner.read().await;
-        let room = db.rooms.get(query.room_id)
-            .ok_or(ErrorKind::RoomNotFound)?;
-        if let None = to {
-            to = Some(room.events.len() - 1);
+        let room = db.rooms.get(room_id).ok_or(ErrorKind::RoomNotFound)?;
+        let keys: HashSet<_> = room.events.iter()
+            .filter_map(|pdu| Some((pdu.event_content().get_type(), pdu.state_key()?)))
+            .collect();
+        Ok(keys.len())
+    }
+
+    async fn get_rooms(&self) -> Result<Vec<String>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.rooms.keys().cloned().collect())
+    }
+
+    async fn purge_events(&self, room_id: &str, before_ts: i64) -> Result<usize, Error> {
+        let mut db = self.inner.write().await;
+        let room = db.rooms.get_mut(room_id).ok_or(ErrorKind::RoomNotFound)?;
+        let mut purged = 0;
+        for pdu in room.events.iter_mut().filter(|pdu| should_purge(pdu, before_ts)) {
+            *pdu = pdu.clone().redact();
+            purged += 1;
         }
+        Ok(purged)
+    }
+
+    async fn room_exists(&self, room_id: &str) -> Result<bool, Error> {
+        Ok(self.inner.read().await.rooms.contains_key(room_id))
+    }
 
-        if let Some(range) = room.events.get(from..=to.unwrap()) {
-            ret.extend(
-                range.iter()
-                .filter(|pdu| query.matches(&pdu.inner()))
-                .cloned());
+    async fn delete_room(&self, room_id: &str) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        db.rooms.remove(room_id);
+        db.aliases.retain(|_alias, target| target != room_id);
+        let MemStorage { aliases, alias_creators, .. } = &mut *db;
+        alias_creators.retain(|alias, _creator| aliases.contains_key(alias));
+        db.published_rooms.remove(room_id);
+        for user in db.users.iter_mut() {
+            user.room_account_data.remove(room_id);
         }
+        Ok(())
+    }
 
-        if query.query_type.is_state() {
-            ret.reverse();
-/*            let mut seen = HashSet::new();
-            // remove pdus that are older than another pdu with the same state key
-            ret.retain(|pdu| {
-                seen.insert(pdu.state_key().to_string().unwrap())
-            });*/
-            ret.reverse();
+    async fn set_room_alias(&self, alias: &str, room_id: &str, creator: &str) -> Result<bool, Error> {
+        let mut db = self.inner.write().await;
+        if db.aliases.contains_key(alias) {
+            return Ok(false);
         }
+        db.aliases.insert(alias.to_string(), room_id.to_string());
+        db.alias_creators.insert(alias.to_string(), creator.to_string());
+        Ok(true)
+    }
 
-        Ok((ret, to.unwrap()))
+    async fn get_room_alias(&self, alias: &str) -> Result<Option<String>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.aliases.get(alias).cloned())
     }
 
-    async fn get_rooms(&self) -> Result<Vec<String>, Error> {
+    async fn get_room_alias_creator(&self, alias: &str) -> Result<Option<String>, Error> {
         let db = self.inner.read().await;
-        Ok(db.rooms.keys().cloned().collect())
+        Ok(db.alias_creators.get(alias).cloned())
+    }
+
+    async fn delete_room_alias(&self, alias: &str) -> Result<bool, Error> {
+        let mut db = self.inner.write().await;
+        db.alias_creators.remove(alias);
+        Ok(db.aliases.remove(alias).is_some())
+    }
+
+    async fn get_room_aliases(&self, room_id: &str) -> Result<Vec<String>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.aliases.iter()
+            .filter(|(_alias, target)| *target == room_id)
+            .map(|(alias, _target)| alias.clone())
+            .collect())
+    }
+
+    async fn set_room_published(&self, room_id: &str, published: bool) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        if published {
+            db.published_rooms.insert(room_id.to_string());
+        } else {
+            db.published_rooms.remove(room_id);
+        }
+        Ok(())
+    }
+
+    async fn get_published_rooms(&self) -> Result<Vec<String>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.published_rooms.iter().cloned().collect())
     }
 
     async fn get_pdu(
@@ -322,6 +1133,7 @@ impl Storage for MemStorageHandle {
     async fn get_all_ephemeral(
         &self,
         room_id: &str,
+        user_id: &MatrixId,
     ) -> Result<HashMap<String, JsonValue>, Error> {
         let db = self.inner.read().await;
         let room = db.rooms.get(room_id)
@@ -334,6 +1146,17 @@ impl Storage for MemStorageHandle {
             typing.user_ids.insert(mxid.clone());
         }
         ephemeral.insert(String::from("m.typing"), serde_json::to_value(typing).unwrap());
+
+        let mut receipts = Receipts::default();
+        for (mxid, (event_id, ts)) in room.receipts.iter() {
+            receipts.insert(event_id, ReceiptType::Read, mxid, Receipt { ts: *ts });
+        }
+        if let Some((event_id, ts)) = room.private_receipts.get(user_id) {
+            receipts.insert(event_id, ReceiptType::ReadPrivate, user_id, Receipt { ts: *ts });
+        }
+        if !receipts.is_empty() {
+            ephemeral.insert(String::from("m.receipt"), serde_json::to_value(receipts).unwrap());
+        }
         Ok(ephemeral)
     }
 
@@ -370,7 +1193,7 @@ impl Storage for MemStorageHandle {
             Some(c) => room.ephemeral.insert(String::from(event_type), c),
             None => room.ephemeral.remove(event_type),
         };
-        let _ = room.notify_send.send(());
+        room.notify_ephemeral();
         Ok(())
     }
 
@@ -384,8 +1207,10 @@ impl Storage for MemStorageHandle {
         let mut db = self.inner.write().await;
         let room = db.rooms.get_mut(room_id)
             .ok_or(ErrorKind::RoomNotFound)?;
+        let now = Instant::now();
+        room.typing.retain(|_, timeout| *timeout > now);
         if is_typing {
-            room.typing.insert(user_id.clone(), Instant::now() + Duration::from_millis(timeout as u64));
+            room.typing.insert(user_id.clone(), now + Duration::from_millis(timeout as u64));
         } else {
             room.typing.remove(user_id);
         }
@@ -394,6 +1219,95 @@ impl Storage for MemStorageHandle {
         Ok(())
     }
 
+    async fn set_receipt(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        receipt_type: ReceiptType,
+        event_id: &str,
+    ) -> Result<bool, Error> {
+        let mut db = self.inner.write().await;
+        let room = db.rooms.get_mut(room_id)
+            .ok_or(ErrorKind::RoomNotFound)?;
+        let receipts = match receipt_type {
+            ReceiptType::Read => &mut room.receipts,
+            ReceiptType::ReadPrivate => &mut room.private_receipts,
+        };
+        if receipts.get(user_id).map(|(e, _)| e == event_id).unwrap_or(false) {
+            return Ok(false);
+        }
+        let ts = chrono::Utc::now().timestamp_millis();
+        receipts.insert(user_id.clone(), (event_id.to_string(), ts));
+        let _ = room.notify_send.send(());
+        Ok(true)
+    }
+
+    async fn set_fully_read(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        event_id: &str,
+    ) -> Result<bool, Error> {
+        let mut db = self.inner.write().await;
+        let room = db.rooms.get_mut(room_id)
+            .ok_or(ErrorKind::RoomNotFound)?;
+        let old = room.fully_read.insert(user_id.clone(), event_id.to_string());
+        let changed = old.as_deref() != Some(event_id);
+        if changed {
+            let _ = room.notify_send.send(());
+        }
+        Ok(changed)
+    }
+
+    async fn get_fully_read(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+    ) -> Result<Option<String>, Error> {
+        let db = self.inner.read().await;
+        let room = db.rooms.get(room_id)
+            .ok_or(ErrorKind::RoomNotFound)?;
+        Ok(room.fully_read.get(user_id).cloned())
+    }
+
+    async fn set_room_forgotten(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        forgotten: bool,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let room = db.rooms.get_mut(room_id)
+            .ok_or(ErrorKind::RoomNotFound)?;
+        if forgotten {
+            room.forgotten_by.insert(user_id.clone());
+        } else {
+            room.forgotten_by.remove(user_id);
+        }
+        Ok(())
+    }
+
+    async fn is_room_forgotten(&self, room_id: &str, user_id: &MatrixId) -> Result<bool, Error> {
+        let db = self.inner.read().await;
+        Ok(db.rooms.get(room_id).map(|room| room.forgotten_by.contains(user_id)).unwrap_or(false))
+    }
+
+    async fn set_presence(&self, username: &str, state: PresenceState) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let user = db.users.iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.presence = Some(Presence::changed_to(user.presence.take(), state));
+        Ok(())
+    }
+
+    async fn get_presence(&self, username: &str) -> Result<Option<Presence>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.users.iter()
+            .find(|u| u.username == username)
+            .and_then(|u| u.presence.clone()))
+    }
+
     async fn get_user_account_data(
         &self,
         username: &str,
@@ -408,6 +1322,98 @@ impl Storage for MemStorageHandle {
         Ok(map)
     }
 
+    async fn set_user_account_data(
+        &self,
+        username: &str,
+        ty: &str,
+        content: JsonValue,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let user = db
+            .users
+            .iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.account_data.insert(ty.to_string(), content);
+        user.account_data_position += 1;
+        user.account_data_changes.insert(ty.to_string(), user.account_data_position);
+        Ok(())
+    }
+
+    async fn get_user_account_data_since(
+        &self,
+        username: &str,
+        since: usize,
+    ) -> Result<(HashMap<String, JsonValue>, usize), Error> {
+        let db = self.inner.read().await;
+        let user = match db.users.iter().find(|u| u.username == username) {
+            Some(u) => u,
+            None => return Ok((HashMap::new(), 0)),
+        };
+        let changed = user.account_data
+            .iter()
+            .filter(|(ty, _)| {
+                let changed_at = user.account_data_changes.get(*ty).copied().unwrap_or(0);
+                changed_at > since || since == 0
+            })
+            .map(|(ty, content)| (ty.clone(), content.clone()))
+            .collect();
+        Ok((changed, user.account_data_position))
+    }
+
+    async fn set_room_account_data(
+        &self,
+        username: &str,
+        room_id: &str,
+        ty: &str,
+        content: JsonValue,
+    ) -> Result<(), Error> {
+        let mut db = self.inner.write().await;
+        let user = db.users.iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.account_data_position += 1;
+        user.room_account_data.entry(room_id.to_string())
+            .or_default()
+            .insert(ty.to_string(), (content, user.account_data_position));
+        Ok(())
+    }
+
+    async fn get_room_account_data_since(
+        &self,
+        username: &str,
+        room_id: &str,
+        since: usize,
+    ) -> Result<HashMap<String, JsonValue>, Error> {
+        let db = self.inner.read().await;
+        let room = db.users.iter()
+            .find(|u| u.username == username)
+            .and_then(|u| u.room_account_data.get(room_id));
+        Ok(room.into_iter()
+            .flatten()
+            .filter(|(_, (_, changed_at))| *changed_at > since || since == 0)
+            .map(|(ty, (content, _))| (ty.clone(), content.clone()))
+            .collect())
+    }
+
+    async fn create_filter(&self, username: &str, filter: JsonValue) -> Result<String, Error> {
+        let mut db = self.inner.write().await;
+        let user = db.users.iter_mut()
+            .find(|u| u.username == username)
+            .ok_or(ErrorKind::UserNotFound)?;
+        let filter_id = Uuid::new_v4().to_simple().to_string();
+        user.filters.insert(filter_id.clone(), filter);
+        Ok(filter_id)
+    }
+
+    async fn get_filter(&self, username: &str, filter_id: &str)
+        -> Result<Option<JsonValue>, Error> {
+        let db = self.inner.read().await;
+        Ok(db.users.iter()
+            .find(|u| u.username == username)
+            .and_then(|user| user.filters.get(filter_id).cloned()))
+    }
+
     async fn get_batch(&self, id: &str) -> Result<Option<Batch>, Error> {
         let db = self.inner.read().await;
         Ok(db.batches.get(id).cloned())
@@ -2,10 +2,10 @@ use async_trait::async_trait;
 use enum_extract::extract;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
-use std::collections::{HashSet, HashMap};
+use std::{collections::{HashSet, HashMap}, time::Duration};
 use uuid::Uuid;
 
-use crate::{error::Error, events::{Event, EventContent, pdu::StoredPdu, room::Membership, room_version::VersionedPdu}, util::MatrixId};
+use crate::{error::Error, events::{Event, EventContent, ephemeral::{PresenceState, ReceiptType, ToDeviceEvent}, pdu::StoredPdu, room::Membership, room_version::VersionedPdu}, util::MatrixId};
 
 #[cfg(feature = "storage-mem")]
 pub mod mem;
@@ -14,12 +14,142 @@ pub mod sled;
 #[cfg(feature = "storage-postgres")]
 pub mod postgres;
 
+/// How long a logged out device's access tokens are kept for, so that clients still using them
+/// can be told to log in again, before `purge_expired_tokens` deletes them.
+const LOGGED_OUT_TOKEN_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct UserProfile {
     pub avatar_url: Option<String>,
     pub displayname: Option<String>,
 }
 
+/// The presence a user last set.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Presence {
+    pub state: PresenceState,
+    /// Milliseconds since the unix epoch
+    pub last_active_ts: i64,
+}
+
+impl Presence {
+    /// What a user's presence becomes when they change it to `state`, given what it was before.
+    fn changed_to(old: Option<Presence>, state: PresenceState) -> Presence {
+        let last_active_ts = match old {
+            Some(old) if state != PresenceState::Online => old.last_active_ts,
+            _ => chrono::Utc::now().timestamp_millis(),
+        };
+        Presence { state, last_active_ts }
+    }
+
+    /// How long a user can go without activity before they stop counting as online.
+    pub const IDLE_TIMEOUT_MS: i64 = 5 * 60 * 1000;
+
+    /// The user's presence as of `now`, in milliseconds since the unix epoch. Users who said they
+    /// were online but haven't been active for a while are shown as unavailable instead.
+    pub fn state_at(&self, now: i64) -> PresenceState {
+        if self.state == PresenceState::Online && now - self.last_active_ts > Presence::IDLE_TIMEOUT_MS {
+            PresenceState::Unavailable
+        } else {
+            self.state
+        }
+    }
+}
+
+/// Whether purging the history from before `before_ts` should drop this event's content. State is
+/// always kept, and events that have already lost their content are skipped.
+fn should_purge(pdu: &StoredPdu, before_ts: i64) -> bool {
+    pdu.state_key().is_none()
+        && pdu.origin_server_ts() < before_ts
+        && pdu.event_content().content_as_json() != JsonValue::Object(Default::default())
+}
+
+/// When an access token that expires at `expires_at` (if ever) should expire once its device is
+/// logged out, in milliseconds since the unix epoch.
+fn logged_out_expiry(expires_at: Option<i64>) -> i64 {
+    let lifetime = LOGGED_OUT_TOKEN_LIFETIME.as_millis() as i64;
+    let kept_until = chrono::Utc::now().timestamp_millis() + lifetime;
+    expires_at.map(|t| t.min(kept_until)).unwrap_or(kept_until)
+}
+
+/// Counts one-time key IDs by the algorithm at their start.
+fn count_by_algorithm<'a>(key_ids: impl Iterator<Item = &'a str>) -> HashMap<String, usize> {
+    let mut counts = HashMap::new();
+    for key_id in key_ids {
+        let algorithm = key_id.split(':').next().unwrap_or(key_id);
+        *counts.entry(String::from(algorithm)).or_default() += 1;
+    }
+    counts
+}
+
+/// Whether a user turns up when searching for `search_term`, which must already be lowercase.
+fn user_matches(username: &str, profile: &UserProfile, search_term: &str) -> bool {
+    username.to_lowercase().contains(search_term)
+        || profile.displayname.as_ref()
+            .map(|name| name.to_lowercase().contains(search_term))
+            .unwrap_or(false)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Medium {
+    Email,
+    // Phone number, including calling code
+    Msisdn,
+}
+
+impl Medium {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Medium::Email => "email",
+            Medium::Msisdn => "msisdn",
+        }
+    }
+}
+
+/// A third party identifier (email address or phone number) bound to an account.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Threepid {
+    pub medium: Medium,
+    pub address: String,
+    /// Milliseconds since the unix epoch
+    pub validated_at: i64,
+    /// Milliseconds since the unix epoch
+    pub added_at: i64,
+}
+
+/// An attempt to prove ownership of a third party identifier, identified by its session ID.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ThreepidSession {
+    pub client_secret: String,
+    pub medium: Medium,
+    pub address: String,
+    /// Milliseconds since the unix epoch, or None if the session hasn't been validated yet
+    pub validated_at: Option<i64>,
+}
+
+/// A user-interactive auth session, tracking which stages of authentication have been done.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UiaaSession {
+    /// Milliseconds since the unix epoch
+    pub created_at: i64,
+    /// The stages completed so far, in order
+    pub completed: Vec<String>,
+    /// A digest of the request the session was started for, so that it can't be used to finish a
+    /// different one
+    pub request: String,
+}
+
+/// A device a user has logged in on. Devices are created the first time they're given an access
+/// token.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Device {
+    pub device_id: String,
+    pub display_name: Option<String>,
+    /// Milliseconds since the unix epoch
+    pub last_seen_ts: i64,
+}
+
 #[derive(Clone)]
 pub struct EventQuery<'a> {
     pub query_type: QueryType<'a>,
@@ -109,7 +239,43 @@ impl<'a> EventQuery<'a> {
     }
 }
 
+impl<'a> EventQuery<'a> {
+    /// Picks the query's results out of the events in its range, which must be given in stream
+    /// order. This is what every backend's `query_pdus` comes down to.
+    ///
+    /// Timeline queries get every matching event. State queries only look at the latest event
+    /// for each type and state key, so they get the room's state as of the end of the range;
+    /// an event which matches but has been overwritten isn't returned. Either way the results
+    /// stay in stream order.
+    pub fn select(&self, pdus: impl IntoIterator<Item = StoredPdu>) -> Vec<StoredPdu> {
+        let pdus = pdus.into_iter();
+        if self.query_type.is_timeline() {
+            return pdus.filter(|pdu| self.matches(pdu.inner())).collect();
+        }
+
+        let mut state: Vec<StoredPdu> = pdus.filter(|pdu| pdu.state_key().is_some()).collect();
+        let mut seen = HashSet::new();
+        state.reverse();
+        state.retain(|pdu| {
+            let key = (pdu.event_content().get_type().to_owned(), pdu.state_key().unwrap().to_owned());
+            seen.insert(key)
+        });
+        state.reverse();
+        state.retain(|pdu| self.matches(pdu.inner()));
+        state
+    }
+}
+
 impl<'a> QueryType<'a> {
+    /// The first and last stream orderings the query covers. A missing end means up to the
+    /// latest event.
+    pub fn range(&self) -> (usize, Option<usize>) {
+        match *self {
+            QueryType::Timeline { from, to } => (from, to),
+            QueryType::State { at, .. } => (0, at),
+        }
+    }
+
     pub fn is_timeline(&self) -> bool {
         match self {
             QueryType::Timeline { .. } => true,
@@ -125,17 +291,158 @@ impl<'a> QueryType<'a> {
     }
 }
 
-#[derive(Clone, Default, Deserialize, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Batch {
     /// Indices into the event storage of the rooms that the user is in.
     pub rooms: HashMap<String, usize>,
     /// A set of rooms to which the user has been invited, where they are already aware of this.
     pub invites: HashSet<String>,
+    /// The layout version of this batch. Batches from before this field existed are version 1.
+    ///
+    /// Batches are stored as JSON, so fields added since are filled in by their defaults when an
+    /// older batch is read.
+    #[serde(default = "Batch::first_version")]
+    pub version: u32,
+    /// How far through the user's account data changes the user is.
+    #[serde(default)]
+    pub account_data: usize,
+    /// The users whose member events have been sent down in each room's state, so lazy loading
+    /// doesn't send them again.
+    #[serde(default)]
+    pub sent_members: HashMap<String, HashSet<String>>,
+    /// The presence last sent for each user, so only changes are sent.
+    #[serde(default)]
+    pub presence: HashMap<String, PresenceState>,
+    /// The device list version last seen for each user sharing a room with the user, by user ID.
+    #[serde(default)]
+    pub device_lists: HashMap<String, usize>,
+    /// A set of rooms which the user has knocked on, where they are already aware of this.
+    #[serde(default)]
+    pub knocks: HashSet<String>,
+    /// The ID of the last to-device message sent to the device. Syncing from this batch shows
+    /// that the device got it, so it and the ones before it can be deleted.
+    #[serde(default)]
+    pub to_device: u64,
+    /// The unread counts sent for each joined room, so the next sync only has to count the
+    /// events that have arrived since.
+    #[serde(default)]
+    pub unread: HashMap<String, UnreadCounts>,
+}
+
+/// A user's unread counts in a room, and the part of the timeline they were counted over.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct UnreadCounts {
+    /// The stream ordering counting started from, just after the user's fully read marker or
+    /// their membership. If that moves, the counts are stale.
+    pub counted_from: usize,
+    /// The stream ordering counting stopped before, and the next count carries on from.
+    pub counted_until: usize,
+    pub notification_count: usize,
+    pub highlight_count: usize,
+}
+
+impl Batch {
+    pub const CURRENT_VERSION: u32 = 9;
+
+    fn first_version() -> u32 {
+        1
+    }
+
+    /// Migrates a batch from an older layout to the current one.
+    ///
+    /// Returns None if the batch can't be understood (e.g. it was written by a newer version of
+    /// the server), in which case the client should just get a full sync.
+    pub fn upgrade(mut self) -> Option<Batch> {
+        match self.version {
+            1 => {
+                // version 2 only added the version field
+                self.version = 2;
+                self.upgrade()
+            },
+            2 => {
+                // version 3 added account data tracking, and starting from the beginning of the
+                // stream just means the user gets all of their account data once more
+                self.version = 3;
+                self.account_data = 0;
+                self.upgrade()
+            },
+            3 => {
+                // version 4 added lazy loading's record of sent members, and forgetting them just
+                // means they're sent once more
+                self.version = 4;
+                self.sent_members = HashMap::new();
+                self.upgrade()
+            },
+            4 => {
+                // version 5 added the presence that has been sent, and forgetting it just means
+                // everyone's presence is sent once more
+                self.version = 5;
+                self.presence = HashMap::new();
+                self.upgrade()
+            },
+            5 => {
+                // version 6 added device list tracking, and forgetting it just means everyone's
+                // devices are queried once more
+                self.version = 6;
+                self.device_lists = HashMap::new();
+                self.upgrade()
+            },
+            6 => {
+                // version 7 added the knocks the user knows about, and forgetting them just means
+                // they're sent once more
+                self.version = 7;
+                self.knocks = HashSet::new();
+                self.upgrade()
+            },
+            7 => {
+                // version 8 added the last to-device message sent, and starting from 0 just means
+                // messages are kept until a later batch acknowledges them
+                self.version = 8;
+                self.to_device = 0;
+                self.upgrade()
+            },
+            8 => {
+                // version 9 added unread counts, and forgetting them just means they're counted
+                // from scratch once more
+                self.version = 9;
+                self.unread = HashMap::new();
+                Some(self)
+            },
+            Batch::CURRENT_VERSION => Some(self),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Batch {
+            rooms: HashMap::new(),
+            invites: HashSet::new(),
+            version: Batch::CURRENT_VERSION,
+            account_data: 0,
+            sent_members: HashMap::new(),
+            presence: HashMap::new(),
+            device_lists: HashMap::new(),
+            knocks: HashSet::new(),
+            to_device: 0,
+            unread: HashMap::new(),
+        }
+    }
 }
 
 #[async_trait]
 pub trait StorageManager: Send + Sync {
+    /// Returns `ErrorKind::StorageUnavailable` if the backend can't be reached, e.g. when a
+    /// database server is down.
     async fn get_handle(&self) -> Result<Box<dyn Storage>, Error>;
+
+    /// Deletes the access tokens which expired at or before `now` (in milliseconds since the unix
+    /// epoch), and returns how many there were. Refresh tokens issued with them still work.
+    ///
+    /// This is housekeeping for the whole server rather than something a request does, so it's
+    /// done here rather than through a handle.
+    async fn purge_expired_tokens(&self, now: i64) -> Result<usize, Error>;
 }
 
 #[async_trait]
@@ -146,6 +453,25 @@ pub trait Storage: Send + Sync {
         password: &str,
     ) -> Result<(), Error>;
 
+    /// Creates a guest account. Guests have no password, so they can only use the access tokens
+    /// issued when they register.
+    async fn create_guest_user(&self, username: &str) -> Result<(), Error>;
+
+    /// Returns whether the given user is a guest.
+    async fn is_guest(&self, username: &str) -> Result<bool, Error>;
+
+    /// Deletes an account, to undo a registration that failed partway through. Nothing else the
+    /// user has is touched, so this is only for accounts that haven't been used yet.
+    async fn delete_user(&self, username: &str) -> Result<(), Error>;
+
+    /// Returns whether an account, guest or otherwise, has the given username.
+    async fn user_exists(&self, username: &str) -> Result<bool, Error> {
+        Ok(self.get_profile(username).await?.is_some())
+    }
+
+    /// Replaces a user's password with a new argon2 hash, encoded the way `create_user` does it.
+    async fn set_password_hash(&self, username: &str, password_hash: &str) -> Result<(), Error>;
+
     async fn verify_password(
         &self,
         username: &str,
@@ -158,14 +484,135 @@ pub trait Storage: Send + Sync {
         device_id: &str,
     ) -> Result<Uuid, Error>;
 
+    /// Creates an access token which stops working after `lifetime`, along with a refresh token
+    /// that can later be exchanged for a new pair.
+    ///
+    /// Returns (access_token, refresh_token).
+    async fn create_refreshable_access_token(
+        &self,
+        username: &str,
+        device_id: &str,
+        lifetime: Duration,
+    ) -> Result<(Uuid, Uuid), Error>;
+
+    /// Exchanges a refresh token for a new pair of tokens for the same device, as in
+    /// `create_refreshable_access_token`. The old refresh token and the access token issued with
+    /// it stop working.
+    ///
+    /// Returns None if the refresh token isn't valid.
+    async fn refresh_access_token(
+        &self,
+        refresh_token: Uuid,
+        lifetime: Duration,
+    ) -> Result<Option<(Uuid, Uuid)>, Error>;
+
     async fn delete_access_token(&self, token: Uuid) -> Result<(), Error>;
 
     /// Deletes all access tokens associated with the same user as this one
     async fn delete_all_access_tokens(&self, token: Uuid) -> Result<(), Error>;
 
-    /// Returns the username for which this token is valid, if any
+    /// Logs out the device that this token belongs to. All of the device's access tokens stop
+    /// working, but are remembered so that `try_auth` can report a soft logout for them until the
+    /// device logs in again, or until they expire after `LOGGED_OUT_TOKEN_LIFETIME`.
+    async fn logout_device(&self, token: Uuid) -> Result<(), Error>;
+
+    /// Logs out every device belonging to the same user as this token, as in `logout_device`.
+    async fn logout_all_devices(&self, token: Uuid) -> Result<(), Error>;
+
+    /// Returns the username for which this token is valid, if any, and marks its device as seen.
+    ///
+    /// Returns `ErrorKind::SoftLogout` if the token has expired or its device was logged out.
     async fn try_auth(&self, token: Uuid) -> Result<Option<String>, Error>;
 
+    /// Gets the ID of the device that this token was issued to.
+    async fn get_token_device(&self, token: Uuid) -> Result<Option<String>, Error>;
+
+    async fn get_devices(&self, username: &str) -> Result<Vec<Device>, Error>;
+
+    async fn get_device(&self, username: &str, device_id: &str) -> Result<Option<Device>, Error>;
+
+    /// Sets or clears a device's display name. Returns false if the device doesn't exist.
+    async fn set_device_display_name(
+        &self,
+        username: &str,
+        device_id: &str,
+        display_name: Option<&str>,
+    ) -> Result<bool, Error>;
+
+    /// Deletes a device along with all of its access and refresh tokens, and its keys.
+    async fn delete_device(&self, username: &str, device_id: &str) -> Result<(), Error>;
+
+    /// Stores the identity keys a device uploaded, replacing any it uploaded before.
+    async fn set_device_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+        keys: JsonValue,
+    ) -> Result<(), Error>;
+
+    /// Gets the identity keys of each of the user's devices that has uploaded some, by device ID.
+    async fn get_device_keys(&self, username: &str) -> Result<HashMap<String, JsonValue>, Error>;
+
+    /// How many times the user's device list has changed, through a device uploading keys or
+    /// being deleted. Syncs compare it to what they last saw to tell clients to query keys again.
+    async fn get_device_list_version(&self, username: &str) -> Result<usize, Error>;
+
+    /// Stores one-time keys a device uploaded, by key ID (`<algorithm>:<key_id>`). A key with the
+    /// same ID as one the device already has replaces it.
+    async fn add_one_time_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+        keys: HashMap<String, JsonValue>,
+    ) -> Result<(), Error>;
+
+    /// Removes and returns one of the device's one-time keys for the algorithm, along with its key
+    /// ID, or None if it has none left. Each key is only ever handed out once, even to claims made
+    /// at the same time.
+    async fn claim_one_time_key(
+        &self,
+        username: &str,
+        device_id: &str,
+        algorithm: &str,
+    ) -> Result<Option<(String, JsonValue)>, Error>;
+
+    /// Counts the device's unclaimed one-time keys, by algorithm.
+    async fn count_one_time_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+    ) -> Result<HashMap<String, usize>, Error>;
+
+    /// Queues a message for the device, to be handed over the next time it syncs.
+    async fn add_to_device_message(
+        &self,
+        username: &str,
+        device_id: &str,
+        event: ToDeviceEvent,
+    ) -> Result<(), Error>;
+
+    /// The messages queued for the device after the one with ID `after`, oldest first, along
+    /// with their IDs. IDs only ever go up, so the last one can be passed back in to get only
+    /// newer messages.
+    ///
+    /// If `wait` is true and there aren't any, this waits until one is queued.
+    async fn get_to_device_messages(
+        &self,
+        username: &str,
+        device_id: &str,
+        after: u64,
+        wait: bool,
+    ) -> Result<Vec<(u64, ToDeviceEvent)>, Error>;
+
+    /// Deletes the device's messages up to and including the one with ID `up_to`, once the device
+    /// is known to have them.
+    async fn delete_to_device_messages(
+        &self,
+        username: &str,
+        device_id: &str,
+        up_to: u64,
+    ) -> Result<(), Error>;
+
     /// Records a transaction ID into the given access token and returns whether it is new
     /// (unique).
     async fn record_txn(&self, token: Uuid, txn_id: String) -> Result<bool, Error>;
@@ -173,6 +620,11 @@ pub trait Storage: Send + Sync {
     /// Returns the given user's avatar URL and display name, if present
     async fn get_profile(&self, username: &str) -> Result<Option<UserProfile>, Error>;
 
+    /// Finds the non-guest users whose username or display name contains `search_term`, ignoring
+    /// case. Results are in no particular order.
+    async fn search_users(&self, search_term: &str)
+        -> Result<Vec<(String, UserProfile)>, Error>;
+
     async fn set_avatar_url(&self, username: &str, avatar_url: &str)
         -> Result<(), Error>;
 
@@ -182,6 +634,65 @@ pub trait Storage: Send + Sync {
         display_name: &str,
     ) -> Result<(), Error>;
 
+    /// Starts a validation session for a third party identifier and returns its ID.
+    async fn create_threepid_session(
+        &self,
+        client_secret: &str,
+        medium: Medium,
+        address: &str,
+    ) -> Result<String, Error>;
+
+    /// Marks a validation session as validated. Does nothing if the session doesn't exist.
+    async fn validate_threepid_session(&self, sid: &str) -> Result<(), Error>;
+
+    /// Returns the session with the given ID, as long as `client_secret` matches the one it was
+    /// created with.
+    async fn get_threepid_session(
+        &self,
+        sid: &str,
+        client_secret: &str,
+    ) -> Result<Option<ThreepidSession>, Error>;
+
+    /// Starts a user-interactive auth session for the request with the given digest, and returns
+    /// its ID.
+    async fn create_uiaa_session(&self, request: &str) -> Result<String, Error>;
+
+    async fn get_uiaa_session(&self, session_id: &str) -> Result<Option<UiaaSession>, Error>;
+
+    /// Records that a stage of a user-interactive auth session has been completed. Does nothing if
+    /// the session doesn't exist.
+    async fn complete_uiaa_stage(&self, session_id: &str, stage: &str) -> Result<(), Error>;
+
+    async fn delete_uiaa_session(&self, session_id: &str) -> Result<(), Error>;
+
+    /// Deletes the user-interactive auth sessions created before `before` (in milliseconds since
+    /// the unix epoch), and returns how many there were.
+    async fn purge_expired_uiaa_sessions(&self, before: i64) -> Result<usize, Error>;
+
+    /// Binds a third party identifier to a user.
+    ///
+    /// Returns `ErrorKind::ThreepidInUse` if it's bound to another user.
+    async fn add_threepid(&self, username: &str, threepid: Threepid) -> Result<(), Error>;
+
+    /// Returns the username that a third party identifier is bound to, if any.
+    async fn get_threepid_owner(
+        &self,
+        medium: Medium,
+        address: &str,
+    ) -> Result<Option<String>, Error>;
+
+    async fn get_threepids(&self, username: &str) -> Result<Vec<Threepid>, Error>;
+
+    /// Unbinds a third party identifier from a user, and returns whether it was bound to them.
+    async fn remove_threepid(
+        &self,
+        username: &str,
+        medium: Medium,
+        address: &str,
+    ) -> Result<bool, Error>;
+
+    /// Stores PDUs at the end of their rooms' timelines. PDUs that are already stored are skipped,
+    /// so they keep their original place.
     async fn add_pdus(&self, pdus: &[StoredPdu]) -> Result<(), Error>;
 
     async fn get_prev_events(&self, room_id: &str) -> Result<(Vec<String>, i64), Error>;
@@ -203,6 +714,66 @@ pub trait Storage: Send + Sync {
 
     async fn get_rooms(&self) -> Result<Vec<String>, Error>;
 
+    /// Returns how many state events make up the room's current state, without loading them all
+    /// at once.
+    async fn count_state_events(&self, room_id: &str) -> Result<usize, Error>;
+
+    /// Returns up to `limit` of the room's current member events, in state key order, starting
+    /// after the member whose state key is `after`. Big member lists can be gone through a page
+    /// at a time this way, rather than loaded all at once.
+    async fn get_member_pdus(
+        &self,
+        room_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StoredPdu>, Error>;
+
+    /// Drops the content of the room's message events sent before `before_ts`, and returns how
+    /// many were purged. The events themselves stay, since the room's event graph runs through
+    /// them, and state events are left alone.
+    async fn purge_events(&self, room_id: &str, before_ts: i64) -> Result<usize, Error>;
+
+    async fn room_exists(&self, room_id: &str) -> Result<bool, Error> {
+        Ok(self.get_rooms().await?.iter().any(|id| id == room_id))
+    }
+
+    /// Removes a room and everything about it: its events, aliases, directory listing, and any
+    /// receipts, read markers and other ephemeral data.
+    async fn delete_room(&self, room_id: &str) -> Result<(), Error>;
+
+    /// Points `alias` at `room_id`, recording `creator` as the user who made it. Returns false if
+    /// the alias is already taken.
+    async fn set_room_alias(&self, alias: &str, room_id: &str, creator: &str) -> Result<bool, Error>;
+
+    async fn get_room_alias(&self, alias: &str) -> Result<Option<String>, Error>;
+
+    /// Gets the user who created the alias, if it exists and its creator was recorded.
+    async fn get_room_alias_creator(&self, alias: &str) -> Result<Option<String>, Error>;
+
+    /// Returns whether the alias existed.
+    async fn delete_room_alias(&self, alias: &str) -> Result<bool, Error>;
+
+    /// Gets every alias which points at the room.
+    async fn get_room_aliases(&self, room_id: &str) -> Result<Vec<String>, Error>;
+
+    /// Sets whether the room is listed in the public room directory.
+    async fn set_room_published(&self, room_id: &str, published: bool) -> Result<(), Error>;
+
+    async fn get_published_rooms(&self) -> Result<Vec<String>, Error>;
+
+    /// Returns every room the user has a membership in, along with what that membership is.
+    /// Rooms they've left or been banned from are included, but ones they've never been in
+    /// aren't.
+    async fn get_user_rooms(&self, user_id: &MatrixId) -> Result<HashMap<String, Membership>, Error> {
+        let mut ret = HashMap::new();
+        for room_id in self.get_rooms().await? {
+            if let Some(membership) = self.get_membership(user_id, &room_id).await? {
+                ret.insert(room_id, membership);
+            }
+        }
+        Ok(ret)
+    }
+
     async fn get_membership(
         &self,
         user_id: &MatrixId,
@@ -308,9 +879,12 @@ pub trait Storage: Send + Sync {
         event_id: &str,
     ) -> Result<Option<StoredPdu>, Error>;
 
+    /// Returns the room's ephemeral events as `user_id` should see them, which includes their own
+    /// private receipts but nobody else's.
     async fn get_all_ephemeral(
         &self,
         room_id: &str,
+        user_id: &MatrixId,
     ) -> Result<HashMap<String, JsonValue>, Error>;
 
     async fn get_ephemeral(
@@ -334,11 +908,98 @@ pub trait Storage: Send + Sync {
         timeout: u32,
     ) -> Result<(), Error>;
 
+    /// Records that the user has read up to `event_id`. Each user has one receipt of each type
+    /// per room, and private receipts are kept apart from public ones.
+    ///
+    /// Returns whether the receipt changed. Setting a receipt to the event it already points at
+    /// does nothing, so that clients repeating themselves don't wake up everyone's syncs.
+    async fn set_receipt(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        receipt_type: ReceiptType,
+        event_id: &str,
+    ) -> Result<bool, Error>;
+
+    /// Sets the user's fully read marker in a room, and returns whether it changed.
+    async fn set_fully_read(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        event_id: &str,
+    ) -> Result<bool, Error>;
+
+    async fn get_fully_read(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+    ) -> Result<Option<String>, Error>;
+
+    /// Sets whether the user has forgotten a room they've left, which keeps it out of their syncs.
+    async fn set_room_forgotten(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        forgotten: bool,
+    ) -> Result<(), Error>;
+
+    async fn is_room_forgotten(&self, room_id: &str, user_id: &MatrixId) -> Result<bool, Error>;
+
+    /// Sets the user's presence. Coming online counts as activity, but other states keep the time
+    /// the user was last active.
+    async fn set_presence(&self, username: &str, state: PresenceState) -> Result<(), Error>;
+
+    /// Returns the presence the user last set, or None if they've never set one.
+    async fn get_presence(&self, username: &str) -> Result<Option<Presence>, Error>;
+
     async fn get_user_account_data(
         &self,
         username: &str,
     ) -> Result<HashMap<String, JsonValue>, Error>;
 
+    /// Sets a piece of the user's global account data, moving their account data stream along.
+    async fn set_user_account_data(
+        &self,
+        username: &str,
+        ty: &str,
+        content: JsonValue,
+    ) -> Result<(), Error>;
+
+    /// Returns the account data which has changed since the given position in the user's account
+    /// data stream, along with the current position.
+    async fn get_user_account_data_since(
+        &self,
+        username: &str,
+        since: usize,
+    ) -> Result<(HashMap<String, JsonValue>, usize), Error>;
+
+    /// Sets a piece of the user's account data for one room. This moves their account data stream
+    /// along in the same way as global account data.
+    async fn set_room_account_data(
+        &self,
+        username: &str,
+        room_id: &str,
+        ty: &str,
+        content: JsonValue,
+    ) -> Result<(), Error>;
+
+    /// Returns the user's account data for the room which has changed since the given position in
+    /// their account data stream. A position of 0 gets all of it.
+    async fn get_room_account_data_since(
+        &self,
+        username: &str,
+        room_id: &str,
+        since: usize,
+    ) -> Result<HashMap<String, JsonValue>, Error>;
+
+    /// Saves a sync filter for the user, and returns the ID it can be fetched with.
+    async fn create_filter(&self, username: &str, filter: JsonValue) -> Result<String, Error>;
+
+    async fn get_filter(&self, username: &str, filter_id: &str)
+        -> Result<Option<JsonValue>, Error>;
+
+    /// Returns the batch as it was stored, which may be in an old layout. Use `Batch::upgrade`
+    /// before relying on it.
     async fn get_batch(&self, id: &str) -> Result<Option<Batch>, Error>;
 
     async fn set_batch(&self, id: &str, batch: Batch) -> Result<(), Error>;
@@ -350,57 +1011,729 @@ pub trait Storage: Send + Sync {
 
 #[cfg(test)]
 mod tests {
-    use super::{Storage, StorageManager};
+    use ring::{rand::SystemRandom, signature::Ed25519KeyPair};
+
+    use std::collections::HashMap;
+
+    use crate::{
+        error::{Error, ErrorKind},
+        events::{
+            room::{Create, Member, Membership}, EventContent, ephemeral::{PresenceState, ReceiptType, ToDeviceEvent},
+            pdu::StoredPdu,
+            room_version::{v4::UnhashedPdu, VersionedPdu},
+        },
+        sign::Key,
+        state::StateResolver,
+        test_util::{assert_errcode, create_event, with_mem_db},
+        util::{MatrixId, StorageExt, storage::NewEvent},
+        validate::auth::AuthStatus,
+    };
+
+    use super::{Batch, EventQuery, QueryType, Storage, StorageManager};
 
     #[cfg(feature = "storage-mem")]
     #[test]
-    fn mem_backend_user_accounts() {
-        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
-        let db_pool = super::mem::MemStorageManager::new();
-        rt.block_on(async {
-            let db = db_pool.get_handle().await.unwrap();
-            user_accounts(&*db).await;
+    fn mem_backend_redactions() {
+        with_mem_db(|db, state_resolver| async move {
+            redactions(&*db, &state_resolver).await;
         });
     }
 
     #[cfg(feature = "storage-sled")]
     #[test]
-    fn sled_backend_user_accounts() {
-        let path = "sled-test-user-accounts";
+    fn sled_backend_redactions() {
+        let path = "sled-test-redactions";
         let _ = std::fs::remove_dir_all(path);
         let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
         let db_pool = super::sled::SledStorage::new(path).unwrap();
         rt.block_on(async {
             let db = db_pool.get_handle().await.unwrap();
-            user_accounts(&*db).await;
+            let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            redactions(&*db, &state_resolver).await;
         });
         let _ = std::fs::remove_dir_all(path);
     }
 
-    async fn user_accounts(db: &dyn Storage) {
-        db.create_user("alice", "password1").await.expect("failed to create first user");
-        db.create_user("alice", "password1").await.expect_err("succeeded making same user twice");
-        db.create_user("alice", "password2").await.expect_err("succeeded making same user twice");
-        db.create_user("bob", "password1").await.expect("failed to create second user");
+    async fn redactions(db: &dyn Storage, state_resolver: &StateResolver) {
+        let keys = HashMap::new();
+        let room_id = "!redactions:example.org";
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        db.add_pdus(&[create_event(room_id, &alice)]).await.unwrap();
+        let event = |content: EventContent, state_key: Option<&str>, redacts: Option<&str>| NewEvent {
+            event_content: content,
+            sender: alice.clone(),
+            state_key: state_key.map(String::from),
+            redacts: redacts.map(String::from),
+            unsigned: None,
+        };
+        let join = EventContent::Member(Member {
+            avatar_url: None,
+            displayname: None,
+            membership: Membership::Join,
+            is_direct: None,
+            reason: None,
+            third_party_invite: None,
+            join_authorised_via_users_server: None,
+        });
+        db.add_event(room_id, event(join, Some(alice.as_str()), None), state_resolver, &keys)
+            .await.unwrap();
 
-        assert!(db.verify_password("alice", "password1").await.unwrap() == true);
-        assert!(db.verify_password("alice", "password2").await.unwrap() == false);
-        assert!(db.verify_password("bob", "password1").await.unwrap() == true);
-        assert!(db.verify_password("bob", "password2").await.unwrap() == false);
+        let message = EventContent::new("m.room.message", serde_json::json!({
+            "msgtype": "m.text",
+            "body": "oops",
+        })).unwrap();
+        let message_id = db.add_event(room_id, event(message, None, None), state_resolver, &keys)
+            .await.unwrap();
+        let redaction = serde_json::from_value(serde_json::json!({ "reason": "typo" })).unwrap();
+        let redaction_id = db.add_event(
+            room_id,
+            event(EventContent::Redaction(redaction), None, Some(&message_id)),
+            state_resolver,
+            &keys,
+        ).await.unwrap();
+        let redaction = serde_json::from_value(serde_json::json!({ "reason": "mistake" })).unwrap();
+        db.add_event(
+            room_id,
+            event(EventContent::Redaction(redaction), None, Some(&redaction_id)),
+            state_resolver,
+            &keys,
+        ).await.unwrap();
 
-        let alice_token_1 = db.create_access_token("alice", "phone").await.expect("failed to create token");
-        let alice_token_2 = db.create_access_token("alice", "laptop").await.expect("failed to create token");
-        let bob_token_1 = db.create_access_token("bob", "laptop").await.expect("failed to create token");
-        let bob_token_2 = db.create_access_token("bob", "phone").await.expect("failed to create token");
-        db.create_access_token("nobody", "kjfnkfn").await.expect_err("succeeded making token for nobody");
+        let message = db.get_pdu(room_id, &message_id).await.unwrap().unwrap();
+        assert_eq!(message.event_content().content_as_json(), serde_json::json!({}));
 
-        db.delete_access_token(alice_token_2).await.expect("failed to delete token");
-        db.delete_access_token(bob_token_2).await.expect("failed to delete token");
+        let redaction = db.get_pdu(room_id, &redaction_id).await.unwrap().unwrap();
+        assert_eq!(redaction.event_id(), redaction_id);
+        let json = serde_json::to_value(redaction.to_client_format()).unwrap();
+        assert_eq!(json["type"], "m.room.redaction");
+        assert_eq!(json["content"], serde_json::json!({}));
+        assert!(json.get("redacts").is_none());
+        assert_eq!(json["unsigned"]["redacted_because"]["content"]["reason"], "mistake");
+        assert_eq!(json["unsigned"]["redacted_because"]["redacts"], redaction_id.as_str());
+    }
 
-        assert_eq!(db.try_auth(alice_token_1).await.expect("failed during auth").as_deref(), Some("alice"));
-        assert_eq!(db.try_auth(alice_token_2).await.expect("failed during auth").as_deref(), None);
-        assert_eq!(db.try_auth(bob_token_1).await.expect("failed during auth").as_deref(), Some("bob"));
-        assert_eq!(db.try_auth(bob_token_2).await.expect("failed during auth").as_deref(), None);
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_sent_events() {
+        with_mem_db(|db, state_resolver| async move {
+            sent_events(&*db, &state_resolver).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_sent_events() {
+        let path = "sled-test-sent-events";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            sent_events(&*db, &state_resolver).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn sent_events(db: &dyn Storage, state_resolver: &StateResolver) {
+        let keys = HashMap::new();
+        let room_id = "!sent:example.org";
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let creation_id = db.add_event(room_id, NewEvent {
+            event_content: EventContent::Create(Create {
+                creator: Some(alice.clone()),
+                room_version: Some(String::from("4")),
+                predecessor: None,
+                extra: HashMap::new(),
+            }),
+            sender: alice.clone(),
+            state_key: Some(String::new()),
+            redacts: None,
+            unsigned: None,
+        }, state_resolver, &keys).await.unwrap();
+        let join_id = db.add_event(room_id, NewEvent {
+            event_content: EventContent::Member(Member {
+                avatar_url: None,
+                displayname: None,
+                membership: Membership::Join,
+                is_direct: None,
+                reason: None,
+                third_party_invite: None,
+                join_authorised_via_users_server: None,
+            }),
+            sender: alice.clone(),
+            state_key: Some(alice.clone_inner()),
+            redacts: None,
+            unsigned: None,
+        }, state_resolver, &keys).await.unwrap();
+        let message_id = db.add_event(room_id, NewEvent {
+            event_content: EventContent::new("m.room.message", serde_json::json!({
+                "msgtype": "m.text",
+                "body": "hello",
+            })).unwrap(),
+            sender: alice.clone(),
+            state_key: None,
+            redacts: None,
+            unsigned: None,
+        }, state_resolver, &keys).await.unwrap();
+
+        let creation = db.get_pdu(room_id, &creation_id).await.unwrap().unwrap();
+        assert!(creation.prev_events().is_empty());
+        assert_eq!(creation.depth(), 0);
+
+        let message = db.get_pdu(room_id, &message_id).await.unwrap().unwrap();
+        assert_eq!(message.event_id(), message_id);
+        assert_eq!(message.sender(), &alice);
+        assert_eq!(message.event_content().content_as_json()["body"], "hello");
+        assert_eq!(message.prev_events(), &[join_id][..]);
+        assert_eq!(message.depth(), 2);
+        assert!(message.auth_events().contains(&creation_id));
+        assert_eq!(db.get_prev_events(room_id).await.unwrap(), (vec![message_id], 2));
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_queries() {
+        with_mem_db(|db, state_resolver| async move {
+            queries(&*db, &state_resolver).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_queries() {
+        let path = "sled-test-queries";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            queries(&*db, &state_resolver).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn queries(db: &dyn Storage, state_resolver: &StateResolver) {
+        let keys = HashMap::new();
+        let room_id = "!queries:example.org";
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let event = |ty: &str, content: serde_json::Value, state_key: Option<&str>| NewEvent {
+            event_content: EventContent::new(ty, content).unwrap(),
+            sender: alice.clone(),
+            state_key: state_key.map(String::from),
+            redacts: None,
+            unsigned: None,
+        };
+        let events = vec![
+            event("m.room.create", serde_json::json!({ "creator": alice, "room_version": "4" }), Some("")),
+            event("m.room.member", serde_json::json!({ "membership": "join" }), Some(alice.as_str())),
+            event("m.room.name", serde_json::json!({ "name": "first" }), Some("")),
+            event("m.room.message", serde_json::json!({ "msgtype": "m.text", "body": "a" }), None),
+            event("m.room.name", serde_json::json!({ "name": "second" }), Some("")),
+            event("m.room.topic", serde_json::json!({ "topic": "queries" }), Some("")),
+            event("m.room.message", serde_json::json!({ "msgtype": "m.text", "body": "b" }), None),
+        ];
+        for event in events {
+            db.add_event(room_id, event, state_resolver, &keys).await.unwrap();
+        }
+        let query = |query_type: QueryType<'static>, types: &'static [&'static str]| EventQuery {
+            query_type,
+            room_id,
+            senders: &[],
+            not_senders: &[],
+            types,
+            not_types: &[],
+            contains_json: None,
+        };
+        let state = |at: Option<usize>| QueryType::State { at, state_keys: &[], not_state_keys: &[] };
+        let summary = |pdus: &[StoredPdu]| pdus.iter()
+            .map(|pdu| {
+                let content = pdu.event_content().content_as_json();
+                let detail = ["name", "topic", "body"].iter()
+                    .find_map(|field| content[field].as_str())
+                    .unwrap_or("");
+                format!("{} {}", pdu.event_content().get_type(), detail)
+            })
+            .collect::<Vec<_>>();
+
+        // the first name has been overwritten, so only the second is part of the state
+        let (pdus, _) = db.query_pdus(query(state(None), &[]), false).await.unwrap();
+        assert_eq!(summary(&pdus), vec![
+            "m.room.create ",
+            "m.room.member ",
+            "m.room.name second",
+            "m.room.topic queries",
+        ]);
+        let (pdus, _) = db.query_pdus(query(state(None), &["m.room.name"]), false).await.unwrap();
+        assert_eq!(summary(&pdus), vec!["m.room.name second"]);
+        let mut first_name = query(state(None), &[]);
+        first_name.contains_json = Some(serde_json::json!({ "name": "first" }));
+        assert!(db.query_pdus(first_name, false).await.unwrap().0.is_empty());
+        assert_eq!(db.count_state_events(room_id).await.unwrap(), 4);
+        let rooms = db.get_user_rooms(&alice).await.unwrap();
+        assert_eq!(rooms.get(room_id), Some(&Membership::Join));
+        let bob = MatrixId::new("bob", "example.org").unwrap();
+        assert!(db.get_user_rooms(&bob).await.unwrap().is_empty());
+        assert!(db.count_state_events("!nowhere:example.org").await.is_err());
+
+        // the timeline has everything, in the order it was sent
+        let timeline = QueryType::Timeline { from: 0, to: None };
+        let (pdus, _) = db.query_pdus(query(timeline.clone(), &[]), false).await.unwrap();
+        assert_eq!(pdus.len(), 7);
+        let first_message = pdus[3].stream_ordering;
+        let (pdus, _) = db.query_pdus(query(timeline.clone(), &["m.room.message", "m.room.name"]), false)
+            .await.unwrap();
+        assert_eq!(summary(&pdus), vec![
+            "m.room.name first",
+            "m.room.message a",
+            "m.room.name second",
+            "m.room.message b",
+        ]);
+        let mut not_names = query(timeline, &["m.room.message", "m.room.name"]);
+        not_names.not_types = &["m.room.name"];
+        let (pdus, _) = db.query_pdus(not_names, false).await.unwrap();
+        assert_eq!(summary(&pdus), vec!["m.room.message a", "m.room.message b"]);
+
+        // state from earlier on still has the first name
+        let (pdus, _) = db.query_pdus(query(state(Some(first_message)), &[]), false).await.unwrap();
+        assert_eq!(summary(&pdus), vec!["m.room.create ", "m.room.member ", "m.room.name first"]);
+
+        // member events come a page at a time in state key order, with only each member's latest
+        for (state_key, membership) in &[
+            ("@carol:example.org", "invite"),
+            ("@bob:example.org", "invite"),
+            ("@bob:example.org", "leave"),
+        ] {
+            let content = serde_json::json!({ "membership": membership });
+            let member = event("m.room.member", content, Some(*state_key));
+            db.add_event(room_id, member, state_resolver, &keys).await.unwrap();
+        }
+        let members = |pdus: Vec<StoredPdu>| pdus.iter()
+            .map(|pdu| {
+                let membership = pdu.event_content().content_as_json()["membership"].clone();
+                format!("{} {}", pdu.state_key().unwrap(), membership.as_str().unwrap())
+            })
+            .collect::<Vec<_>>();
+        let page = db.get_member_pdus(room_id, None, 2).await.unwrap();
+        assert_eq!(members(page), vec!["@alice:example.org join", "@bob:example.org leave"]);
+        let page = db.get_member_pdus(room_id, Some("@bob:example.org"), 2).await.unwrap();
+        assert_eq!(members(page), vec!["@carol:example.org invite"]);
+        assert!(db.get_member_pdus(room_id, Some("@carol:example.org"), 2).await.unwrap().is_empty());
+        assert!(db.get_member_pdus("!nowhere:example.org", None, 2).await.is_err());
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_room_deletion() {
+        with_mem_db(|db, state_resolver| async move {
+            room_deletion(&*db, &state_resolver).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_room_deletion() {
+        let path = "sled-test-room-deletion";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            room_deletion(&*db, &state_resolver).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn room_deletion(db: &dyn Storage, state_resolver: &StateResolver) {
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let mut join_ids = Vec::new();
+        for room_id in &["!doomed:example.org", "!kept:example.org"] {
+            db.add_pdus(&[create_event(room_id, &alice)]).await.unwrap();
+            let join_id = db.add_event(room_id, NewEvent {
+                event_content: EventContent::Member(Member {
+                    avatar_url: None,
+                    displayname: None,
+                    membership: Membership::Join,
+                    is_direct: None,
+                    reason: None,
+                    third_party_invite: None,
+                    join_authorised_via_users_server: None,
+                }),
+                sender: alice.clone(),
+                state_key: Some(alice.clone_inner()),
+                redacts: None,
+                unsigned: None,
+            }, state_resolver, &HashMap::new()).await.unwrap();
+            join_ids.push(join_id);
+        }
+
+        assert!(db.set_room_alias("#doomed:example.org", "!doomed:example.org", "alice").await.unwrap());
+        assert!(db.set_room_alias("#doom:example.org", "!doomed:example.org", "alice").await.unwrap());
+        assert!(db.set_room_alias("#kept:example.org", "!kept:example.org", "alice").await.unwrap());
+        assert!(!db.set_room_alias("#kept:example.org", "!doomed:example.org", "bob").await.unwrap());
+        assert_eq!(
+            db.get_room_alias_creator("#kept:example.org").await.unwrap().as_deref(),
+            Some("alice"),
+        );
+        db.set_room_published("!doomed:example.org", true).await.unwrap();
+        db.set_room_published("!kept:example.org", true).await.unwrap();
+        for room_id in &["!doomed:example.org", "!kept:example.org"] {
+            db.set_room_forgotten(room_id, &alice, true).await.unwrap();
+        }
+        assert!(db.is_room_forgotten("!kept:example.org", &alice).await.unwrap());
+
+        db.delete_room("!doomed:example.org").await.unwrap();
+
+        assert_eq!(db.get_rooms().await.unwrap(), vec![String::from("!kept:example.org")]);
+        assert!(db.get_pdu("!doomed:example.org", &join_ids[0]).await.unwrap().is_none());
+        assert!(db.get_pdu("!kept:example.org", &join_ids[1]).await.unwrap().is_some());
+        assert_eq!(db.get_room_alias("#doomed:example.org").await.unwrap(), None);
+        assert_eq!(db.get_room_alias("#doom:example.org").await.unwrap(), None);
+        assert_eq!(db.get_room_alias_creator("#doomed:example.org").await.unwrap(), None);
+        assert_eq!(
+            db.get_room_alias("#kept:example.org").await.unwrap().as_deref(),
+            Some("!kept:example.org"),
+        );
+        assert_eq!(db.get_published_rooms().await.unwrap(), vec![String::from("!kept:example.org")]);
+        assert!(!db.is_room_forgotten("!doomed:example.org", &alice).await.unwrap());
+        assert!(db.is_room_forgotten("!kept:example.org", &alice).await.unwrap());
+        db.set_room_forgotten("!kept:example.org", &alice, false).await.unwrap();
+        assert!(!db.is_room_forgotten("!kept:example.org", &alice).await.unwrap());
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_signed_pdus() {
+        with_mem_db(|db, _| async move {
+            signed_pdus(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_signed_pdus() {
+        let path = "sled-test-signed-pdus";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            signed_pdus(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn signed_pdus(db: &dyn Storage) {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key = Key::Ed25519(Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap());
+        let mut keys = HashMap::new();
+        keys.insert(String::from("ed25519:test"), key);
+
+        let room_id = "!signed:example.org";
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let mut creation = create_event(room_id, &alice).inner().clone();
+        creation.sign("example.org", &keys);
+        let event_id = creation.event_id();
+        let original = serde_json::to_value(&creation).unwrap();
+        db.add_pdus(&[StoredPdu::new(creation, AuthStatus::Pass)]).await.unwrap();
+
+        let stored = db.get_pdu(room_id, &event_id).await.unwrap().unwrap();
+        let stored_json = serde_json::to_value(stored.inner()).unwrap();
+        assert!(stored_json["signatures"]["example.org"]["ed25519:test"].is_string());
+        assert_eq!(stored_json["signatures"], original["signatures"]);
+        assert_eq!(stored_json["hashes"], original["hashes"]);
+
+        // the JSON form round-trips without losing them either
+        let reparsed: VersionedPdu = serde_json::from_value(stored_json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&reparsed).unwrap(), stored_json);
+        assert_eq!(reparsed.event_id(), event_id);
+
+        let client_json = serde_json::to_value(stored.to_client_format()).unwrap();
+        assert_eq!(client_json["type"], "m.room.create");
+        for field in &["hashes", "signatures", "prev_events", "auth_events", "depth", "origin"] {
+            assert!(client_json.get(field).is_none(), "{} leaked to the client", field);
+        }
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_duplicate_pdus() {
+        with_mem_db(|db, _| async move {
+            duplicate_pdus(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_duplicate_pdus() {
+        let path = "sled-test-duplicate-pdus";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            duplicate_pdus(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn duplicate_pdus(db: &dyn Storage) {
+        let room_id = "!duplicates:example.org";
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let creation = create_event(room_id, &alice);
+        let creation_id = creation.event_id().to_owned();
+        let message = UnhashedPdu {
+            event_content: EventContent::new("m.room.message", serde_json::json!({
+                "msgtype": "m.text",
+                "body": "hello",
+            })).unwrap(),
+            room_id: String::from(room_id),
+            sender: alice.clone(),
+            state_key: None,
+            unsigned: None,
+            redacts: None,
+            origin: String::from("example.org"),
+            origin_server_ts: 1,
+            prev_events: vec![creation_id.clone()],
+            depth: 1,
+            auth_events: vec![creation_id.clone()],
+        }.finalize();
+        let message = StoredPdu::new(VersionedPdu::V4(message), AuthStatus::Pass);
+
+        db.add_pdus(&[creation.clone()]).await.unwrap();
+        // a duplicate keeps its original place, whether it comes alone or alongside new PDUs
+        db.add_pdus(&[creation.clone()]).await.unwrap();
+        db.add_pdus(&[creation, message.clone()]).await.unwrap();
+        db.add_pdus(&[message]).await.unwrap();
+
+        let query = EventQuery {
+            query_type: QueryType::Timeline { from: 0, to: None },
+            room_id,
+            senders: &[],
+            not_senders: &[],
+            types: &[],
+            not_types: &[],
+            contains_json: None,
+        };
+        let (pdus, _) = db.query_pdus(query, false).await.unwrap();
+        assert_eq!(pdus.len(), 2);
+        assert_eq!(pdus[0].event_id(), creation_id);
+        assert_eq!(pdus[0].stream_ordering, 0);
+        assert_eq!(pdus[1].stream_ordering, 1);
+        let (prev_events, max_depth) = db.get_prev_events(room_id).await.unwrap();
+        assert_eq!(prev_events, vec![pdus[1].event_id().to_owned()]);
+        assert_eq!(max_depth, 1);
+    }
+
+    #[test]
+    fn batch_v1_upgrade() {
+        let v1 = serde_json::json!({
+            "rooms": { "!a:example.org": 5 },
+            "invites": ["!b:example.org"],
+        });
+        let batch: Batch = serde_json::from_value(v1).unwrap();
+        assert_eq!(batch.version, 1);
+        let batch = batch.upgrade().expect("failed to upgrade v1 batch");
+        assert_eq!(batch.version, Batch::CURRENT_VERSION);
+        assert_eq!(batch.rooms.get("!a:example.org"), Some(&5));
+        assert!(batch.invites.contains("!b:example.org"));
+
+        let mut future = Batch::default();
+        future.version = Batch::CURRENT_VERSION + 1;
+        assert!(future.upgrade().is_none());
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_from_token_survives_reopen() {
+        let path = "sled-test-stream-ordering";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        rt.block_on(async {
+            let keys = HashMap::new();
+            let room_id = "!ordering:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let timeline = |from| EventQuery {
+                query_type: QueryType::Timeline { from, to: None },
+                room_id,
+                senders: &[],
+                not_senders: &[],
+                types: &[],
+                not_types: &[],
+                contains_json: None,
+            };
+            let message = |body: &str| NewEvent {
+                event_content: EventContent::new("m.room.message", serde_json::json!({
+                    "msgtype": "m.text",
+                    "body": body,
+                })).unwrap(),
+                sender: alice.clone(),
+                state_key: None,
+                redacts: None,
+                unsigned: None,
+            };
+
+            let from = {
+                let sled_db = sled::open(path).unwrap();
+                let db_pool = super::sled::SledStorage::with_db(sled_db.clone()).unwrap();
+                let db = db_pool.get_handle().await.unwrap();
+                let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+                db.add_pdus(&[create_event(room_id, &alice)]).await.unwrap();
+                let join = NewEvent {
+                    event_content: EventContent::Member(Member {
+                        avatar_url: None,
+                        displayname: None,
+                        membership: Membership::Join,
+                        is_direct: None,
+                        reason: None,
+                        third_party_invite: None,
+                        join_authorised_via_users_server: None,
+                    }),
+                    sender: alice.clone(),
+                    state_key: Some(alice.clone_inner()),
+                    redacts: None,
+                    unsigned: None,
+                };
+                db.add_event(room_id, join, &state_resolver, &keys).await.unwrap();
+                db.add_event(room_id, message("before"), &state_resolver, &keys).await.unwrap();
+
+                let (pdus, progress) = db.query_pdus(timeline(0), false).await.unwrap();
+                assert_eq!(pdus.len(), 3);
+                for (idx, pdu) in pdus.iter().enumerate() {
+                    assert_eq!(pdu.stream_ordering, idx);
+                }
+
+                // the database is only closed, letting go of its lock, once nothing uses it
+                drop(state_resolver);
+                drop(db);
+                drop(db_pool);
+                sled_db.flush().unwrap();
+                drop(sled_db);
+                progress + 1
+            };
+
+            let db_pool = super::sled::SledStorage::new(path).unwrap();
+            let db = db_pool.get_handle().await.unwrap();
+            let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+            let (pdus, _) = db.query_pdus(timeline(from), false).await.unwrap();
+            assert!(pdus.is_empty());
+
+            db.add_event(room_id, message("after"), &state_resolver, &keys).await.unwrap();
+            let (pdus, progress) = db.query_pdus(timeline(from), false).await.unwrap();
+            assert_eq!(pdus.len(), 1);
+            assert_eq!(pdus[0].event_content().content_as_json()["body"], "after");
+            assert_eq!(pdus[0].stream_ordering, from);
+            assert_eq!(progress, from);
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_old_batches() {
+        use sled::Db;
+
+        let path = "sled-test-old-batches";
+        let _ = std::fs::remove_dir_all(path);
+        {
+            use bincode::Options;
+            let db: Db = sled::open(path).unwrap();
+            let batches = db.open_tree("batches").unwrap();
+            let v1 = serde_json::json!({
+                "rooms": { "!a:example.org": 5 },
+                "invites": [],
+            });
+            batches.insert("json", serde_json::to_vec(&v1).unwrap()).unwrap();
+            // batches used to be bincode, which has no way of telling which layout it was in
+            let bytes = bincode::DefaultOptions::new().serialize(&Batch::default()).unwrap();
+            batches.insert("bincode", bytes).unwrap();
+            db.flush().unwrap();
+        }
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let batch = db.get_batch("json").await.unwrap().and_then(Batch::upgrade).unwrap();
+            assert_eq!(batch.version, Batch::CURRENT_VERSION);
+            assert_eq!(batch.rooms.get("!a:example.org"), Some(&5));
+            assert!(db.get_batch("bincode").await.unwrap().is_none());
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    /// A database that can't be reached is reported as storage being unavailable, not as some
+    /// other failure. This needs no database, so unlike `pg_backend` it always runs.
+    #[cfg(feature = "storage-postgres")]
+    #[test]
+    fn pg_backend_unreachable() {
+        use actix_web::{ResponseError, http::StatusCode};
+
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async {
+            // nothing listens on port 1
+            let url = "host=127.0.0.1 port=1 user=kerux dbname=kerux connect_timeout=5";
+            let err = super::postgres::PgStorageManager::connect(url, 4).await.err()
+                .expect("connected to nothing");
+            assert!(matches!(err.kind(), ErrorKind::StorageUnavailable(_)), "unexpected error: {}", err);
+            assert_eq!(err.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+            assert_errcode!(err, "M_UNKNOWN");
+        });
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_user_accounts() {
+        with_mem_db(|db, _| async move {
+            user_accounts(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_user_accounts() {
+        let path = "sled-test-user-accounts";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            user_accounts(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn user_accounts(db: &dyn Storage) {
+        db.create_user("alice", "password1").await.expect("failed to create first user");
+        db.create_user("alice", "password1").await.expect_err("succeeded making same user twice");
+        db.create_user("alice", "password2").await.expect_err("succeeded making same user twice");
+        db.create_user("bob", "password1").await.expect("failed to create second user");
+
+        assert!(db.verify_password("alice", "password1").await.unwrap() == true);
+        assert!(db.verify_password("alice", "password2").await.unwrap() == false);
+        assert!(db.verify_password("bob", "password1").await.unwrap() == true);
+        assert!(db.verify_password("bob", "password2").await.unwrap() == false);
+
+        db.create_guest_user("12345").await.expect("failed to create guest");
+        db.create_guest_user("alice").await.expect_err("succeeded making guest with taken name");
+        assert!(db.is_guest("12345").await.unwrap() == true);
+        assert!(db.is_guest("alice").await.unwrap() == false);
+        assert!(db.verify_password("12345", "").await.unwrap() == false);
+
+        let alice_token_1 = db.create_access_token("alice", "phone").await.expect("failed to create token");
+        let alice_token_2 = db.create_access_token("alice", "laptop").await.expect("failed to create token");
+        let bob_token_1 = db.create_access_token("bob", "laptop").await.expect("failed to create token");
+        let bob_token_2 = db.create_access_token("bob", "phone").await.expect("failed to create token");
+        db.create_access_token("nobody", "kjfnkfn").await.expect_err("succeeded making token for nobody");
+
+        db.delete_access_token(alice_token_2).await.expect("failed to delete token");
+        db.delete_access_token(bob_token_2).await.expect("failed to delete token");
+
+        assert_eq!(db.try_auth(alice_token_1).await.expect("failed during auth").as_deref(), Some("alice"));
+        assert_eq!(db.try_auth(alice_token_2).await.expect("failed during auth").as_deref(), None);
+        assert_eq!(db.try_auth(bob_token_1).await.expect("failed during auth").as_deref(), Some("bob"));
+        assert_eq!(db.try_auth(bob_token_2).await.expect("failed during auth").as_deref(), None);
 
         db.delete_all_access_tokens(alice_token_1).await.expect("failed to delete all tokens");
         assert_eq!(db.try_auth(alice_token_1).await.expect("failed during auth").as_deref(), None);
@@ -409,11 +1742,467 @@ mod tests {
 
     #[cfg(feature = "storage-mem")]
     #[test]
-    fn mem_backend_transactions() {
+    fn mem_backend_soft_logout() {
+        with_mem_db(|db, _| async move {
+            soft_logout(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_soft_logout() {
+        let path = "sled-test-soft-logout";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            soft_logout(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn soft_logout(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        let phone_1 = db.create_access_token("alice", "phone").await.unwrap();
+        let phone_2 = db.create_access_token("alice", "phone").await.unwrap();
+        let laptop = db.create_access_token("alice", "laptop").await.unwrap();
+
+        db.logout_device(phone_1).await.expect("failed to log out device");
+        for token in [phone_1, phone_2].iter() {
+            let err = db.try_auth(*token).await.expect_err("logged out token still valid");
+            assert_errcode!(err, "M_UNKNOWN_TOKEN");
+            assert_eq!(err.to_json()["soft_logout"], true);
+        }
+        assert_eq!(db.try_auth(laptop).await.unwrap().as_deref(), Some("alice"));
+
+        // Logging in again as the same device forgets the old tokens
+        let phone_3 = db.create_access_token("alice", "phone").await.unwrap();
+        assert_eq!(db.try_auth(phone_1).await.unwrap(), None);
+        assert_eq!(db.try_auth(phone_3).await.unwrap().as_deref(), Some("alice"));
+
+        db.logout_all_devices(laptop).await.expect("failed to log out all devices");
+        for token in [phone_3, laptop].iter() {
+            let err = db.try_auth(*token).await.expect_err("logged out token still valid");
+            assert_eq!(err.to_json()["soft_logout"], true);
+        }
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_token_expiry() {
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            token_expiry(&db_pool).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_token_expiry() {
+        let path = "sled-test-token-expiry";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            token_expiry(&db_pool).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn token_expiry(db_pool: &dyn StorageManager) {
+        let db = db_pool.get_handle().await.unwrap();
+        db.create_user("alice", "password").await.unwrap();
+        let lifetime = std::time::Duration::from_secs(60);
+        let phone = db.create_access_token("alice", "phone").await.unwrap();
+        let (laptop, laptop_refresh) = db.create_refreshable_access_token("alice", "laptop", lifetime)
+            .await.unwrap();
+
+        let now = chrono::Utc::now().timestamp_millis();
+        assert_eq!(db_pool.purge_expired_tokens(now).await.unwrap(), 0);
+        assert_eq!(db.try_auth(laptop).await.unwrap().as_deref(), Some("alice"));
+
+        // a minute later the laptop's token has expired, but the phone's never does
+        let later = now + 61_000;
+        assert_eq!(db_pool.purge_expired_tokens(later).await.unwrap(), 1);
+        assert_eq!(db.try_auth(laptop).await.unwrap(), None);
+        assert_eq!(db.try_auth(phone).await.unwrap().as_deref(), Some("alice"));
+        let (laptop, _) = db.refresh_access_token(laptop_refresh, lifetime).await.unwrap()
+            .expect("refresh token went with the access token");
+        assert_eq!(db.try_auth(laptop).await.unwrap().as_deref(), Some("alice"));
+
+        // logged out tokens are kept for a while to tell their clients so, but not forever
+        db.logout_device(phone).await.unwrap();
+        let grace_over = now + super::LOGGED_OUT_TOKEN_LIFETIME.as_millis() as i64 + 61_000;
+        assert_eq!(db.try_auth(phone).await.unwrap_err().to_json()["soft_logout"], true);
+        assert_eq!(db_pool.purge_expired_tokens(grace_over).await.unwrap(), 2);
+        assert_eq!(db.try_auth(phone).await.unwrap(), None);
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_devices() {
+        with_mem_db(|db, _| async move {
+            devices(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_devices() {
+        let path = "sled-test-devices";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            devices(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn devices(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        db.create_user("bob", "password").await.unwrap();
+        let lifetime = std::time::Duration::from_secs(60);
+        let phone = db.create_access_token("alice", "phone").await.unwrap();
+        let (laptop, laptop_refresh) = db.create_refreshable_access_token("alice", "laptop", lifetime)
+            .await.unwrap();
+        let bob_phone = db.create_access_token("bob", "phone").await.unwrap();
+
+        let mut device_ids: Vec<_> = db.get_devices("alice").await.unwrap()
+            .into_iter()
+            .map(|device| device.device_id)
+            .collect();
+        device_ids.sort();
+        assert_eq!(device_ids, vec!["laptop", "phone"]);
+
+        assert!(db.set_device_display_name("alice", "phone", Some("Pocket")).await.unwrap());
+        assert!(!db.set_device_display_name("alice", "toaster", Some("Toast")).await.unwrap());
+        let device = db.get_device("alice", "phone").await.unwrap().unwrap();
+        assert_eq!(device.display_name.as_deref(), Some("Pocket"));
+        assert!(db.get_device("alice", "toaster").await.unwrap().is_none());
+        assert!(db.get_device("bob", "phone").await.unwrap().unwrap().display_name.is_none());
+
+        // uploading keys and deleting devices both change the device list
+        assert_eq!(db.get_device_list_version("alice").await.unwrap(), 0);
+        db.set_device_keys("alice", "laptop", serde_json::json!({})).await.unwrap();
+        assert_eq!(db.get_device_list_version("alice").await.unwrap(), 1);
+
+        // the device's tokens go with it, but nobody else's do
+        db.delete_device("alice", "laptop").await.unwrap();
+        assert_eq!(db.get_device_list_version("alice").await.unwrap(), 2);
+        assert_eq!(db.get_device_list_version("bob").await.unwrap(), 0);
+        assert!(db.get_device("alice", "laptop").await.unwrap().is_none());
+        assert_eq!(db.try_auth(laptop).await.unwrap(), None);
+        assert_eq!(db.refresh_access_token(laptop_refresh, lifetime).await.unwrap(), None);
+        assert_eq!(db.try_auth(phone).await.unwrap().as_deref(), Some("alice"));
+        assert_eq!(db.try_auth(bob_phone).await.unwrap().as_deref(), Some("bob"));
+        assert_eq!(db.get_devices("alice").await.unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_one_time_keys() {
+        with_mem_db(|db, _| async move {
+            one_time_keys(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_one_time_keys() {
+        let path = "sled-test-one-time-keys";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            one_time_keys(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn one_time_keys(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        db.create_access_token("alice", "phone").await.unwrap();
+        db.create_access_token("alice", "laptop").await.unwrap();
+        let keys = |ids: &[&str]| ids.iter()
+            .map(|id| (String::from(*id), serde_json::json!({ "key": id })))
+            .collect::<HashMap<_, _>>();
+        assert!(db.count_one_time_keys("alice", "phone").await.unwrap().is_empty());
+        assert!(db.add_one_time_keys("bob", "phone", keys(&["curve25519:A"])).await.is_err());
+
+        db.add_one_time_keys("alice", "phone", keys(&[
+            "curve25519:A",
+            "signed_curve25519:B",
+            "signed_curve25519:C",
+        ])).await.unwrap();
+        // uploading a key again doesn't count it twice
+        db.add_one_time_keys("alice", "phone", keys(&["signed_curve25519:C"])).await.unwrap();
+        db.add_one_time_keys("alice", "laptop", keys(&["curve25519:A"])).await.unwrap();
+        let mut expected = HashMap::new();
+        expected.insert(String::from("curve25519"), 1);
+        expected.insert(String::from("signed_curve25519"), 2);
+        assert_eq!(db.count_one_time_keys("alice", "phone").await.unwrap(), expected);
+
+        // they go along with the device
+        db.delete_device("alice", "phone").await.unwrap();
+        assert!(db.count_one_time_keys("alice", "phone").await.unwrap().is_empty());
+        assert_eq!(db.count_one_time_keys("alice", "laptop").await.unwrap().len(), 1);
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_one_time_key_claims() {
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            one_time_key_claims(&*db).await;
+            concurrent_claims(&db_pool).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_one_time_key_claims() {
+        let path = "sled-test-one-time-key-claims";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            one_time_key_claims(&*db).await;
+            concurrent_claims(&db_pool).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn one_time_key_claims(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        db.create_access_token("alice", "phone").await.unwrap();
+        let mut keys = HashMap::new();
+        keys.insert(String::from("curve25519:A"), serde_json::json!("a"));
+        keys.insert(String::from("signed_curve25519:B"), serde_json::json!({ "key": "b" }));
+        db.add_one_time_keys("alice", "phone", keys).await.unwrap();
+
+        let claimed = db.claim_one_time_key("alice", "phone", "signed_curve25519").await.unwrap();
+        assert_eq!(claimed, Some((String::from("signed_curve25519:B"), serde_json::json!({ "key": "b" }))));
+        assert_eq!(db.claim_one_time_key("alice", "phone", "signed_curve25519").await.unwrap(), None);
+        assert_eq!(db.claim_one_time_key("alice", "laptop", "curve25519").await.unwrap(), None);
+        let counts = db.count_one_time_keys("alice", "phone").await.unwrap();
+        assert_eq!(counts.get("curve25519"), Some(&1));
+        assert_eq!(counts.get("signed_curve25519"), None);
+    }
+
+    /// Claims the same key from two tasks at once, which only one of them may get.
+    async fn concurrent_claims(db_pool: &dyn StorageManager) {
+        let mut keys = HashMap::new();
+        keys.insert(String::from("signed_curve25519:C"), serde_json::json!({ "key": "c" }));
+        let db = db_pool.get_handle().await.unwrap();
+        db.add_one_time_keys("alice", "phone", keys).await.unwrap();
+
+        let claim = |db: Box<dyn Storage>| tokio::spawn(async move {
+            db.claim_one_time_key("alice", "phone", "signed_curve25519").await.unwrap()
+        });
+        let first = claim(db_pool.get_handle().await.unwrap());
+        let second = claim(db_pool.get_handle().await.unwrap());
+        let (first, second) = (first.await.unwrap(), second.await.unwrap());
+        assert_eq!(first.is_some() as u8 + second.is_some() as u8, 1);
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_to_device() {
+        with_mem_db(|db, _| async move {
+            to_device(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_to_device() {
+        let path = "sled-test-to-device";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            to_device(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_uiaa_sessions() {
+        with_mem_db(|db, _| async move {
+            uiaa_sessions(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_uiaa_sessions() {
+        let path = "sled-test-uiaa-sessions";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            uiaa_sessions(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn uiaa_sessions(db: &dyn Storage) {
+        let session_id = db.create_uiaa_session("register").await.unwrap();
+        db.complete_uiaa_stage(&session_id, "m.login.dummy").await.unwrap();
+        let session = db.get_uiaa_session(&session_id).await.unwrap().unwrap();
+        assert_eq!(session.request, "register");
+        assert_eq!(session.completed, vec![String::from("m.login.dummy")]);
+
+        // only sessions created before the cutoff are purged
+        assert_eq!(db.purge_expired_uiaa_sessions(session.created_at).await.unwrap(), 0);
+        assert!(db.get_uiaa_session(&session_id).await.unwrap().is_some());
+        assert_eq!(db.purge_expired_uiaa_sessions(session.created_at + 1).await.unwrap(), 1);
+        assert!(db.get_uiaa_session(&session_id).await.unwrap().is_none());
+    }
+
+    async fn to_device(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        db.create_access_token("alice", "phone").await.unwrap();
+        db.create_access_token("alice", "laptop").await.unwrap();
+        let message = |n: u32| ToDeviceEvent {
+            sender: MatrixId::new("bob", "example.org").unwrap(),
+            ty: String::from("m.room_key_request"),
+            content: serde_json::json!({ "n": n }),
+        };
+        for n in 0..3 {
+            db.add_to_device_message("alice", "phone", message(n)).await.unwrap();
+        }
+        db.add_to_device_message("alice", "laptop", message(3)).await.unwrap();
+        db.add_to_device_message("nobody", "phone", message(4)).await
+            .expect_err("queued a message for a user that doesn't exist");
+
+        let messages = db.get_to_device_messages("alice", "phone", 0, false).await.unwrap();
+        let events: Vec<_> = messages.iter().map(|(_, event)| event.clone()).collect();
+        assert_eq!(events, vec![message(0), message(1), message(2)]);
+        assert!(messages.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        // messages stay until they're deleted, and later ones can be asked for on their own
+        assert_eq!(db.get_to_device_messages("alice", "phone", 0, false).await.unwrap(), messages);
+        let (first_id, _) = messages[0];
+        assert_eq!(db.get_to_device_messages("alice", "phone", first_id, false).await.unwrap(), messages[1..]);
+
+        db.delete_to_device_messages("alice", "phone", first_id).await.unwrap();
+        assert_eq!(db.get_to_device_messages("alice", "phone", 0, false).await.unwrap(), messages[1..]);
+        let (last_id, _) = messages[2];
+        db.delete_to_device_messages("alice", "phone", last_id).await.unwrap();
+        assert_eq!(db.get_to_device_messages("alice", "phone", 0, false).await.unwrap(), Vec::new());
+
+        // IDs keep going up once the queue is empty, so a device's last seen ID stays behind
+        // anything new
+        db.add_to_device_message("alice", "phone", message(5)).await.unwrap();
+        let messages = db.get_to_device_messages("alice", "phone", last_id, false).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].1, message(5));
+
+        // waiting for a message gets it as soon as it's queued
+        let (messages, _) = futures::join!(
+            db.get_to_device_messages("alice", "phone", messages[0].0, true),
+            db.add_to_device_message("alice", "phone", message(6)),
+        );
+        assert_eq!(messages.unwrap()[0].1, message(6));
+
+        // deleting the device throws away whatever it hadn't picked up
+        db.delete_device("alice", "laptop").await.unwrap();
+        assert_eq!(db.get_to_device_messages("alice", "laptop", 0, false).await.unwrap(), Vec::new());
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_presence() {
+        with_mem_db(|db, _| async move {
+            presence(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_presence() {
+        let path = "sled-test-presence";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            presence(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn presence(db: &dyn Storage) {
+        db.create_user("alice", "password").await.unwrap();
+        assert_eq!(db.get_presence("alice").await.unwrap(), None);
+        assert!(db.set_presence("bob", PresenceState::Online).await.is_err());
+
+        db.set_presence("alice", PresenceState::Online).await.unwrap();
+        let online = db.get_presence("alice").await.unwrap().unwrap();
+        assert_eq!(online.state, PresenceState::Online);
+
+        // going idle doesn't count as being active
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        db.set_presence("alice", PresenceState::Unavailable).await.unwrap();
+        let idle = db.get_presence("alice").await.unwrap().unwrap();
+        assert_eq!(idle.state, PresenceState::Unavailable);
+        assert_eq!(idle.last_active_ts, online.last_active_ts);
+
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        db.set_presence("alice", PresenceState::Online).await.unwrap();
+        let back = db.get_presence("alice").await.unwrap().unwrap();
+        assert!(back.last_active_ts > online.last_active_ts);
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_snapshot() {
         let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
         let db_pool = super::mem::MemStorageManager::new();
         rt.block_on(async {
             let db = db_pool.get_handle().await.unwrap();
+            db.create_user("alice", "password").await.unwrap();
+            let token = db.create_access_token("alice", "phone").await.unwrap();
+            assert!(db.set_room_alias("#lobby:example.org", "!lobby:example.org", "alice").await.unwrap());
+            let snapshot = db_pool.snapshot().await;
+
+            db.create_user("bob", "password").await.unwrap();
+            db.set_display_name("alice", "Alice").await.unwrap();
+            db.delete_room_alias("#lobby:example.org").await.unwrap();
+            db.delete_device("alice", "phone").await.unwrap();
+            db_pool.restore(&snapshot).await;
+
+            assert!(!db.user_exists("bob").await.unwrap());
+            assert_eq!(db.get_profile("alice").await.unwrap().unwrap().displayname, None);
+            assert_eq!(
+                db.get_room_alias("#lobby:example.org").await.unwrap().as_deref(),
+                Some("!lobby:example.org"),
+            );
+            assert_eq!(db.try_auth(token).await.unwrap().as_deref(), Some("alice"));
+
+            // the same snapshot can be gone back to as many times as needed
+            db.create_user("bob", "password").await.unwrap();
+            db_pool.restore(&snapshot).await;
+            let db = db_pool.get_handle().await.unwrap();
+            assert!(!db.user_exists("bob").await.unwrap());
+        });
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_transactions() {
+        with_mem_db(|db, _| async move {
             transactions(&*db).await;
         });
     }
@@ -432,6 +2221,199 @@ mod tests {
         let _ = std::fs::remove_dir_all(path);
     }
 
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_read_marker_wakeups() {
+        with_mem_db(|db, _| async move {
+            read_marker_wakeups(&*db).await;
+        });
+    }
+
+    #[cfg(feature = "storage-sled")]
+    #[test]
+    fn sled_backend_read_marker_wakeups() {
+        let path = "sled-test-read-marker-wakeups";
+        let _ = std::fs::remove_dir_all(path);
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        let db_pool = super::sled::SledStorage::new(path).unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            read_marker_wakeups(&*db).await;
+        });
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    async fn read_marker_wakeups(db: &dyn Storage) {
+        let room_id = "!markers:example.org";
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let create = create_event(room_id, &alice);
+        let create_id = create.event_id().to_owned();
+        db.add_pdus(&[create]).await.unwrap();
+
+        // a sync waiting for anything after the creation event is woken by either marker moving,
+        // even though neither adds an event
+        let wait = || db.query_pdus(EventQuery {
+            query_type: QueryType::Timeline { from: 1, to: None },
+            room_id,
+            senders: &[],
+            not_senders: &[],
+            types: &[],
+            not_types: &[],
+            contains_json: None,
+        }, true);
+        let (res, _) = futures::join!(
+            wait(),
+            db.set_receipt(room_id, &alice, ReceiptType::Read, &create_id),
+        );
+        assert!(res.unwrap().0.is_empty());
+        let (res, _) = futures::join!(wait(), db.set_fully_read(room_id, &alice, &create_id));
+        assert!(res.unwrap().0.is_empty());
+    }
+
+    #[cfg(feature = "storage-mem")]
+    #[test]
+    fn mem_backend_ephemeral_wakeups() {
+        use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+        use tokio::time::delay_for;
+
+        let mut rt = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_time()
+            .build()
+            .unwrap();
+        let db_pool = super::mem::MemStorageManager::new();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let room_id = "!typing:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            db.add_pdus(&[create_event(room_id, &alice)]).await.unwrap();
+
+            // a sync waiting for anything after the creation event, which goes straight back to
+            // waiting each time it's woken
+            let waiter = db_pool.get_handle().await.unwrap();
+            let wakeups = Arc::new(AtomicUsize::new(0));
+            let counter = Arc::clone(&wakeups);
+            tokio::spawn(async move {
+                loop {
+                    waiter.query_pdus(EventQuery {
+                        query_type: QueryType::Timeline { from: 1, to: None },
+                        room_id,
+                        senders: &[],
+                        not_senders: &[],
+                        types: &[],
+                        not_types: &[],
+                        contains_json: None,
+                    }, true).await.unwrap();
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+            delay_for(std::time::Duration::from_millis(10)).await;
+
+            for i in 0..5 {
+                db.set_ephemeral(room_id, "org.example.status", Some(serde_json::json!({ "n": i })))
+                    .await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(wakeups.load(Ordering::SeqCst), 0);
+            delay_for(super::mem::EPHEMERAL_DEBOUNCE * 4).await;
+            let woken = wakeups.load(Ordering::SeqCst);
+            assert!((1..=2).contains(&woken), "woken {} times", woken);
+            assert_eq!(
+                db.get_ephemeral(room_id, "org.example.status").await.unwrap(),
+                Some(serde_json::json!({ "n": 4 })),
+            );
+        });
+    }
+
+    /// Setting ephemeral data and typing notifications mustn't need a timer, as not every runtime
+    /// has one.
+    #[test]
+    fn mem_backend_ephemeral_without_timer() {
+        with_mem_db(|db, _| async move {
+            let room_id = "!typing:example.org";
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            db.add_pdus(&[create_event(room_id, &alice)]).await.unwrap();
+
+            db.set_ephemeral(room_id, "org.example.status", Some(serde_json::json!({ "n": 1 })))
+                .await.unwrap();
+            db.set_typing(room_id, &alice, true, 0).await.unwrap();
+            db.set_typing(room_id, &alice, true, 60000).await.unwrap();
+            let typing = db.get_ephemeral(room_id, "m.typing").await.unwrap().unwrap();
+            assert_eq!(typing["user_ids"], serde_json::json!(["@alice:example.org"]));
+            db.set_typing(room_id, &alice, false, 0).await.unwrap();
+            let typing = db.get_ephemeral(room_id, "m.typing").await.unwrap().unwrap();
+            assert_eq!(typing["user_ids"], serde_json::json!([]));
+        });
+    }
+
+    /// The postgres backend needs a database to test against, which is taken from this
+    /// environment variable. Everything in it gets deleted! The test is skipped if it isn't set.
+    #[cfg(feature = "storage-postgres")]
+    const TEST_DATABASE_URL: &str = "KERUX_TEST_DATABASE_URL";
+
+    #[cfg(feature = "storage-postgres")]
+    #[test]
+    fn pg_backend() {
+        let url = match std::env::var(TEST_DATABASE_URL) {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!("{} isn't set, skipping the postgres backend tests", TEST_DATABASE_URL);
+                return;
+            },
+        };
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().enable_all().build().unwrap();
+        rt.block_on(async {
+            let db_pool = super::postgres::PgStorageManager::connect(&url, 4).await.unwrap();
+            // connecting again shouldn't trip over the existing schema
+            let db_pool = {
+                drop(db_pool);
+                super::postgres::PgStorageManager::connect(&url, 4).await.unwrap()
+            };
+            let db = db_pool.get_handle().await.unwrap();
+            let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+
+            db_pool.clear().await.unwrap();
+            redactions(&*db, &state_resolver).await;
+            db_pool.clear().await.unwrap();
+            sent_events(&*db, &state_resolver).await;
+            db_pool.clear().await.unwrap();
+            queries(&*db, &state_resolver).await;
+            db_pool.clear().await.unwrap();
+            room_deletion(&*db, &state_resolver).await;
+            db_pool.clear().await.unwrap();
+            signed_pdus(&*db).await;
+            db_pool.clear().await.unwrap();
+            duplicate_pdus(&*db).await;
+            db_pool.clear().await.unwrap();
+            user_accounts(&*db).await;
+            db_pool.clear().await.unwrap();
+            soft_logout(&*db).await;
+            db_pool.clear().await.unwrap();
+            token_expiry(&db_pool).await;
+            db_pool.clear().await.unwrap();
+            devices(&*db).await;
+            db_pool.clear().await.unwrap();
+            one_time_keys(&*db).await;
+            db_pool.clear().await.unwrap();
+            one_time_key_claims(&*db).await;
+            concurrent_claims(&db_pool).await;
+            db_pool.clear().await.unwrap();
+            to_device(&*db).await;
+            db_pool.clear().await.unwrap();
+            presence(&*db).await;
+            db_pool.clear().await.unwrap();
+            transactions(&*db).await;
+            db_pool.clear().await.unwrap();
+            uiaa_sessions(&*db).await;
+            db_pool.clear().await.unwrap();
+            read_marker_wakeups(&*db).await;
+
+            db.set_batch("batch", Batch::default()).await.unwrap();
+            let batch = db.get_batch("batch").await.unwrap().expect("batch went missing");
+            assert_eq!(batch.version, Batch::CURRENT_VERSION);
+        });
+    }
+
     async fn transactions(db: &dyn Storage) {
         db.create_user("alice", "password").await.unwrap();
         let token = db.create_access_token("alice", "phone").await.unwrap();
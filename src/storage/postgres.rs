@@ -1,442 +1,1663 @@
+use std::{collections::HashMap, convert::TryFrom, sync::Arc, time::Duration};
+
 use async_trait::async_trait;
 use crossbeam::queue::ArrayQueue;
-use futures::{
-    FutureExt,
-};
-use pg::{Client, Error as DbError, NoTls};
-use serde_json::{Value as JsonValue, to_value as to_json_value};
-use std::{
-    collections::HashMap,
-    sync::Arc
-};
-
-use crate::{
-    events::{room::Membership, PduV4, Event},
-    storage::UserProfile,
-};
-
-pub struct DbPool {
+use futures::FutureExt;
+use pg::{Client, NoTls, Row, error::SqlState};
+use serde_json::Value as JsonValue;
+use tokio::sync::{Mutex, broadcast::{channel, Sender}};
+use uuid::Uuid;
+
+use crate::{error::{Error, ErrorKind}, events::{EventContent, ephemeral::{PresenceState, Receipt, ReceiptType, Receipts, ToDeviceEvent, Typing}, pdu::StoredPdu, room::Membership}, storage::{Storage, StorageManager}, util::MatrixId};
+
+use super::{Batch, Device, EventQuery, Medium, Presence, Threepid, ThreepidSession, UiaaSession, UserProfile, count_by_algorithm, logged_out_expiry, should_purge, user_matches};
+
+/// Creates whatever is missing from the schema. This runs every time the server starts, so each
+/// statement has to be harmless against a database that's already up to date.
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY,
+    -- empty for guests, who can't log in with a password
+    password_hash TEXT NOT NULL DEFAULT '',
+    is_guest BOOLEAN NOT NULL DEFAULT FALSE,
+    avatar_url TEXT,
+    displayname TEXT,
+    account_data_position BIGINT NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS account_data (
+    username TEXT NOT NULL,
+    type TEXT NOT NULL,
+    content JSONB NOT NULL,
+    -- where this type of account data was last changed in the user's account data stream
+    stream_position BIGINT NOT NULL,
+    PRIMARY KEY (username, type)
+);
+CREATE TABLE IF NOT EXISTS room_account_data (
+    username TEXT NOT NULL,
+    room_id TEXT NOT NULL,
+    type TEXT NOT NULL,
+    content JSONB NOT NULL,
+    -- where this type of account data was last changed in the user's account data stream
+    stream_position BIGINT NOT NULL,
+    PRIMARY KEY (username, room_id, type)
+);
+CREATE TABLE IF NOT EXISTS access_tokens (
+    token UUID PRIMARY KEY,
+    username TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    -- milliseconds since the unix epoch, or null if the token never expires
+    expires_at BIGINT,
+    logged_out BOOLEAN NOT NULL DEFAULT FALSE
+);
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    token UUID PRIMARY KEY,
+    username TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    access_token UUID NOT NULL
+);
+CREATE TABLE IF NOT EXISTS devices (
+    username TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    display_name TEXT,
+    last_seen_ts BIGINT NOT NULL,
+    PRIMARY KEY (username, device_id)
+);
+CREATE TABLE IF NOT EXISTS device_keys (
+    username TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    keys JSONB NOT NULL,
+    PRIMARY KEY (username, device_id)
+);
+CREATE TABLE IF NOT EXISTS device_lists (
+    username TEXT PRIMARY KEY,
+    version BIGINT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS one_time_keys (
+    username TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    key_id TEXT NOT NULL,
+    key JSONB NOT NULL,
+    PRIMARY KEY (username, device_id, key_id)
+);
+CREATE TABLE IF NOT EXISTS to_device (
+    -- the order messages were sent in
+    id BIGSERIAL PRIMARY KEY,
+    username TEXT NOT NULL,
+    device_id TEXT NOT NULL,
+    event JSONB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS txn_ids (
+    token UUID NOT NULL,
+    txn_id TEXT NOT NULL,
+    PRIMARY KEY (token, txn_id)
+);
+CREATE TABLE IF NOT EXISTS threepids (
+    medium TEXT NOT NULL,
+    address TEXT NOT NULL,
+    username TEXT NOT NULL,
+    validated_at BIGINT NOT NULL,
+    added_at BIGINT NOT NULL,
+    PRIMARY KEY (medium, address)
+);
+CREATE TABLE IF NOT EXISTS threepid_sessions (
+    sid TEXT PRIMARY KEY,
+    client_secret TEXT NOT NULL,
+    medium TEXT NOT NULL,
+    address TEXT NOT NULL,
+    validated_at BIGINT
+);
+CREATE TABLE IF NOT EXISTS uiaa_sessions (
+    session_id TEXT PRIMARY KEY,
+    created_at BIGINT NOT NULL,
+    completed TEXT[] NOT NULL DEFAULT '{}'
+);
+-- sessions from before this have nothing to match, so they can't be finished
+ALTER TABLE uiaa_sessions ADD COLUMN IF NOT EXISTS request TEXT NOT NULL DEFAULT '';
+CREATE TABLE IF NOT EXISTS rooms (
+    room_id TEXT PRIMARY KEY,
+    max_depth BIGINT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS events (
+    room_id TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    stream_ordering BIGINT NOT NULL,
+    pdu JSONB NOT NULL,
+    PRIMARY KEY (room_id, event_id),
+    UNIQUE (room_id, stream_ordering)
+);
+CREATE TABLE IF NOT EXISTS forward_extremities (
+    room_id TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    PRIMARY KEY (room_id, event_id)
+);
+CREATE TABLE IF NOT EXISTS room_aliases (
+    alias TEXT PRIMARY KEY,
+    room_id TEXT NOT NULL
+);
+-- aliases from before this have no recorded creator
+ALTER TABLE room_aliases ADD COLUMN IF NOT EXISTS creator TEXT;
+CREATE TABLE IF NOT EXISTS published_rooms (
+    room_id TEXT PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS ephemeral (
+    room_id TEXT NOT NULL,
+    type TEXT NOT NULL,
+    content JSONB NOT NULL,
+    PRIMARY KEY (room_id, type)
+);
+CREATE TABLE IF NOT EXISTS typing (
+    room_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    -- milliseconds since the unix epoch
+    expires_at BIGINT NOT NULL,
+    PRIMARY KEY (room_id, user_id)
+);
+CREATE TABLE IF NOT EXISTS receipts (
+    room_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    receipt_type TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    ts BIGINT NOT NULL,
+    PRIMARY KEY (room_id, user_id, receipt_type)
+);
+CREATE TABLE IF NOT EXISTS fully_read (
+    room_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    event_id TEXT NOT NULL,
+    PRIMARY KEY (room_id, user_id)
+);
+CREATE TABLE IF NOT EXISTS forgotten_rooms (
+    room_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    PRIMARY KEY (room_id, user_id)
+);
+CREATE TABLE IF NOT EXISTS presence (
+    username TEXT PRIMARY KEY,
+    state TEXT NOT NULL,
+    -- milliseconds since the unix epoch
+    last_active_ts BIGINT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS filters (
+    username TEXT NOT NULL,
+    filter_id TEXT NOT NULL,
+    filter JSONB NOT NULL,
+    PRIMARY KEY (username, filter_id)
+);
+CREATE TABLE IF NOT EXISTS batches (
+    id TEXT PRIMARY KEY,
+    batch JSONB NOT NULL
+);
+"#;
+
+/// Tables holding data about a single room, which all get emptied when it's deleted.
+const ROOM_TABLES: &[&str] = &[
+    "events", "forward_extremities", "room_aliases", "published_rooms", "ephemeral", "typing",
+    "receipts", "fully_read", "forgotten_rooms", "room_account_data", "rooms",
+];
+
+/// Storage in a postgres database.
+///
+/// Only one server process can use a database at a time. Waiting `/sync` requests are woken up
+/// for new events and ephemeral data by the process that stored them, so anything another
+/// process stored would go unnoticed until the request timed out.
+pub struct PgStorageManager {
     db_address: String,
     queue: Arc<ArrayQueue<Client>>,
+    /// Wakes up queries waiting for new events in each room, by room ID, and for to-device
+    /// messages for each device, by `to_device_notifier_key`. Only changes made through this
+    /// server process are noticed.
+    notifiers: Arc<Mutex<HashMap<String, Sender<()>>>>,
 }
 
-pub struct ClientGuard {
-    queue: Arc<ArrayQueue<Client>>,
-    inner: Option<Client>,
+/// Room IDs start with `!` and usernames can't contain NUL, so these can't clash with room IDs
+/// or each other.
+fn to_device_notifier_key(username: &str, device_id: &str) -> String {
+    format!("\0{}\0{}", username, device_id)
 }
 
-impl DbPool {
-    pub fn new(db_address: String, cap: usize) -> Self {
-        DbPool {
-            db_address,
+impl PgStorageManager {
+    /// Connects to the database at `db_address` and brings its schema up to date. At most `cap`
+    /// idle connections are kept around to be reused.
+    pub async fn connect(db_address: &str, cap: usize) -> Result<Self, Error> {
+        let manager = PgStorageManager {
+            db_address: db_address.to_string(),
             queue: Arc::new(ArrayQueue::new(cap)),
-        }
+            notifiers: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let client = manager.new_client().await?;
+        client.batch_execute(SCHEMA).await?;
+        let _ = manager.queue.push(client);
+        Ok(manager)
     }
-}
 
-#[async_trait]
-impl super::StorageManager for DbPool {
-    type Handle = ClientGuard;
-    type Error = pg::Error;
-
-    async fn get_handle(&self) -> Result<ClientGuard, pg::Error> {
-        if let Ok(client) = self.queue.pop() {
-            return Ok(ClientGuard {
-                queue: Arc::clone(&self.queue),
-                inner: Some(client),
-            });
-        } else {
-            let (client, conn) = pg::connect(&*self.db_address, NoTls).await.map_err(|e| {
-                log::warn!("{}", e); e
-            })?;
-            tokio::spawn(conn.map(|_| ()));
-            return Ok(ClientGuard {
-                queue: Arc::clone(&self.queue),
-                inner: Some(client),
-            });
-        }
+    async fn new_client(&self) -> Result<Client, Error> {
+        let (client, conn) = pg::connect(&self.db_address, NoTls).await.map_err(|e| {
+            tracing::warn!(error = %e, "Couldn't connect to the database");
+            ErrorKind::StorageUnavailable(format!("{}", e))
+        })?;
+        tokio::spawn(conn.map(|res| {
+            if let Err(e) = res {
+                tracing::warn!(error = %e, "Lost connection to the database");
+            }
+        }));
+        Ok(client)
     }
-}
 
-impl std::fmt::Debug for DbPool {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "DbPool {{ db_address: \"{}\", ... }}", self.db_address)?;
+    /// A handle on a pooled connection, or a new one if none are idle.
+    async fn handle(&self) -> Result<PgStorageHandle, Error> {
+        let client = match self.queue.pop() {
+            Ok(client) if !client.is_closed() => client,
+            _ => self.new_client().await?,
+        };
+        Ok(PgStorageHandle {
+            client: Some(client),
+            queue: Arc::clone(&self.queue),
+            notifiers: Arc::clone(&self.notifiers),
+        })
+    }
+
+    /// Empties every table, so that each test starts from a clean database.
+    #[cfg(test)]
+    pub async fn clear(&self) -> Result<(), Error> {
+        let client = self.new_client().await?;
+        client.batch_execute(
+            "TRUNCATE users, account_data, room_account_data, access_tokens, refresh_tokens, devices,
+                device_keys, device_lists, one_time_keys, to_device, txn_ids, threepids,
+                threepid_sessions, uiaa_sessions, rooms, events, forward_extremities, room_aliases, published_rooms, ephemeral, typing, receipts,
+                fully_read, forgotten_rooms, presence, filters, batches;"
+        ).await?;
         Ok(())
     }
 }
-/*
-impl std::ops::Deref for ClientGuard {
-    type Target = Client;
 
-    fn deref(&self) -> &Client {
-        self.inner.as_ref().unwrap()
+#[async_trait]
+impl StorageManager for PgStorageManager {
+    async fn get_handle(&self) -> Result<Box<dyn Storage>, Error> {
+        Ok(Box::new(self.handle().await?))
     }
-}
 
-impl std::ops::DerefMut for ClientGuard {
-    fn deref_mut(&mut self) -> &mut Client {
-        self.inner.as_mut().unwrap()
+    async fn purge_expired_tokens(&self, now: i64) -> Result<usize, Error> {
+        let handle = self.handle().await?;
+        let purged = handle.db()
+            .execute("DELETE FROM access_tokens WHERE expires_at <= $1", &[&now])
+            .await?;
+        Ok(purged as usize)
     }
 }
-*/
-impl Drop for ClientGuard {
+
+pub struct PgStorageHandle {
+    /// Only ever None while the handle is being dropped, when the client goes back to the pool
+    client: Option<Client>,
+    queue: Arc<ArrayQueue<Client>>,
+    notifiers: Arc<Mutex<HashMap<String, Sender<()>>>>,
+}
+
+impl Drop for PgStorageHandle {
     fn drop(&mut self) {
-        let _ = self.queue.push(self.inner.take().unwrap());
+        if let Some(client) = self.client.take() {
+            if !client.is_closed() {
+                let _ = self.queue.push(client);
+            }
+        }
     }
 }
 
-#[async_trait]
-impl super::Storage for ClientGuard {
-    type Error = pg::Error;
+fn now_millis() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Events are stored as JSON, since their content is flattened into them. Their stream ordering
+/// is kept in its own column, which is the authority on it.
+fn pdu_from_row(row: &Row) -> Result<StoredPdu, Error> {
+    let mut pdu: StoredPdu = serde_json::from_value(row.get("pdu"))?;
+    pdu.stream_ordering = row.get::<_, i64>("stream_ordering") as usize;
+    Ok(pdu)
+}
+
+fn mxid_from_row(row: &Row, column: &str) -> Result<MatrixId, Error> {
+    MatrixId::try_from(row.get::<_, String>(column))
+        .map_err(|e| ErrorKind::Unknown(format!("Invalid user ID in the database: {}", e)).into())
+}
 
-    async fn create_user(&mut self, username: &str, password_hash: Option<&str>) -> Result<(), DbError> {
-        let db = self.inner.as_mut().unwrap();
-        let stmt = db.prepare("INSERT INTO users(id, password_hash) VALUES ($1, $2);").await?;
-        db.execute(&stmt, &[&username, &password_hash]).await?;
+fn device_from_row(row: &Row) -> Device {
+    Device {
+        device_id: row.get("device_id"),
+        display_name: row.get("display_name"),
+        last_seen_ts: row.get("last_seen_ts"),
+    }
+}
+
+fn medium_from_row(row: &Row) -> Result<Medium, Error> {
+    serde_json::from_value(JsonValue::from(row.get::<_, String>("medium"))).map_err(Into::into)
+}
+
+impl PgStorageHandle {
+    fn db(&self) -> &Client {
+        self.client.as_ref().unwrap()
+    }
+
+    async fn notify_room(&self, room_id: &str) {
+        if let Some(notify_send) = self.notifiers.lock().await.get(room_id) {
+            let _ = notify_send.send(());
+        }
+    }
+
+    async fn user_exists(&self, username: &str) -> Result<bool, Error> {
+        let row = self.db()
+            .query_opt("SELECT 1 FROM users WHERE username = $1", &[&username])
+            .await?;
+        Ok(row.is_some())
+    }
+
+    async fn bump_device_list(&self, username: &str) -> Result<(), Error> {
+        self.db().execute(
+            "INSERT INTO device_lists (username, version) VALUES ($1, 1)
+                ON CONFLICT (username) DO UPDATE SET version = device_lists.version + 1",
+            &[&username],
+        ).await?;
+        Ok(())
+    }
+
+    /// Finds who a token belongs to, as (username, device_id).
+    async fn token_owner(&self, token: Uuid) -> Result<Option<(String, String)>, Error> {
+        let row = self.db()
+            .query_opt("SELECT username, device_id FROM access_tokens WHERE token = $1", &[&token])
+            .await?;
+        Ok(row.map(|row| (row.get("username"), row.get("device_id"))))
+    }
+
+    /// Drops the logged out tokens of a device once it has logged in again.
+    async fn forget_logged_out(&self, username: &str, device_id: &str) -> Result<(), Error> {
+        self.db().execute(
+            "DELETE FROM access_tokens WHERE logged_out AND username = $1 AND device_id = $2",
+            &[&username, &device_id],
+        ).await?;
+        Ok(())
+    }
+
+    /// Marks a device as seen, creating it first if it's new.
+    async fn touch_device(&self, username: &str, device_id: &str) -> Result<(), Error> {
+        self.db().execute(
+            "INSERT INTO devices (username, device_id, last_seen_ts) VALUES ($1, $2, $3)
+                ON CONFLICT (username, device_id) DO UPDATE SET last_seen_ts = $3",
+            &[&username, &device_id, &now_millis()],
+        ).await?;
         Ok(())
     }
 
-    async fn verify_password(&mut self, username: &str, password: &str) -> Result<bool, DbError> {
-        let db = self.inner.as_mut().unwrap();
-        let rows = db.query(
-            "SELECT password_hash FROM users WHERE id=$1;", &[&username]).await?;
-        let user = match rows.get(0) {
-            Some(v) => v,
-            None => return Ok(false),
+    async fn get_events(&self, query: &EventQuery<'_>, from: usize, to: Option<usize>) -> Result<(Vec<StoredPdu>, usize), Error> {
+        let to = match to {
+            Some(to) => to,
+            None => {
+                let row = self.db().query_one(
+                    "SELECT MAX(stream_ordering) FROM events WHERE room_id = $1",
+                    &[&query.room_id],
+                ).await?;
+                match row.get::<_, Option<i64>>(0) {
+                    Some(to) => to as usize,
+                    None => return Err(ErrorKind::RoomNotFound.into()),
+                }
+            },
         };
+        if from > to {
+            return Ok((Vec::new(), to));
+        }
 
-        match argon2::verify_encoded(user.get("password_hash"), password.as_bytes()) {
-            Ok(true) => Ok(true),
-            Ok(false) | Err(_) => Ok(false),
+        let rows = self.db().query(
+            "SELECT pdu, stream_ordering FROM events
+                WHERE room_id = $1 AND stream_ordering BETWEEN $2 AND $3
+                ORDER BY stream_ordering",
+            &[&query.room_id, &(from as i64), &(to as i64)],
+        ).await?;
+        let pdus = rows.iter().map(pdu_from_row).collect::<Result<Vec<_>, _>>()?;
+        Ok((query.select(pdus), to))
+    }
+
+    async fn get_typing(&self, room_id: &str) -> Result<Typing, Error> {
+        let rows = self.db().query(
+            "SELECT user_id FROM typing WHERE room_id = $1 AND expires_at > $2",
+            &[&room_id, &now_millis()],
+        ).await?;
+        let mut ret = Typing::default();
+        for row in rows.iter() {
+            ret.user_ids.insert(mxid_from_row(row, "user_id")?);
         }
+        Ok(ret)
     }
 
-    async fn create_access_token(&mut self, username: &str, device_id: &str) -> Result<uuid::Uuid, DbError> {
-        let db = self.inner.as_mut().unwrap();
-        let access_token = uuid::Uuid::new_v4();
-        db.execute(
-            "INSERT INTO access_tokens(token, username, device_id) VALUES ($1, $2, $3);",
-            &[&access_token, &username, &device_id]
+    async fn get_receipts(&self, room_id: &str, user_id: &MatrixId) -> Result<Receipts, Error> {
+        let rows = self.db().query(
+            "SELECT user_id, receipt_type, event_id, ts FROM receipts
+                WHERE room_id = $1 AND (receipt_type = $2 OR user_id = $3)",
+            &[&room_id, &ReceiptType::Read.as_str(), &user_id.as_str()],
         ).await?;
-        Ok(access_token)
+        let mut ret = Receipts::default();
+        for row in rows.iter() {
+            let receipt_type = match row.get::<_, &str>("receipt_type") {
+                "m.read" => ReceiptType::Read,
+                _ => ReceiptType::ReadPrivate,
+            };
+            ret.insert(
+                row.get("event_id"),
+                receipt_type,
+                &mxid_from_row(row, "user_id")?,
+                Receipt { ts: row.get("ts") },
+            );
+        }
+        Ok(ret)
+    }
+}
+
+#[async_trait]
+impl Storage for PgStorageHandle {
+    async fn create_user(&self, username: &str, password: &str) -> Result<(), Error> {
+        let salt: [u8; 16] = rand::random();
+        let password_hash = argon2::hash_encoded(password.as_bytes(), &salt, &Default::default())?;
+        let inserted = self.db().execute(
+            "INSERT INTO users (username, password_hash) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            &[&username, &password_hash],
+        ).await?;
+        match inserted {
+            0 => Err(ErrorKind::UsernameTaken.into()),
+            _ => Ok(()),
+        }
     }
 
-    async fn delete_access_token(&mut self, token: uuid::Uuid) -> Result<(), DbError> {
-        let db = self.inner.as_mut().unwrap();
-        db.execute("DELETE FROM access_tokens WHERE token = $1;", &[&token]).await?;
+    async fn create_guest_user(&self, username: &str) -> Result<(), Error> {
+        let inserted = self.db().execute(
+            "INSERT INTO users (username, is_guest) VALUES ($1, TRUE) ON CONFLICT DO NOTHING",
+            &[&username],
+        ).await?;
+        match inserted {
+            0 => Err(ErrorKind::UsernameTaken.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn delete_user(&self, username: &str) -> Result<(), Error> {
+        self.db().execute("DELETE FROM users WHERE username = $1", &[&username]).await?;
         Ok(())
     }
 
-    async fn delete_all_access_tokens(&mut self, token: uuid::Uuid) -> Result<(), DbError> {
-        let db = self.inner.as_mut().unwrap();
-        db.execute("
-            WITH name AS (
-                SELECT username FROM access_tokens WHERE token = $1
-            )
-            DELETE FROM access_tokens WHERE username IN name;
-        ", &[&token]).await?;
+    async fn is_guest(&self, username: &str) -> Result<bool, Error> {
+        let row = self.db()
+            .query_opt("SELECT is_guest FROM users WHERE username = $1", &[&username])
+            .await?
+            .ok_or(ErrorKind::UserNotFound)?;
+        Ok(row.get("is_guest"))
+    }
+
+    async fn set_password_hash(&self, username: &str, password_hash: &str) -> Result<(), Error> {
+        let updated = self.db().execute(
+            "UPDATE users SET password_hash = $2 WHERE username = $1",
+            &[&username, &password_hash],
+        ).await?;
+        if updated == 0 {
+            return Err(ErrorKind::UserNotFound.into());
+        }
         Ok(())
     }
 
-    async fn try_auth(&mut self, token: uuid::Uuid) -> Result<Option<String>, DbError> {
-        let db = self.inner.as_mut().unwrap();
-        let rows = db.query(
-            "SELECT username FROM access_tokens WHERE token = $1;", &[&token]
+    async fn verify_password(&self, username: &str, password: &str) -> Result<bool, Error> {
+        let row = self.db()
+            .query_opt("SELECT password_hash FROM users WHERE username = $1", &[&username])
+            .await?;
+        if let Some(row) = row {
+            match argon2::verify_encoded(row.get("password_hash"), password.as_bytes()) {
+                Ok(true) => Ok(true),
+                Ok(false) => Ok(false),
+                Err(_) => Ok(false),
+            }
+        } else {
+            Ok(false)
+        }
+    }
+
+    async fn create_access_token(
+        &self,
+        username: &str,
+        device_id: &str,
+    ) -> Result<Uuid, Error> {
+        let token = Uuid::new_v4();
+        if !self.user_exists(username).await? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        self.forget_logged_out(username, device_id).await?;
+        self.touch_device(username, device_id).await?;
+        self.db().execute(
+            "INSERT INTO access_tokens (token, username, device_id) VALUES ($1, $2, $3)",
+            &[&token, &username, &device_id],
+        ).await?;
+        Ok(token)
+    }
+
+    async fn create_refreshable_access_token(
+        &self,
+        username: &str,
+        device_id: &str,
+        lifetime: Duration,
+    ) -> Result<(Uuid, Uuid), Error> {
+        if !self.user_exists(username).await? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        let access_token = Uuid::new_v4();
+        let refresh_token = Uuid::new_v4();
+        let expires_at = now_millis() + lifetime.as_millis() as i64;
+        self.forget_logged_out(username, device_id).await?;
+        self.touch_device(username, device_id).await?;
+        self.db().execute(
+            "INSERT INTO access_tokens (token, username, device_id, expires_at)
+                VALUES ($1, $2, $3, $4)",
+            &[&access_token, &username, &device_id, &expires_at],
         ).await?;
-        let id = match rows.get(0) {
-            Some(r) => r.get("username"),
-            None => None,
-        };
-        Ok(id)
+        self.db().execute(
+            "INSERT INTO refresh_tokens (token, username, device_id, access_token)
+                VALUES ($1, $2, $3, $4)",
+            &[&refresh_token, &username, &device_id, &access_token],
+        ).await?;
+        Ok((access_token, refresh_token))
     }
 
-    async fn get_profile(&mut self, username: &str) -> Result<Option<UserProfile>, DbError> {
-        let db = self.inner.as_mut().unwrap();
-        let rows = db.query(
-            "SELECT avatar_url, display_name FROM users WHERE id = $1;", &[&username]
+    async fn refresh_access_token(
+        &self,
+        refresh_token: Uuid,
+        lifetime: Duration,
+    ) -> Result<Option<(Uuid, Uuid)>, Error> {
+        let row = self.db().query_opt(
+            "DELETE FROM refresh_tokens WHERE token = $1
+                RETURNING username, device_id, access_token",
+            &[&refresh_token],
         ).await?;
-        let row = match rows.get(0) {
-            Some(v) => v,
+        let row = match row {
+            Some(row) => row,
             None => return Ok(None),
         };
-        let profile = UserProfile {
-            avatar_url: row.try_get("avatar_url")?,
-            displayname: row.try_get("display_name")?,
+        let access_token: Uuid = row.get("access_token");
+        self.db().execute("DELETE FROM access_tokens WHERE token = $1", &[&access_token]).await?;
+        let username: String = row.get("username");
+        let device_id: String = row.get("device_id");
+        self.create_refreshable_access_token(&username, &device_id, lifetime)
+            .await
+            .map(Some)
+    }
+
+    async fn delete_access_token(&self, token: Uuid) -> Result<(), Error> {
+        self.db().execute("DELETE FROM access_tokens WHERE token = $1", &[&token]).await?;
+        self.db().execute("DELETE FROM refresh_tokens WHERE access_token = $1", &[&token]).await?;
+        Ok(())
+    }
+
+    async fn delete_all_access_tokens(&self, token: Uuid) -> Result<(), Error> {
+        if let Some((username, _)) = self.token_owner(token).await? {
+            self.db().execute("DELETE FROM access_tokens WHERE username = $1", &[&username]).await?;
+            self.db().execute("DELETE FROM refresh_tokens WHERE username = $1", &[&username]).await?;
+        }
+        Ok(())
+    }
+
+    async fn logout_device(&self, token: Uuid) -> Result<(), Error> {
+        if let Some((username, device_id)) = self.token_owner(token).await? {
+            // the tokens are kept around for a while so that clients using them can be told to log
+            // in again as the same device
+            self.db().execute(
+                "UPDATE access_tokens SET logged_out = TRUE, expires_at = LEAST(expires_at, $3)
+                    WHERE username = $1 AND device_id = $2",
+                &[&username, &device_id, &logged_out_expiry(None)],
+            ).await?;
+            self.db().execute(
+                "DELETE FROM refresh_tokens WHERE username = $1 AND device_id = $2",
+                &[&username, &device_id],
+            ).await?;
+        }
+        Ok(())
+    }
+
+    async fn logout_all_devices(&self, token: Uuid) -> Result<(), Error> {
+        if let Some((username, _)) = self.token_owner(token).await? {
+            self.db().execute(
+                "UPDATE access_tokens SET logged_out = TRUE, expires_at = LEAST(expires_at, $2)
+                    WHERE username = $1",
+                &[&username, &logged_out_expiry(None)],
+            ).await?;
+            self.db().execute("DELETE FROM refresh_tokens WHERE username = $1", &[&username]).await?;
+        }
+        Ok(())
+    }
+
+    async fn try_auth(&self, token: Uuid) -> Result<Option<String>, Error> {
+        let row = self.db().query_opt(
+            "SELECT username, device_id, expires_at, logged_out FROM access_tokens
+                WHERE token = $1",
+            &[&token],
+        ).await?;
+        let row = match row {
+            Some(row) => row,
+            None => return Ok(None),
         };
-        Ok(Some(profile))
+        let expires_at: Option<i64> = row.get("expires_at");
+        if row.get("logged_out") || expires_at.map(|t| t <= now_millis()).unwrap_or(false) {
+            return Err(ErrorKind::SoftLogout.into());
+        }
+        let username: String = row.get("username");
+        self.touch_device(&username, row.get("device_id")).await?;
+        Ok(Some(username))
+    }
+
+    async fn get_token_device(&self, token: Uuid) -> Result<Option<String>, Error> {
+        Ok(self.token_owner(token).await?.map(|(_, device_id)| device_id))
+    }
+
+    async fn get_devices(&self, username: &str) -> Result<Vec<Device>, Error> {
+        let rows = self.db().query(
+            "SELECT device_id, display_name, last_seen_ts FROM devices WHERE username = $1",
+            &[&username],
+        ).await?;
+        Ok(rows.iter().map(device_from_row).collect())
+    }
+
+    async fn get_device(&self, username: &str, device_id: &str) -> Result<Option<Device>, Error> {
+        let row = self.db().query_opt(
+            "SELECT device_id, display_name, last_seen_ts FROM devices
+                WHERE username = $1 AND device_id = $2",
+            &[&username, &device_id],
+        ).await?;
+        Ok(row.as_ref().map(device_from_row))
+    }
+
+    async fn set_device_display_name(
+        &self,
+        username: &str,
+        device_id: &str,
+        display_name: Option<&str>,
+    ) -> Result<bool, Error> {
+        let updated = self.db().execute(
+            "UPDATE devices SET display_name = $3 WHERE username = $1 AND device_id = $2",
+            &[&username, &device_id, &display_name],
+        ).await?;
+        Ok(updated == 1)
+    }
+
+    async fn delete_device(&self, username: &str, device_id: &str) -> Result<(), Error> {
+        let tables = &[
+            "devices", "device_keys", "one_time_keys", "to_device", "access_tokens", "refresh_tokens",
+        ];
+        for table in tables {
+            self.db().execute(
+                &*format!("DELETE FROM {} WHERE username = $1 AND device_id = $2", table),
+                &[&username, &device_id],
+            ).await?;
+        }
+        self.bump_device_list(username).await
+    }
+
+    async fn set_device_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+        keys: JsonValue,
+    ) -> Result<(), Error> {
+        if !self.user_exists(username).await? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        self.db().execute(
+            "INSERT INTO device_keys (username, device_id, keys) VALUES ($1, $2, $3)
+                ON CONFLICT (username, device_id) DO UPDATE SET keys = $3",
+            &[&username, &device_id, &keys],
+        ).await?;
+        self.bump_device_list(username).await
     }
 
-    async fn set_avatar_url(&mut self, username: &str, avatar_url: &str) -> Result<(), DbError> {
-        let db = self.inner.as_mut().unwrap();
-        db.execute(
-            "UPDATE users SET avatar_url = $1 WHERE id = $2;", &[&avatar_url, &username]
+    async fn get_device_list_version(&self, username: &str) -> Result<usize, Error> {
+        let row = self.db().query_opt(
+            "SELECT version FROM device_lists WHERE username = $1",
+            &[&username],
         ).await?;
+        Ok(row.map(|row| row.get::<_, i64>("version") as usize).unwrap_or(0))
+    }
+
+    async fn get_device_keys(&self, username: &str) -> Result<HashMap<String, JsonValue>, Error> {
+        let rows = self.db().query(
+            "SELECT device_id, keys FROM device_keys WHERE username = $1",
+            &[&username],
+        ).await?;
+        Ok(rows.iter().map(|row| (row.get("device_id"), row.get("keys"))).collect())
+    }
+
+    async fn add_one_time_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+        keys: HashMap<String, JsonValue>,
+    ) -> Result<(), Error> {
+        if !self.user_exists(username).await? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        for (key_id, key) in keys.iter() {
+            self.db().execute(
+                "INSERT INTO one_time_keys (username, device_id, key_id, key) VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (username, device_id, key_id) DO UPDATE SET key = $4",
+                &[&username, &device_id, key_id, key],
+            ).await?;
+        }
         Ok(())
     }
 
-    async fn set_display_name(&mut self, username: &str, display_name: &str) -> Result<(), DbError> {
-        let db = self.inner.as_mut().unwrap();
-        db.execute(
-            "UPDATE users SET display_name = $1 WHERE id = $2;", &[&display_name, &username]
+    async fn claim_one_time_key(
+        &self,
+        username: &str,
+        device_id: &str,
+        algorithm: &str,
+    ) -> Result<Option<(String, JsonValue)>, Error> {
+        // rows being claimed by someone else are skipped over rather than handed out twice
+        let row = self.db().query_opt(
+            "DELETE FROM one_time_keys WHERE (username, device_id, key_id) = (
+                SELECT username, device_id, key_id FROM one_time_keys
+                    WHERE username = $1 AND device_id = $2 AND left(key_id, length($3)) = $3
+                    LIMIT 1 FOR UPDATE SKIP LOCKED
+            ) RETURNING key_id, key",
+            &[&username, &device_id, &format!("{}:", algorithm)],
+        ).await?;
+        Ok(row.map(|row| (row.get("key_id"), row.get("key"))))
+    }
+
+    async fn count_one_time_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+    ) -> Result<HashMap<String, usize>, Error> {
+        let rows = self.db().query(
+            "SELECT key_id FROM one_time_keys WHERE username = $1 AND device_id = $2",
+            &[&username, &device_id],
+        ).await?;
+        let key_ids: Vec<String> = rows.iter().map(|row| row.get("key_id")).collect();
+        Ok(count_by_algorithm(key_ids.iter().map(String::as_str)))
+    }
+
+    async fn add_to_device_message(
+        &self,
+        username: &str,
+        device_id: &str,
+        event: ToDeviceEvent,
+    ) -> Result<(), Error> {
+        if !self.user_exists(username).await? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        self.db().execute(
+            "INSERT INTO to_device (username, device_id, event) VALUES ($1, $2, $3)",
+            &[&username, &device_id, &serde_json::to_value(&event)?],
         ).await?;
+        if let Some(notify_send) = self.notifiers.lock().await.get(&to_device_notifier_key(username, device_id)) {
+            let _ = notify_send.send(());
+        }
         Ok(())
     }
 
-    async fn add_pdus(
-        &mut self,
-        pdus: &[PduV4],
-    ) -> Result<(), DbError> {
-        let db = self.inner.as_mut().unwrap();
-        let stmt = db.prepare("
-            INSERT INTO room_events(room_id, sender, origin, origin_server_ts, type, state_key,
-                    content, prev_events, depth, auth_events, redacts, unsigned, hash,
-                    signatures)
-                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14);
-        ").await?;
-        for event in pdus {
-            db.execute(&stmt, &[
-                &event.room_id,
-                &event.sender,
-                &event.origin,
-                &event.origin_server_ts,
-                &event.ty,
-                &event.state_key,
-                &JsonValue::from(event.content.clone()),
-                &to_json_value(&event.prev_events).unwrap(),
-                &event.depth,
-                &to_json_value(&event.auth_events).unwrap(),
-                &event.redacts,
-                &event.unsigned.as_ref().map(to_json_value).map(Result::unwrap),
-                &event.hashes.sha256,
-                &to_json_value(&event.signatures).unwrap(),
-            ]).await?;
-            handle_event(db, &event).await?;
+    async fn get_to_device_messages(
+        &self,
+        username: &str,
+        device_id: &str,
+        after: u64,
+        wait: bool,
+    ) -> Result<Vec<(u64, ToDeviceEvent)>, Error> {
+        // subscribe before looking, so that a message queued in between still wakes us up
+        let mut recv = self.notifiers.lock().await
+            .entry(to_device_notifier_key(username, device_id))
+            .or_insert_with(|| channel(1).0)
+            .subscribe();
+        let query = "SELECT id, event FROM to_device
+            WHERE username = $1 AND device_id = $2 AND id > $3
+            ORDER BY id";
+        let after = after as i64;
+        let mut rows = self.db().query(query, &[&username, &device_id, &after]).await?;
+        if wait && rows.is_empty() {
+            let _ = recv.recv().await;
+            rows = self.db().query(query, &[&username, &device_id, &after]).await?;
         }
+        rows.into_iter()
+            .map(|row| Ok((row.get::<_, i64>("id") as u64, serde_json::from_value(row.get("event"))?)))
+            .collect()
+    }
+
+    async fn delete_to_device_messages(
+        &self,
+        username: &str,
+        device_id: &str,
+        up_to: u64,
+    ) -> Result<(), Error> {
+        self.db().execute(
+            "DELETE FROM to_device WHERE username = $1 AND device_id = $2 AND id <= $3",
+            &[&username, &device_id, &(up_to as i64)],
+        ).await?;
+        Ok(())
+    }
+
+    async fn record_txn(&self, token: Uuid, txn_id: String) -> Result<bool, Error> {
+        let inserted = self.db().execute(
+            "INSERT INTO txn_ids (token, txn_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            &[&token, &txn_id],
+        ).await?;
+        Ok(inserted == 1)
+    }
+
+    async fn get_profile(&self, username: &str) -> Result<Option<UserProfile>, Error> {
+        let row = self.db().query_opt(
+            "SELECT avatar_url, displayname FROM users WHERE username = $1",
+            &[&username],
+        ).await?;
+        Ok(row.map(|row| UserProfile {
+            avatar_url: row.get("avatar_url"),
+            displayname: row.get("displayname"),
+        }))
+    }
+
+    async fn search_users(&self, search_term: &str)
+        -> Result<Vec<(String, UserProfile)>, Error> {
+        let search_term = search_term.to_lowercase();
+        let rows = self.db().query(
+            "SELECT username, avatar_url, displayname FROM users WHERE NOT is_guest",
+            &[],
+        ).await?;
+        let mut ret = Vec::new();
+        for row in rows.iter() {
+            let username: String = row.get("username");
+            let profile = UserProfile {
+                avatar_url: row.get("avatar_url"),
+                displayname: row.get("displayname"),
+            };
+            if user_matches(&username, &profile, &search_term) {
+                ret.push((username, profile));
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn set_avatar_url(&self, username: &str, avatar_url: &str) -> Result<(), Error> {
+        let updated = self.db().execute(
+            "UPDATE users SET avatar_url = $2 WHERE username = $1",
+            &[&username, &avatar_url],
+        ).await?;
+        match updated {
+            0 => Err(ErrorKind::UserNotFound.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn set_display_name(&self, username: &str, display_name: &str) -> Result<(), Error> {
+        let updated = self.db().execute(
+            "UPDATE users SET displayname = $2 WHERE username = $1",
+            &[&username, &display_name],
+        ).await?;
+        match updated {
+            0 => Err(ErrorKind::UserNotFound.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn create_threepid_session(
+        &self,
+        client_secret: &str,
+        medium: Medium,
+        address: &str,
+    ) -> Result<String, Error> {
+        let sid = Uuid::new_v4().to_simple().to_string();
+        self.db().execute(
+            "INSERT INTO threepid_sessions (sid, client_secret, medium, address)
+                VALUES ($1, $2, $3, $4)",
+            &[&sid, &client_secret, &medium.as_str(), &address],
+        ).await?;
+        Ok(sid)
+    }
+
+    async fn validate_threepid_session(&self, sid: &str) -> Result<(), Error> {
+        self.db().execute(
+            "UPDATE threepid_sessions SET validated_at = $2 WHERE sid = $1",
+            &[&sid, &now_millis()],
+        ).await?;
         Ok(())
     }
 
-    async fn get_memberships_by_user(&mut self, user_id: &str)
-            -> Result<HashMap<String, Membership>, DbError> {
-        let db = self.inner.as_mut().unwrap();
-        let query = db.prepare("
-            SELECT room_id, membership FROM room_memberships WHERE user_id = $1;
-        ").await?;
-        let memberships = db.query(&query, &[&user_id]).await?.iter()
-            .map(|row| {
-                let membership_str: &str = row.get("membership");
-                let membership = membership_str.parse().unwrap();
-                (row.get("room_id"), membership)
+    async fn get_threepid_session(
+        &self,
+        sid: &str,
+        client_secret: &str,
+    ) -> Result<Option<ThreepidSession>, Error> {
+        let row = self.db().query_opt(
+            "SELECT client_secret, medium, address, validated_at FROM threepid_sessions
+                WHERE sid = $1 AND client_secret = $2",
+            &[&sid, &client_secret],
+        ).await?;
+        row.map(|row| -> Result<ThreepidSession, Error> {
+            Ok(ThreepidSession {
+                client_secret: row.get("client_secret"),
+                medium: medium_from_row(&row)?,
+                address: row.get("address"),
+                validated_at: row.get("validated_at"),
             })
-            .collect::<HashMap<_, _>>();
-        Ok(memberships)
-    }
-
-    async fn get_membership(&mut self, user_id: &str, room_id: &str) 
-            -> Result<Option<Membership>, DbError> {
-        let db = self.inner.as_mut().unwrap();
-        let rows = db.query("
-            SELECT membership
-                FROM room_memberships
-                WHERE user_id = $1 AND room_id = $2;
-        ", &[&user_id, &room_id]).await?;
-        match rows.get(0) {
-            Some(row) => {
-                let membership_str: &str = row.get("membership");
-                let membership = membership_str.parse().unwrap();
-                Ok(Some(membership))
+        }).transpose()
+    }
+
+    async fn create_uiaa_session(&self, request: &str) -> Result<String, Error> {
+        let session_id = Uuid::new_v4().to_simple().to_string();
+        self.db().execute(
+            "INSERT INTO uiaa_sessions (session_id, created_at, request) VALUES ($1, $2, $3)",
+            &[&session_id, &now_millis(), &request],
+        ).await?;
+        Ok(session_id)
+    }
+
+    async fn get_uiaa_session(&self, session_id: &str) -> Result<Option<UiaaSession>, Error> {
+        let row = self.db().query_opt(
+            "SELECT created_at, completed, request FROM uiaa_sessions WHERE session_id = $1",
+            &[&session_id],
+        ).await?;
+        Ok(row.map(|row| UiaaSession {
+            created_at: row.get("created_at"),
+            completed: row.get("completed"),
+            request: row.get("request"),
+        }))
+    }
+
+    async fn complete_uiaa_stage(&self, session_id: &str, stage: &str) -> Result<(), Error> {
+        self.db().execute(
+            "UPDATE uiaa_sessions SET completed = array_append(completed, $2) WHERE session_id = $1",
+            &[&session_id, &stage],
+        ).await?;
+        Ok(())
+    }
+
+    async fn delete_uiaa_session(&self, session_id: &str) -> Result<(), Error> {
+        self.db().execute("DELETE FROM uiaa_sessions WHERE session_id = $1", &[&session_id]).await?;
+        Ok(())
+    }
+
+    async fn purge_expired_uiaa_sessions(&self, before: i64) -> Result<usize, Error> {
+        let purged = self.db()
+            .execute("DELETE FROM uiaa_sessions WHERE created_at < $1", &[&before])
+            .await?;
+        Ok(purged as usize)
+    }
+
+    async fn add_threepid(&self, username: &str, threepid: Threepid) -> Result<(), Error> {
+        let owner = self.get_threepid_owner(threepid.medium, &threepid.address).await?;
+        match owner {
+            Some(owner) if owner == username => Ok(()),
+            Some(_) => Err(ErrorKind::ThreepidInUse.into()),
+            None => {
+                let inserted = self.db().execute(
+                    "INSERT INTO threepids (medium, address, username, validated_at, added_at)
+                        VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING",
+                    &[
+                        &threepid.medium.as_str(),
+                        &threepid.address,
+                        &username,
+                        &threepid.validated_at,
+                        &threepid.added_at,
+                    ],
+                ).await?;
+                match inserted {
+                    // someone else got there first
+                    0 => Err(ErrorKind::ThreepidInUse.into()),
+                    _ => Ok(()),
+                }
             },
-            None => Ok(None),
-        }
-    }
-
-    async fn get_room_member_counts(&mut self, room_id: &str) -> Result<(i64, i64), DbError> {
-        let db = self.inner.as_mut().unwrap();
-        let rows = db.query("
-            SELECT membership, COUNT(membership) FROM room_memberships
-                WHERE room_id = $1
-                GROUP BY membership;
-        ", &[&room_id]).await?;
-        let mut joined_member_count = 0;
-        let mut invited_member_count = 0;
-        for row in rows {
-            let count = row.get("count");
-            match row.get("membership") {
-                "join" => joined_member_count = count,
-                "invite" => invited_member_count = count,
-                _ => {},
+        }
+    }
+
+    async fn get_threepid_owner(
+        &self,
+        medium: Medium,
+        address: &str,
+    ) -> Result<Option<String>, Error> {
+        let row = self.db().query_opt(
+            "SELECT username FROM threepids WHERE medium = $1 AND address = $2",
+            &[&medium.as_str(), &address],
+        ).await?;
+        Ok(row.map(|row| row.get("username")))
+    }
+
+    async fn get_threepids(&self, username: &str) -> Result<Vec<Threepid>, Error> {
+        let rows = self.db().query(
+            "SELECT medium, address, validated_at, added_at FROM threepids WHERE username = $1",
+            &[&username],
+        ).await?;
+        rows.iter()
+            .map(|row| -> Result<Threepid, Error> {
+                Ok(Threepid {
+                    medium: medium_from_row(row)?,
+                    address: row.get("address"),
+                    validated_at: row.get("validated_at"),
+                    added_at: row.get("added_at"),
+                })
+            })
+            .collect()
+    }
+
+    async fn remove_threepid(
+        &self,
+        username: &str,
+        medium: Medium,
+        address: &str,
+    ) -> Result<bool, Error> {
+        let deleted = self.db().execute(
+            "DELETE FROM threepids WHERE medium = $1 AND address = $2 AND username = $3",
+            &[&medium.as_str(), &address, &username],
+        ).await?;
+        Ok(deleted > 0)
+    }
+
+    async fn add_pdus(&self, pdus: &[StoredPdu]) -> Result<(), Error> {
+        'pdus: for pdu in pdus {
+            let room_id = pdu.room_id();
+            let event_id = pdu.event_id();
+            if self.get_pdu(room_id, event_id).await?.is_some() {
+                continue;
+            }
+            // the room's greatest depth lives alongside it
+            self.db().execute(
+                "INSERT INTO rooms (room_id, max_depth) VALUES ($1, $2)
+                    ON CONFLICT (room_id)
+                    DO UPDATE SET max_depth = GREATEST(rooms.max_depth, EXCLUDED.max_depth)",
+                &[&room_id, &pdu.depth()],
+            ).await?;
+
+            let json = serde_json::to_value(pdu)?;
+            loop {
+                let res = self.db().execute(
+                    "INSERT INTO events (room_id, event_id, stream_ordering, pdu)
+                        SELECT $1, $2, COALESCE(MAX(stream_ordering) + 1, 0), $3
+                        FROM events WHERE room_id = $1",
+                    &[&room_id, &event_id, &json],
+                ).await;
+                match res {
+                    Ok(_) => break,
+                    // either someone else took this place in the timeline first, or they added
+                    // this same event
+                    Err(e) if e.code() == Some(&SqlState::UNIQUE_VIOLATION) => {
+                        if self.get_pdu(room_id, event_id).await?.is_some() {
+                            continue 'pdus;
+                        }
+                    },
+                    Err(e) => return Err(e.into()),
+                }
             }
+
+            for prev_event in pdu.prev_events() {
+                self.db().execute(
+                    "DELETE FROM forward_extremities WHERE room_id = $1 AND event_id = $2",
+                    &[&room_id, prev_event],
+                ).await?;
+            }
+            self.db().execute(
+                "INSERT INTO forward_extremities (room_id, event_id) VALUES ($1, $2)
+                    ON CONFLICT DO NOTHING",
+                &[&room_id, &event_id],
+            ).await?;
+
+            if let (EventContent::Redaction(_), Some(target_id)) = (pdu.event_content(), pdu.redacts()) {
+                if pdu.did_pass_auth() {
+                    if let Some(target) = self.get_pdu(room_id, target_id).await? {
+                        let redacted = serde_json::to_value(target.redact_because(pdu))?;
+                        self.db().execute(
+                            "UPDATE events SET pdu = $3 WHERE room_id = $1 AND event_id = $2",
+                            &[&room_id, &target_id, &redacted],
+                        ).await?;
+                    }
+                }
+            }
+
+            self.notify_room(room_id).await;
         }
-        Ok((joined_member_count, invited_member_count))
+        Ok(())
     }
 
-    async fn get_full_state(&mut self, room_id: &str) -> Result<Vec<Event>, DbError> {
-        let db = self.inner.as_mut().unwrap();
-        let rows = db.query("
-            SELECT DISTINCT ON (type, state_key)
-                sender, type, state_key, content, unsigned, redacts, hash, origin_server_ts
-                FROM room_events
-                WHERE room_id = $1 AND state_key != NULL
-                ORDER BY type, state_key, depth DESC;
-        ", &[&room_id]).await?;
-        let mut ret = Vec::new();
-        for row in rows {
-            ret.push(Event {
-                room_id: None,
-                sender: row.get("sender"),
-                ty: row.get("type"),
-                state_key: row.get("state_key"),
-                content: row.get("content"),
-                unsigned: row.get("unsigned"),
-                redacts: row.get("redacts"),
-                event_id: row.get("hash"),
-                origin_server_ts: row.get("origin_server_ts"),
-            });
+    async fn get_prev_events(&self, room_id: &str) -> Result<(Vec<String>, i64), Error> {
+        let max_depth = self.db()
+            .query_opt("SELECT max_depth FROM rooms WHERE room_id = $1", &[&room_id])
+            .await?
+            .map(|row| row.get("max_depth"))
+            .unwrap_or(-1);
+        let prev_events = self.db()
+            .query("SELECT event_id FROM forward_extremities WHERE room_id = $1", &[&room_id])
+            .await?
+            .iter()
+            .map(|row| row.get("event_id"))
+            .collect();
+        Ok((prev_events, max_depth))
+    }
+
+    async fn query_pdus<'a>(
+        &self,
+        query: EventQuery<'a>,
+        wait: bool,
+    ) -> Result<(Vec<StoredPdu>, usize), Error> {
+        if !self.room_exists(query.room_id).await? {
+            return Err(ErrorKind::RoomNotFound.into());
+        }
+
+        let (from, to) = query.query_type.range();
+
+        // subscribe before looking, so that events added in between still wake us up
+        let mut recv = self.notifiers.lock().await
+            .entry(query.room_id.to_string())
+            .or_insert_with(|| channel(1).0)
+            .subscribe();
+
+        let res = self.get_events(&query, from, to).await?;
+
+        // if we don't need to wait, return asap
+        if !(wait && res.0.is_empty() && query.query_type.is_timeline()) {
+            return Ok(res);
+        }
+
+        // Lagging behind just means there's more than one new event, which is fine
+        let _ = recv.recv().await;
+
+        // this time we roll with it
+        self.get_events(&query, from, None).await
+    }
+
+    async fn get_user_rooms(&self, user_id: &MatrixId) -> Result<HashMap<String, Membership>, Error> {
+        let rows = self.db().query(
+            "SELECT DISTINCT ON (room_id) room_id, pdu->'inner'->'content'->'membership' AS membership
+                FROM events
+                WHERE pdu->'inner'->>'type' = 'm.room.member' AND pdu->'inner'->>'state_key' = $1
+                ORDER BY room_id, stream_ordering DESC",
+            &[&user_id.as_str()],
+        ).await?;
+        let mut ret = HashMap::new();
+        for row in rows.iter() {
+            let membership = serde_json::from_value(row.get("membership"))?;
+            ret.insert(row.get("room_id"), membership);
         }
         Ok(ret)
     }
 
-    async fn get_state_event(&mut self, room_id: &str, event_type: &str, state_key: &str)
-            -> Result<Option<Event>, DbError> {
-        let db = self.inner.as_mut().unwrap();
-        let rows = db.query("
-            SELECT DISTINCT ON (state_key)
-                sender, type, state_key, content, unsigned, redacts, hash, origin_server_ts
-                FROM room_events
-                WHERE room_id = $1 AND type = $2 AND state_key = $3
-                ORDER BY state_key, depth DESC;
-        ", &[&room_id, &event_type, &state_key]).await?;
-        if let Some(row) = rows.get(0) {
-            Ok(Some(Event {
-                room_id: None,
-                sender: row.get("sender"),
-                ty: row.get("type"),
-                state_key: row.get("state_key"),
-                content: row.get("content"),
-                unsigned: row.get("unsigned"),
-                redacts: row.get("redacts"),
-                event_id: row.get("hash"),
-                origin_server_ts: row.get("origin_server_ts"),
-            }))
-        } else {
-            Ok(None)
+    async fn get_member_pdus(
+        &self,
+        room_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StoredPdu>, Error> {
+        if !self.room_exists(room_id).await? {
+            return Err(ErrorKind::RoomNotFound.into());
         }
+        let rows = self.db().query(
+            "SELECT DISTINCT ON (pdu->'inner'->>'state_key') pdu, stream_ordering FROM events
+                WHERE room_id = $1 AND pdu->'inner'->>'type' = 'm.room.member'
+                    AND ($2::TEXT IS NULL OR pdu->'inner'->>'state_key' > $2)
+                ORDER BY pdu->'inner'->>'state_key', stream_ordering DESC
+                LIMIT $3",
+            &[&room_id, &after, &(limit as i64)],
+        ).await?;
+        rows.iter().map(pdu_from_row).collect()
     }
 
-    async fn get_events_since(&mut self, room_id: &str, since: u64)
-            -> Result<Vec<Event>, DbError> {
-        let db = self.inner.as_mut().unwrap();
-        let since: i64 = since as i64;
-        let rows = db.query("
-            SELECT
-                sender, type, state_key, content, unsigned, redacts, hash, origin_server_ts
-                FROM room_events
-                WHERE room_id = $1 AND ordering > $2;
-        ", &[&room_id, &since]).await?;
-        let mut ret = Vec::new();
-        for row in rows {
-            ret.push(Event {
-                room_id: None,
-                sender: row.get("sender"),
-                ty: row.get("type"),
-                state_key: row.get("state_key"),
-                content: row.get("content"),
-                unsigned: row.get("unsigned"),
-                redacts: row.get("redacts"),
-                event_id: row.get("hash"),
-                origin_server_ts: row.get("origin_server_ts"),
-            });
+    async fn count_state_events(&self, room_id: &str) -> Result<usize, Error> {
+        if !self.room_exists(room_id).await? {
+            return Err(ErrorKind::RoomNotFound.into());
+        }
+        let row = self.db().query_one(
+            "SELECT COUNT(*) FROM (
+                SELECT DISTINCT pdu->'inner'->>'type', pdu->'inner'->>'state_key' FROM events
+                WHERE room_id = $1 AND pdu->'inner' ? 'state_key'
+            ) AS state",
+            &[&room_id],
+        ).await?;
+        Ok(row.get::<_, i64>(0) as usize)
+    }
+
+    async fn get_rooms(&self) -> Result<Vec<String>, Error> {
+        let rows = self.db().query("SELECT room_id FROM rooms", &[]).await?;
+        Ok(rows.iter().map(|row| row.get("room_id")).collect())
+    }
+
+    async fn purge_events(&self, room_id: &str, before_ts: i64) -> Result<usize, Error> {
+        if !self.room_exists(room_id).await? {
+            return Err(ErrorKind::RoomNotFound.into());
+        }
+        let rows = self.db().query(
+            "SELECT pdu, stream_ordering FROM events WHERE room_id = $1",
+            &[&room_id],
+        ).await?;
+        let mut purged = 0;
+        for row in rows.iter() {
+            let pdu = pdu_from_row(row)?;
+            if should_purge(&pdu, before_ts) {
+                let event_id = pdu.event_id().to_owned();
+                self.db().execute(
+                    "UPDATE events SET pdu = $3 WHERE room_id = $1 AND event_id = $2",
+                    &[&room_id, &event_id, &serde_json::to_value(pdu.redact())?],
+                ).await?;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    async fn room_exists(&self, room_id: &str) -> Result<bool, Error> {
+        let row = self.db()
+            .query_opt("SELECT 1 FROM rooms WHERE room_id = $1", &[&room_id])
+            .await?;
+        Ok(row.is_some())
+    }
+
+    async fn delete_room(&self, room_id: &str) -> Result<(), Error> {
+        for table in ROOM_TABLES {
+            self.db().execute(
+                &*format!("DELETE FROM {} WHERE room_id = $1", table),
+                &[&room_id],
+            ).await?;
+        }
+        self.notifiers.lock().await.remove(room_id);
+        Ok(())
+    }
+
+    async fn set_room_alias(&self, alias: &str, room_id: &str, creator: &str) -> Result<bool, Error> {
+        let inserted = self.db().execute(
+            "INSERT INTO room_aliases (alias, room_id, creator) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+            &[&alias, &room_id, &creator],
+        ).await?;
+        Ok(inserted == 1)
+    }
+
+    async fn get_room_alias(&self, alias: &str) -> Result<Option<String>, Error> {
+        let row = self.db()
+            .query_opt("SELECT room_id FROM room_aliases WHERE alias = $1", &[&alias])
+            .await?;
+        Ok(row.map(|row| row.get("room_id")))
+    }
+
+    async fn get_room_alias_creator(&self, alias: &str) -> Result<Option<String>, Error> {
+        let row = self.db()
+            .query_opt("SELECT creator FROM room_aliases WHERE alias = $1", &[&alias])
+            .await?;
+        Ok(row.and_then(|row| row.get("creator")))
+    }
+
+    async fn delete_room_alias(&self, alias: &str) -> Result<bool, Error> {
+        let deleted = self.db()
+            .execute("DELETE FROM room_aliases WHERE alias = $1", &[&alias])
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    async fn get_room_aliases(&self, room_id: &str) -> Result<Vec<String>, Error> {
+        let rows = self.db()
+            .query("SELECT alias FROM room_aliases WHERE room_id = $1", &[&room_id])
+            .await?;
+        Ok(rows.iter().map(|row| row.get("alias")).collect())
+    }
+
+    async fn set_room_published(&self, room_id: &str, published: bool) -> Result<(), Error> {
+        let statement = match published {
+            true => "INSERT INTO published_rooms (room_id) VALUES ($1) ON CONFLICT DO NOTHING",
+            false => "DELETE FROM published_rooms WHERE room_id = $1",
+        };
+        self.db().execute(statement, &[&room_id]).await?;
+        Ok(())
+    }
+
+    async fn get_published_rooms(&self) -> Result<Vec<String>, Error> {
+        let rows = self.db().query("SELECT room_id FROM published_rooms", &[]).await?;
+        Ok(rows.iter().map(|row| row.get("room_id")).collect())
+    }
+
+    async fn get_pdu(&self, room_id: &str, event_id: &str) -> Result<Option<StoredPdu>, Error> {
+        self.db()
+            .query_opt(
+                "SELECT pdu, stream_ordering FROM events WHERE room_id = $1 AND event_id = $2",
+                &[&room_id, &event_id],
+            )
+            .await?
+            .map(|row| pdu_from_row(&row))
+            .transpose()
+    }
+
+    async fn get_all_ephemeral(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+    ) -> Result<HashMap<String, JsonValue>, Error> {
+        let rows = self.db()
+            .query("SELECT type, content FROM ephemeral WHERE room_id = $1", &[&room_id])
+            .await?;
+        let mut ret: HashMap<String, JsonValue> = rows.iter()
+            .map(|row| (row.get("type"), row.get("content")))
+            .collect();
+        ret.insert(
+            String::from("m.typing"),
+            serde_json::to_value(self.get_typing(room_id).await?)?,
+        );
+        let receipts = self.get_receipts(room_id, user_id).await?;
+        if !receipts.is_empty() {
+            ret.insert(String::from("m.receipt"), serde_json::to_value(receipts)?);
         }
         Ok(ret)
     }
 
-    async fn get_event(&mut self, room_id: &str, event_id: &str)
-            -> Result<Option<Event>, DbError> {
-        let db = self.inner.as_mut().unwrap();
-        let rows = db.query("
-            SELECT
-                sender, type, state_key, content, unsigned, redacts, hash, origin_server_ts
-                FROM room_events
-                WHERE room_id = $1 AND event_id = $2;
-        ", &[&room_id, &event_id]).await?;
-        if let Some(row) = rows.get(0) {
-            Ok(Some(Event {
-                room_id: None,
-                sender: row.get("sender"),
-                ty: row.get("type"),
-                state_key: row.get("state_key"),
-                content: row.get("content"),
-                unsigned: row.get("unsigned"),
-                redacts: row.get("redacts"),
-                event_id: row.get("hash"),
-                origin_server_ts: row.get("origin_server_ts"),
-            }))
+    async fn get_ephemeral(
+        &self,
+        room_id: &str,
+        event_type: &str,
+    ) -> Result<Option<JsonValue>, Error> {
+        if event_type == "m.typing" {
+            let typing = self.get_typing(room_id).await?;
+            match typing.user_ids.is_empty() {
+                true => Ok(None),
+                false => Ok(Some(serde_json::to_value(typing)?)),
+            }
         } else {
-            Ok(None)
+            let row = self.db().query_opt(
+                "SELECT content FROM ephemeral WHERE room_id = $1 AND type = $2",
+                &[&room_id, &event_type],
+            ).await?;
+            Ok(row.map(|row| row.get("content")))
         }
     }
 
-    async fn get_prev_event_ids(&mut self, room_id: &str)
-            -> Result<Option<(i64, Vec<String>)>, DbError> {
-        let db = self.inner.as_mut().unwrap();
-        let rows = db.query("
-            SELECT depth, hash FROM room_events 
-                WHERE depth = (SELECT MAX(depth) FROM room_events WHERE room_id = $1);
-        ", &[&room_id]).await?;
-        let mut ret = Vec::new();
-        let mut depth = 0;
-        for row in rows {
-            ret.push(row.get("hash"));
-            depth = row.get("depth");   // same every time but oh well
-        }
-        if ret.len() != 0 {
-            return Ok(Some((depth, ret)));
+    async fn set_ephemeral(
+        &self,
+        room_id: &str,
+        event_type: &str,
+        content: Option<JsonValue>,
+    ) -> Result<(), Error> {
+        assert!(
+            event_type != "m.typing",
+            "m.typing should not be set directly"
+        );
+        match content {
+            Some(c) => self.db().execute(
+                "INSERT INTO ephemeral (room_id, type, content) VALUES ($1, $2, $3)
+                    ON CONFLICT (room_id, type) DO UPDATE SET content = EXCLUDED.content",
+                &[&room_id, &event_type, &c],
+            ).await?,
+            None => self.db().execute(
+                "DELETE FROM ephemeral WHERE room_id = $1 AND type = $2",
+                &[&room_id, &event_type],
+            ).await?,
+        };
+        Ok(())
+    }
+
+    async fn set_typing(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        is_typing: bool,
+        timeout: u32,
+    ) -> Result<(), Error> {
+        if is_typing {
+            let expires_at = now_millis() + timeout as i64;
+            self.db().execute(
+                "INSERT INTO typing (room_id, user_id, expires_at) VALUES ($1, $2, $3)
+                    ON CONFLICT (room_id, user_id) DO UPDATE SET expires_at = EXCLUDED.expires_at",
+                &[&room_id, &user_id.as_str(), &expires_at],
+            ).await?;
         } else {
-            return Ok(None);
+            self.db().execute(
+                "DELETE FROM typing WHERE room_id = $1 AND user_id = $2",
+                &[&room_id, &user_id.as_str()],
+            ).await?;
+        }
+        Ok(())
+    }
+
+    async fn set_receipt(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        receipt_type: ReceiptType,
+        event_id: &str,
+    ) -> Result<bool, Error> {
+        let old = self.db().query_opt(
+            "SELECT event_id FROM receipts
+                WHERE room_id = $1 AND user_id = $2 AND receipt_type = $3",
+            &[&room_id, &user_id.as_str(), &receipt_type.as_str()],
+        ).await?;
+        if old.map(|row| row.get::<_, &str>("event_id") == event_id).unwrap_or(false) {
+            return Ok(false);
         }
+        self.db().execute(
+            "INSERT INTO receipts (room_id, user_id, receipt_type, event_id, ts)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (room_id, user_id, receipt_type)
+                DO UPDATE SET event_id = EXCLUDED.event_id, ts = EXCLUDED.ts",
+            &[&room_id, &user_id.as_str(), &receipt_type.as_str(), &event_id, &now_millis()],
+        ).await?;
+        self.notify_room(room_id).await;
+        Ok(true)
     }
 
-    async fn get_user_account_data(&mut self, username: &str)
-            -> Result<HashMap<String, JsonValue>, DbError> {
-        let db = self.inner.as_mut().unwrap();
-        let rows = db.query("
-            SELECT (type, content) FROM user_account_data WHERE username = $1;
-        ", &[&username]).await?;
-        let mut ret = HashMap::new();
-        for row in rows {
-            ret.insert(row.get("type"), row.get("content"));
+    async fn set_fully_read(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        event_id: &str,
+    ) -> Result<bool, Error> {
+        let old = self.get_fully_read(room_id, user_id).await?;
+        self.db().execute(
+            "INSERT INTO fully_read (room_id, user_id, event_id) VALUES ($1, $2, $3)
+                ON CONFLICT (room_id, user_id) DO UPDATE SET event_id = EXCLUDED.event_id",
+            &[&room_id, &user_id.as_str(), &event_id],
+        ).await?;
+        let changed = old.as_deref() != Some(event_id);
+        if changed {
+            self.notify_room(room_id).await;
         }
-        Ok(ret)
+        Ok(changed)
     }
-}
 
-async fn handle_event(db: &mut Client, event: &PduV4) -> Result<(), DbError> {
-    match &*event.ty {
-        "m.room.member" => {
-            let membership = event.content.get("membership").unwrap().as_str().unwrap();
-            if membership != "leave" {
-                let stmt = db.prepare("
-                    INSERT INTO room_memberships(user_id, room_id, membership, event_id)
-                        VALUES ($1, $2, $3, $4)
-                        ON CONFLICT(user_id, room_id) DO UPDATE SET membership = $3, event_id = $4;
-                ").await?;
-                db.execute(&stmt,
-                    &[&event.state_key, &event.room_id, &membership, &event.hashes.sha256]
-                ).await?;
-            } else {
-                let stmt = db.prepare("
-                    DELETE FROM room_memberships WHERE user_id = $1 AND room_id = $2;
-                ").await?;
-                db.execute(&stmt, &[&event.state_key, &event.room_id]).await?;
-            }
-        },
-        "m.room.create" => {
-            let stmt = db.prepare("
-                INSERT INTO rooms(id)
-                    VALUES ($1);
-            ").await?;
-            db.execute(&stmt, &[&event.room_id]).await?;
+    async fn get_fully_read(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+    ) -> Result<Option<String>, Error> {
+        let row = self.db().query_opt(
+            "SELECT event_id FROM fully_read WHERE room_id = $1 AND user_id = $2",
+            &[&room_id, &user_id.as_str()],
+        ).await?;
+        Ok(row.map(|row| row.get("event_id")))
+    }
+
+    async fn set_room_forgotten(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        forgotten: bool,
+    ) -> Result<(), Error> {
+        let statement = match forgotten {
+            true => "INSERT INTO forgotten_rooms (room_id, user_id) VALUES ($1, $2)
+                ON CONFLICT DO NOTHING",
+            false => "DELETE FROM forgotten_rooms WHERE room_id = $1 AND user_id = $2",
+        };
+        self.db().execute(statement, &[&room_id, &user_id.as_str()]).await?;
+        Ok(())
+    }
+
+    async fn is_room_forgotten(&self, room_id: &str, user_id: &MatrixId) -> Result<bool, Error> {
+        let row = self.db().query_opt(
+            "SELECT 1 FROM forgotten_rooms WHERE room_id = $1 AND user_id = $2",
+            &[&room_id, &user_id.as_str()],
+        ).await?;
+        Ok(row.is_some())
+    }
+
+    async fn set_presence(&self, username: &str, state: PresenceState) -> Result<(), Error> {
+        if !self.user_exists(username).await? {
+            return Err(ErrorKind::UserNotFound.into());
         }
-        _ => {},
+        let old = self.get_presence(username).await?;
+        let presence = Presence::changed_to(old, state);
+        self.db().execute(
+            "INSERT INTO presence (username, state, last_active_ts) VALUES ($1, $2, $3)
+                ON CONFLICT (username)
+                DO UPDATE SET state = EXCLUDED.state, last_active_ts = EXCLUDED.last_active_ts",
+            &[&username, &presence.state.as_str(), &presence.last_active_ts],
+        ).await?;
+        Ok(())
+    }
+
+    async fn get_presence(&self, username: &str) -> Result<Option<Presence>, Error> {
+        let row = self.db().query_opt(
+            "SELECT state, last_active_ts FROM presence WHERE username = $1",
+            &[&username],
+        ).await?;
+        Ok(row.map(|row| Presence {
+            state: match row.get::<_, &str>("state") {
+                "online" => PresenceState::Online,
+                "unavailable" => PresenceState::Unavailable,
+                _ => PresenceState::Offline,
+            },
+            last_active_ts: row.get("last_active_ts"),
+        }))
+    }
+
+    async fn get_user_account_data(
+        &self,
+        username: &str,
+    ) -> Result<HashMap<String, JsonValue>, Error> {
+        if !self.user_exists(username).await? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        let rows = self.db()
+            .query("SELECT type, content FROM account_data WHERE username = $1", &[&username])
+            .await?;
+        Ok(rows.iter().map(|row| (row.get("type"), row.get("content"))).collect())
+    }
+
+    async fn set_user_account_data(
+        &self,
+        username: &str,
+        ty: &str,
+        content: JsonValue,
+    ) -> Result<(), Error> {
+        let position: i64 = self.db()
+            .query_opt(
+                "UPDATE users SET account_data_position = account_data_position + 1
+                    WHERE username = $1 RETURNING account_data_position",
+                &[&username],
+            )
+            .await?
+            .ok_or(ErrorKind::UserNotFound)?
+            .get("account_data_position");
+        self.db().execute(
+            "INSERT INTO account_data (username, type, content, stream_position)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (username, type)
+                DO UPDATE SET content = EXCLUDED.content, stream_position = EXCLUDED.stream_position",
+            &[&username, &ty, &content, &position],
+        ).await?;
+        Ok(())
+    }
+
+    async fn get_user_account_data_since(
+        &self,
+        username: &str,
+        since: usize,
+    ) -> Result<(HashMap<String, JsonValue>, usize), Error> {
+        let position: i64 = match self.db()
+            .query_opt(
+                "SELECT account_data_position FROM users WHERE username = $1",
+                &[&username],
+            )
+            .await? {
+            Some(row) => row.get("account_data_position"),
+            None => return Ok((HashMap::new(), 0)),
+        };
+        let rows = self.db().query(
+            "SELECT type, content FROM account_data
+                WHERE username = $1 AND (stream_position > $2 OR $2 = 0)",
+            &[&username, &(since as i64)],
+        ).await?;
+        let changed = rows.iter().map(|row| (row.get("type"), row.get("content"))).collect();
+        Ok((changed, position as usize))
+    }
+
+    async fn set_room_account_data(
+        &self,
+        username: &str,
+        room_id: &str,
+        ty: &str,
+        content: JsonValue,
+    ) -> Result<(), Error> {
+        let position: i64 = self.db()
+            .query_opt(
+                "UPDATE users SET account_data_position = account_data_position + 1
+                    WHERE username = $1 RETURNING account_data_position",
+                &[&username],
+            )
+            .await?
+            .ok_or(ErrorKind::UserNotFound)?
+            .get("account_data_position");
+        self.db().execute(
+            "INSERT INTO room_account_data (username, room_id, type, content, stream_position)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT (username, room_id, type)
+                DO UPDATE SET content = EXCLUDED.content, stream_position = EXCLUDED.stream_position",
+            &[&username, &room_id, &ty, &content, &position],
+        ).await?;
+        Ok(())
+    }
+
+    async fn get_room_account_data_since(
+        &self,
+        username: &str,
+        room_id: &str,
+        since: usize,
+    ) -> Result<HashMap<String, JsonValue>, Error> {
+        let rows = self.db().query(
+            "SELECT type, content FROM room_account_data
+                WHERE username = $1 AND room_id = $2 AND (stream_position > $3 OR $3 = 0)",
+            &[&username, &room_id, &(since as i64)],
+        ).await?;
+        Ok(rows.iter().map(|row| (row.get("type"), row.get("content"))).collect())
+    }
+
+    async fn create_filter(&self, username: &str, filter: JsonValue) -> Result<String, Error> {
+        if !self.user_exists(username).await? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        let filter_id = Uuid::new_v4().to_simple().to_string();
+        self.db().execute(
+            "INSERT INTO filters (username, filter_id, filter) VALUES ($1, $2, $3)",
+            &[&username, &filter_id, &filter],
+        ).await?;
+        Ok(filter_id)
+    }
+
+    async fn get_filter(&self, username: &str, filter_id: &str)
+        -> Result<Option<JsonValue>, Error> {
+        let row = self.db().query_opt(
+            "SELECT filter FROM filters WHERE username = $1 AND filter_id = $2",
+            &[&username, &filter_id],
+        ).await?;
+        Ok(row.map(|row| row.get("filter")))
+    }
+
+    async fn get_batch(&self, id: &str) -> Result<Option<Batch>, Error> {
+        let row = self.db()
+            .query_opt("SELECT batch FROM batches WHERE id = $1", &[&id])
+            .await?;
+        let batch = match row {
+            Some(row) => row.get("batch"),
+            None => return Ok(None),
+        };
+        // batches are JSON, so older layouts are filled in by their serde defaults
+        match serde_json::from_value(batch) {
+            Ok(batch) => Ok(Some(batch)),
+            Err(_) => {
+                tracing::warn!(batch = id, "Discarding unreadable batch");
+                Ok(None)
+            },
+        }
+    }
+
+    async fn set_batch(&self, id: &str, batch: Batch) -> Result<(), Error> {
+        self.db().execute(
+            "INSERT INTO batches (id, batch) VALUES ($1, $2)
+                ON CONFLICT (id) DO UPDATE SET batch = EXCLUDED.batch",
+            &[&id, &serde_json::to_value(batch)?],
+        ).await?;
+        Ok(())
     }
-    Ok(())
 }
@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     convert::TryInto,
     sync::Arc,
     time::{Duration, Instant},
@@ -14,12 +14,13 @@ use sled::{
     transaction::{ConflictableTransactionError, TransactionalTree},
     Db, IVec, Tree,
 };
-use tokio::sync::Mutex;
+use futures::future;
+use tokio::sync::{Mutex, broadcast::{channel, Sender}};
 use uuid::Uuid;
 
-use crate::{error::{Error, ErrorKind}, events::{ephemeral::Typing, pdu::StoredPdu}, storage::{Storage, StorageManager}, util::MatrixId};
+use crate::{error::{Error, ErrorKind}, events::{EventContent, ephemeral::{PresenceState, Receipt, ReceiptType, Receipts, ToDeviceEvent, Typing}, pdu::StoredPdu}, storage::{Storage, StorageManager}, util::MatrixId};
 
-use super::{Batch, EventQuery, QueryType, UserProfile};
+use super::{Batch, Device, EventQuery, Medium, Presence, Threepid, ThreepidSession, UiaaSession, UserProfile, count_by_algorithm, logged_out_expiry, should_purge, user_matches};
 
 trait TreeExt {
     type Error;
@@ -150,18 +151,118 @@ struct User {
     password_hash: String,
     profile: UserProfile,
     account_data: HashMap<String, JsonValue>,
+    is_guest: bool,
 }
 
 #[derive(Deserialize, Serialize)]
 struct AccessTokenData {
     username: String,
     device_id: String,
+    /// Milliseconds since the unix epoch, or None if the token never expires
+    expires_at: Option<i64>,
+    /// Whether the token's device has been logged out. Such tokens are kept around so that
+    /// clients using them can be told to log in again as the same device.
+    logged_out: bool,
+}
+
+/// How users were laid out before guests.
+#[derive(Deserialize, Serialize)]
+struct UserV1 {
+    password_hash: String,
+    profile: UserProfile,
+    account_data: HashMap<String, JsonValue>,
+}
+
+/// How access tokens were laid out before they could expire or be soft logged out.
+#[derive(Deserialize, Serialize)]
+struct AccessTokenDataV1 {
+    username: String,
+    device_id: String,
+}
+
+#[derive(Deserialize, Serialize)]
+struct RefreshTokenData {
+    username: String,
+    device_id: String,
+    /// The bytes of the access token issued alongside this refresh token
+    access_token: [u8; 16],
+}
+
+/// Tracks changes to a user's account data. This is kept apart from `User` so that it can be
+/// added without changing the layout of existing users.
+#[derive(Default, Deserialize, Serialize)]
+struct AccountDataStream {
+    position: usize,
+    /// Where each type of account data was last changed in the stream
+    changes: HashMap<String, usize>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct ThreepidData {
+    username: String,
+    threepid: Threepid,
+}
+
+/// Filter IDs are hex, so they can't run into the username.
+/// Usernames can't contain NUL, so no two users' filters can share a key.
+fn filter_key(username: &str, filter_id: &str) -> String {
+    format!("{}\0{}", username, filter_id)
+}
+
+/// Usernames can't contain NUL, so each user's devices can be found by prefix.
+fn device_key(username: &str, device_id: &str) -> String {
+    format!("{}\0{}", username, device_id)
+}
+
+/// One-time keys are found by prefix, either one device's or all of a user's.
+fn one_time_key_key(username: &str, device_id: &str, key_id: &str) -> String {
+    format!("{}\0{}", device_key(username, device_id), key_id)
+}
+
+/// Each message's ID comes last, so a device's messages are found by prefix and come out in the
+/// order they were sent.
+fn to_device_key(username: &str, device_id: &str, id: Option<u64>) -> Vec<u8> {
+    let mut key = format!("{}\0", device_key(username, device_id)).into_bytes();
+    if let Some(id) = id {
+        key.extend_from_slice(&id.to_be_bytes());
+    }
+    key
+}
+
+/// The message ID at the end of a key made by `to_device_key`.
+fn to_device_id(key: &[u8]) -> u64 {
+    let mut id = [0; 8];
+    id.copy_from_slice(&key[key.len() - 8..]);
+    u64::from_be_bytes(id)
+}
+
+/// Keys start with the room ID so that they can be found by prefix when the room is deleted, in
+/// the same way as read markers. Usernames can't contain NUL, so each user's data for a room can
+/// be found by prefix too.
+fn room_account_data_key(room_id: &str, username: &str, ty: &str) -> String {
+    format!("{}_{}\0{}", room_id, username, ty)
+}
+
+/// Reads a key from a room's ordering tree, which is the event's stream ordering.
+fn stream_ordering(key: &[u8]) -> Result<usize, Error> {
+    match key.try_into() {
+        Ok(bytes) => Ok(usize::from_be_bytes(bytes)),
+        Err(_) => Err(ErrorKind::Unknown(format!("malformed stream ordering {:?}", key)).into()),
+    }
+}
+
+fn threepid_key(medium: Medium, address: &str) -> String {
+    format!("{}:{}", medium.as_str(), address)
 }
 
 #[derive(Default)]
 struct Ephemeral {
     ephemeral: HashMap<String, JsonValue>,
     typing: HashMap<MatrixId, Instant>,
+    /// Each user's public read receipt, as (event_id, ts)
+    receipts: HashMap<MatrixId, (String, i64)>,
+    /// Each user's private read receipt, as (event_id, ts)
+    private_receipts: HashMap<MatrixId, (String, i64)>,
 }
 
 impl Ephemeral {
@@ -173,24 +274,217 @@ impl Ephemeral {
         }
         ret
     }
+
+    fn get_receipts(&self, user_id: &MatrixId) -> Receipts {
+        let mut ret = Receipts::default();
+        for (mxid, (event_id, ts)) in self.receipts.iter() {
+            ret.insert(event_id, ReceiptType::Read, mxid, Receipt { ts: *ts });
+        }
+        if let Some((event_id, ts)) = self.private_receipts.get(user_id) {
+            ret.insert(event_id, ReceiptType::ReadPrivate, user_id, Receipt { ts: *ts });
+        }
+        ret
+    }
+}
+
+/// The key in the default tree holding which of `MIGRATIONS` have been applied. Databases from
+/// before it existed are version 1.
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+
+type Migration = fn(&Db) -> Result<(), Error>;
+
+/// Migrations in the order they're applied. Applying the first takes a database from version 1 to
+/// version 2, and so on.
+const MIGRATIONS: &[Migration] = &[
+    json_events_and_wide_keys,
+    store_event_ids,
+    add_account_fields,
+    json_batches,
+];
+
+const SCHEMA_VERSION: usize = MIGRATIONS.len() + 1;
+
+/// Applies whichever migrations the database hasn't had yet.
+fn migrate(db: &Db) -> Result<(), Error> {
+    let version: usize = db.get_value(SCHEMA_VERSION_KEY)?.unwrap_or(1);
+    if version > SCHEMA_VERSION {
+        return Err(ErrorKind::StorageUnavailable(format!(
+            "database is at schema version {}, but only {} is supported",
+            version,
+            SCHEMA_VERSION,
+        )).into());
+    }
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version - 1) {
+        migration(db)?;
+        // bumped after each one, so a migration that fails partway is retried on the next start
+        db.overwrite_value(SCHEMA_VERSION_KEY, idx + 2)?;
+    }
+    db.flush()?;
+    Ok(())
+}
+
+/// Version 2: events are stored as JSON rather than bincode, and ordering trees are keyed by
+/// `usize` rather than `u32`. Version 1 queried its ordering trees with `usize` keys, so it could
+/// never read back what it had stored.
+fn json_events_and_wide_keys(db: &Db) -> Result<(), Error> {
+    let events = db.open_tree("events")?;
+    for res in events.iter() {
+        let (name, bytes) = res?;
+        if serde_json::from_slice::<JsonValue>(&bytes).is_ok() {
+            continue;
+        }
+        let pdu: StoredPdu = DefaultOptions::new().deserialize(&bytes).map_err(|e| {
+            ErrorKind::Unknown(format!("can't migrate event {}: {}", String::from_utf8_lossy(&name), e))
+        })?;
+        events.insert(name, serde_json::to_vec(&pdu)?)?;
+    }
+
+    let rooms = db.open_tree("rooms")?;
+    for res in rooms.iter() {
+        let (room_id, _) = res?;
+        let ordering_tree = db.open_tree(&room_id)?;
+        let narrow = ordering_tree
+            .iter()
+            .filter_ok(|(key, _)| key.len() == 4)
+            .collect::<Result<Vec<_>, _>>()?;
+        for (key, event_id) in narrow {
+            let idx = u32::from_be_bytes([key[0], key[1], key[2], key[3]]) as usize;
+            let res = ordering_tree.compare_and_swap(
+                usize::to_be_bytes(idx),
+                Option::<&[u8]>::None,
+                Some(&event_id),
+            )?;
+            // a migration that failed partway will already have moved some of them
+            if let Err(e) = res {
+                if e.current.as_ref() != Some(&event_id) {
+                    return Err(ErrorKind::Unknown(format!(
+                        "can't migrate {}: stream ordering {} is taken",
+                        String::from_utf8_lossy(&room_id),
+                        idx,
+                    )).into());
+                }
+            }
+            ordering_tree.remove(key)?;
+        }
+    }
+    Ok(())
+}
+
+/// Version 3: PDUs carry their event ID and stream ordering, rather than having them worked out
+/// from the PDU and the room's ordering tree each time.
+fn store_event_ids(db: &Db) -> Result<(), Error> {
+    let events = db.open_tree("events")?;
+    let rooms = db.open_tree("rooms")?;
+    for res in rooms.iter() {
+        let (room_id, _) = res?;
+        let room_id = String::from_utf8_lossy(&room_id);
+        let ordering_tree = db.open_tree(room_id.as_bytes())?;
+        for res in ordering_tree.iter() {
+            let (key, event_id) = res?;
+            let name = format!("{}_{}", room_id, String::from_utf8_lossy(&event_id));
+            let bytes = match events.get(&name)? {
+                Some(bytes) => bytes,
+                None => continue,
+            };
+            // deserializing fills in the event ID, and serializing again stores it
+            let mut pdu: StoredPdu = serde_json::from_slice(&bytes)?;
+            pdu.stream_ordering = stream_ordering(&key)?;
+            events.insert(name, serde_json::to_vec(&pdu)?)?;
+        }
+    }
+    Ok(())
+}
+
+/// Version 4: users record whether they're guests, and access tokens record when they expire and
+/// whether their device has been logged out.
+fn add_account_fields(db: &Db) -> Result<(), Error> {
+    let users = db.open_tree("users")?;
+    for res in users.iter() {
+        let (username, bytes) = res?;
+        // a migration that failed partway will already have rewritten some of them
+        if DefaultOptions::new().deserialize::<User>(&bytes).is_ok() {
+            continue;
+        }
+        let old: UserV1 = DefaultOptions::new().deserialize(&bytes)?;
+        users.overwrite_value(username, User {
+            password_hash: old.password_hash,
+            profile: old.profile,
+            account_data: old.account_data,
+            is_guest: false,
+        })?;
+    }
+
+    let access_tokens = db.open_tree("access_tokens")?;
+    for res in access_tokens.iter() {
+        let (token, bytes) = res?;
+        if DefaultOptions::new().deserialize::<AccessTokenData>(&bytes).is_ok() {
+            continue;
+        }
+        let old: AccessTokenDataV1 = DefaultOptions::new().deserialize(&bytes)?;
+        access_tokens.overwrite_value(token, AccessTokenData {
+            username: old.username,
+            device_id: old.device_id,
+            expires_at: None,
+            logged_out: false,
+        })?;
+    }
+    Ok(())
+}
+
+/// Version 5: sync batches are stored as JSON rather than bincode, so batches from older layouts
+/// can be read with their new fields defaulted. A bincode batch doesn't say which layout it's in,
+/// so they're dropped, and their clients just do a full sync.
+fn json_batches(db: &Db) -> Result<(), Error> {
+    let batches = db.open_tree("batches")?;
+    for res in batches.iter() {
+        let (id, bytes) = res?;
+        if serde_json::from_slice::<JsonValue>(&bytes).is_err() {
+            batches.remove(id)?;
+        }
+    }
+    Ok(())
 }
 
 pub struct SledStorage(SledStorageHandle);
 
 impl SledStorage {
     pub fn new(path: &str) -> Result<Self, Error> {
-        let db = sled::open(path)?;
+        Self::with_db(sled::open(path)?)
+    }
+
+    /// Uses a database that's already open, migrating it first if it's from an older version.
+    pub(super) fn with_db(db: Db) -> Result<Self, Error> {
+        migrate(&db)?;
         Ok(Self(SledStorageHandle {
             all: db.clone(),
             events: db.open_tree("events")?,
             rooms: db.open_tree("rooms")?,
             users: db.open_tree("users")?,
             access_tokens: db.open_tree("access_tokens")?,
+            refresh_tokens: db.open_tree("refresh_tokens")?,
+            devices: db.open_tree("devices")?,
+            device_keys: db.open_tree("device_keys")?,
+            one_time_keys: db.open_tree("one_time_keys")?,
+            to_device: db.open_tree("to_device")?,
             txn_ids: db.open_tree("txn_ids")?,
             batches: db.open_tree("batches")?,
+            filters: db.open_tree("filters")?,
+            threepids: db.open_tree("threepids")?,
+            threepid_sessions: db.open_tree("threepid_sessions")?,
+            uiaa_sessions: db.open_tree("uiaa_sessions")?,
+            account_data_streams: db.open_tree("account_data_streams")?,
+            room_account_data: db.open_tree("room_account_data")?,
+            fully_read: db.open_tree("fully_read")?,
+            forgotten_rooms: db.open_tree("forgotten_rooms")?,
+            presence: db.open_tree("presence")?,
+            device_lists: db.open_tree("device_lists")?,
+            aliases: db.open_tree("aliases")?,
+            alias_creators: db.open_tree("alias_creators")?,
+            published_rooms: db.open_tree("published_rooms")?,
             room_orderings: Arc::new(Mutex::new(HashMap::new())),
             headless_events: db.open_tree("headless_events")?,
             ephemeral: Arc::new(Mutex::new(HashMap::new())),
+            notifiers: Arc::new(Mutex::new(HashMap::new())),
         }))
     }
 }
@@ -200,6 +494,21 @@ impl StorageManager for SledStorage {
     async fn get_handle(&self) -> Result<Box<dyn Storage>, Error> {
         Ok(Box::new(self.0.clone()))
     }
+
+    async fn purge_expired_tokens(&self, now: i64) -> Result<usize, Error> {
+        let mut to_delete = Vec::new();
+        for res in self.0.access_tokens.iter() {
+            let (key, val) = res?;
+            let data: AccessTokenData = DefaultOptions::new().deserialize(&val)?;
+            if data.expires_at.map(|t| t <= now).unwrap_or(false) {
+                to_delete.push(key);
+            }
+        }
+        for key in to_delete.iter() {
+            self.0.access_tokens.remove(key)?;
+        }
+        Ok(to_delete.len())
+    }
 }
 
 #[derive(Clone)]
@@ -209,14 +518,111 @@ pub struct SledStorageHandle {
     rooms: Tree,
     users: Tree,
     access_tokens: Tree,
+    refresh_tokens: Tree,
+    devices: Tree,
+    device_keys: Tree,
+    one_time_keys: Tree,
+    /// Messages waiting to be delivered to devices
+    to_device: Tree,
     txn_ids: Tree,
     batches: Tree,
+    filters: Tree,
+    threepids: Tree,
+    threepid_sessions: Tree,
+    uiaa_sessions: Tree,
+    account_data_streams: Tree,
+    room_account_data: Tree,
+    fully_read: Tree,
+    /// Rooms that users have forgotten, keyed like read markers
+    forgotten_rooms: Tree,
+    presence: Tree,
+    /// Each user's device list version
+    device_lists: Tree,
+    aliases: Tree,
+    /// Each alias's creator, for aliases made once this was recorded
+    alias_creators: Tree,
+    published_rooms: Tree,
     room_orderings: Arc<Mutex<HashMap<String, Tree>>>,
     headless_events: Tree,
     ephemeral: Arc<Mutex<HashMap<String, Ephemeral>>>,
+    /// Wakes anyone waiting on a room when something other than its events changes, like
+    /// receipts and read markers, which aren't stored in a tree that can be watched.
+    notifiers: Arc<Mutex<HashMap<String, Sender<()>>>>,
 }
 
 impl SledStorageHandle {
+    async fn notify_room(&self, room_id: &str) {
+        if let Some(notify_send) = self.notifiers.lock().await.get(room_id) {
+            let _ = notify_send.send(());
+        }
+    }
+
+    fn bump_device_list(&self, username: &str) -> Result<(), Error> {
+        let version: usize = self.device_lists.get_value(username)?.unwrap_or(0);
+        self.device_lists.overwrite_value(username, version + 1).map(drop)
+    }
+
+    /// Marks every access token matching `pred` as logged out, to expire after
+    /// `LOGGED_OUT_TOKEN_LIFETIME`, and deletes the refresh tokens belonging to the same devices.
+    fn log_out_tokens(&self, pred: impl Fn(&AccessTokenData) -> bool) -> Result<(), Error> {
+        let mut devices = HashSet::new();
+        for res in self.access_tokens.iter() {
+            let (key, val) = res?;
+            let mut data: AccessTokenData = DefaultOptions::new().deserialize(&val)?;
+            if pred(&data) {
+                devices.insert((data.username.clone(), data.device_id.clone()));
+                data.logged_out = true;
+                data.expires_at = Some(logged_out_expiry(data.expires_at));
+                self.access_tokens.overwrite_value(key, data)?;
+            }
+        }
+
+        let mut to_delete = Vec::new();
+        for res in self.refresh_tokens.iter() {
+            let (key, val) = res?;
+            let data: RefreshTokenData = DefaultOptions::new().deserialize(&val)?;
+            if devices.contains(&(data.username, data.device_id)) {
+                to_delete.push(key);
+            }
+        }
+        for key in to_delete.into_iter() {
+            self.refresh_tokens.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the logged out tokens of a device once it has logged in again.
+    fn forget_logged_out(&self, username: &str, device_id: &str) -> Result<(), Error> {
+        let mut to_delete = Vec::new();
+        for res in self.access_tokens.iter() {
+            let (key, val) = res?;
+            let data: AccessTokenData = DefaultOptions::new().deserialize(&val)?;
+            if data.logged_out && data.username == username && data.device_id == device_id {
+                to_delete.push(key);
+            }
+        }
+        for key in to_delete.into_iter() {
+            self.access_tokens.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// Marks a device as seen, creating it first if it's new.
+    fn touch_device(&self, username: &str, device_id: &str) -> Result<(), Error> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let key = device_key(username, device_id);
+        let device = match self.devices.get_value(&key)? {
+            Some(device) => Device { last_seen_ts: now, ..device },
+            None => Device {
+                device_id: device_id.to_string(),
+                display_name: None,
+                last_seen_ts: now,
+            },
+        };
+        self.devices.overwrite_value(key, device)?;
+        Ok(())
+    }
+
     async fn get_room_ordering_tree(&self, room_id: &str) -> Result<Tree, Error> {
         let mut ordering_trees = self.room_orderings.lock().await;
         if let Some(tree) = ordering_trees.get(room_id) {
@@ -228,36 +634,46 @@ impl SledStorageHandle {
         }
     }
 
+    /// Events are stored as JSON rather than bincode, since their content is flattened into them
+    /// and bincode can't deserialize that.
+    fn get_pdu_by_name(&self, name: &str) -> Result<Option<StoredPdu>, Error> {
+        self.events.get(name)?
+            .map(|bytes| serde_json::from_slice(&bytes))
+            .transpose()
+            .map_err(Into::into)
+    }
+
+    fn put_pdu(&self, name: &str, pdu: &StoredPdu) -> Result<(), Error> {
+        self.events.insert(name, serde_json::to_vec(pdu)?)?;
+        Ok(())
+    }
+
     async fn get_events(&self, ordering_tree: &Tree, query: &EventQuery<'_>, from: usize, to: Option<usize>) -> Result<(Vec<StoredPdu>, usize), Error> {
-        let mut ret = Vec::new();
+        let mut pdus = Vec::new();
 
-        let from_bytes = from.to_be_bytes();
-        let to_bytes = to.clone().map(usize::to_be_bytes);
-        let pdu_iter = match to_bytes {
-                Some(to_bytes) => ordering_tree.range(from_bytes..=to_bytes),
-                None => ordering_tree.range(from_bytes..),
-            }
-            .map_ok(|(_key, event_id)| {
-                self.events.get(&format!(
-                        "{}_{}",
-                        query.room_id,
-                        String::from_utf8(Vec::from(event_id.as_ref())).unwrap()
-                        ))
-            })
-            // flatten
-            .map(|res| match res {
-                Ok(Ok(v)) => Ok(v),
-                Ok(Err(e)) | Err(e) => Err(e),
-            });
-        for pdu in pdu_iter {
-            // is Ok(None) if the event is not present, but it must be present if it's in the
-            // ordering tree
-            let pdu: StoredPdu = DefaultOptions::new().deserialize(pdu?.unwrap().as_ref())?;
-            if query.matches(&pdu.inner()) {
-                ret.push(pdu);
-            }
+        let to = match to {
+            Some(to) => to,
+            None => match ordering_tree.last()? {
+                Some((key, _value)) => stream_ordering(&key)?,
+                None => return Err(ErrorKind::RoomNotFound.into()),
+            },
+        };
+        if from > to {
+            return Ok((pdus, to));
+        }
+
+        for res in ordering_tree.range(from.to_be_bytes()..=to.to_be_bytes()) {
+            let (_key, event_id) = res?;
+            let name = format!("{}_{}", query.room_id, String::from_utf8_lossy(&event_id));
+            // the event gets its place in the ordering tree just before it's stored, so if it's
+            // missing it's still being added and so is everything after it
+            let pdu = match self.get_pdu_by_name(&name)? {
+                Some(pdu) => pdu,
+                None => break,
+            };
+            pdus.push(pdu);
         }
-        Ok((ret, to.unwrap()))
+        Ok((query.select(pdus), to))
     }
 }
 
@@ -279,6 +695,37 @@ impl Storage for SledStorageHandle {
         }
     }
 
+    async fn create_guest_user(&self, username: &str) -> Result<(), Error> {
+        let did_insert = self.users.try_insert_value(
+            username,
+            &User {
+                is_guest: true,
+                ..Default::default()
+            },
+        )?;
+        match did_insert {
+            true => Ok(()),
+            false => Err(ErrorKind::UsernameTaken.into()),
+        }
+    }
+
+    async fn delete_user(&self, username: &str) -> Result<(), Error> {
+        self.users.remove(username)?;
+        Ok(())
+    }
+
+    async fn is_guest(&self, username: &str) -> Result<bool, Error> {
+        let user: User = self.users.get_value(username)?.ok_or(ErrorKind::UserNotFound)?;
+        Ok(user.is_guest)
+    }
+
+    async fn set_password_hash(&self, username: &str, password_hash: &str) -> Result<(), Error> {
+        let mut user: User = self.users.get_value(username)?.ok_or(ErrorKind::UserNotFound)?;
+        user.password_hash = password_hash.to_string();
+        self.users.overwrite_value(username, user)?;
+        Ok(())
+    }
+
     async fn verify_password(&self, username: &str, password: &str) -> Result<bool, Error> {
         let user: Option<User> = self.users.get_value(username)?;
         if let Some(user) = user {
@@ -301,18 +748,82 @@ impl Storage for SledStorageHandle {
         if !self.users.contains_key(username)? {
             return Err(ErrorKind::UserNotFound.into());
         }
+        self.forget_logged_out(username, device_id)?;
+        self.touch_device(username, device_id)?;
         self.access_tokens.try_insert_value(
             token.as_bytes(),
             &AccessTokenData {
                 username: username.to_string(),
                 device_id: device_id.to_string(),
+                expires_at: None,
+                logged_out: false,
             },
         )?;
         Ok(token)
     }
 
+    async fn create_refreshable_access_token(
+        &self,
+        username: &str,
+        device_id: &str,
+        lifetime: Duration,
+    ) -> Result<(Uuid, Uuid), Error> {
+        if !self.users.contains_key(username)? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        let access_token = Uuid::new_v4();
+        let refresh_token = Uuid::new_v4();
+        let expires_at = chrono::Utc::now().timestamp_millis() + lifetime.as_millis() as i64;
+        self.forget_logged_out(username, device_id)?;
+        self.touch_device(username, device_id)?;
+        self.access_tokens.try_insert_value(
+            access_token.as_bytes(),
+            &AccessTokenData {
+                username: username.to_string(),
+                device_id: device_id.to_string(),
+                expires_at: Some(expires_at),
+                logged_out: false,
+            },
+        )?;
+        self.refresh_tokens.try_insert_value(
+            refresh_token.as_bytes(),
+            &RefreshTokenData {
+                username: username.to_string(),
+                device_id: device_id.to_string(),
+                access_token: *access_token.as_bytes(),
+            },
+        )?;
+        Ok((access_token, refresh_token))
+    }
+
+    async fn refresh_access_token(
+        &self,
+        refresh_token: Uuid,
+        lifetime: Duration,
+    ) -> Result<Option<(Uuid, Uuid)>, Error> {
+        let data: RefreshTokenData = match self.refresh_tokens.remove(refresh_token.as_bytes())? {
+            Some(bytes) => DefaultOptions::new().deserialize(&bytes)?,
+            None => return Ok(None),
+        };
+        self.access_tokens.remove(data.access_token)?;
+        self.create_refreshable_access_token(&data.username, &data.device_id, lifetime)
+            .await
+            .map(Some)
+    }
+
     async fn delete_access_token(&self, token: Uuid) -> Result<(), Error> {
         self.access_tokens.remove(token.as_bytes())?;
+        let mut to_delete = Vec::new();
+        for res in self.refresh_tokens.iter() {
+            let (key, val) = res?;
+            let data: RefreshTokenData = DefaultOptions::new().deserialize(&val)?;
+            if &data.access_token == token.as_bytes() {
+                to_delete.push(key);
+            }
+        }
+        for key in to_delete.into_iter() {
+            self.refresh_tokens.remove(key)?;
+        }
         Ok(())
     }
 
@@ -334,16 +845,266 @@ impl Storage for SledStorageHandle {
             for key in to_delete.into_iter() {
                 self.access_tokens.remove(key)?;
             }
+
+            let mut to_delete = Vec::new();
+            for res in self.refresh_tokens.iter() {
+                let (key, val) = res?;
+                let data: RefreshTokenData = DefaultOptions::new().deserialize(&val)?;
+                if data.username == username {
+                    to_delete.push(key);
+                }
+            }
+            for key in to_delete.into_iter() {
+                self.refresh_tokens.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn logout_device(&self, token: Uuid) -> Result<(), Error> {
+        let data: Option<AccessTokenData> = self.access_tokens.get_value(token.as_bytes())?;
+        if let Some(data) = data {
+            self.log_out_tokens(|other| {
+                other.username == data.username && other.device_id == data.device_id
+            })?;
+        }
+        Ok(())
+    }
+
+    async fn logout_all_devices(&self, token: Uuid) -> Result<(), Error> {
+        let data: Option<AccessTokenData> = self.access_tokens.get_value(token.as_bytes())?;
+        if let Some(data) = data {
+            self.log_out_tokens(|other| other.username == data.username)?;
+        }
+        Ok(())
+    }
+
+    async fn try_auth(&self, token: Uuid) -> Result<Option<String>, Error> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let data: Option<AccessTokenData> = self.access_tokens.get_value(token.as_bytes())?;
+        match data {
+            Some(data) if data.logged_out => Err(ErrorKind::SoftLogout.into()),
+            Some(data) if data.expires_at.map(|t| t <= now).unwrap_or(false) => {
+                Err(ErrorKind::SoftLogout.into())
+            },
+            Some(data) => {
+                self.touch_device(&data.username, &data.device_id)?;
+                Ok(Some(data.username))
+            },
+            None => Ok(None),
+        }
+    }
+
+    async fn get_token_device(&self, token: Uuid) -> Result<Option<String>, Error> {
+        let data: Option<AccessTokenData> = self.access_tokens.get_value(token.as_bytes())?;
+        Ok(data.map(|data| data.device_id))
+    }
+
+    async fn get_devices(&self, username: &str) -> Result<Vec<Device>, Error> {
+        let mut devices = Vec::new();
+        for res in self.devices.scan_prefix(device_key(username, "")).values() {
+            devices.push(DefaultOptions::new().deserialize(&res?)?);
+        }
+        Ok(devices)
+    }
+
+    async fn get_device(&self, username: &str, device_id: &str) -> Result<Option<Device>, Error> {
+        self.devices.get_value(device_key(username, device_id))
+    }
+
+    async fn set_device_display_name(
+        &self,
+        username: &str,
+        device_id: &str,
+        display_name: Option<&str>,
+    ) -> Result<bool, Error> {
+        let key = device_key(username, device_id);
+        let device: Option<Device> = self.devices.get_value(&key)?;
+        match device {
+            Some(mut device) => {
+                device.display_name = display_name.map(String::from);
+                self.devices.overwrite_value(key, device)?;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    async fn delete_device(&self, username: &str, device_id: &str) -> Result<(), Error> {
+        self.devices.remove(device_key(username, device_id))?;
+        self.device_keys.remove(device_key(username, device_id))?;
+        let one_time_keys = self.one_time_keys.scan_prefix(one_time_key_key(username, device_id, ""))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        for key in one_time_keys.into_iter() {
+            self.one_time_keys.remove(key)?;
+        }
+        let to_device = self.to_device.scan_prefix(to_device_key(username, device_id, None))
+            .keys()
+            .collect::<Result<Vec<_>, _>>()?;
+        for key in to_device.into_iter() {
+            self.to_device.remove(key)?;
+        }
+        self.bump_device_list(username)?;
+        let mut to_delete = Vec::new();
+        for res in self.access_tokens.iter() {
+            let (key, val) = res?;
+            let data: AccessTokenData = DefaultOptions::new().deserialize(&val)?;
+            if data.username == username && data.device_id == device_id {
+                to_delete.push(key);
+            }
+        }
+        for key in to_delete.into_iter() {
+            self.access_tokens.remove(key)?;
+        }
+
+        let mut to_delete = Vec::new();
+        for res in self.refresh_tokens.iter() {
+            let (key, val) = res?;
+            let data: RefreshTokenData = DefaultOptions::new().deserialize(&val)?;
+            if data.username == username && data.device_id == device_id {
+                to_delete.push(key);
+            }
+        }
+        for key in to_delete.into_iter() {
+            self.refresh_tokens.remove(key)?;
+        }
+        Ok(())
+    }
+
+    async fn set_device_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+        keys: JsonValue,
+    ) -> Result<(), Error> {
+        if !self.users.contains_key(username)? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        // stored as JSON, since bincode can't deserialize arbitrary JSON values
+        self.device_keys.insert(device_key(username, device_id), serde_json::to_vec(&keys)?)?;
+        self.bump_device_list(username)
+    }
+
+    async fn get_device_list_version(&self, username: &str) -> Result<usize, Error> {
+        Ok(self.device_lists.get_value(username)?.unwrap_or(0))
+    }
+
+    async fn get_device_keys(&self, username: &str) -> Result<HashMap<String, JsonValue>, Error> {
+        let prefix = device_key(username, "");
+        let mut keys = HashMap::new();
+        for res in self.device_keys.scan_prefix(&prefix) {
+            let (key, val) = res?;
+            let device_id = String::from_utf8_lossy(&key[prefix.len()..]).into_owned();
+            keys.insert(device_id, serde_json::from_slice(&val)?);
+        }
+        Ok(keys)
+    }
+
+    async fn add_one_time_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+        keys: HashMap<String, JsonValue>,
+    ) -> Result<(), Error> {
+        if !self.users.contains_key(username)? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        for (key_id, key) in keys {
+            let db_key = one_time_key_key(username, device_id, &key_id);
+            self.one_time_keys.insert(db_key, serde_json::to_vec(&key)?)?;
+        }
+        Ok(())
+    }
+
+    async fn claim_one_time_key(
+        &self,
+        username: &str,
+        device_id: &str,
+        algorithm: &str,
+    ) -> Result<Option<(String, JsonValue)>, Error> {
+        let device_prefix = one_time_key_key(username, device_id, "");
+        let prefix = one_time_key_key(username, device_id, &format!("{}:", algorithm));
+        for res in self.one_time_keys.scan_prefix(&prefix) {
+            let (key, _) = res?;
+            // whoever removes the key gets it, and anyone else who found it moves on to the next
+            if let Some(val) = self.one_time_keys.remove(&key)? {
+                let key_id = String::from_utf8_lossy(&key[device_prefix.len()..]).into_owned();
+                return Ok(Some((key_id, serde_json::from_slice(&val)?)));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn count_one_time_keys(
+        &self,
+        username: &str,
+        device_id: &str,
+    ) -> Result<HashMap<String, usize>, Error> {
+        let prefix = one_time_key_key(username, device_id, "");
+        let mut key_ids = Vec::new();
+        for res in self.one_time_keys.scan_prefix(&prefix) {
+            let (key, _) = res?;
+            key_ids.push(String::from_utf8_lossy(&key[prefix.len()..]).into_owned());
+        }
+        Ok(count_by_algorithm(key_ids.iter().map(String::as_str)))
+    }
+
+    async fn add_to_device_message(
+        &self,
+        username: &str,
+        device_id: &str,
+        event: ToDeviceEvent,
+    ) -> Result<(), Error> {
+        if !self.users.contains_key(username)? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        // IDs start at 1, since a batch that hasn't sent any messages has 0
+        let key = to_device_key(username, device_id, Some(self.all.generate_id()? + 1));
+        self.to_device.insert(key, serde_json::to_vec(&event)?)?;
+        Ok(())
+    }
+
+    async fn get_to_device_messages(
+        &self,
+        username: &str,
+        device_id: &str,
+        after: u64,
+        wait: bool,
+    ) -> Result<Vec<(u64, ToDeviceEvent)>, Error> {
+        let get = || -> Result<Vec<(u64, ToDeviceEvent)>, Error> {
+            let start = to_device_key(username, device_id, Some(after.saturating_add(1)));
+            let end = to_device_key(username, device_id, Some(u64::MAX));
+            let mut events = Vec::new();
+            for res in self.to_device.range(start..=end) {
+                let (key, val) = res?;
+                events.push((to_device_id(&key), serde_json::from_slice(&val)?));
+            }
+            Ok(events)
+        };
+        // subscribe before looking, so that a message queued in between still wakes us up
+        let subscriber = self.to_device.watch_prefix(to_device_key(username, device_id, None));
+        let events = get()?;
+        if !(wait && events.is_empty()) {
+            return Ok(events);
         }
-        Ok(())
+        subscriber.await;
+        get()
     }
 
-    async fn try_auth(&self, token: Uuid) -> Result<Option<String>, Error> {
-        let maybe_username = self
-            .access_tokens
-            .get_value(token.as_bytes())?
-            .map(|data: AccessTokenData| data.username);
-        Ok(maybe_username)
+    async fn delete_to_device_messages(
+        &self,
+        username: &str,
+        device_id: &str,
+        up_to: u64,
+    ) -> Result<(), Error> {
+        let start = to_device_key(username, device_id, Some(0));
+        let end = to_device_key(username, device_id, Some(up_to));
+        for res in self.to_device.range(start..=end) {
+            let (key, _) = res?;
+            self.to_device.remove(key)?;
+        }
+        Ok(())
     }
 
     async fn record_txn(&self, token: Uuid, txn_id: String) -> Result<bool, Error> {
@@ -352,11 +1113,30 @@ impl Storage for SledStorageHandle {
         Ok(is_new)
     }
 
+    async fn user_exists(&self, username: &str) -> Result<bool, Error> {
+        Ok(self.users.contains_key(username)?)
+    }
+
     async fn get_profile(&self, username: &str) -> Result<Option<UserProfile>, Error> {
         let profile = self.users.get_value(username)?.map(|u: User| u.profile);
         Ok(profile)
     }
 
+    async fn search_users(&self, search_term: &str)
+        -> Result<Vec<(String, UserProfile)>, Error> {
+        let search_term = search_term.to_lowercase();
+        let mut ret = Vec::new();
+        for entry in self.users.iter() {
+            let (username, user) = entry?;
+            let username = String::from_utf8(username.to_vec())?;
+            let user: User = DefaultOptions::new().deserialize(&user)?;
+            if !user.is_guest && user_matches(&username, &user.profile, &search_term) {
+                ret.push((username, user.profile));
+            }
+        }
+        Ok(ret)
+    }
+
     async fn set_avatar_url(&self, username: &str, avatar_url: &str) -> Result<(), Error> {
         let mut user: User = self
             .users
@@ -377,29 +1157,182 @@ impl Storage for SledStorageHandle {
         Ok(())
     }
 
+    async fn create_threepid_session(
+        &self,
+        client_secret: &str,
+        medium: Medium,
+        address: &str,
+    ) -> Result<String, Error> {
+        let sid = Uuid::new_v4().to_simple().to_string();
+        self.threepid_sessions.overwrite_value(&sid, &ThreepidSession {
+            client_secret: client_secret.to_string(),
+            medium,
+            address: address.to_string(),
+            validated_at: None,
+        })?;
+        Ok(sid)
+    }
+
+    async fn validate_threepid_session(&self, sid: &str) -> Result<(), Error> {
+        let session: Option<ThreepidSession> = self.threepid_sessions.get_value(sid)?;
+        if let Some(mut session) = session {
+            session.validated_at = Some(chrono::Utc::now().timestamp_millis());
+            self.threepid_sessions.overwrite_value(sid, &session)?;
+        }
+        Ok(())
+    }
+
+    async fn get_threepid_session(
+        &self,
+        sid: &str,
+        client_secret: &str,
+    ) -> Result<Option<ThreepidSession>, Error> {
+        let session: Option<ThreepidSession> = self.threepid_sessions.get_value(sid)?;
+        Ok(session.filter(|session| session.client_secret == client_secret))
+    }
+
+    async fn create_uiaa_session(&self, request: &str) -> Result<String, Error> {
+        let session_id = Uuid::new_v4().to_simple().to_string();
+        self.uiaa_sessions.overwrite_value(&session_id, &UiaaSession {
+            created_at: chrono::Utc::now().timestamp_millis(),
+            completed: Vec::new(),
+            request: request.to_string(),
+        })?;
+        Ok(session_id)
+    }
+
+    async fn get_uiaa_session(&self, session_id: &str) -> Result<Option<UiaaSession>, Error> {
+        // sessions from before they were tied to a request can't be read, and they'd have had to
+        // start over anyway
+        Ok(self.uiaa_sessions.get_value(session_id).unwrap_or(None))
+    }
+
+    async fn complete_uiaa_stage(&self, session_id: &str, stage: &str) -> Result<(), Error> {
+        let session: Option<UiaaSession> = self.uiaa_sessions.get_value(session_id)?;
+        if let Some(mut session) = session {
+            session.completed.push(stage.to_string());
+            self.uiaa_sessions.overwrite_value(session_id, &session)?;
+        }
+        Ok(())
+    }
+
+    async fn delete_uiaa_session(&self, session_id: &str) -> Result<(), Error> {
+        self.uiaa_sessions.remove(session_id)?;
+        Ok(())
+    }
+
+    async fn purge_expired_uiaa_sessions(&self, before: i64) -> Result<usize, Error> {
+        let mut to_delete = Vec::new();
+        for res in self.uiaa_sessions.iter() {
+            let (key, val) = res?;
+            let session: Option<UiaaSession> = DefaultOptions::new().deserialize(&val).ok();
+            if session.map(|session| session.created_at < before).unwrap_or(true) {
+                to_delete.push(key);
+            }
+        }
+        for key in to_delete.iter() {
+            self.uiaa_sessions.remove(key)?;
+        }
+        Ok(to_delete.len())
+    }
+
+    async fn add_threepid(&self, username: &str, threepid: Threepid) -> Result<(), Error> {
+        let key = threepid_key(threepid.medium, &threepid.address);
+        let existing: Option<ThreepidData> = self.threepids.get_value(&key)?;
+        match existing {
+            Some(data) if data.username == username => Ok(()),
+            Some(_) => Err(ErrorKind::ThreepidInUse.into()),
+            None => {
+                self.threepids.try_insert_value(&key, &ThreepidData {
+                    username: username.to_string(),
+                    threepid,
+                })?;
+                Ok(())
+            },
+        }
+    }
+
+    async fn get_threepid_owner(
+        &self,
+        medium: Medium,
+        address: &str,
+    ) -> Result<Option<String>, Error> {
+        let data: Option<ThreepidData> = self.threepids.get_value(threepid_key(medium, address))?;
+        Ok(data.map(|data| data.username))
+    }
+
+    async fn get_threepids(&self, username: &str) -> Result<Vec<Threepid>, Error> {
+        let mut ret = Vec::new();
+        for res in self.threepids.iter() {
+            let (_key, val) = res?;
+            let data: ThreepidData = DefaultOptions::new().deserialize(&val)?;
+            if data.username == username {
+                ret.push(data.threepid);
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn remove_threepid(
+        &self,
+        username: &str,
+        medium: Medium,
+        address: &str,
+    ) -> Result<bool, Error> {
+        let key = threepid_key(medium, address);
+        let data: Option<ThreepidData> = self.threepids.get_value(&key)?;
+        match data {
+            Some(data) if data.username == username => {
+                self.threepids.remove(&key)?;
+                Ok(true)
+            },
+            _ => Ok(false),
+        }
+    }
+
     async fn add_pdus(&self, pdus: &[StoredPdu]) -> Result<(), Error> {
         for pdu in pdus {
             let name = format!("{}_{}", pdu.room_id(), pdu.event_id());
-            self.events.try_insert_value(name, pdu)?;
+            if self.events.contains_key(&name)? {
+                continue;
+            }
             let ordering_tree = self.get_room_ordering_tree(&pdu.room_id()).await?;
-            'cas: loop {
-                if let Some((key, _value)) = ordering_tree.last()? {
-                    let idx = u32::from_be_bytes(key[0..4].try_into().unwrap()) + 1;
-                    let res = ordering_tree.compare_and_swap(
-                        &u32::to_be_bytes(idx),
-                        Option::<&[u8]>::None,
-                        Some(&*pdu.event_id()),
-                    )?;
-                    if res.is_ok() {
-                        break 'cas;
-                    }
+            let stream_ordering = 'cas: loop {
+                let idx = match ordering_tree.last()? {
+                    Some((key, _value)) => stream_ordering(&key)? + 1,
+                    None => 0,
+                };
+                let res = ordering_tree.compare_and_swap(
+                    &usize::to_be_bytes(idx),
+                    Option::<&[u8]>::None,
+                    Some(&*pdu.event_id()),
+                )?;
+                if res.is_ok() {
+                    break 'cas idx;
                 }
-            }
+            };
+            let mut pdu = pdu.clone();
+            pdu.stream_ordering = stream_ordering;
+            self.put_pdu(&name, &pdu)?;
             for prev_event in pdu.prev_events() {
                 self.headless_events.remove(&format!("{}~{}", pdu.room_id(), prev_event))?;
             }
             self.headless_events.insert(&format!("{}~{}", pdu.room_id(), pdu.event_id()), &[])?;
+            // the room's greatest depth lives alongside its forward extremities
+            let max_depth: Option<i64> = self.headless_events.get_value(pdu.room_id())?;
+            if max_depth.map(|depth| depth < pdu.depth()).unwrap_or(true) {
+                self.headless_events.replace_value(pdu.room_id(), pdu.depth())?;
+            }
             self.rooms.insert(pdu.room_id().clone(), &[])?;
+
+            if let (EventContent::Redaction(_), Some(target_id)) = (pdu.event_content(), pdu.redacts()) {
+                if pdu.did_pass_auth() {
+                    let target_name = format!("{}_{}", pdu.room_id(), target_id);
+                    if let Some(target) = self.get_pdu_by_name(&target_name)? {
+                        self.put_pdu(&target_name, &target.redact_because(&pdu))?;
+                    }
+                }
+            }
         }
         Ok(())
     }
@@ -429,10 +1362,14 @@ impl Storage for SledStorageHandle {
             return Err(ErrorKind::RoomNotFound.into());
         }
 
-        let (mut from, mut to) = match &query.query_type {
-            &QueryType::Timeline { from, to } => (from, to),
-            &QueryType::State { at, .. } => (0, at),
-        };
+        let (from, to) = query.query_type.range();
+
+        // subscribe before looking, so that changes in between still wake us up
+        let events_changed = self.events.watch_prefix(&query.room_id);
+        let mut recv = self.notifiers.lock().await
+            .entry(query.room_id.to_string())
+            .or_insert_with(|| channel(1).0)
+            .subscribe();
 
         let res = self.get_events(&ordering_tree, &query, from, to).await?;
 
@@ -441,12 +1378,69 @@ impl Storage for SledStorageHandle {
             return Ok(res);
         }
 
-        self.events.watch_prefix(&query.room_id).await;
-        from = to.unwrap();
-        to = None;
+        // Lagging behind on notifications just means there's more than one, which is fine
+        future::select(events_changed, Box::pin(recv.recv())).await;
 
         // this time we roll with it
-        self.get_events(&ordering_tree, &query, from, to).await
+        self.get_events(&ordering_tree, &query, from, None).await
+    }
+
+    async fn get_member_pdus(
+        &self,
+        room_id: &str,
+        after: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<StoredPdu>, Error> {
+        let ordering_tree = self.get_room_ordering_tree(room_id).await?;
+        if ordering_tree.is_empty() {
+            return Err(ErrorKind::RoomNotFound.into());
+        }
+        // only the name of each member's current event is kept while going through the room
+        let mut members = BTreeMap::new();
+        for res in ordering_tree.iter() {
+            let (_key, event_id) = res?;
+            let name = format!("{}_{}", room_id, String::from_utf8_lossy(&event_id));
+            // as in get_events, a missing event is still being added
+            let pdu = match self.get_pdu_by_name(&name)? {
+                Some(pdu) => pdu,
+                None => break,
+            };
+            if pdu.event_content().get_type() != "m.room.member" {
+                continue;
+            }
+            match pdu.state_key() {
+                Some(state_key) if after.map_or(true, |after| state_key > after) => {
+                    members.insert(state_key.to_owned(), name);
+                },
+                _ => {},
+            }
+        }
+        let mut pdus = Vec::new();
+        for name in members.values().take(limit) {
+            pdus.extend(self.get_pdu_by_name(name)?);
+        }
+        Ok(pdus)
+    }
+
+    async fn count_state_events(&self, room_id: &str) -> Result<usize, Error> {
+        let ordering_tree = self.get_room_ordering_tree(room_id).await?;
+        if ordering_tree.is_empty() {
+            return Err(ErrorKind::RoomNotFound.into());
+        }
+        let mut keys = HashSet::new();
+        for res in ordering_tree.iter() {
+            let (_key, event_id) = res?;
+            let name = format!("{}_{}", room_id, String::from_utf8_lossy(&event_id));
+            // as in get_events, a missing event is still being added
+            let pdu = match self.get_pdu_by_name(&name)? {
+                Some(pdu) => pdu,
+                None => break,
+            };
+            if let Some(state_key) = pdu.state_key() {
+                keys.insert((pdu.event_content().get_type().to_owned(), state_key.to_owned()));
+            }
+        }
+        Ok(keys.len())
     }
 
     async fn get_rooms(&self) -> Result<Vec<String>, Error> {
@@ -456,13 +1450,110 @@ impl Storage for SledStorageHandle {
             .map_err(Into::into)
     }
 
-    async fn get_pdu(&self, room_id: &str, event_id: &str) -> Result<Option<StoredPdu>, Error> {
-        self.events
-            .get_value(&format!("{}_{}", room_id, event_id))
+    async fn purge_events(&self, room_id: &str, before_ts: i64) -> Result<usize, Error> {
+        if !self.rooms.contains_key(room_id)? {
+            return Err(ErrorKind::RoomNotFound.into());
+        }
+        let ordering_tree = self.get_room_ordering_tree(room_id).await?;
+        let mut purged = 0;
+        for res in ordering_tree.iter() {
+            let (_key, event_id) = res?;
+            let name = format!("{}_{}", room_id, String::from_utf8_lossy(&event_id));
+            if let Some(pdu) = self.get_pdu_by_name(&name)? {
+                if should_purge(&pdu, before_ts) {
+                    self.put_pdu(&name, &pdu.redact())?;
+                    purged += 1;
+                }
+            }
+        }
+        Ok(purged)
+    }
+
+    async fn room_exists(&self, room_id: &str) -> Result<bool, Error> {
+        Ok(self.rooms.contains_key(room_id)?)
+    }
+
+    async fn delete_room(&self, room_id: &str) -> Result<(), Error> {
+        for alias in self.get_room_aliases(room_id).await? {
+            self.aliases.remove(&alias)?;
+            self.alias_creators.remove(alias)?;
+        }
+        self.published_rooms.remove(room_id)?;
+        self.ephemeral.lock().await.remove(room_id);
+        let room_trees = &[&self.events, &self.fully_read, &self.forgotten_rooms, &self.room_account_data];
+        for tree in room_trees {
+            for key in tree.scan_prefix(format!("{}_", room_id)).keys() {
+                tree.remove(key?)?;
+            }
+        }
+        self.headless_events.remove(room_id)?;
+        for key in self.headless_events.scan_prefix(format!("{}~", room_id)).keys() {
+            self.headless_events.remove(key?)?;
+        }
+        self.room_orderings.lock().await.remove(room_id);
+        self.all.drop_tree(room_id)?;
+        self.rooms.remove(room_id)?;
+        Ok(())
+    }
+
+    async fn set_room_alias(&self, alias: &str, room_id: &str, creator: &str) -> Result<bool, Error> {
+        if !self.aliases.try_insert_value(alias, room_id)? {
+            return Ok(false);
+        }
+        self.alias_creators.overwrite_value(alias, creator)?;
+        Ok(true)
+    }
+
+    async fn get_room_alias(&self, alias: &str) -> Result<Option<String>, Error> {
+        self.aliases.get_value(alias)
+    }
+
+    async fn get_room_alias_creator(&self, alias: &str) -> Result<Option<String>, Error> {
+        self.alias_creators.get_value(alias)
+    }
+
+    async fn delete_room_alias(&self, alias: &str) -> Result<bool, Error> {
+        self.alias_creators.remove(alias)?;
+        Ok(self.aliases.remove(alias)?.is_some())
+    }
+
+    async fn get_room_aliases(&self, room_id: &str) -> Result<Vec<String>, Error> {
+        let mut ret = Vec::new();
+        for res in self.aliases.iter() {
+            let (key, value) = res?;
+            let target: String = DefaultOptions::new().deserialize(&value)?;
+            if target == room_id {
+                ret.push(String::from_utf8(Vec::from(key.as_ref())).unwrap());
+            }
+        }
+        Ok(ret)
+    }
+
+    async fn set_room_published(&self, room_id: &str, published: bool) -> Result<(), Error> {
+        if published {
+            self.published_rooms.insert(room_id, &[])?;
+        } else {
+            self.published_rooms.remove(room_id)?;
+        }
+        Ok(())
+    }
+
+    async fn get_published_rooms(&self) -> Result<Vec<String>, Error> {
+        self.published_rooms.iter()
+            .map_ok(|(key, _value)| String::from_utf8(Vec::from(key.as_ref())).unwrap())
+            .collect::<Result<Vec<_>, _>>()
             .map_err(Into::into)
     }
 
-    async fn get_all_ephemeral(&self, room_id: &str) -> Result<HashMap<String, JsonValue>, Error> {
+    async fn get_pdu(&self, room_id: &str, event_id: &str) -> Result<Option<StoredPdu>, Error> {
+        self.get_pdu_by_name(&format!("{}_{}", room_id, event_id))
+    }
+
+    async fn get_all_ephemeral(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+    ) -> Result<HashMap<String, JsonValue>, Error> {
         //TODO: this inserts an ephemeral entry even if the room doesn't actually exist - figure
         // out what to do about it
         let mut ephemerals = self
@@ -476,6 +1567,10 @@ impl Storage for SledStorageHandle {
             String::from("m.typing"),
             serde_json::to_value(ephemeral.get_typing()).unwrap(),
         );
+        let receipts = ephemeral.get_receipts(user_id);
+        if !receipts.is_empty() {
+            ret.insert(String::from("m.receipt"), serde_json::to_value(receipts).unwrap());
+        }
         Ok(ret)
     }
 
@@ -545,10 +1640,96 @@ impl Storage for SledStorageHandle {
         } else {
             ephemeral.typing.remove(user_id);
         }
+        drop(ephemerals);
+        self.notify_room(room_id).await;
+
+        Ok(())
+    }
+
+    async fn set_receipt(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        receipt_type: ReceiptType,
+        event_id: &str,
+    ) -> Result<bool, Error> {
+        let mut ephemerals = self
+            .ephemeral
+            .lock()
+            .await;
+        let ephemeral = ephemerals.entry(String::from(room_id))
+            .or_default();
+        let receipts = match receipt_type {
+            ReceiptType::Read => &mut ephemeral.receipts,
+            ReceiptType::ReadPrivate => &mut ephemeral.private_receipts,
+        };
+        if receipts.get(user_id).map(|(e, _)| e == event_id).unwrap_or(false) {
+            return Ok(false);
+        }
+        let ts = chrono::Utc::now().timestamp_millis();
+        receipts.insert(user_id.clone(), (event_id.to_string(), ts));
+        drop(ephemerals);
+        self.notify_room(room_id).await;
+        Ok(true)
+    }
+
+    async fn set_fully_read(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        event_id: &str,
+    ) -> Result<bool, Error> {
+        let old: Option<String> = self.fully_read.replace_value(
+            format!("{}_{}", room_id, user_id.as_str()),
+            event_id.to_string(),
+        )?;
+        let changed = old.as_deref() != Some(event_id);
+        if changed {
+            self.notify_room(room_id).await;
+        }
+        Ok(changed)
+    }
+
+    async fn get_fully_read(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+    ) -> Result<Option<String>, Error> {
+        self.fully_read.get_value(format!("{}_{}", room_id, user_id.as_str()))
+    }
+
+    async fn set_room_forgotten(
+        &self,
+        room_id: &str,
+        user_id: &MatrixId,
+        forgotten: bool,
+    ) -> Result<(), Error> {
+        let key = format!("{}_{}", room_id, user_id.as_str());
+        if forgotten {
+            self.forgotten_rooms.insert(key, &[])?;
+        } else {
+            self.forgotten_rooms.remove(key)?;
+        }
+        Ok(())
+    }
 
+    async fn is_room_forgotten(&self, room_id: &str, user_id: &MatrixId) -> Result<bool, Error> {
+        Ok(self.forgotten_rooms.contains_key(format!("{}_{}", room_id, user_id.as_str()))?)
+    }
+
+    async fn set_presence(&self, username: &str, state: PresenceState) -> Result<(), Error> {
+        if !self.users.contains_key(username)? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        let old = self.presence.get_value(username)?;
+        self.presence.overwrite_value(username, Presence::changed_to(old, state))?;
         Ok(())
     }
 
+    async fn get_presence(&self, username: &str) -> Result<Option<Presence>, Error> {
+        self.presence.get_value(username)
+    }
+
     async fn get_user_account_data(
         &self,
         username: &str,
@@ -560,11 +1741,217 @@ impl Storage for SledStorageHandle {
         Ok(user.account_data.clone())
     }
 
+    async fn set_user_account_data(
+        &self,
+        username: &str,
+        ty: &str,
+        content: JsonValue,
+    ) -> Result<(), Error> {
+        let mut user: User = self
+            .users
+            .get_value(username)?
+            .ok_or(ErrorKind::UserNotFound)?;
+        user.account_data.insert(ty.to_string(), content);
+        self.users.overwrite_value(username, &user)?;
+
+        let mut stream: AccountDataStream = self.account_data_streams.get_value(username)?
+            .unwrap_or_default();
+        stream.position += 1;
+        stream.changes.insert(ty.to_string(), stream.position);
+        self.account_data_streams.overwrite_value(username, &stream)?;
+        Ok(())
+    }
+
+    async fn get_user_account_data_since(
+        &self,
+        username: &str,
+        since: usize,
+    ) -> Result<(HashMap<String, JsonValue>, usize), Error> {
+        let user: User = match self.users.get_value(username)? {
+            Some(u) => u,
+            None => return Ok((HashMap::new(), 0)),
+        };
+        let stream: AccountDataStream = self.account_data_streams.get_value(username)?
+            .unwrap_or_default();
+        let changed = user.account_data
+            .into_iter()
+            .filter(|(ty, _)| {
+                // account data from before the stream existed counts as being at the start
+                let changed_at = stream.changes.get(ty).copied().unwrap_or(0);
+                changed_at > since || since == 0
+            })
+            .collect();
+        Ok((changed, stream.position))
+    }
+
+    async fn set_room_account_data(
+        &self,
+        username: &str,
+        room_id: &str,
+        ty: &str,
+        content: JsonValue,
+    ) -> Result<(), Error> {
+        if !self.users.contains_key(username)? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        let mut stream: AccountDataStream = self.account_data_streams.get_value(username)?
+            .unwrap_or_default();
+        stream.position += 1;
+        self.account_data_streams.overwrite_value(username, &stream)?;
+        // stored as JSON, since bincode can't deserialize arbitrary JSON values
+        self.room_account_data.insert(
+            room_account_data_key(room_id, username, ty),
+            serde_json::to_vec(&(stream.position, content))?,
+        )?;
+        Ok(())
+    }
+
+    async fn get_room_account_data_since(
+        &self,
+        username: &str,
+        room_id: &str,
+        since: usize,
+    ) -> Result<HashMap<String, JsonValue>, Error> {
+        let prefix = room_account_data_key(room_id, username, "");
+        let mut changed = HashMap::new();
+        for res in self.room_account_data.scan_prefix(&prefix) {
+            let (key, val) = res?;
+            let (changed_at, content): (usize, JsonValue) = serde_json::from_slice(&val)?;
+            if changed_at > since || since == 0 {
+                let ty = String::from_utf8_lossy(&key[prefix.len()..]).into_owned();
+                changed.insert(ty, content);
+            }
+        }
+        Ok(changed)
+    }
+
+    async fn create_filter(&self, username: &str, filter: JsonValue) -> Result<String, Error> {
+        if !self.users.contains_key(username)? {
+            return Err(ErrorKind::UserNotFound.into());
+        }
+        let filter_id = Uuid::new_v4().to_simple().to_string();
+        // stored as JSON, since bincode can't deserialize arbitrary JSON values
+        self.filters.insert(filter_key(username, &filter_id), serde_json::to_vec(&filter)?)?;
+        Ok(filter_id)
+    }
+
+    async fn get_filter(&self, username: &str, filter_id: &str)
+        -> Result<Option<JsonValue>, Error> {
+        self.filters.get(filter_key(username, filter_id))?
+            .map(|bytes| serde_json::from_slice(&bytes))
+            .transpose()
+            .map_err(Into::into)
+    }
+
     async fn get_batch(&self, id: &str) -> Result<Option<Batch>, Error> {
-        self.batches.get_value(id)
+        let bytes = match self.batches.get(id)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        // batches are JSON, so older layouts are filled in by their serde defaults
+        match serde_json::from_slice(&bytes) {
+            Ok(batch) => Ok(Some(batch)),
+            Err(_) => {
+                tracing::warn!(batch = id, "Discarding unreadable batch");
+                Ok(None)
+            },
+        }
     }
 
     async fn set_batch(&self, id: &str, batch: Batch) -> Result<(), Error> {
-        self.batches.overwrite_value(id, batch).map(drop)
+        self.batches.insert(id, serde_json::to_vec(&batch)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        storage::StorageManager,
+        test_util::create_event,
+        util::MatrixId,
+    };
+
+    use bincode::{DefaultOptions, Options};
+    use uuid::Uuid;
+
+    use super::{
+        AccessTokenDataV1, SCHEMA_VERSION, SCHEMA_VERSION_KEY, SledStorage, TreeExt, UserProfile,
+        UserV1,
+    };
+
+    #[test]
+    fn migrate_v1() {
+        let path = "sled-test-migrate-v1";
+        let _ = std::fs::remove_dir_all(path);
+        let room_id = "!migrated:example.org";
+        let alice = MatrixId::new("alice", "example.org").unwrap();
+        let pdu = create_event(room_id, &alice);
+        let event_id = pdu.event_id().to_owned();
+
+        let db = sled::open(path).unwrap();
+        // users and access tokens are laid out the way version 1 wrote them, with bincode
+        let password_hash = argon2::hash_encoded(b"hunter2", b"saltsaltsalt", &Default::default())
+            .unwrap();
+        let user = UserV1 {
+            password_hash,
+            profile: UserProfile {
+                avatar_url: None,
+                displayname: Some(String::from("Alice")),
+            },
+            account_data: HashMap::new(),
+        };
+        db.open_tree("users").unwrap()
+            .insert("alice", DefaultOptions::new().serialize(&user).unwrap())
+            .unwrap();
+        let token = Uuid::new_v4();
+        let token_data = AccessTokenDataV1 {
+            username: String::from("alice"),
+            device_id: String::from("PHONE"),
+        };
+        db.open_tree("access_tokens").unwrap()
+            .insert(token.as_bytes(), DefaultOptions::new().serialize(&token_data).unwrap())
+            .unwrap();
+
+        // version 1 couldn't store PDUs at all, since bincode can't encode their flattened
+        // content, so the room is laid out as JSON with neither an event ID nor a stream
+        // ordering, but with version 1's `u32` key in the ordering tree
+        assert!(DefaultOptions::new().serialize(&pdu).is_err());
+        let mut json = serde_json::to_value(&pdu).unwrap();
+        json.as_object_mut().unwrap().remove("event_id");
+        json.as_object_mut().unwrap().remove("stream_ordering");
+        let name = format!("{}_{}", room_id, event_id);
+        db.open_tree("events").unwrap().insert(&name, serde_json::to_vec(&json).unwrap()).unwrap();
+        db.open_tree("rooms").unwrap().insert(room_id, &[]).unwrap();
+        db.open_tree(room_id).unwrap().insert(0u32.to_be_bytes(), event_id.as_bytes()).unwrap();
+
+        let db_pool = SledStorage::with_db(db).unwrap();
+        let version: Option<usize> = db_pool.0.all.get_value(SCHEMA_VERSION_KEY).unwrap();
+        assert_eq!(version, Some(SCHEMA_VERSION));
+        let stored = db_pool.0.events.get(&name).unwrap().unwrap();
+        let stored: serde_json::Value = serde_json::from_slice(&stored).unwrap();
+        assert_eq!(stored["event_id"], event_id.as_str());
+
+        let mut rt = tokio::runtime::Builder::new().basic_scheduler().build().unwrap();
+        rt.block_on(async {
+            let db = db_pool.get_handle().await.unwrap();
+            let migrated = db.get_pdu(room_id, &event_id).await.unwrap().unwrap();
+            assert_eq!(migrated.event_id(), event_id);
+            assert_eq!(migrated.stream_ordering, 0);
+            assert_eq!(db.get_rooms().await.unwrap(), vec![String::from(room_id)]);
+
+            assert!(db.verify_password("alice", "hunter2").await.unwrap());
+            assert!(!db.is_guest("alice").await.unwrap());
+            let profile = db.get_profile("alice").await.unwrap().unwrap();
+            assert_eq!(profile.displayname.as_deref(), Some("Alice"));
+            assert_eq!(db.try_auth(token).await.unwrap().as_deref(), Some("alice"));
+            assert_eq!(db.get_token_device(token).await.unwrap().as_deref(), Some("PHONE"));
+        });
+        let ordering_tree = db_pool.0.all.open_tree(room_id).unwrap();
+        assert_eq!(ordering_tree.len(), 1);
+        assert!(ordering_tree.contains_key(0usize.to_be_bytes()).unwrap());
+        let _ = std::fs::remove_dir_all(path);
     }
 }
@@ -0,0 +1,428 @@
+//! Helpers for setting up servers and storage in tests, and for checking the errors they get
+//! back.
+
+use actix_http::Request;
+use actix_web::{
+    App, ResponseError,
+    dev::{Service, ServiceResponse},
+    http::StatusCode,
+    test,
+    web::{self, JsonConfig},
+};
+use serde_json::{json, Value as JsonValue};
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use crate::{
+    Config, ServerState,
+    client_api::configure_endpoints,
+    error::Error,
+    events::{
+        EventContent, pdu::StoredPdu,
+        room::{Create, JoinRule, JoinRules, Member, Membership},
+        room_version::{v4::UnhashedPdu, VersionedPdu},
+    },
+    state::StateResolver,
+    storage::{Storage, StorageManager, mem::MemStorageManager},
+    util::{MatrixId, RateLimiter, StorageExt, storage::NewEvent},
+    validate::auth::AuthStatus,
+};
+
+/// Server state on top of `db_pool`, for `example.org`. `config` is merged over the fields every
+/// config needs, so tests only have to give the ones they care about.
+pub async fn test_state(db_pool: MemStorageManager, config: JsonValue) -> Arc<ServerState> {
+    let mut full_config = json!({
+        "domain": "example.org",
+        "bind_address": "127.0.0.1:0",
+        "storage": "mem",
+    });
+    if let JsonValue::Object(config) = config {
+        full_config.as_object_mut().unwrap().extend(config);
+    }
+    let config: Config = serde_json::from_value(full_config).unwrap();
+    Arc::new(ServerState {
+        login_limiter: RateLimiter::new(config.login_rate_limit),
+        config,
+        state_resolver: StateResolver::new(db_pool.get_handle().await.unwrap()),
+        db_pool: Box::new(db_pool),
+        keys: HashMap::new(),
+    })
+}
+
+/// The client API, set up the way the server serves it.
+pub async fn test_app(
+    state: &Arc<ServerState>,
+) -> impl Service<Request = Request, Response = ServiceResponse, Error = actix_web::Error> {
+    test::init_service(
+        App::new()
+            .data(Arc::clone(state))
+            .data(JsonConfig::default().error_handler(|e, _req| Error::from(e).into()))
+            .service(web::scope("/_matrix/client").configure(configure_endpoints))
+    ).await
+}
+
+/// A version 4 create event, for tests that put rooms together from PDUs rather than with
+/// `RoomBuilder`.
+pub fn create_event(room_id: &str, creator: &MatrixId) -> StoredPdu {
+    create_event_for_version(room_id, creator, "4")
+}
+
+/// Room versions from 11 on leave the creator out of the create event's content.
+fn create_event_for_version(room_id: &str, creator: &MatrixId, room_version: &str) -> StoredPdu {
+    let has_creator = room_version.parse::<u32>().map(|v| v < 11).unwrap_or(true);
+    let creation = UnhashedPdu {
+        event_content: EventContent::Create(Create {
+            creator: if has_creator { Some(creator.clone()) } else { None },
+            room_version: Some(String::from(room_version)),
+            predecessor: None,
+            extra: HashMap::new(),
+        }),
+        room_id: String::from(room_id),
+        sender: creator.clone(),
+        state_key: Some(String::new()),
+        unsigned: None,
+        redacts: None,
+        origin: String::from(creator.domain()),
+        origin_server_ts: 0,
+        prev_events: Vec::new(),
+        depth: 0,
+        auth_events: Vec::new(),
+    }.finalize();
+    StoredPdu::new(VersionedPdu::V4(creation), AuthStatus::Pass)
+}
+
+/// Builds a room with known contents, so tests don't have to put the events together by hand.
+///
+/// The room is created by `creator`, who joins it and sets the join rules, which are public unless
+/// asked otherwise. Everything else happens in the order it's asked for.
+pub struct RoomBuilder<'a> {
+    db: &'a dyn Storage,
+    state_resolver: &'a StateResolver,
+    room_id: String,
+    creator: MatrixId,
+    room_version: String,
+    join_rules: JoinRules,
+    steps: Vec<Step>,
+}
+
+enum Step {
+    Join(MatrixId),
+    Invite(MatrixId, MatrixId),
+    Message(MatrixId, String),
+    State(MatrixId, EventContent, String),
+}
+
+/// A room made by `RoomBuilder`.
+pub struct TestRoom {
+    pub room_id: String,
+    /// Every event in the room, in the order they were added, starting with the create event
+    pub event_ids: Vec<String>,
+    /// The messages' event IDs, in the order they were added
+    pub message_ids: Vec<String>,
+}
+
+impl<'a> RoomBuilder<'a> {
+    pub fn new(
+        db: &'a dyn Storage,
+        state_resolver: &'a StateResolver,
+        room_id: &str,
+        creator: &MatrixId,
+    ) -> Self {
+        RoomBuilder {
+            db,
+            state_resolver,
+            room_id: String::from(room_id),
+            creator: creator.clone(),
+            room_version: String::from("4"),
+            join_rules: JoinRules {
+                join_rule: JoinRule::Public,
+                allow: Vec::new(),
+            },
+            steps: Vec::new(),
+        }
+    }
+
+    pub fn room_version(mut self, room_version: &str) -> Self {
+        self.room_version = String::from(room_version);
+        self
+    }
+
+    pub fn join_rules(mut self, join_rules: JoinRules) -> Self {
+        self.join_rules = join_rules;
+        self
+    }
+
+    pub fn join(mut self, user_id: &MatrixId) -> Self {
+        self.steps.push(Step::Join(user_id.clone()));
+        self
+    }
+
+    /// Invites `user_id`, which `sender` needs the power for.
+    pub fn invite(mut self, sender: &MatrixId, user_id: &MatrixId) -> Self {
+        self.steps.push(Step::Invite(sender.clone(), user_id.clone()));
+        self
+    }
+
+    /// Sends an `m.text` message, which the sender needs to have joined for.
+    pub fn message(mut self, sender: &MatrixId, body: &str) -> Self {
+        self.steps.push(Step::Message(sender.clone(), String::from(body)));
+        self
+    }
+
+    /// Sends a state event, which the sender needs the power for.
+    pub fn state(mut self, sender: &MatrixId, content: EventContent, state_key: &str) -> Self {
+        self.steps.push(Step::State(sender.clone(), content, String::from(state_key)));
+        self
+    }
+
+    /// Adds the room's events to storage. Panics if any of them are rejected.
+    pub async fn build(mut self) -> TestRoom {
+        let creation = create_event_for_version(&self.room_id, &self.creator, &self.room_version);
+        let mut room = TestRoom {
+            room_id: self.room_id.clone(),
+            event_ids: vec![creation.event_id().to_owned()],
+            message_ids: Vec::new(),
+        };
+        self.db.add_pdus(&[creation]).await.unwrap();
+
+        let steps = std::iter::once(Step::Join(self.creator.clone())).chain(std::mem::take(&mut self.steps));
+        for (idx, step) in steps.enumerate() {
+            let (event, is_message) = match step {
+                Step::Join(user_id) => (join_event(&user_id), false),
+                Step::Invite(sender, user_id) => (invite_event(&sender, &user_id), false),
+                Step::Message(sender, body) => (message_event(&sender, &body), true),
+                Step::State(sender, content, state_key) => {
+                    (state_event(&sender, content, &state_key), false)
+                },
+            };
+            let event_id = self.add_event(event).await;
+            if is_message {
+                room.message_ids.push(event_id.clone());
+            }
+            room.event_ids.push(event_id);
+            // the join rules go right after the creator's join, so that everyone else can join
+            if idx == 0 {
+                let join_rules = EventContent::JoinRules(self.join_rules.clone());
+                let join_rules = state_event(&self.creator, join_rules, "");
+                room.event_ids.push(self.add_event(join_rules).await);
+            }
+        }
+        room
+    }
+
+    async fn add_event(&self, event: NewEvent) -> String {
+        self.db.add_event(&self.room_id, event, self.state_resolver, &HashMap::new()).await
+            .unwrap()
+    }
+}
+
+/// Runs `test` on a fresh runtime with timers, with an in-memory database and a state resolver
+/// over it.
+pub fn with_mem_db<F, Fut>(test: F)
+where
+    F: FnOnce(Box<dyn Storage>, StateResolver) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut rt = tokio::runtime::Builder::new().basic_scheduler().enable_time().build().unwrap();
+    let db_pool = MemStorageManager::new();
+    rt.block_on(async {
+        let db = db_pool.get_handle().await.unwrap();
+        let state_resolver = StateResolver::new(db_pool.get_handle().await.unwrap());
+        test(db, state_resolver).await;
+    });
+}
+
+pub fn join_event(user_id: &MatrixId) -> NewEvent {
+    membership_event(user_id, user_id, Membership::Join)
+}
+
+pub fn invite_event(sender: &MatrixId, user_id: &MatrixId) -> NewEvent {
+    membership_event(sender, user_id, Membership::Invite)
+}
+
+/// Leaves the room if `sender` is `user_id`, and kicks `user_id` otherwise.
+pub fn leave_event(sender: &MatrixId, user_id: &MatrixId) -> NewEvent {
+    membership_event(sender, user_id, Membership::Leave)
+}
+
+fn membership_event(sender: &MatrixId, user_id: &MatrixId, membership: Membership) -> NewEvent {
+    let content = EventContent::Member(Member {
+        avatar_url: None,
+        displayname: None,
+        membership,
+        is_direct: None,
+        reason: None,
+        third_party_invite: None,
+        join_authorised_via_users_server: None,
+    });
+    state_event(sender, content, user_id.as_str())
+}
+
+pub fn state_event(sender: &MatrixId, content: EventContent, state_key: &str) -> NewEvent {
+    NewEvent {
+        event_content: content,
+        sender: sender.clone(),
+        state_key: Some(String::from(state_key)),
+        redacts: None,
+        unsigned: None,
+    }
+}
+
+/// An `m.text` message.
+pub fn message_event(sender: &MatrixId, body: &str) -> NewEvent {
+    NewEvent {
+        event_content: EventContent::new("m.room.message", json!({
+            "msgtype": "m.text",
+            "body": body,
+        })).unwrap(),
+        sender: sender.clone(),
+        state_key: None,
+        redacts: None,
+        unsigned: None,
+    }
+}
+
+/// Asserts the Matrix errcode, e.g. `"M_FORBIDDEN"`, of an error, a failed `Result<_, Error>`, or
+/// the JSON body of an error response.
+macro_rules! assert_errcode {
+    ($err:expr, $errcode:expr) => {
+        assert_eq!($crate::test_util::Errcode::errcode(&$err), $errcode);
+    };
+}
+pub(crate) use assert_errcode;
+
+/// Anything with a Matrix errcode to check with `assert_errcode!`.
+pub trait Errcode {
+    fn errcode(&self) -> JsonValue;
+}
+
+impl Errcode for Error {
+    fn errcode(&self) -> JsonValue {
+        self.to_json()["errcode"].clone()
+    }
+}
+
+impl<T> Errcode for Result<T, Error> {
+    fn errcode(&self) -> JsonValue {
+        match self {
+            Ok(_) => panic!("expected an error, but the result was Ok"),
+            Err(e) => e.errcode(),
+        }
+    }
+}
+
+impl Errcode for JsonValue {
+    fn errcode(&self) -> JsonValue {
+        self["errcode"].clone()
+    }
+}
+
+/// Asserts the HTTP status of a response, or of the response a `Result<_, Error>` would turn
+/// into.
+macro_rules! assert_status {
+    ($res:expr, $status:expr) => {
+        assert_eq!($crate::test_util::HttpStatus::http_status(&$res), $status);
+    };
+}
+pub(crate) use assert_status;
+
+/// Anything with an HTTP status to check with `assert_status!`.
+pub trait HttpStatus {
+    fn http_status(&self) -> StatusCode;
+}
+
+impl HttpStatus for ServiceResponse {
+    fn http_status(&self) -> StatusCode {
+        self.status()
+    }
+}
+
+impl HttpStatus for Error {
+    fn http_status(&self) -> StatusCode {
+        self.status_code()
+    }
+}
+
+impl<T> HttpStatus for Result<T, Error> {
+    fn http_status(&self) -> StatusCode {
+        match self {
+            Ok(_) => StatusCode::OK,
+            Err(e) => e.status_code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, HttpResponse};
+    use serde_json::json;
+
+    use crate::{
+        error::{Error, ErrorKind},
+        events::{EventContent, room::Membership},
+        util::MatrixId,
+    };
+
+    use super::{assert_errcode, assert_status, RoomBuilder, with_mem_db};
+
+    #[test]
+    fn built_room_has_its_state() {
+        with_mem_db(|db, state_resolver| async move {
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            let room = RoomBuilder::new(&*db, &state_resolver, "!built:example.org", &alice)
+                .message(&alice, "first")
+                .join(&bob)
+                .message(&bob, "second")
+                .build()
+                .await;
+
+            // create, alice's join, join rules, a message, bob's join and another message
+            assert_eq!(room.event_ids.len(), 6);
+            assert_eq!(room.message_ids, vec![room.event_ids[3].clone(), room.event_ids[5].clone()]);
+            let mut state: Vec<_> = db.get_full_state(&room.room_id).await.unwrap()
+                .into_iter()
+                .map(|event| (event.event_content.get_type().to_owned(), event.state_key.unwrap()))
+                .collect();
+            state.sort();
+            assert_eq!(state, vec![
+                (String::from("m.room.create"), String::new()),
+                (String::from("m.room.join_rules"), String::new()),
+                (String::from("m.room.member"), String::from("@alice:example.org")),
+                (String::from("m.room.member"), String::from("@bob:example.org")),
+            ]);
+            assert_eq!(db.get_membership(&bob, &room.room_id).await.unwrap(), Some(Membership::Join));
+            let message = db.get_pdu(&room.room_id, &room.message_ids[1]).await.unwrap().unwrap();
+            match message.event_content() {
+                EventContent::Unknown { content, .. } => assert_eq!(content["body"], "second"),
+                _ => panic!("not a message"),
+            }
+        });
+    }
+
+    #[test]
+    fn error_assertions() {
+        let forbidden: Result<(), Error> = Err(ErrorKind::Forbidden.into());
+        assert_status!(forbidden, StatusCode::FORBIDDEN);
+        assert_errcode!(forbidden, "M_FORBIDDEN");
+        let bad_state: Result<(), Error> = Err(ErrorKind::BadState(String::from("no")).into());
+        assert_status!(bad_state, StatusCode::BAD_REQUEST);
+        assert_errcode!(bad_state, "M_BAD_STATE");
+        assert_status!(Ok::<_, Error>(()), StatusCode::OK);
+        assert_errcode!(Error::from(ErrorKind::NotFound), "M_NOT_FOUND");
+        assert_errcode!(json!({ "errcode": "M_NOT_FOUND", "error": "" }), "M_NOT_FOUND");
+        let res = test::TestRequest::default().to_srv_response(HttpResponse::NotFound().finish());
+        assert_status!(res, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    #[should_panic]
+    fn wrong_errcode() {
+        assert_errcode!(Err::<(), Error>(ErrorKind::NotFound.into()), "M_FORBIDDEN");
+    }
+
+    #[test]
+    #[should_panic]
+    fn errcode_of_success() {
+        assert_errcode!(Ok::<(), Error>(()), "M_FORBIDDEN");
+    }
+}
@@ -4,10 +4,12 @@ use std::sync::Arc;
 use crate::ServerState;
 
 pub mod mxid;
+pub mod rate_limit;
 pub mod storage;
 
 pub use storage::StorageExt;
-pub use mxid::{MatrixId, MxidError};
+pub use mxid::{Domain, MatrixId, PercentDecoded, RoomAliasId, RoomId};
+pub use rate_limit::{RateLimitConfig, RateLimiter};
 
 #[post("/_debug/print_the_world")]
 pub async fn print_the_world(state: Data<Arc<ServerState>>) -> String {
@@ -1,18 +1,62 @@
 use displaydoc::Display;
 use lazy_static::lazy_static;
+use percent_encoding::percent_decode_str;
 use regex::Regex;
-use serde::{Deserialize, Serialize};
-use std::convert::TryFrom;
+use serde::{Deserialize, Deserializer, Serialize, de::Error as _};
+use std::{convert::TryFrom, fmt};
 
 lazy_static! {
     static ref SERVER_NAME_REGEX: Regex =
         Regex::new(include_str!("./mxid_server_name.regex")).unwrap();
 }
 
+/// A user ID, like `@alice:example.org`.
+///
+/// IDs parsed from elsewhere are kept exactly as they were written, since they end up in events
+/// that get hashed and signed. Only IDs built with `MatrixId::new` have their domain lowercased, so
+/// use `has_domain` rather than comparing `domain()` directly.
 #[derive(Clone, Debug, Deserialize, Serialize, Hash, PartialEq, Eq)]
 #[serde(try_from = "String")]
 pub struct MatrixId(String);
 
+/// A server name, as in the part of a Matrix ID after the colon.
+///
+/// DNS names are case-insensitive, so they're lowercased here to make equal names compare equal.
+/// The port is kept as it is, and so are IP literals, since those aren't names.
+#[derive(Clone, Debug, Deserialize, Serialize, Hash, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Domain(String);
+
+impl Domain {
+    pub fn new(domain: &str) -> Result<Self, MxidError> {
+        if !SERVER_NAME_REGEX.is_match(domain) {
+            return Err(MxidError::InvalidDomain);
+        }
+        // IPv4 literals have no letters to lowercase, so only IPv6 ones need skipping
+        if domain.starts_with('[') {
+            return Ok(Domain(domain.to_string()));
+        }
+        Ok(Domain(domain.to_ascii_lowercase()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &*self.0
+    }
+}
+
+impl TryFrom<String> for Domain {
+    type Error = MxidError;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Domain::new(&value)
+    }
+}
+
+impl fmt::Display for Domain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
 #[derive(Debug, Display)]
 pub enum MxidError {
     /// A Matrix ID can only be 255 characters long, including the '@', localpart, ':' and domain.
@@ -21,16 +65,25 @@ pub enum MxidError {
     InvalidChar,
     /// A Matrix ID must begin with an '@'.
     NoLeadingAt,
-    /// A Matrix ID must contain exactly one colon.
+    /// A Matrix ID must contain a colon between its localpart and domain.
     WrongNumberOfColons,
     /// A Matrix ID must contain a valid domain name.
     InvalidDomain,
+    /// A room alias must begin with a '#'.
+    NoLeadingHash,
+    /// A room alias's localpart can't be empty or contain whitespace or control characters.
+    InvalidAliasLocalpart,
+    /// A room ID must begin with a '!'.
+    NoLeadingBang,
+    /// A room ID's opaque part can't be empty.
+    EmptyRoomIdLocalpart,
 }
 
 impl MatrixId {
     pub fn new(localpart: &str, domain: &str) -> Result<Self, MxidError> {
         Self::validate_parts(localpart, domain)?;
-        Ok(MatrixId(format!("@{}:{}", localpart, domain)))
+        let domain = Domain::new(domain)?;
+        Ok(MatrixId(format!("@{}:{}", localpart, domain.as_str())))
     }
 
     pub fn as_str(&self) -> &str {
@@ -50,7 +103,13 @@ impl MatrixId {
     }
 
     pub fn domain(&self) -> &str {
-        self.0.split(':').nth(1).unwrap()
+        // the domain can have a port, so only the first colon separates the two
+        self.0.split_once(':').unwrap().1
+    }
+
+    /// Whether this ID belongs to `domain`, ignoring the case of the domain.
+    pub fn has_domain(&self, domain: &str) -> bool {
+        self.domain().eq_ignore_ascii_case(domain)
     }
 
     /// Verifies that a localpart and domain could together form a valid Matrix ID.
@@ -67,9 +126,7 @@ impl MatrixId {
             return Err(MxidError::InvalidChar);
         }
 
-        if !SERVER_NAME_REGEX.is_match(domain) {
-            return Err(MxidError::InvalidDomain);
-        }
+        Domain::new(domain)?;
 
         if localpart.len() + domain.len() + 2 > 255 {
             return Err(MxidError::TooLong);
@@ -84,15 +141,7 @@ impl MatrixId {
             return Err(MxidError::NoLeadingAt);
         }
         let remaining: &str = &mxid[1..];
-        let (localpart, domain) = {
-            let mut iter = remaining.split(':');
-            let localpart = iter.next().unwrap();
-            let domain = iter.next().ok_or(MxidError::WrongNumberOfColons)?;
-            if iter.next() != None {
-                return Err(MxidError::WrongNumberOfColons);
-            }
-            (localpart, domain)
-        };
+        let (localpart, domain) = remaining.split_once(':').ok_or(MxidError::WrongNumberOfColons)?;
         Self::validate_parts(localpart, domain)?;
 
         Ok(())
@@ -102,8 +151,7 @@ impl MatrixId {
 impl TryFrom<String> for MatrixId {
     type Error = MxidError;
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        MatrixId::validate_all(&value)?;
-        Ok(MatrixId(value))
+        MatrixId::try_from(value.as_str())
     }
 }
 
@@ -111,6 +159,255 @@ impl TryFrom<&str> for MatrixId {
     type Error = MxidError;
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         MatrixId::validate_all(value)?;
-        Ok(MatrixId(value.to_string()))
+        Ok(MatrixId(value.to_owned()))
+    }
+}
+
+/// A room alias, like `#lobby:example.org`. Like a `MatrixId`, the domain is only lowercased when
+/// it's built with `new`.
+#[derive(Clone, Debug, Deserialize, Serialize, Hash, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct RoomAliasId(String);
+
+impl RoomAliasId {
+    pub fn new(localpart: &str, domain: &str) -> Result<Self, MxidError> {
+        Self::validate_parts(localpart, domain)?;
+        let domain = Domain::new(domain)?;
+        Ok(RoomAliasId(format!("#{}:{}", localpart, domain.as_str())))
+    }
+
+    fn validate_parts(localpart: &str, domain: &str) -> Result<(), MxidError> {
+        // unlike a user's localpart, an alias's can have any printable character but ':'
+        if localpart.is_empty() || localpart.contains(|c: char| c.is_whitespace() || c.is_control()) {
+            return Err(MxidError::InvalidAliasLocalpart);
+        }
+        if localpart.contains(':') {
+            return Err(MxidError::WrongNumberOfColons);
+        }
+        Domain::new(domain)?;
+        if localpart.len() + domain.len() + 2 > 255 {
+            return Err(MxidError::TooLong);
+        }
+        Ok(())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &*self.0
+    }
+
+    pub fn localpart(&self) -> &str {
+        self.0[1..].split_once(':').unwrap().0
+    }
+
+    pub fn domain(&self) -> &str {
+        // the domain can have a port, so only the first colon separates the two
+        self.0.split_once(':').unwrap().1
+    }
+
+    /// The alias with its domain lowercased, as `new` would build it. Aliases are stored like
+    /// this, so ones that differ only in the case of their domain are the same alias.
+    pub fn normalized(&self) -> RoomAliasId {
+        // the parts were already checked when this alias was made
+        RoomAliasId::new(self.localpart(), self.domain()).unwrap()
+    }
+
+    /// Whether this alias belongs to `domain`, ignoring the case of the domain.
+    pub fn has_domain(&self, domain: &str) -> bool {
+        self.domain().eq_ignore_ascii_case(domain)
+    }
+}
+
+impl fmt::Display for RoomAliasId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TryFrom<String> for RoomAliasId {
+    type Error = MxidError;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        RoomAliasId::try_from(value.as_str())
+    }
+}
+
+impl TryFrom<&str> for RoomAliasId {
+    type Error = MxidError;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        if !value.starts_with('#') {
+            return Err(MxidError::NoLeadingHash);
+        }
+        let (localpart, domain) = value[1..].split_once(':').ok_or(MxidError::WrongNumberOfColons)?;
+        RoomAliasId::validate_parts(localpart, domain)?;
+        Ok(RoomAliasId(value.to_owned()))
+    }
+}
+
+/// A room ID, like `!opaque:example.org`. The part before the colon means nothing to anyone but
+/// the server that made it, so only its domain is checked.
+#[derive(Clone, Debug, Deserialize, Serialize, Hash, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct RoomId(String);
+
+impl RoomId {
+    pub fn as_str(&self) -> &str {
+        &*self.0
+    }
+
+    pub fn domain(&self) -> &str {
+        self.0.split_once(':').unwrap().1
+    }
+
+    /// Whether this room was made by `domain`, ignoring the case of the domain.
+    pub fn has_domain(&self, domain: &str) -> bool {
+        self.domain().eq_ignore_ascii_case(domain)
+    }
+}
+
+impl fmt::Display for RoomId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TryFrom<String> for RoomId {
+    type Error = MxidError;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        RoomId::try_from(value.as_str())
+    }
+}
+
+impl TryFrom<&str> for RoomId {
+    type Error = MxidError;
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        if !value.starts_with('!') {
+            return Err(MxidError::NoLeadingBang);
+        }
+        // the domain can have a port, so only the first colon separates the two
+        let mut parts = value[1..].splitn(2, ':');
+        let localpart = parts.next().unwrap();
+        let domain = parts.next().ok_or(MxidError::WrongNumberOfColons)?;
+        if localpart.is_empty() {
+            return Err(MxidError::EmptyRoomIdLocalpart);
+        }
+        Domain::new(domain)?;
+        if value.len() > 255 {
+            return Err(MxidError::TooLong);
+        }
+        Ok(RoomId(value.to_owned()))
+    }
+}
+
+/// An ID taken from a URL path, percent-decoded before it's parsed.
+///
+/// actix only decodes the characters it considers safe in a path, so things like `%2F` (which
+/// can appear in a user ID's localpart) are still encoded by the time the path is extracted.
+#[derive(Debug)]
+pub struct PercentDecoded<T>(pub T);
+
+impl<'de, T> Deserialize<'de> for PercentDecoded<T>
+where
+    T: TryFrom<String>,
+    T::Error: fmt::Display,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+        let decoded = percent_decode_str(&raw).decode_utf8().map_err(D::Error::custom)?;
+        T::try_from(decoded.into_owned()).map(PercentDecoded).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use super::{Domain, MatrixId, RoomAliasId, RoomId};
+
+    #[test]
+    fn domains_are_lowercased() {
+        let domain = Domain::new("Example.com:8448").unwrap();
+        assert_eq!(domain.as_str(), "example.com:8448");
+        assert_eq!(domain, Domain::new("example.com:8448").unwrap());
+        assert_ne!(domain, Domain::new("example.com").unwrap());
+
+        // IP literals aren't names, so they're left alone
+        assert_eq!(Domain::new("[::FFFF:1.2.3.4]:80").unwrap().as_str(), "[::FFFF:1.2.3.4]:80");
+        assert_eq!(Domain::new("1.2.3.4").unwrap().as_str(), "1.2.3.4");
+        assert!(Domain::new("exa mple.com").is_err());
+    }
+
+    #[test]
+    fn parsed_mxids_are_kept_as_written() {
+        let mxid = MatrixId::try_from("@alice:Example.COM").unwrap();
+        assert_eq!(mxid.domain(), "Example.COM");
+        assert!(mxid.has_domain("example.com"));
+        assert!(!mxid.has_domain("example.org"));
+        let parsed: MatrixId = serde_json::from_str("\"@alice:EXAMPLE.com\"").unwrap();
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), "\"@alice:EXAMPLE.com\"");
+
+        // only IDs minted here are normalised
+        assert_eq!(MatrixId::new("alice", "Example.com").unwrap().as_str(), "@alice:example.com");
+    }
+
+    #[test]
+    fn mxids_with_ports() {
+        let mxid = MatrixId::new("alice", "Example.com:8448").unwrap();
+        assert_eq!(mxid.as_str(), "@alice:example.com:8448");
+        assert_eq!(mxid.localpart(), "alice");
+        assert_eq!(mxid.domain(), "example.com:8448");
+        assert!(mxid.has_domain("example.com:8448"));
+        assert!(!mxid.has_domain("example.com"));
+
+        let parsed = MatrixId::try_from("@bob:EXAMPLE.com:8448").unwrap();
+        assert_eq!(parsed.domain(), "EXAMPLE.com:8448");
+        assert!(parsed.has_domain("example.com:8448"));
+        assert!(MatrixId::try_from("@bob:example.com:8448:1").is_err());
+        assert!(MatrixId::try_from("@bob").is_err());
+    }
+
+    #[test]
+    fn room_aliases() {
+        let alias = RoomAliasId::try_from("#lobby:Example.org").unwrap();
+        assert_eq!(alias.as_str(), "#lobby:Example.org");
+        assert!(alias.has_domain("example.org"));
+        assert_eq!(alias.localpart(), "lobby");
+        assert_eq!(alias.normalized().as_str(), "#lobby:example.org");
+        assert_eq!(RoomAliasId::new("lobby", "Example.org").unwrap().as_str(), "#lobby:example.org");
+
+        assert_eq!(RoomAliasId::try_from(alias.to_string()).unwrap(), alias);
+
+        // aliases can use characters that user IDs can't
+        let alias = RoomAliasId::try_from("#Café_Ünïcode!:example.org").unwrap();
+        assert_eq!(alias.to_string(), "#Café_Ünïcode!:example.org");
+        assert_eq!(RoomAliasId::try_from(alias.to_string()).unwrap(), alias);
+
+        assert!(RoomAliasId::try_from("#lob\u{0}by:example.org").is_err());
+        assert!(RoomAliasId::try_from("#lob\u{7f}by:example.org").is_err());
+        assert!(RoomAliasId::try_from("lobby:example.org").is_err());
+        assert!(RoomAliasId::try_from("#lobby").is_err());
+        assert!(RoomAliasId::try_from("#:example.org").is_err());
+        assert!(RoomAliasId::try_from("#the lobby:example.org").is_err());
+        assert!(RoomAliasId::try_from("#lobby:example.org:8448:1").is_err());
+
+        let alias = RoomAliasId::try_from("#lobby:Example.com:8448").unwrap();
+        assert_eq!(alias.domain(), "Example.com:8448");
+        assert!(alias.has_domain("example.com:8448"));
+        assert!(!alias.has_domain("example.com"));
+        assert_eq!(alias.normalized().as_str(), "#lobby:example.com:8448");
+        let alias = RoomAliasId::new("lobby", "Example.com:8448").unwrap();
+        assert_eq!(alias.as_str(), "#lobby:example.com:8448");
+        assert_eq!(RoomAliasId::try_from(alias.to_string()).unwrap(), alias);
+    }
+
+    #[test]
+    fn room_ids() {
+        let room_id = RoomId::try_from("!AbC123:Example.org:8448").unwrap();
+        assert_eq!(room_id.as_str(), "!AbC123:Example.org:8448");
+        assert_eq!(room_id.domain(), "Example.org:8448");
+        assert!(room_id.has_domain("example.org:8448"));
+
+        assert!(RoomId::try_from("AbC123:example.org").is_err());
+        assert!(RoomId::try_from("!AbC123").is_err());
+        assert!(RoomId::try_from("!:example.org").is_err());
+        assert!(RoomId::try_from("!AbC123:exa mple.org").is_err());
     }
 }
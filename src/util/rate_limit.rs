@@ -0,0 +1,69 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::error::{Error, ErrorKind};
+
+#[derive(Clone, Copy, Deserialize)]
+pub struct RateLimitConfig {
+    /// How many failed attempts can be made in each window
+    pub attempts: u32,
+    /// How long a window lasts, in milliseconds
+    pub window_ms: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            attempts: 5,
+            window_ms: 60 * 1000,
+        }
+    }
+}
+
+/// Limits how often something can be failed at for each key (e.g. logging in as a user from an
+/// address), by counting failed attempts in fixed windows. Only failures count, so someone who
+/// keeps succeeding is never limited.
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    /// key -> when its window started, and how many failed attempts were made in it
+    windows: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        RateLimiter {
+            config,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fails with `M_LIMIT_EXCEEDED` if there have been too many failed attempts for `key`
+    /// already.
+    pub fn check(&self, key: &str) -> Result<(), Error> {
+        let mut windows = self.windows.lock().unwrap();
+        self.expire(&mut windows);
+        match windows.get(key) {
+            Some((_, failures)) if *failures >= self.config.attempts => {
+                Err(ErrorKind::LimitExceeded.into())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a failed attempt for `key`.
+    pub fn record_failure(&self, key: &str) {
+        let mut windows = self.windows.lock().unwrap();
+        self.expire(&mut windows);
+        windows.entry(key.to_owned()).or_insert((Instant::now(), 0)).1 += 1;
+    }
+
+    fn expire(&self, windows: &mut HashMap<String, (Instant, u32)>) {
+        let window = Duration::from_millis(self.config.window_ms);
+        let now = Instant::now();
+        windows.retain(|_, (start, _)| now.duration_since(*start) < window);
+    }
+}
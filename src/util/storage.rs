@@ -1,8 +1,9 @@
 use async_trait::async_trait;
 use displaydoc::Display;
 use serde_json::Value as JsonValue;
+use std::collections::HashMap;
 
-use crate::{error::Error, events::{EventContent, room::Membership, room_version::{VersionedPdu, v4::UnhashedPdu}, pdu::StoredPdu}, state::{StateResolver, State}, storage::Storage, util::MatrixId};
+use crate::{error::{Error, ErrorKind}, events::{EventContent, room::Membership, room_version::{VersionedPdu, v4::UnhashedPdu}, pdu::StoredPdu}, sign::Key, state::{StateResolver, State}, storage::Storage, util::MatrixId};
 
 // TODO: builder pattern
 #[derive(Debug)]
@@ -30,9 +31,14 @@ pub enum AddEventError {
     InvalidEvent(String),
 }
 
-pub fn calc_auth_events(event: &NewEvent, state: &State) -> Vec<String> {
+pub fn calc_auth_events(event: &NewEvent, state: &State) -> Result<Vec<String>, Error> {
     let mut auth_events = Vec::new();
-    auth_events.push(state.get(("m.room.create", "")).unwrap().to_string());
+    if let EventContent::Create(_) = event.event_content {
+        return Ok(auth_events);
+    }
+    let create_event = state.get(("m.room.create", ""))
+        .ok_or_else(|| ErrorKind::Unknown(String::from("room has no create event in its state")))?;
+    auth_events.push(create_event.to_string());
     if let Some(power_levels_event) = state.get(("m.room.power_levels", "")) {
         auth_events.push(power_levels_event.to_string());
     }
@@ -40,7 +46,10 @@ pub fn calc_auth_events(event: &NewEvent, state: &State) -> Vec<String> {
         auth_events.push(member_event.to_string());
     }
     if let EventContent::Member(content) = &event.event_content {
-        if let Some(target_member_event) = state.get(("m.room.member", event.state_key.as_ref().unwrap())) {
+        let target = event.state_key.as_ref().ok_or_else(|| {
+            AddEventError::InvalidEvent(String::from("member event has no state key"))
+        })?;
+        if let Some(target_member_event) = state.get(("m.room.member", target)) {
             auth_events.push(target_member_event.to_string());
         }
         if content.membership == Membership::Join
@@ -51,23 +60,84 @@ pub fn calc_auth_events(event: &NewEvent, state: &State) -> Vec<String> {
             }
         // TODO: third party invites
     }
-    auth_events
+    Ok(auth_events)
 }
 
 #[async_trait]
 pub trait StorageExt {
+    /// Creates an event from `event`, signs it with `keys`, and adds it to the room if it passes
+    /// the auth rules.
     async fn add_event(
         &self,
         room_id: &str,
         event: NewEvent,
         state_resolver: &StateResolver,
+        keys: &HashMap<String, Key>,
     ) -> Result<String, Error>;
 
     async fn get_sender_power_level(&self, room_id: &str, event_id: &str) -> Result<u32, Error>;
 
+    /// Gets an event that another event or the room's state refers to. Such events should always
+    /// have been stored, so one that's missing is an error rather than None.
+    async fn get_referenced_pdu(&self, room_id: &str, event_id: &str) -> Result<StoredPdu, Error>;
+
     async fn create_test_users(&self) -> Result<(), Error>;
 }
 
+/// Creates an event from `event` following on from `prev_events`, signs it with `keys`, and adds
+/// it to the room if it passes the auth rules.
+async fn add_event_after(
+    db: &dyn Storage,
+    room_id: &str,
+    event: NewEvent,
+    prev_events: Vec<String>,
+    max_depth: i64,
+    state_resolver: &StateResolver,
+    keys: &HashMap<String, Key>,
+) -> Result<String, Error> {
+    let state = state_resolver.resolve(room_id, &prev_events).await?;
+
+    // a tombstoned room has been replaced, so all that's left to do in it is leave
+    let is_leave = matches!(
+        &event.event_content,
+        EventContent::Member(member) if member.membership == Membership::Leave
+    );
+    if state.get(("m.room.tombstone", "")).is_some() && !is_leave {
+        let msg = "the room has been replaced, so only leaving it is allowed";
+        return Err(ErrorKind::BadState(String::from(msg)).into());
+    }
+
+    let auth_events = calc_auth_events(&event, &state)?;
+
+    let origin = event.sender.domain().to_owned();
+    let server_name = origin.clone();
+    let unhashed = UnhashedPdu {
+        event_content: event.event_content,
+        room_id: String::from(room_id),
+        sender: event.sender,
+        state_key: event.state_key,
+        unsigned: event.unsigned,
+        redacts: event.redacts,
+        origin,
+        origin_server_ts: chrono::Utc::now().timestamp_millis(),
+        prev_events,
+        depth: max_depth.saturating_add(1),
+        auth_events,
+    };
+    let mut pdu = VersionedPdu::V4(unhashed.finalize());
+    pdu.sign(&server_name, keys);
+
+    let auth_status = crate::validate::auth::auth_check_v1(db, &pdu, &state).await?;
+    if !auth_status.is_pass() {
+        return Err(ErrorKind::Forbidden.into());
+    }
+    let stored_pdu = StoredPdu::new(pdu, auth_status);
+    let event_id = stored_pdu.event_id().to_owned();
+    db.add_pdus(&[stored_pdu]).await?;
+
+    Ok(event_id)
+}
+
 #[async_trait]
 impl<'a> StorageExt for dyn Storage + 'a {
     async fn add_event(
@@ -75,69 +145,83 @@ impl<'a> StorageExt for dyn Storage + 'a {
         room_id: &str,
         event: NewEvent,
         state_resolver: &StateResolver,
+        keys: &HashMap<String, Key>,
     ) -> Result<String, Error> {
         if let EventContent::Create(_) = event.event_content {
-            panic!("wrong function");
+            // this starts the room off, so there's nothing before it to follow on from
+            return add_event_after(self, room_id, event, Vec::new(), -1, state_resolver, keys).await;
         }
-        let (prev_events, max_depth) = self.get_prev_events(room_id).await?;
-        let state = state_resolver.resolve(room_id, &prev_events).await?;
-
-        let auth_events = calc_auth_events(&event, &state);
-
-        let origin = event.sender.domain().to_owned();
-        let unhashed = UnhashedPdu {
-            event_content: event.event_content,
-            room_id: String::from(room_id),
-            sender: event.sender,
-            state_key: event.state_key,
-            unsigned: event.unsigned,
-            redacts: event.redacts,
-            origin,
-            origin_server_ts: chrono::Utc::now().timestamp_millis(),
-            prev_events,
-            depth: max_depth.saturating_add(1),
-            auth_events,
-        };
-        let pdu = VersionedPdu::V4(unhashed.finalize());
-
-        let auth_status = crate::validate::auth::auth_check_v1(self, &pdu, &state).await?;
-        let stored_pdu = StoredPdu {
-            inner: pdu,
-            auth_status,
-        };
-        let event_id = stored_pdu.event_id().to_owned();
-        self.add_pdus(&[stored_pdu]).await?;
+        let max_prev_events = state_resolver.max_prev_events();
+        let (mut prev_events, mut max_depth) = self.get_prev_events(room_id).await?;
+        while prev_events.len() > max_prev_events {
+            // There are too many forward extremities to reference from one event, so merge some
+            // of them with an empty event first. If the sender isn't allowed to send that, just
+            // leave the rest for a later event to pick up.
+            let merge = NewEvent {
+                event_content: EventContent::new("org.matrix.dummy_event", serde_json::json!({}))
+                    .unwrap(),
+                sender: event.sender.clone(),
+                state_key: None,
+                redacts: None,
+                unsigned: None,
+            };
+            let batch = prev_events[..max_prev_events].to_vec();
+            match add_event_after(self, room_id, merge, batch, max_depth, state_resolver, keys).await {
+                Ok(_) => {},
+                Err(e) if matches!(e.kind(), ErrorKind::Forbidden) => break,
+                Err(e) => return Err(e),
+            }
+            let extremities = self.get_prev_events(room_id).await?;
+            prev_events = extremities.0;
+            max_depth = extremities.1;
+        }
+        prev_events.truncate(max_prev_events);
 
-        Ok(event_id)
+        add_event_after(self, room_id, event, prev_events, max_depth, state_resolver, keys).await
     }
 
-    //TODO: check return type
-    //TODO: should we handle users that aren't in the room
+    /// The power level of the event's sender, going by the power levels in its auth events. If
+    /// there aren't any, the room's creator has 100 and everyone else has 0, whether they're in
+    /// the room or not.
+    ///
+    /// Auth events that can't be found are an error, since the level can't be worked out without
+    /// them.
     async fn get_sender_power_level(&self, room_id: &str, event_id: &str) -> Result<u32, Error> {
-        let event = self.get_pdu(room_id, event_id).await?.expect("event not found");
-        let mut create_event_content = None;
+        let event = self.get_pdu(room_id, event_id).await?.ok_or(ErrorKind::NotFound)?;
+        let mut creator = match event.event_content() {
+            EventContent::Create(create) => Some(create.effective_creator(event.sender()).clone()),
+            _ => None,
+        };
         for auth_event_id in event.auth_events().iter() {
-            let auth_event = self.get_pdu(room_id, auth_event_id).await?.expect("event not found");
+            let auth_event = self.get_referenced_pdu(room_id, auth_event_id).await?;
             match auth_event.event_content() {
                 EventContent::PowerLevels(levels) => {
                     return Ok(levels.get_user_level(event.sender()));
                 },
                 EventContent::Create(create) => {
-                    create_event_content = Some(create.clone());
+                    creator = Some(create.effective_creator(auth_event.sender()).clone());
                 },
                 _ => {},
             }
         }
 
         // at this point there is no power levels event
-        if *event.sender() == create_event_content.expect("event has no create in auth").creator {
-            return Ok(100);
+        let creator = creator.ok_or_else(|| {
+            ErrorKind::Unknown(format!("event {} has no create event in its auth events", event_id))
+        })?;
+        if *event.sender() == creator {
+            Ok(100)
         } else {
-            return Ok(0);
+            Ok(0)
         }
     }
 
-
+    async fn get_referenced_pdu(&self, room_id: &str, event_id: &str) -> Result<StoredPdu, Error> {
+        self.get_pdu(room_id, event_id).await?.ok_or_else(|| {
+            let msg = format!("event {} in {} is referred to but missing", event_id, room_id);
+            ErrorKind::Unknown(msg).into()
+        })
+    }
 
     async fn create_test_users(&self) -> Result<(), Error> {
         // all passwords are "password"
@@ -153,3 +237,44 @@ impl<'a> StorageExt for dyn Storage + 'a {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, convert::TryFrom};
+
+    use crate::{
+        events::{EventContent, room::{Membership, Tombstone}},
+        test_util::{assert_errcode, leave_event, message_event, state_event, RoomBuilder, with_mem_db},
+        util::{MatrixId, RoomId},
+    };
+
+    use super::StorageExt;
+
+    #[test]
+    fn only_leaving_after_tombstone() {
+        with_mem_db(|db, state_resolver| async move {
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            let keys = HashMap::new();
+            let room = RoomBuilder::new(&*db, &state_resolver, "!old:example.org", &alice)
+                .join(&bob)
+                .build()
+                .await;
+            let tombstone = EventContent::Tombstone(Tombstone {
+                body: String::from("moved"),
+                replacement_room: Some(RoomId::try_from("!new:example.org").unwrap()),
+            });
+
+            db.add_event(&room.room_id, message_event(&bob, "hello?"), &state_resolver, &keys)
+                .await.unwrap();
+            db.add_event(&room.room_id, state_event(&alice, tombstone, ""), &state_resolver, &keys)
+                .await.unwrap();
+            let message = message_event(&alice, "hello?");
+            let res = db.add_event(&room.room_id, message, &state_resolver, &keys).await;
+            assert_errcode!(res, "M_BAD_STATE");
+            db.add_event(&room.room_id, leave_event(&bob, &bob), &state_resolver, &keys)
+                .await.unwrap();
+            assert_eq!(db.get_membership(&bob, &room.room_id).await.unwrap(), Some(Membership::Leave));
+        });
+    }
+}
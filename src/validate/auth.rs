@@ -2,7 +2,7 @@ use std::{collections::HashMap, convert::TryFrom};
 
 use serde::{Deserialize, Serialize};
 
-use crate::{error::Error, events::{EventContent, room::{Create, JoinRule, JoinRules, Member, Membership, PowerLevels}, room_version::VersionedPdu}, state::State, storage::Storage, util::MatrixId};
+use crate::{error::Error, events::{EventContent, room::{JoinRule, JoinRules, Member, Membership, PowerLevels}, room_version::{self, VersionedPdu}}, state::State, storage::Storage, util::{MatrixId, StorageExt}};
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum AuthStatus {
@@ -19,16 +19,20 @@ impl AuthStatus {
 pub async fn auth_check_v1(db: &dyn Storage, pdu: &VersionedPdu, state: &State) -> Result<AuthStatus, Error> {
     use AuthStatus::{Pass, Fail};
 
-    // This function panics a lot eg when auth_events or prev_events don't exist. This is
-    // intentional at the moment because if we're crafting a new event and we get that stuff
-    // wrong it's a program error, and I think if we're receiving an event via federation then
-    // we should have already attempted to receive any missing {auth,prev}_events
+    // This function returns errors when auth_events or prev_events don't exist, rather than
+    // failing the event. This is intentional at the moment because if we're crafting a new event
+    // and we get that stuff wrong it's a program error, and I think if we're receiving an event
+    // via federation then we should have already attempted to receive any missing
+    // {auth,prev}_events. Events that are malformed in themselves just fail.
     if let EventContent::Create(_) = pdu.event_content() {
         if !pdu.prev_events().is_empty() {
             return Ok(Fail);
         }
-        let room_id_domain = pdu.room_id().split_once(':').expect("invalid room id").1;
-        if pdu.sender().domain() != room_id_domain {
+        let room_id_domain = match pdu.room_id().split_once(':') {
+            Some((_, domain)) => domain,
+            None => return Ok(Fail),
+        };
+        if !pdu.sender().has_domain(room_id_domain) {
             return Ok(Fail);
         }
         // cant check room version if v4 is embedded in the type system lmao
@@ -37,8 +41,13 @@ pub async fn auth_check_v1(db: &dyn Storage, pdu: &VersionedPdu, state: &State)
 
     let mut auth_events = HashMap::new();
     for event_id in pdu.auth_events().iter() {
-        let pdu = db.get_pdu(&pdu.room_id(), event_id).await?.expect("auth event doesn't exist");
-        auth_events.insert((pdu.event_content().get_type().to_string(), pdu.state_key().expect("auth event isn't state").to_string()), pdu);
+        let pdu = db.get_referenced_pdu(&pdu.room_id(), event_id).await?;
+        let state_key = match pdu.state_key() {
+            Some(state_key) => state_key.to_string(),
+            // only state events can authorise others
+            None => return Ok(Fail),
+        };
+        auth_events.insert((pdu.event_content().get_type().to_string(), state_key), pdu);
     }
 
     if !auth_events.contains_key(&("m.room.create".to_string(), "".to_string())) {
@@ -54,11 +63,22 @@ pub async fn auth_check_v1(db: &dyn Storage, pdu: &VersionedPdu, state: &State)
         // also 4-3 is misleading because it looks like a short circuit but isnt wheeeeeee
     }
 
-    let creator = state.get_content::<Create>(db, "").await?.unwrap().creator;
+    let create_event = &auth_events[&("m.room.create".to_string(), "".to_string())];
+    let (creator, room_version) = match create_event.event_content() {
+        EventContent::Create(create) => {
+            (create.effective_creator(create_event.sender()).clone(), create.version())
+        },
+        _ => return Ok(Fail),
+    };
     let power_levels = state.get_content::<PowerLevels>(db, "").await?
         .unwrap_or_else(|| PowerLevels::no_event_default_levels(&creator));
 
     if let EventContent::Member(content) = &pdu.event_content() {
+        // member events are about the user in their state key
+        let target_user_id = match pdu.state_key().map(MatrixId::try_from) {
+            Some(Ok(target_user_id)) => target_user_id,
+            _ => return Ok(Fail),
+        };
         match content.membership {
             Membership::Join => {
                 // do step 5-2-2 before 5-2-1 because it makes more sense
@@ -69,18 +89,13 @@ pub async fn auth_check_v1(db: &dyn Storage, pdu: &VersionedPdu, state: &State)
 
                 // if the room has just been created by this user, allow them to join
                 if pdu.prev_events().len() == 1 {
-                    let prev_event = db.get_pdu(&pdu.room_id(), &pdu.prev_events()[0]).await?
-                        .expect("prev_event doesn't exist");
+                    let prev_event = db.get_referenced_pdu(&pdu.room_id(), &pdu.prev_events()[0]).await?;
                     if let EventContent::Create(create_content) = prev_event.event_content() {
-                        if *pdu.sender() == create_content.creator {
+                        if pdu.sender() == create_content.effective_creator(prev_event.sender()) {
                             return Ok(Pass);
                         }
-                    } else {
-                        // oh no
-                        panic!("oh no");
                     }
-                    // not so sure about this bit
-                    return Ok(Fail);
+                    // otherwise this is just a join with a single prev_event, so carry on
                 }
 
                 // get the user's membership in this room if they have one
@@ -96,12 +111,16 @@ pub async fn auth_check_v1(db: &dyn Storage, pdu: &VersionedPdu, state: &State)
                 let join_rule = state.get_content::<JoinRules>(db, "").await?
                     .map(|c| c.join_rule);
 
-                if join_rule == Some(JoinRule::Invite)
-                    && (membership == Some(Membership::Join) || membership == Some(Membership::Invite)) {
-                        return Ok(Pass);
-                    } else if join_rule == Some(JoinRule::Public) {
-                        return Ok(Pass);
-                    }
+                // join rules from later room versions mean nothing in earlier ones, and restricted
+                // joins are left out until rooms can be created in version 8
+                let invite_only = join_rule == Some(JoinRule::Invite)
+                    || (join_rule == Some(JoinRule::Knock) && room_version::has_knocking(room_version));
+                let already_in = membership == Some(Membership::Join) || membership == Some(Membership::Invite);
+                if invite_only && already_in {
+                    return Ok(Pass);
+                } else if join_rule == Some(JoinRule::Public) {
+                    return Ok(Pass);
+                }
 
                 return Ok(Fail);
             },
@@ -118,8 +137,7 @@ pub async fn auth_check_v1(db: &dyn Storage, pdu: &VersionedPdu, state: &State)
                 }
 
                 // can't invite people if they're banned or already in
-                let target_user_id = pdu.state_key().clone().expect("invitation has no target");
-                let target_user_membership = state.get_content::<Member>(db, &target_user_id).await?
+                let target_user_membership = state.get_content::<Member>(db, target_user_id.as_str()).await?
                     .map(|c| c.membership);
                 match target_user_membership {
                     Some(Membership::Join | Membership::Ban) => return Ok(Fail),
@@ -138,10 +156,11 @@ pub async fn auth_check_v1(db: &dyn Storage, pdu: &VersionedPdu, state: &State)
                     .map(|c| c.membership);
 
                 // if a user is leaving of their own accord, only allow it if they were
-                // previously in the room, or if they are declining an invite
+                // previously in the room, or if they are declining an invite or taking back a
+                // knock
                 if pdu.state_key().as_deref() == Some(pdu.sender().as_str()) {
                     match sender_membership {
-                        Some(Membership::Join | Membership::Invite) => return Ok(Pass),
+                        Some(Membership::Join | Membership::Invite | Membership::Knock) => return Ok(Pass),
                         _ => return Ok(Fail),
                     }
                 }
@@ -151,8 +170,7 @@ pub async fn auth_check_v1(db: &dyn Storage, pdu: &VersionedPdu, state: &State)
                     return Ok(Fail);
                 }
 
-                let target_user_id = pdu.state_key().clone().expect("kick has no target");
-                let target_user_membership = state.get_content::<Member>(db, &target_user_id).await?
+                let target_user_membership = state.get_content::<Member>(db, target_user_id.as_str()).await?
                     .map(|c| c.membership);
 
                 // can't turn someone's ban to a kick if you don't have permission to unban
@@ -164,9 +182,7 @@ pub async fn auth_check_v1(db: &dyn Storage, pdu: &VersionedPdu, state: &State)
                 // can only kick someone if you have permission to kick, and they're lower than
                 // you in power level
                 let sender_level = power_levels.get_user_level(&pdu.sender());
-                let target_level = power_levels.get_user_level(
-                    &MatrixId::try_from(target_user_id).expect("target not valid matrix id")
-                    );
+                let target_level = power_levels.get_user_level(&target_user_id);
                 if sender_level >= power_levels.kick() && sender_level > target_level {
                     return Ok(Pass);
                 }
@@ -183,18 +199,37 @@ pub async fn auth_check_v1(db: &dyn Storage, pdu: &VersionedPdu, state: &State)
                 }
 
                 let sender_level = power_levels.get_user_level(&pdu.sender());
-                let target_user_id = pdu.state_key().clone().expect("ban has no target");
-                let target_level = power_levels.get_user_level(
-                    &MatrixId::try_from(target_user_id).expect("target not valid matrix id")
-                    );
+                let target_level = power_levels.get_user_level(&target_user_id);
 
                 if sender_level >= power_levels.ban() && sender_level > target_level {
                     return Ok(Pass);
                 }
 
                 return Ok(Fail);
-            }
-            _ => return Ok(Fail),
+            },
+            Membership::Knock => {
+                // only rooms that say so can be knocked on, and only from the room versions with
+                // the join rule that says so
+                let join_rule = state.get_content::<JoinRules>(db, "").await?.map(|c| c.join_rule);
+                let knockable = join_rule == Some(JoinRule::Knock) && room_version::has_knocking(room_version);
+                if !knockable {
+                    return Ok(Fail);
+                }
+
+                // users can only knock for themselves
+                if pdu.state_key() != Some(pdu.sender().as_str()) {
+                    return Ok(Fail);
+                }
+
+                // there's no need to knock if you're already in or invited, and no point if
+                // you're banned
+                let membership = state.get_content::<Member>(db, pdu.sender().as_str()).await?
+                    .map(|c| c.membership);
+                match membership {
+                    Some(Membership::Ban | Membership::Invite | Membership::Join) => return Ok(Fail),
+                    _ => return Ok(Pass),
+                }
+            },
         }
     }
 
@@ -263,6 +298,12 @@ pub async fn auth_check_v1(db: &dyn Storage, pdu: &VersionedPdu, state: &State)
             && (old_power_levels.users_default() > sender_level || new_power_levels.users_default() > sender_level) {
                 return Ok(Fail);
             }
+        let (old_notify, new_notify) = (old_power_levels.notifications().room, new_power_levels.notifications().room);
+        if room_version::has_notifications_power_level(room_version)
+            && old_notify != new_notify
+            && (old_notify > sender_level || new_notify > sender_level) {
+                return Ok(Fail);
+            }
 
         for (key, new_value) in new_power_levels.events.iter() {
             let old_value = old_power_levels.events.get(key);
@@ -318,6 +359,20 @@ pub async fn auth_check_v1(db: &dyn Storage, pdu: &VersionedPdu, state: &State)
         if sender_level >= power_levels.redact() {
             return Ok(Pass);
         }
+        // anyone can redact their own events, as long as they could still send them
+        let redacted = match pdu.redacts() {
+            Some(event_id) => db.get_pdu(pdu.room_id(), event_id).await?,
+            None => None,
+        };
+        if let Some(redacted) = redacted {
+            let required_level = power_levels.get_event_level(
+                redacted.event_content().get_type(),
+                redacted.state_key().is_some(),
+            );
+            if redacted.sender() == pdu.sender() && sender_level >= required_level {
+                return Ok(Pass);
+            }
+        }
 
         //TODO: figure out how to handle 11-2, given event id domains don't exist past room
         // version 4
@@ -327,3 +382,192 @@ pub async fn auth_check_v1(db: &dyn Storage, pdu: &VersionedPdu, state: &State)
 
     Ok(Pass)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        events::{
+            room::{JoinRule, JoinRules, Retention},
+            EventContent, pdu::StoredPdu, room_version::{v4::UnhashedPdu, VersionedPdu},
+        },
+        test_util::{assert_errcode, join_event, message_event, state_event, RoomBuilder, with_mem_db},
+        util::{MatrixId, StorageExt, storage::NewEvent},
+    };
+
+    use super::AuthStatus;
+
+    #[test]
+    fn retention_needs_power() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            let room_id = "!retention:example.org";
+
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+            let power_levels = EventContent::new("m.room.power_levels", serde_json::json!({
+                "users": { alice.as_str(): 100 },
+                "events": { "m.room.retention": 100 },
+            })).unwrap();
+            db.add_event(room_id, state_event(&alice, power_levels, ""), &state_resolver, &keys)
+                .await.unwrap();
+            db.add_event(room_id, join_event(&bob), &state_resolver, &keys).await.unwrap();
+
+            let retention = || EventContent::Retention(Retention {
+                max_lifetime: Some(1000),
+                min_lifetime: None,
+            });
+            let err = db.add_event(room_id, state_event(&bob, retention(), ""), &state_resolver, &keys)
+                .await
+                .expect_err("user without power set a retention policy");
+            assert_errcode!(err, "M_FORBIDDEN");
+            assert!(db.get_state_event(room_id, "m.room.retention", "").await.unwrap().is_none());
+
+            db.add_event(room_id, state_event(&alice, retention(), ""), &state_resolver, &keys)
+                .await.unwrap();
+            assert!(db.get_state_event(room_id, "m.room.retention", "").await.unwrap().is_some());
+        });
+    }
+
+    #[test]
+    fn notifications_need_power() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            let power_levels = |notify: u32| EventContent::new("m.room.power_levels", serde_json::json!({
+                "users": { alice.as_str(): 100, bob.as_str(): 50 },
+                "notifications": { "room": notify },
+            })).unwrap();
+
+            // from room version 6, bob can't put notifying the room out of their own reach
+            for (room_id, room_version, allowed) in &[("!v4:example.org", "4", true), ("!v7:example.org", "7", false)] {
+                RoomBuilder::new(&*db, &state_resolver, room_id, &alice)
+                    .room_version(room_version)
+                    .join(&bob)
+                    .build()
+                    .await;
+                db.add_event(room_id, state_event(&alice, power_levels(50), ""), &state_resolver, &keys)
+                    .await.unwrap();
+                let res = db.add_event(room_id, state_event(&bob, power_levels(100), ""), &state_resolver, &keys)
+                    .await;
+                assert_eq!(res.is_ok(), *allowed, "room version {}", room_version);
+            }
+        });
+    }
+
+    #[test]
+    fn redact_own_events() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            let room_id = "!redactions:example.org";
+
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice).build().await;
+            db.add_event(room_id, join_event(&bob), &state_resolver, &keys).await.unwrap();
+            let redaction = |sender: &MatrixId, event_id: &str| NewEvent {
+                event_content: EventContent::new("m.room.redaction", serde_json::json!({})).unwrap(),
+                sender: sender.clone(),
+                state_key: None,
+                redacts: Some(String::from(event_id)),
+                unsigned: None,
+            };
+            let bobs = db.add_event(room_id, message_event(&bob, "oops"), &state_resolver, &keys)
+                .await.unwrap();
+            let alices = db.add_event(room_id, message_event(&alice, "oops"), &state_resolver, &keys)
+                .await.unwrap();
+
+            // bob has the default power level, which is below the level needed to redact
+            db.add_event(room_id, redaction(&bob, &bobs), &state_resolver, &keys).await
+                .expect("user couldn't redact their own message");
+            let content = |pdu: Option<StoredPdu>| pdu.unwrap().event_content().content_as_json();
+            assert_eq!(content(db.get_pdu(room_id, &bobs).await.unwrap()), serde_json::json!({}));
+            let err = db.add_event(room_id, redaction(&bob, &alices), &state_resolver, &keys)
+                .await
+                .expect_err("user without power redacted someone else's message");
+            assert_errcode!(err, "M_FORBIDDEN");
+            assert_eq!(content(db.get_pdu(room_id, &alices).await.unwrap())["body"], "oops");
+        });
+    }
+
+    #[test]
+    fn creator_from_sender() {
+        with_mem_db(|db, state_resolver| async move {
+            let keys = HashMap::new();
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let bob = MatrixId::new("bob", "example.org").unwrap();
+            let room_id = "!v11:example.org";
+
+            // room version 11 leaves the creator out of the content
+            RoomBuilder::new(&*db, &state_resolver, room_id, &alice)
+                .room_version("11")
+                .build()
+                .await;
+            let (alices, _) = db.get_prev_events(room_id).await.unwrap();
+            assert_eq!(db.get_sender_power_level(room_id, &alices[0]).await.unwrap(), 100);
+            let bob_join = db.add_event(room_id, join_event(&bob), &state_resolver, &keys).await.unwrap();
+            assert_eq!(db.get_sender_power_level(room_id, &bob_join).await.unwrap(), 0);
+
+            // without a power levels event, only the creator has the power to redact others
+            let redaction = |sender: &MatrixId| NewEvent {
+                event_content: EventContent::new("m.room.redaction", serde_json::json!({})).unwrap(),
+                sender: sender.clone(),
+                state_key: None,
+                redacts: Some(bob_join.clone()),
+                unsigned: None,
+            };
+            db.add_event(room_id, redaction(&alice), &state_resolver, &keys).await
+                .expect("creator couldn't redact");
+        });
+    }
+
+    #[test]
+    fn broken_auth_chain() {
+        with_mem_db(|db, state_resolver| async move {
+            let alice = MatrixId::new("alice", "example.org").unwrap();
+            let carol = MatrixId::new("carol", "example.org").unwrap();
+            let room_id = "!broken:example.org";
+            let room = RoomBuilder::new(&*db, &state_resolver, room_id, &alice)
+                .join_rules(JoinRules {
+                    join_rule: JoinRule::Invite,
+                    allow: Vec::new(),
+                })
+                .build()
+                .await;
+            let create_id = room.event_ids[0].clone();
+
+            let message = |sender: &MatrixId, auth_events: Vec<String>| UnhashedPdu {
+                event_content: EventContent::new("m.room.message", serde_json::json!({
+                    "msgtype": "m.text",
+                    "body": "hello",
+                })).unwrap(),
+                room_id: String::from(room_id),
+                sender: sender.clone(),
+                state_key: None,
+                unsigned: None,
+                redacts: None,
+                origin: String::from(sender.domain()),
+                origin_server_ts: 0,
+                prev_events: vec![create_id.clone()],
+                depth: 1,
+                auth_events,
+            }.finalize();
+
+            // carol isn't in the room, so with no power levels they have the default
+            let outsider = message(&carol, vec![create_id.clone()]);
+            let broken = message(&alice, vec![String::from("$missing:example.org")]);
+            let (outsider_id, broken_id) = (outsider.event_id(), broken.event_id());
+            db.add_pdus(&[
+                StoredPdu::new(VersionedPdu::V4(outsider), AuthStatus::Pass),
+                StoredPdu::new(VersionedPdu::V4(broken), AuthStatus::Pass),
+            ]).await.unwrap();
+            assert_eq!(db.get_sender_power_level(room_id, &outsider_id).await.unwrap(), 0);
+            assert_eq!(db.get_sender_power_level(room_id, &create_id).await.unwrap(), 100);
+            assert!(db.get_sender_power_level(room_id, &broken_id).await.is_err());
+            assert!(db.get_sender_power_level(room_id, "$nonexistent:example.org").await.is_err());
+        });
+    }
+}